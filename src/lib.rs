@@ -0,0 +1,3 @@
+//! PlayStation emulator. `psx::System` runs the console, the `psx` module
+//! also holds the memory card manager and the STR player tools
+pub mod psx;
@@ -1,8 +1,461 @@
-use anyhow::Result;
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+    thread,
+    time::{Duration, Instant},
+};
 
-mod psx;
+use anyhow::{anyhow, Result};
+
+use psx_rust::psx::{self, memcard_manager, str_player};
 
 fn main() -> Result<()> {
-    psx::run()?;
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    match args.first().map(|a| a.as_str()) {
+        Some("str") => str_player::play(&str_options(&args[1..])?)?,
+        Some("memcard") => memcard_manager::run(&memcard_command(&args[1..])?)?,
+        _ => run(run_options(&args)?)?,
+    }
+
     Ok(())
 }
+
+const USAGE: &str = "usage: psx-rust [options] [<disc image> | <file.exe>]
+       psx-rust str <file.STR | disc image> [--file <path>] [--out <dir>]
+       psx-rust memcard <list|copy|delete|export|import> ...
+
+options:
+  --bios <file>             BIOS image (default ./bios/scph1001.bin)
+  --bios-dir <dir>          pick a known BIOS matching the region from <dir>,
+                            or an OpenBIOS image
+  --hle-bios                built-in kernel instead of a BIOS image
+  --fast-boot               patch the BIOS to skip the intro
+  --bios-tty                patch the BIOS to enable its TTY output
+                            (no region check patch: boot discs of other
+                            regions with OpenBIOS or --hle-bios)
+  --disc <image>            disc image to boot
+  --exe <file>              PS-X EXE to side-load
+  --region <ntsc-j|ntsc-u|pal>
+                            run at the refresh rate of the region and pick
+                            its BIOS with --bios-dir, the video mode is
+                            still set by the software
+  --port1/--port2 <devices> controller or multitap:<slot>,<slot>,<slot>,<slot>
+                            with slots as <controller>[+<card.mcr>]
+  --memcard1/--memcard2 <file.mcr>
+  --dualshock               DualShock in port 1
+  --link <listen|connect>:<port>
+                            link cable to another instance
+  --exp-rom <file>          cartridge ROM in the parallel port
+  --cart-switch             cartridge switch on
+  --parallel-link <listen|connect>:<port>
+                            host link on the cartridge parallel port
+  --devkit                  8MiB of RAM
+  --strict                  halt on accesses to unmapped addresses
+  --headless                run as fast as possible
+  --frames <n>              stop after n frames
+  --instructions <n>        stop after n instructions
+  --trace <file | ->        log every executed instruction
+  --exit-on <pc=<address> | tty=<text>>
+                            stop when the condition is met, with an error if
+                            a limit is reached first";
+
+/// Emulator run settings, on top of the machine configuration
+struct RunOptions {
+    config: psx::Config,
+    bios: Option<PathBuf>,
+    /// Directory to pick a BIOS from when none is given
+    bios_dir: Option<PathBuf>,
+    disc: Option<PathBuf>,
+    exe: Option<PathBuf>,
+    /// Don't slow down to real time
+    headless: bool,
+    max_frames: Option<u64>,
+    max_instructions: Option<u64>,
+    /// Instruction trace output, "-" for stdout
+    trace: Option<PathBuf>,
+    exit_on: Vec<ExitCondition>,
+}
+
+/// Condition that ends the emulation successfully
+enum ExitCondition {
+    /// The CPU is about to run the instruction at this address
+    Pc(u32),
+    /// The software printed this text through the BIOS
+    Tty(String),
+}
+
+fn run(mut options: RunOptions) -> Result<()> {
+    let mut builder = psx::System::builder();
+
+    if !options.config.hle_bios {
+        let path = bios_path(&options)?;
+        let bios = fs::read(&path).map_err(|e| anyhow!("Can't read BIOS {}: {}", path.display(), e))?;
+
+        builder = builder.bios(bios);
+    }
+
+    builder = builder.config(std::mem::take(&mut options.config));
+
+    if let Some(path) = &options.exe {
+        let exe = fs::read(path).map_err(|e| anyhow!("Can't read {}: {}", path.display(), e))?;
+        builder = builder.exe(exe);
+    }
+
+    if let Some(path) = &options.disc {
+        builder = builder.disc(path);
+    }
+
+    let mut system = builder.build()?;
+
+    if let Some(check) = system.bios_check() {
+        report_bios(check);
+    }
+
+    if let Some(path) = &options.trace {
+        let trace: Box<dyn Write> = match path.to_str() {
+            Some("-") => Box::new(io::stdout()),
+            _ => Box::new(BufWriter::new(
+                File::create(path).map_err(|e| anyhow!("Can't create {}: {}", path.display(), e))?,
+            )),
+        };
+
+        system.set_trace(Some(trace));
+    }
+
+    let result = emulate(&mut system, &options);
+
+    // Save what the memory cards still hold, even when stopping on an error
+    let flushed = system.flush_memory_cards(true);
+
+    result.and(flushed)
+}
+
+/// Run until an exit condition or a limit is reached
+fn emulate(system: &mut psx::System, options: &RunOptions) -> Result<()> {
+    let exit_pcs: Vec<u32> = options
+        .exit_on
+        .iter()
+        .filter_map(|c| match c {
+            ExitCondition::Pc(pc) => Some(*pc),
+            _ => None,
+        })
+        .collect();
+
+    let start = Instant::now();
+    let mut tty = String::new();
+    let mut frames = 0u64;
+    let mut instructions = 0u64;
+
+    loop {
+        let frame_end = system.cycles() + system.frame_cycles();
+
+        while system.cycles() < frame_end {
+            if exit_pcs.contains(&system.registers().pc) {
+                return Ok(());
+            }
+
+            if options.max_instructions == Some(instructions) {
+                return limit_reached(&options.exit_on, "instructions");
+            }
+
+            system.step()?;
+            instructions += 1;
+        }
+
+        frames += 1;
+
+        system.flush_memory_cards(false)?;
+
+        let output = system.take_tty();
+        print!("{}", output);
+        tty.push_str(&output);
+
+        for condition in &options.exit_on {
+            if let ExitCondition::Tty(text) = condition {
+                if tty.contains(text.as_str()) {
+                    return Ok(());
+                }
+            }
+        }
+
+        if options.max_frames == Some(frames) {
+            return limit_reached(&options.exit_on, "frames");
+        }
+
+        if !options.headless {
+            let emulated = Duration::from_secs_f64(system.cycles() as f64 / psx::CPU_CLOCK_HZ as f64);
+
+            if let Some(ahead) = emulated.checked_sub(start.elapsed()) {
+                thread::sleep(ahead);
+            }
+        }
+    }
+}
+
+/// Print what the BIOS is, warning about unknown or corrupt images
+fn report_bios(check: psx::BiosCheck) {
+    match check {
+        psx::BiosCheck::Known(b) => println!("BIOS {} v{} ({}, {})", b.model, b.version, b.region, b.date),
+        psx::BiosCheck::OpenBios => println!("OpenBIOS replacement BIOS"),
+        psx::BiosCheck::Unknown(Some(date)) => {
+            println!("Warning: unknown BIOS dated {:08x}, it may be a bad dump", date)
+        }
+        psx::BiosCheck::Unknown(None) => println!("Warning: unknown BIOS, it may be a bad dump"),
+        psx::BiosCheck::Invalid => println!("Warning: the image doesn't look like a PlayStation BIOS"),
+    }
+}
+
+/// BIOS given on the command line, or the one from the BIOS directory
+/// matching the forced region, or the disc region, or NTSC-U
+fn bios_path(options: &RunOptions) -> Result<PathBuf> {
+    if let Some(path) = &options.bios {
+        return Ok(path.clone());
+    }
+
+    let Some(dir) = &options.bios_dir else {
+        return Ok(PathBuf::from("./bios/scph1001.bin"));
+    };
+
+    let disc_region = match &options.disc {
+        Some(disc) => psx::disc_region(disc)?,
+        None => None,
+    };
+
+    let region = options.config.region.or(disc_region).unwrap_or(psx::Region::NtscU);
+
+    psx::select_bios(dir, region)
+}
+
+/// The frame or instruction limit stopped the emulation
+fn limit_reached(exit_on: &[ExitCondition], limit: &str) -> Result<()> {
+    match exit_on.is_empty() {
+        true => Ok(()),
+        false => Err(anyhow!("Exit condition not met before the {} limit", limit)),
+    }
+}
+
+/// Parse the emulator options, see `USAGE`
+fn run_options(args: &[String]) -> Result<RunOptions> {
+    let mut config = psx::Config::new();
+    let mut options = RunOptions {
+        config: psx::Config::new(),
+        bios: None,
+        bios_dir: None,
+        disc: None,
+        exe: None,
+        headless: false,
+        max_frames: None,
+        max_instructions: None,
+        trace: None,
+        exit_on: Vec::new(),
+    };
+    let mut memory_cards = [None, None];
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| anyhow!("Missing value for {}", arg));
+
+        match arg.as_str() {
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            "--bios" => options.bios = Some(PathBuf::from(value()?)),
+            "--bios-dir" => options.bios_dir = Some(PathBuf::from(value()?)),
+            "--hle-bios" => config.hle_bios = true,
+            "--fast-boot" => config.fast_boot = true,
+            "--bios-tty" => config.bios_tty = true,
+            "--disc" => options.disc = Some(PathBuf::from(value()?)),
+            "--exe" => options.exe = Some(PathBuf::from(value()?)),
+            "--region" => config.region = Some(region(value()?)?),
+            "--headless" => options.headless = true,
+            "--frames" => options.max_frames = Some(count(value()?)?),
+            "--instructions" => options.max_instructions = Some(count(value()?)?),
+            "--trace" => options.trace = Some(PathBuf::from(value()?)),
+            "--exit-on" => options.exit_on.push(exit_condition(value()?)?),
+            "--dualshock" => config.ports[0] = port_config("dualshock")?,
+            "--port1" => config.ports[0] = port_config(value()?)?,
+            "--port2" => config.ports[1] = port_config(value()?)?,
+            "--memcard1" => memory_cards[0] = Some(PathBuf::from(value()?)),
+            "--memcard2" => memory_cards[1] = Some(PathBuf::from(value()?)),
+            "--strict" => config.strict = true,
+            "--devkit" => config.dev_kit = true,
+            "--exp-rom" => config.expansion_rom = Some(PathBuf::from(value()?)),
+            "--cart-switch" => config.cartridge_switch = true,
+            "--parallel-link" => config.parallel_link = Some(link_config(value()?)?),
+            "--link" => config.link = Some(link_config(value()?)?),
+            a if a.starts_with('-') => return Err(anyhow!("Unknown option {}\n{}", arg, USAGE)),
+            // Executables are recognized by their extension, anything else
+            // is a disc image
+            _ => {
+                let path = PathBuf::from(arg);
+                let is_exe = path
+                    .extension()
+                    .and_then(|e| e.to_str())
+                    .is_some_and(|e| e.eq_ignore_ascii_case("exe") || e.eq_ignore_ascii_case("psexe"));
+
+                match is_exe {
+                    true => options.exe = Some(path),
+                    false => options.disc = Some(path),
+                }
+            }
+        }
+    }
+
+    for (port, card) in config.ports.iter_mut().zip(memory_cards) {
+        if card.is_none() {
+            continue;
+        }
+
+        match port {
+            psx::PortConfig::Direct(slot) => slot.memory_card = card,
+            psx::PortConfig::Multitap(_) => {
+                return Err(anyhow!("Memory cards behind a multitap go in its slot list"))
+            }
+        }
+    }
+
+    options.config = config;
+
+    Ok(options)
+}
+
+fn region(name: &str) -> Result<psx::Region> {
+    match name {
+        "ntsc-j" => Ok(psx::Region::NtscJ),
+        "ntsc-u" => Ok(psx::Region::NtscU),
+        "pal" => Ok(psx::Region::Pal),
+        _ => Err(anyhow!("Unknown region {}, expected ntsc-j, ntsc-u or pal", name)),
+    }
+}
+
+fn count(value: &str) -> Result<u64> {
+    value.parse().map_err(|_| anyhow!("Invalid count {}", value))
+}
+
+/// Parse `pc=<address>` or `tty=<text>`
+fn exit_condition(spec: &str) -> Result<ExitCondition> {
+    match spec.split_once('=') {
+        Some(("pc", address)) => {
+            let address = u32::from_str_radix(address.trim_start_matches("0x"), 16)
+                .map_err(|_| anyhow!("Invalid address {}", address))?;
+
+            Ok(ExitCondition::Pc(address))
+        }
+        Some(("tty", text)) => Ok(ExitCondition::Tty(text.to_string())),
+        _ => Err(anyhow!("Invalid exit condition {}, expected pc=<address> or tty=<text>", spec)),
+    }
+}
+
+/// Parse `<controller>` or `multitap:<slot>,<slot>,<slot>,<slot>` where each
+/// slot is `<controller>[+<file.mcr>]`
+fn port_config(spec: &str) -> Result<psx::PortConfig> {
+    let Some(slots) = spec.strip_prefix("multitap:") else {
+        return Ok(psx::PortConfig::Direct(psx::Slot::new(controller(spec)?)));
+    };
+
+    let slots = slots
+        .split(',')
+        .map(|slot| {
+            let (name, card) = match slot.split_once('+') {
+                Some((name, card)) => (name, Some(PathBuf::from(card))),
+                None => (slot, None),
+            };
+
+            Ok(psx::Slot {
+                controller: controller(name)?,
+                memory_card: card,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let slots: [psx::Slot; 4] = slots
+        .try_into()
+        .map_err(|_| anyhow!("A multitap has 4 slots: {}", spec))?;
+
+    Ok(psx::PortConfig::Multitap(slots))
+}
+
+/// Parse `listen:<port>` or `connect:<port>`
+fn link_config(spec: &str) -> Result<psx::LinkConfig> {
+    let (mode, port) = spec
+        .split_once(':')
+        .ok_or_else(|| anyhow!("Invalid link {}, expected listen:<port> or connect:<port>", spec))?;
+
+    let port = port.parse().map_err(|_| anyhow!("Invalid link port {}", port))?;
+
+    match mode {
+        "listen" => Ok(psx::LinkConfig::Listen(port)),
+        "connect" => Ok(psx::LinkConfig::Connect(port)),
+        _ => Err(anyhow!("Invalid link mode {}", mode)),
+    }
+}
+
+fn controller(name: &str) -> Result<psx::Controller> {
+    match name {
+        "none" | "" => Ok(psx::Controller::None),
+        "digital" => Ok(psx::Controller::Digital),
+        "dualshock" => Ok(psx::Controller::DualShock),
+        "mouse" => Ok(psx::Controller::Mouse),
+        "guncon" => Ok(psx::Controller::GunCon),
+        _ => Err(anyhow!("Unknown controller {}", name)),
+    }
+}
+
+/// Parse `str <input> [--file <path on disc>] [--out <dir>]`
+fn str_options(args: &[String]) -> Result<str_player::Options> {
+    let mut args = args.iter();
+
+    let input = args
+        .next()
+        .ok_or_else(|| anyhow!("usage: str <file.STR | disc image> [--file <path>] [--out <dir>]"))?;
+
+    let mut options = str_player::Options::new(&PathBuf::from(input));
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| anyhow!("Missing value for {}", arg));
+
+        match arg.as_str() {
+            "--file" => options.file = Some(value()?.clone()),
+            "--out" => options.out_dir = PathBuf::from(value()?),
+            _ => return Err(anyhow!("Unknown option {}", arg)),
+        }
+    }
+
+    Ok(options)
+}
+
+/// Parse `memcard <list|copy|delete|export|import> ...`
+fn memcard_command(args: &[String]) -> Result<memcard_manager::Command> {
+    use memcard_manager::Command;
+
+    const USAGE: &str = "usage: memcard list <card>
+       memcard copy <src card> <slot> <dst card>
+       memcard delete <card> <slot>
+       memcard export <card> <slot> <file.mcs|.psx|.gme|.mcd>
+       memcard import <card> <file.mcs|.psx|.gme|.mcd>";
+
+    let path = |i: usize| args.get(i).map(PathBuf::from).ok_or_else(|| anyhow!(USAGE));
+    let slot = |i: usize| -> Result<usize> {
+        let arg = args.get(i).ok_or_else(|| anyhow!(USAGE))?;
+
+        match arg.parse() {
+            Ok(s @ 1..=15) => Ok(s),
+            _ => Err(anyhow!("Invalid slot {}, expected 1-15", arg)),
+        }
+    };
+
+    let command = match args.first().map(|a| a.as_str()) {
+        Some("list") => Command::List { card: path(1)? },
+        Some("copy") => Command::Copy { src: path(1)?, slot: slot(2)?, dst: path(3)? },
+        Some("delete") => Command::Delete { card: path(1)?, slot: slot(2)? },
+        Some("export") => Command::Export { card: path(1)?, slot: slot(2)?, file: path(3)? },
+        Some("import") => Command::Import { card: path(1)?, file: path(2)? },
+        _ => return Err(anyhow!(USAGE)),
+    };
+
+    Ok(command)
+}
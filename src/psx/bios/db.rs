@@ -0,0 +1,233 @@
+use anyhow::{anyhow, Result};
+use std::{fs, path::Path, path::PathBuf};
+
+use crate::psx::Region;
+
+/// Known BIOS dump
+#[derive(Clone, Copy, Debug)]
+pub struct BiosInfo {
+    /// Console model the BIOS comes from
+    pub model: &'static str,
+    pub version: &'static str,
+    pub region: Region,
+    /// Build date, YYYY-MM-DD
+    pub date: &'static str,
+    /// MD5 of the 512KiB image
+    md5: &'static str,
+}
+
+pub(super) const fn bios(
+    model: &'static str,
+    version: &'static str,
+    region: Region,
+    date: &'static str,
+    md5: &'static str,
+) -> BiosInfo {
+    BiosInfo {
+        model,
+        version,
+        region,
+        date,
+        md5,
+    }
+}
+
+impl BiosInfo {
+    /// Major and minor version numbers, so that "10.0" sorts after "4.5"
+    fn version_number(&self) -> (u32, u32) {
+        let (major, minor) = self.version.split_once('.').unwrap_or((self.version, "0"));
+
+        (major.parse().unwrap_or(0), minor.parse().unwrap_or(0))
+    }
+}
+
+/// Retail BIOS dumps. Development (DTL-H) BIOSes aren't listed as no
+/// dump hash could be verified, they are reported as unknown along with
+/// their build date
+const DATABASE: [BiosInfo; 11] = [
+    bios("SCPH-1000", "1.0", Region::NtscJ, "1994-09-22", "239665b1a3dade1b5a52c06338011044"),
+    bios("SCPH-3000", "1.1", Region::NtscJ, "1995-01-22", "849515939161e62f6b866f6853006780"),
+    bios("SCPH-1002", "2.0", Region::Pal, "1995-05-10", "54847e693405ffeb0359c6287434cbef"),
+    bios("SCPH-3500", "2.1", Region::NtscJ, "1995-07-17", "cba733ceeff5aef5c32254f1d617fa62"),
+    bios("SCPH-1001", "2.2", Region::NtscU, "1995-12-04", "924e392ed05558ffdb115408c263dccf"),
+    bios("SCPH-5500", "3.0", Region::NtscJ, "1996-09-09", "8dd7d5296a650fac7319bce665a6a53c"),
+    bios("SCPH-5501", "3.0", Region::NtscU, "1996-11-18", "490f666e1afb15b7362b406ed1cea246"),
+    bios("SCPH-5502", "3.0", Region::Pal, "1997-01-06", "32736f17079d0b2b7024407c39bd3050"),
+    bios("SCPH-7001", "4.1", Region::NtscU, "1997-12-16", "1e68c231d0896b7eadcad1d7d8e76129"),
+    bios("SCPH-7002/7502", "4.1", Region::Pal, "1997-12-16", "b9d9a0286c33dc6b7237bb13cd46fdee"),
+    bios("SCPH-101", "4.5", Region::NtscU, "2000-05-25", "6e3735ff4c7dc899ee98981385f6f3d0"),
+];
+
+/// Offset of the BCD build date (0xYYYYMMDD) in the kernel header
+const DATE_OFFSET: usize = 0x100;
+/// Offset of the copyright string in the kernel header
+const COPYRIGHT_OFFSET: usize = 0x108;
+const COPYRIGHT: &[u8] = b"Sony Computer Entertainment Inc.";
+
+/// Found in the images of the PCSX-Redux OpenBIOS, a free replacement
+const OPENBIOS_SIGNATURE: &[u8] = b"OpenBIOS";
+
+/// What a BIOS image turned out to be
+#[derive(Clone, Copy, Debug)]
+pub enum BiosCheck {
+    Known(BiosInfo),
+    /// OpenBIOS, which boots any region and can't be patched
+    OpenBios,
+    /// Image with a kernel header but an unknown hash: a BIOS missing from
+    /// the database, a patched one or a bad dump. Holds the header date
+    Unknown(Option<u32>),
+    /// Not a PlayStation BIOS
+    Invalid,
+}
+
+/// Look the image up in the database
+pub fn identify_bios(data: &[u8]) -> BiosCheck {
+    let md5 = format!("{:x}", md5::compute(data));
+
+    if let Some(info) = DATABASE.iter().find(|b| b.md5 == md5) {
+        return BiosCheck::Known(*info);
+    }
+
+    if data.windows(OPENBIOS_SIGNATURE.len()).any(|w| w == OPENBIOS_SIGNATURE) {
+        return BiosCheck::OpenBios;
+    }
+
+    if data.get(COPYRIGHT_OFFSET..COPYRIGHT_OFFSET + COPYRIGHT.len()) != Some(COPYRIGHT) {
+        return BiosCheck::Invalid;
+    }
+
+    let date = data
+        .get(DATE_OFFSET..DATE_OFFSET + 4)
+        .map(|d| u32::from_le_bytes(d.try_into().unwrap()));
+
+    BiosCheck::Unknown(date)
+}
+
+/// Newest known BIOS for `region` among the files of `dir`, or an OpenBIOS
+/// image when there's none
+pub fn select_bios(dir: &Path, region: Region) -> Result<PathBuf> {
+    let entries = fs::read_dir(dir).map_err(|e| anyhow!("Can't list {}: {}", dir.display(), e))?;
+
+    let mut found = Vec::new();
+
+    for entry in entries {
+        let path = entry?.path();
+
+        // Skip anything that can't be a BIOS before reading it
+        match fs::metadata(&path) {
+            Ok(m) if m.is_file() && m.len() <= 512 * 1024 => (),
+            _ => continue,
+        }
+
+        found.push((identify_bios(&fs::read(&path)?), path));
+    }
+
+    pick_bios(found, region).ok_or_else(|| anyhow!("No known {} BIOS in {}", region, dir.display()))
+}
+
+/// Newest known BIOS for `region`, the last OpenBIOS image otherwise. Ties
+/// on the version go to the latest build
+fn pick_bios(found: Vec<(BiosCheck, PathBuf)>, region: Region) -> Option<PathBuf> {
+    let mut best: Option<(BiosInfo, PathBuf)> = None;
+    let mut open_bios = None;
+
+    for (check, path) in found {
+        let info = match check {
+            BiosCheck::Known(info) => info,
+            BiosCheck::OpenBios => {
+                open_bios = Some(path);
+                continue;
+            }
+            _ => continue,
+        };
+
+        let newer = best
+            .as_ref()
+            .is_none_or(|(b, _)| (info.version_number(), info.date) > (b.version_number(), b.date));
+
+        if info.region == region && newer {
+            best = Some((info, path));
+        }
+    }
+
+    best.map(|(_, path)| path).or(open_bios)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Kernel header with the BCD build `date`
+    fn header(date: u32) -> Vec<u8> {
+        let mut data = vec![0; 512 * 1024];
+        data[DATE_OFFSET..DATE_OFFSET + 4].copy_from_slice(&date.to_le_bytes());
+        data[COPYRIGHT_OFFSET..COPYRIGHT_OFFSET + COPYRIGHT.len()].copy_from_slice(COPYRIGHT);
+        data
+    }
+
+    #[test]
+    fn identifies_images_without_a_known_hash() {
+        assert!(matches!(identify_bios(&header(0x19951204)), BiosCheck::Unknown(Some(0x19951204))));
+        assert!(matches!(identify_bios(&[0; 1024]), BiosCheck::Invalid));
+        assert!(matches!(identify_bios(&header(0)[..COPYRIGHT_OFFSET + 4]), BiosCheck::Invalid));
+
+        let mut open_bios = vec![0; 1024];
+        open_bios[0x200..0x208].copy_from_slice(OPENBIOS_SIGNATURE);
+        assert!(matches!(identify_bios(&open_bios), BiosCheck::OpenBios));
+    }
+
+    #[test]
+    fn versions_compare_as_numbers() {
+        let old = bios("SCPH-101", "4.5", Region::NtscU, "2000-05-25", "");
+        let new = bios("SCPH-X", "10.0", Region::NtscU, "2001-01-01", "");
+
+        assert!(new.version_number() > old.version_number());
+        assert_eq!(bios("", "3", Region::Pal, "", "").version_number(), (3, 0));
+    }
+
+    #[test]
+    fn picks_the_newest_bios_of_the_region() {
+        let known = |model, version, region, date| BiosCheck::Known(bios(model, version, region, date, ""));
+        let found = || {
+            vec![
+                (BiosCheck::OpenBios, PathBuf::from("openbios.bin")),
+                (known("SCPH-1001", "2.2", Region::NtscU, "1995-12-04"), PathBuf::from("1001.bin")),
+                (known("SCPH-X", "10.0", Region::NtscU, "2001-01-01"), PathBuf::from("x.bin")),
+                (known("SCPH-101", "4.5", Region::NtscU, "2000-05-25"), PathBuf::from("101.bin")),
+                (BiosCheck::Unknown(None), PathBuf::from("unknown.bin")),
+                (known("SCPH-5502", "3.0", Region::Pal, "1997-01-06"), PathBuf::from("5502.bin")),
+                (known("SCPH-1002", "2.0", Region::Pal, "1995-05-10"), PathBuf::from("1002.bin")),
+                (known("SCPH-7002", "4.1", Region::Pal, "1997-12-16"), PathBuf::from("7002.bin")),
+                (known("SCPH-7502", "4.1", Region::Pal, "1998-01-01"), PathBuf::from("7502.bin")),
+            ]
+        };
+
+        assert_eq!(pick_bios(found(), Region::NtscU), Some(PathBuf::from("x.bin")));
+        assert_eq!(pick_bios(found(), Region::Pal), Some(PathBuf::from("7502.bin")));
+        // No Japanese BIOS, OpenBIOS boots anything
+        assert_eq!(pick_bios(found(), Region::NtscJ), Some(PathBuf::from("openbios.bin")));
+        assert_eq!(pick_bios(found()[1..].to_vec(), Region::NtscJ), None);
+    }
+
+    #[test]
+    fn selects_from_a_directory() {
+        let dir = std::env::temp_dir().join(format!("psx-rust-bios-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        fs::write(dir.join("garbage.bin"), [0; 16]).unwrap();
+        fs::write(dir.join("unknown.bin"), header(0x19951204)).unwrap();
+        assert!(select_bios(&dir, Region::NtscU).is_err());
+
+        let mut open_bios = vec![0; 1024];
+        open_bios[0x200..0x208].copy_from_slice(OPENBIOS_SIGNATURE);
+        fs::write(dir.join("openbios.bin"), open_bios).unwrap();
+        // Too big to be a BIOS, not even read
+        fs::write(dir.join("big.bin"), vec![0; 512 * 1024 + 1]).unwrap();
+
+        let selected = select_bios(&dir, Region::NtscU);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(selected.unwrap(), dir.join("openbios.bin"));
+        assert!(select_bios(&dir, Region::NtscU).is_err());
+    }
+}
@@ -0,0 +1,167 @@
+use anyhow::{anyhow, Result};
+
+use super::hle;
+
+mod db;
+mod patch;
+
+pub use db::{identify_bios, select_bios, BiosCheck, BiosInfo};
+pub use patch::{Patch, FAST_BOOT, TTY};
+
+/// PSX BIOS implementation
+pub struct Bios {
+    data: Vec<u8>,
+    /// What the image was identified as, None for the HLE kernel ROM.
+    /// Patches are only applied to known versions
+    check: Option<BiosCheck>,
+}
+
+impl Bios {
+    const BIOS_SIZE: usize = 512 * 1024;
+
+    pub fn new(mut data: Vec<u8>) -> Result<Bios> {
+        // Replacement BIOSes can be smaller than the ROM, the rest reads 0
+        if data.len() < Bios::BIOS_SIZE && matches!(identify_bios(&data), BiosCheck::OpenBios) {
+            data.resize(Bios::BIOS_SIZE, 0);
+        }
+
+        if data.len() == Bios::BIOS_SIZE {
+            let check = Some(identify_bios(&data));
+
+            Ok(Bios { data, check })
+        } else {
+            Err(anyhow!("Invalid BIOS size: {} bytes, expected {}", data.len(), Bios::BIOS_SIZE))
+        }
+    }
+
+    /// ROM of the high level emulated kernel, see `hle::Kernel`
+    pub fn hle() -> Bios {
+        Bios {
+            data: hle::rom(),
+            check: None,
+        }
+    }
+
+    /// What the image was identified as, None for the HLE kernel ROM
+    pub fn check(&self) -> Option<BiosCheck> {
+        self.check
+    }
+
+    /// Apply `patch`, refusing to touch images it wasn't written for or
+    /// whose code doesn't match what it expects to replace
+    pub fn patch(&mut self, patch: &Patch) -> Result<()> {
+        let info = match self.check {
+            Some(BiosCheck::Known(info)) if patch.supports(info.model) => info,
+            Some(BiosCheck::Known(info)) => {
+                return Err(anyhow!("The {} patch doesn't support BIOS {}", patch.name, info.model))
+            }
+            _ => return Err(anyhow!("Can't apply the {} patch to an unknown BIOS", patch.name)),
+        };
+
+        for &(addr, original, _) in patch.words() {
+            let found = self.load32(addr & 0x7ffff);
+
+            if found != original {
+                return Err(anyhow!(
+                    "Can't apply the {} patch to BIOS {}: expected {:08x} at {:08x}, found {:08x}",
+                    patch.name,
+                    info.model,
+                    original,
+                    addr,
+                    found
+                ));
+            }
+        }
+
+        for &(addr, _, word) in patch.words() {
+            let offset = (addr & 0x7ffff) as usize;
+            self.data[offset..offset + 4].copy_from_slice(&word.to_le_bytes());
+        }
+
+        println!("Applied the {} patch to BIOS {}", patch.name, info.model);
+
+        Ok(())
+    }
+
+    /// Load 8 bits from the BIOS with some offset position
+    pub fn load8(&self, offset: u32) -> u8 {
+        self.data[offset as usize]
+    }
+
+    /// Load 16 bits from the BIOS with some offset position
+    pub fn load16(&self, offset: u32) -> u16 {
+        let offset = offset as usize;
+
+        u16::from_le_bytes([self.data[offset], self.data[offset + 1]])
+    }
+
+    /// Load 32 bits from the BIOS with some offset position
+    pub fn load32(&self, offset: u32) -> u32 {
+        let offset = offset as usize;
+
+        let b0 = self.data[offset] as u32;
+        let b1 = self.data[offset + 1] as u32;
+        let b2 = self.data[offset + 2] as u32;
+        let b3 = self.data[offset + 3] as u32;
+
+        b0 | (b1 << 8) | (b2 << 16) | (b3 << 24)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psx::Region;
+
+    fn known(model: &'static str) -> Bios {
+        let info = db::bios(model, "2.2", Region::NtscU, "1995-12-04", "");
+
+        Bios {
+            data: vec![0; Bios::BIOS_SIZE],
+            check: Some(BiosCheck::Known(info)),
+        }
+    }
+
+    fn with_originals(mut bios: Bios, patch: &Patch) -> Bios {
+        for &(addr, original, _) in patch.words() {
+            let offset = (addr & 0x7ffff) as usize;
+            bios.data[offset..offset + 4].copy_from_slice(&original.to_le_bytes());
+        }
+
+        bios
+    }
+
+    #[test]
+    fn patches_replace_the_expected_code() {
+        let mut bios = with_originals(known("SCPH-1001"), &TTY);
+
+        bios.patch(&TTY).unwrap();
+
+        for &(addr, _, word) in TTY.words() {
+            assert_eq!(bios.load32(addr & 0x7ffff), word);
+        }
+    }
+
+    #[test]
+    fn patches_refuse_other_versions() {
+        let mut bios = with_originals(known("SCPH-5501"), &FAST_BOOT);
+
+        assert!(bios.patch(&FAST_BOOT).is_err());
+        assert!(Bios::hle().patch(&FAST_BOOT).is_err());
+    }
+
+    #[test]
+    fn patches_refuse_unexpected_code() {
+        let mut bios = with_originals(known("SCPH-1001"), &TTY);
+        let (addr, original, _) = TTY.words()[1];
+        let offset = (addr & 0x7ffff) as usize;
+
+        bios.data[offset] ^= 1;
+
+        assert!(bios.patch(&TTY).is_err());
+        // Nothing is written when any word doesn't match
+        let (first, first_original, _) = TTY.words()[0];
+        assert_eq!(bios.load32(first & 0x7ffff), first_original);
+        assert_eq!(bios.load32(addr & 0x7ffff), original ^ 1);
+    }
+}
@@ -0,0 +1,46 @@
+/// Change to the BIOS code, applied when it's loaded
+pub struct Patch {
+    pub name: &'static str,
+    /// Models of the BIOS dumps the patch was written for, see `BiosInfo`
+    models: &'static [&'static str],
+    /// Words to overwrite, by physical address, with the original word
+    /// expected there and the replacement
+    words: &'static [(u32, u32, u32)],
+}
+
+impl Patch {
+    /// True if the patch was written for the BIOS of `model`
+    pub fn supports(&self, model: &str) -> bool {
+        self.models.contains(&model)
+    }
+
+    pub fn words(&self) -> &'static [(u32, u32, u32)] {
+        self.words
+    }
+}
+
+/// Replace the shell with a return to the bootstrap code, which then boots
+/// the disc straight away instead of playing the intro. The display is
+/// turned off first so the logo isn't left on screen
+pub const FAST_BOOT: Patch = Patch {
+    name: "fast boot",
+    models: &["SCPH-1001"],
+    words: &[
+        (0x1fc18000, 0x27bdffe8, 0x3c011f80), // lui   $at, 0x1f80
+        (0x1fc18004, 0xafbf0010, 0x3c0a0300), // lui   $t2, 0x0300
+        (0x1fc18008, 0x0c00c1b4, 0xac2a1814), // sw    $t2, 0x1814($at)
+        (0x1fc1800c, 0x00000000, 0x03e00008), // jr    $ra
+        (0x1fc18010, 0x8fbf0010, 0x00000000), // nop
+    ],
+};
+
+/// Set the kernel flag enabling the TTY output of `printf` and `putchar`,
+/// which the retail kernels clear at boot
+pub const TTY: Patch = Patch {
+    name: "TTY",
+    models: &["SCPH-1001"],
+    words: &[
+        (0x1fc06f0c, 0x00000000, 0x24010001), // li    $at, 1
+        (0x1fc06f14, 0xaf80a9c0, 0xaf81a9c0), // sw    $at, -0x5640($gp)
+    ],
+};
@@ -0,0 +1,657 @@
+use std::collections::HashMap;
+
+use super::disasm::REGISTER_NAMES;
+
+/// Assemble `source` for loading at `origin`. The syntax is the one of the
+/// disassembler, plus:
+///
+/// - `label:` definitions, usable as branch and jump targets and by `la`
+/// - `.word <value>, ...` and `.space <bytes>` directives
+/// - `li`, `la`, `move`, `b`, `beqz` and `bnez` pseudo-instructions
+/// - comments starting with `#` or `;`
+///
+/// Branches have their delay slot like on the CPU, nothing is reordered or
+/// filled in. Errors name the faulty line
+pub fn assemble(source: &str, origin: u32) -> Result<Vec<u32>, String> {
+    let lines: Vec<Line> = source
+        .lines()
+        .enumerate()
+        .map(|(n, text)| parse_line(text).map_err(|e| format!("line {}: {}", n + 1, e)))
+        .collect::<Result<_, _>>()?;
+
+    // First pass: label addresses
+    let mut labels = HashMap::new();
+    let mut pc = origin;
+
+    for (n, line) in lines.iter().enumerate() {
+        for label in &line.labels {
+            if labels.insert(label.clone(), pc).is_some() {
+                return Err(format!("line {}: label {} defined twice", n + 1, label));
+            }
+        }
+
+        if let Some((mnemonic, operands)) = &line.statement {
+            let words = size(mnemonic, operands).map_err(|e| format!("line {}: {}", n + 1, e))?;
+            pc = pc.wrapping_add(words * 4);
+        }
+    }
+
+    // Second pass: encoding
+    let mut code = Vec::new();
+
+    for (n, line) in lines.iter().enumerate() {
+        if let Some((mnemonic, operands)) = &line.statement {
+            let pc = origin.wrapping_add(code.len() as u32 * 4);
+            let asm = Assembler { labels: &labels, pc };
+
+            let words = asm
+                .encode(mnemonic, operands)
+                .map_err(|e| format!("line {}: {}: {}", n + 1, e, line.text))?;
+
+            code.extend(words);
+        }
+    }
+
+    Ok(code)
+}
+
+struct Line {
+    text: String,
+    labels: Vec<String>,
+    statement: Option<(String, Vec<String>)>,
+}
+
+fn parse_line(text: &str) -> Result<Line, String> {
+    let mut rest = text.split(['#', ';']).next().unwrap_or("").trim();
+    let mut labels = Vec::new();
+
+    while let Some((label, after)) = rest.split_once(':') {
+        let label = label.trim();
+
+        if label.is_empty() || !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.') {
+            return Err(format!("invalid label {:?}", label));
+        }
+
+        labels.push(label.to_string());
+        rest = after.trim();
+    }
+
+    let statement = match rest.split_once(char::is_whitespace) {
+        _ if rest.is_empty() => None,
+        Some((mnemonic, operands)) => Some((
+            mnemonic.to_ascii_lowercase(),
+            operands.split(',').map(|o| o.trim().to_string()).collect(),
+        )),
+        None => Some((rest.to_ascii_lowercase(), Vec::new())),
+    };
+
+    Ok(Line {
+        text: text.trim().to_string(),
+        labels,
+        statement,
+    })
+}
+
+/// Number of words generated by a statement
+fn size(mnemonic: &str, operands: &[String]) -> Result<u32, String> {
+    match mnemonic {
+        ".word" => Ok(operands.len() as u32),
+        ".space" => space_words(operands.first().ok_or("missing size")?),
+        "la" => Ok(2),
+        "li" => {
+            let value = operands.get(1).ok_or("missing immediate")?;
+            Ok(li_words(number(value)?))
+        }
+        _ => Ok(1),
+    }
+}
+
+/// `li` takes a single instruction when the value fits `addiu` or `ori`
+fn li_words(value: i64) -> u32 {
+    match value {
+        -0x8000..=0xffff => 1,
+        _ => 2,
+    }
+}
+
+/// Words reserved by `.space <bytes>`
+fn space_words(text: &str) -> Result<u32, String> {
+    let bytes = number(text)?;
+
+    if bytes <= 0 || bytes % 4 != 0 {
+        return Err(format!("size {} isn't a positive multiple of 4", bytes));
+    }
+
+    Ok(bytes as u32 / 4)
+}
+
+fn number(text: &str) -> Result<i64, String> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16),
+        None => digits.parse(),
+    }
+    .map_err(|_| format!("invalid number {:?}", text))?;
+
+    if value > u32::MAX as i64 {
+        return Err(format!("{} doesn't fit in 32 bits", text));
+    }
+
+    Ok(if negative { -value } else { value })
+}
+
+fn register(text: &str) -> Result<u32, String> {
+    let name = text
+        .strip_prefix('$')
+        .ok_or_else(|| format!("expected a register, got {:?}", text))?;
+
+    if let Ok(n) = name.parse::<u32>() {
+        if n < 32 {
+            return Ok(n);
+        }
+    }
+
+    match name {
+        "s8" => Ok(30),
+        _ => REGISTER_NAMES
+            .iter()
+            .position(|&r| r == name)
+            .map(|r| r as u32)
+            .ok_or_else(|| format!("unknown register {:?}", text)),
+    }
+}
+
+/// Coprocessor register, `$n`
+fn cop_register(text: &str) -> Result<u32, String> {
+    match text.strip_prefix('$').map(|n| n.parse::<u32>()) {
+        Some(Ok(n)) if n < 32 => Ok(n),
+        _ => Err(format!("invalid coprocessor register {:?}", text)),
+    }
+}
+
+fn r_type(s: u32, t: u32, d: u32, shift: u32, function: u32) -> u32 {
+    (s << 21) | (t << 16) | (d << 11) | (shift << 6) | function
+}
+
+fn i_type(op: u32, s: u32, t: u32, imm: u32) -> u32 {
+    (op << 26) | (s << 21) | (t << 16) | (imm & 0xffff)
+}
+
+struct Assembler<'a> {
+    labels: &'a HashMap<String, u32>,
+    /// Address of the statement being encoded
+    pc: u32,
+}
+
+impl Assembler<'_> {
+    /// Label or absolute address
+    fn address(&self, text: &str) -> Result<u32, String> {
+        match self.labels.get(text) {
+            Some(&addr) => Ok(addr),
+            None if text.starts_with(|c: char| c.is_ascii_digit() || c == '-') => Ok(number(text)? as u32),
+            None => Err(format!("unknown label {}", text)),
+        }
+    }
+
+    /// 16 bit immediate, signed or not depending on the instruction
+    fn immediate(&self, text: &str, signed: bool) -> Result<u32, String> {
+        let value = number(text)?;
+
+        let range = match signed {
+            true => -0x8000..=0x7fff,
+            false => 0..=0xffff,
+        };
+
+        match range.contains(&value) {
+            true => Ok(value as u32 & 0xffff),
+            false => Err(format!("immediate {} out of range", text)),
+        }
+    }
+
+    /// Branch offset from the delay slot to `target`
+    fn branch_offset(&self, target: &str) -> Result<u32, String> {
+        let target = self.address(target)?;
+        let offset = target.wrapping_sub(self.pc.wrapping_add(4)) as i32;
+
+        if offset % 4 != 0 || !(-0x20000..0x20000).contains(&offset) {
+            return Err(format!("branch target {:08x} out of reach", target));
+        }
+
+        Ok((offset >> 2) as u32 & 0xffff)
+    }
+
+    /// `offset(base)` memory operand
+    fn memory(&self, text: &str) -> Result<(u32, u32), String> {
+        let (offset, base) = text
+            .strip_suffix(')')
+            .and_then(|t| t.split_once('('))
+            .ok_or_else(|| format!("expected offset(base), got {:?}", text))?;
+
+        let offset = match offset.trim() {
+            "" => 0,
+            offset => self.immediate(offset, true)?,
+        };
+
+        Ok((offset, register(base.trim())?))
+    }
+
+    fn encode(&self, mnemonic: &str, ops: &[String]) -> Result<Vec<u32>, String> {
+        let op = |i: usize| ops.get(i).map(|o| o.as_str()).ok_or_else(|| "missing operand".to_string());
+        let reg = |i: usize| op(i).and_then(register);
+
+        let count = |n: usize| match ops.len() == n || (n == 0 && ops.len() == 1 && ops[0].is_empty()) {
+            true => Ok(()),
+            false => Err(format!("expected {} operands", n)),
+        };
+
+        let shift = |function: u32| -> Result<u32, String> {
+            count(3)?;
+
+            let sa = number(op(2)?)?;
+            if !(0..32).contains(&sa) {
+                return Err(format!("shift amount {} out of range", sa));
+            }
+
+            Ok(r_type(0, reg(1)?, reg(0)?, sa as u32, function))
+        };
+        let three = |function: u32| -> Result<u32, String> {
+            count(3)?;
+            Ok(r_type(reg(1)?, reg(2)?, reg(0)?, 0, function))
+        };
+        let shiftv = |function: u32| -> Result<u32, String> {
+            count(3)?;
+            Ok(r_type(reg(2)?, reg(1)?, reg(0)?, 0, function))
+        };
+        let arith = |opcode: u32, signed: bool| -> Result<u32, String> {
+            count(3)?;
+            Ok(i_type(opcode, reg(1)?, reg(0)?, self.immediate(op(2)?, signed)?))
+        };
+        let mem = |opcode: u32| -> Result<u32, String> {
+            count(2)?;
+            let (offset, base) = self.memory(op(1)?)?;
+            Ok(i_type(opcode, base, reg(0)?, offset))
+        };
+        let cop_mem = |opcode: u32| -> Result<u32, String> {
+            count(2)?;
+            let (offset, base) = self.memory(op(1)?)?;
+            Ok(i_type(opcode, base, cop_register(op(0)?)?, offset))
+        };
+        let regimm = |rt: u32| -> Result<u32, String> {
+            count(2)?;
+            Ok(i_type(0x01, reg(0)?, rt, self.branch_offset(op(1)?)?))
+        };
+        let jump = |opcode: u32| -> Result<u32, String> {
+            count(1)?;
+
+            let target = self.address(op(0)?)?;
+            if (target ^ self.pc.wrapping_add(4)) & 0xf000_0000 != 0 || target % 4 != 0 {
+                return Err(format!("jump target {:08x} out of reach", target));
+            }
+
+            Ok((opcode << 26) | ((target >> 2) & 0x3ffffff))
+        };
+        // Coprocessor instructions: the number is the last character
+        let cop = mnemonic
+            .chars()
+            .last()
+            .and_then(|c| c.to_digit(10))
+            .filter(|&n| n < 4)
+            .unwrap_or(0);
+        let cop_move = |rs: u32| -> Result<u32, String> {
+            count(2)?;
+            Ok(((0x10 | cop) << 26) | (rs << 21) | (reg(0)? << 16) | (cop_register(op(1)?)? << 11))
+        };
+
+        let word = match mnemonic {
+            ".word" => return ops.iter().map(|o| self.address(o)).collect(),
+            ".space" => return Ok(vec![0; space_words(op(0)?)? as usize]),
+            "li" => {
+                count(2)?;
+
+                let t = reg(0)?;
+                let value = number(op(1)?)?;
+
+                return Ok(match value {
+                    -0x8000..=0x7fff => vec![i_type(0x09, 0, t, value as u32)],
+                    0x8000..=0xffff => vec![i_type(0x0d, 0, t, value as u32)],
+                    _ => vec![
+                        i_type(0x0f, 0, t, value as u32 >> 16),
+                        i_type(0x0d, t, t, value as u32),
+                    ],
+                });
+            }
+            "la" => {
+                count(2)?;
+
+                let t = reg(0)?;
+                let addr = self.address(op(1)?)?;
+
+                return Ok(vec![i_type(0x0f, 0, t, addr >> 16), i_type(0x0d, t, t, addr)]);
+            }
+            "move" => {
+                count(2)?;
+                r_type(reg(1)?, 0, reg(0)?, 0, 0x21)
+            }
+            "b" => {
+                count(1)?;
+                i_type(0x04, 0, 0, self.branch_offset(op(0)?)?)
+            }
+            "beqz" | "bnez" => {
+                count(2)?;
+
+                let opcode = match mnemonic {
+                    "beqz" => 0x04,
+                    _ => 0x05,
+                };
+
+                i_type(opcode, reg(0)?, 0, self.branch_offset(op(1)?)?)
+            }
+            "nop" => {
+                count(0)?;
+                0
+            }
+            "sll" => shift(0x00)?,
+            "srl" => shift(0x02)?,
+            "sra" => shift(0x03)?,
+            "sllv" => shiftv(0x04)?,
+            "srlv" => shiftv(0x06)?,
+            "srav" => shiftv(0x07)?,
+            "jr" => {
+                count(1)?;
+                r_type(reg(0)?, 0, 0, 0, 0x08)
+            }
+            "jalr" => match ops.len() {
+                1 => r_type(reg(0)?, 0, 31, 0, 0x09),
+                _ => {
+                    count(2)?;
+                    r_type(reg(1)?, 0, reg(0)?, 0, 0x09)
+                }
+            },
+            "syscall" => {
+                count(0)?;
+                0x0c
+            }
+            "break" => {
+                let code = match ops.first().map(|o| o.as_str()) {
+                    None | Some("") => 0,
+                    Some(code) => number(code)? as u32 & 0xfffff,
+                };
+
+                (code << 6) | 0x0d
+            }
+            "mfhi" | "mflo" => {
+                count(1)?;
+                r_type(0, 0, reg(0)?, 0, if mnemonic == "mfhi" { 0x10 } else { 0x12 })
+            }
+            "mthi" | "mtlo" => {
+                count(1)?;
+                r_type(reg(0)?, 0, 0, 0, if mnemonic == "mthi" { 0x11 } else { 0x13 })
+            }
+            "mult" | "multu" | "div" | "divu" => {
+                count(2)?;
+
+                let function = match mnemonic {
+                    "mult" => 0x18,
+                    "multu" => 0x19,
+                    "div" => 0x1a,
+                    _ => 0x1b,
+                };
+
+                r_type(reg(0)?, reg(1)?, 0, 0, function)
+            }
+            "add" => three(0x20)?,
+            "addu" => three(0x21)?,
+            "sub" => three(0x22)?,
+            "subu" => three(0x23)?,
+            "and" => three(0x24)?,
+            "or" => three(0x25)?,
+            "xor" => three(0x26)?,
+            "nor" => three(0x27)?,
+            "slt" => three(0x2a)?,
+            "sltu" => three(0x2b)?,
+            "bltz" => regimm(0x00)?,
+            "bgez" => regimm(0x01)?,
+            "bltzal" => regimm(0x10)?,
+            "bgezal" => regimm(0x11)?,
+            "j" => jump(0x02)?,
+            "jal" => jump(0x03)?,
+            "beq" | "bne" => {
+                count(3)?;
+
+                let opcode = match mnemonic {
+                    "beq" => 0x04,
+                    _ => 0x05,
+                };
+
+                i_type(opcode, reg(0)?, reg(1)?, self.branch_offset(op(2)?)?)
+            }
+            "blez" | "bgtz" => {
+                count(2)?;
+
+                let opcode = match mnemonic {
+                    "blez" => 0x06,
+                    _ => 0x07,
+                };
+
+                i_type(opcode, reg(0)?, 0, self.branch_offset(op(1)?)?)
+            }
+            "addi" => arith(0x08, true)?,
+            "addiu" => arith(0x09, true)?,
+            "slti" => arith(0x0a, true)?,
+            "sltiu" => arith(0x0b, true)?,
+            "andi" => arith(0x0c, false)?,
+            "ori" => arith(0x0d, false)?,
+            "xori" => arith(0x0e, false)?,
+            "lui" => {
+                count(2)?;
+                i_type(0x0f, 0, reg(0)?, self.immediate(op(1)?, false)?)
+            }
+            "mfc0" | "mfc1" | "mfc2" | "mfc3" => cop_move(0x00)?,
+            "cfc0" | "cfc1" | "cfc2" | "cfc3" => cop_move(0x02)?,
+            "mtc0" | "mtc1" | "mtc2" | "mtc3" => cop_move(0x04)?,
+            "ctc0" | "ctc1" | "ctc2" | "ctc3" => cop_move(0x06)?,
+            "cop0" | "cop1" | "cop2" | "cop3" => {
+                count(1)?;
+
+                let command = number(op(0)?)? as u32;
+                ((0x10 | cop) << 26) | (1 << 25) | (command & 0x1ff_ffff)
+            }
+            "rfe" => {
+                count(0)?;
+                0x42000010
+            }
+            "lb" => mem(0x20)?,
+            "lh" => mem(0x21)?,
+            "lwl" => mem(0x22)?,
+            "lw" => mem(0x23)?,
+            "lbu" => mem(0x24)?,
+            "lhu" => mem(0x25)?,
+            "lwr" => mem(0x26)?,
+            "sb" => mem(0x28)?,
+            "sh" => mem(0x29)?,
+            "swl" => mem(0x2a)?,
+            "sw" => mem(0x2b)?,
+            "swr" => mem(0x2e)?,
+            "lwc0" | "lwc1" | "lwc2" | "lwc3" => cop_mem(0x30 | cop)?,
+            "swc0" | "swc1" | "swc2" | "swc3" => cop_mem(0x38 | cop)?,
+            _ => return Err(format!("unknown instruction {}", mnemonic)),
+        };
+
+        Ok(vec![word])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::disasm::disassemble;
+    use super::*;
+
+    /// Every line must come back unchanged from the disassembler
+    #[test]
+    fn round_trips_through_the_disassembler() {
+        let origin = 0x80010000;
+        let source = "\
+            nop
+            sll $v0, $a0, 3
+            srl $v0, $a0, 31
+            sra $t9, $k0, 0
+            sllv $v0, $a0, $a1
+            srlv $v0, $a0, $a1
+            srav $v0, $a0, $a1
+            jr $ra
+            jalr $ra, $t0
+            syscall
+            break 0x123
+            mfhi $s0
+            mthi $s1
+            mflo $s2
+            mtlo $s3
+            mult $a0, $a1
+            multu $a0, $a1
+            div $a0, $a1
+            divu $a0, $a1
+            add $v0, $a0, $a1
+            addu $v0, $a0, $a1
+            sub $v0, $a0, $a1
+            subu $v0, $a0, $a1
+            and $v0, $a0, $a1
+            or $v0, $a0, $a1
+            xor $v0, $a0, $a1
+            nor $v0, $a0, $a1
+            slt $v0, $a0, $a1
+            sltu $v0, $a0, $a1
+            bltz $a0, 0x80010000
+            bgez $a0, 0x80010100
+            bltzal $a0, 0x8000ff00
+            bgezal $a0, 0x80010000
+            j 0x80020000
+            jal 0x80000080
+            beq $a0, $a1, 0x80010000
+            bne $a0, $zero, 0x80010000
+            blez $a0, 0x80010000
+            bgtz $a0, 0x80010000
+            addi $v0, $a0, -0x1
+            addiu $sp, $sp, -0x18
+            slti $v0, $a0, 0x7fff
+            sltiu $v0, $a0, -0x8000
+            andi $v0, $a0, 0xffff
+            ori $v0, $a0, 0x1234
+            xori $v0, $a0, 0x1
+            lui $gp, 0x8001
+            mfc0 $t0, $12
+            mtc0 $t0, $13
+            cfc2 $t0, $31
+            ctc2 $t0, $0
+            mfc2 $v0, $7
+            mtc2 $v0, $9
+            cop2 0x0180001
+            rfe
+            lb $v0, 0x10($sp)
+            lh $v0, -0x10($sp)
+            lwl $v0, 0x3($a0)
+            lw $ra, 0x14($sp)
+            lbu $v0, 0x0($a0)
+            lhu $v0, 0x2($a0)
+            lwr $v0, 0x0($a0)
+            sb $v0, 0x10($sp)
+            sh $v0, 0x10($sp)
+            swl $v0, 0x3($a0)
+            sw $ra, 0x14($sp)
+            swr $v0, 0x0($a0)
+            lwc2 $5, 0x4($a0)
+            swc2 $5, 0x4($a0)";
+
+        let code = assemble(source, origin).unwrap();
+
+        for (i, (line, word)) in source.lines().zip(code).enumerate() {
+            let pc = origin + i as u32 * 4;
+            assert_eq!(disassemble(word, pc), line.trim(), "word {:08x}", word);
+        }
+    }
+
+    #[test]
+    fn resolves_labels_forwards_and_backwards() {
+        let code = assemble(
+            "start:  addiu $t0, $t0, 1
+                     bne $t0, $t1, start
+                     nop
+                     beq $zero, $zero, end
+                     nop
+                     j start
+                     nop
+             end:    jal end",
+            0x80010000,
+        )
+        .unwrap();
+
+        assert_eq!(code[1], 0x1509fffe);
+        assert_eq!(code[3], 0x10000003);
+        assert_eq!(code[5], 0x08004000);
+        assert_eq!(code[7], 0x0c004007);
+    }
+
+    #[test]
+    fn expands_pseudo_instructions() {
+        let code = assemble(
+            "li $t0, 0x12345678
+             li $t1, -2
+             li $t2, 0xffff
+             la $a0, data
+             move $v0, $a0
+             b data
+             beqz $a0, data
+             bnez $a0, data
+             data: .word 0xdeadbeef, data",
+            0x80010000,
+        )
+        .unwrap();
+
+        assert_eq!(
+            code,
+            [
+                0x3c081234, 0x35085678, // li $t0
+                0x2409fffe,             // li $t1
+                0x340affff,             // li $t2
+                0x3c048001, 0x34840028, // la $a0, data
+                0x00801021,             // move
+                0x10000002,             // b
+                0x10800001,             // beqz
+                0x14800000,             // bnez
+                0xdeadbeef, 0x80010028,
+            ]
+        );
+    }
+
+    #[test]
+    fn reserves_space_and_skips_comments() {
+        let code = assemble(
+            "# header
+             .space 8   ; two words
+             after: .word after",
+            0x100,
+        )
+        .unwrap();
+
+        assert_eq!(code, [0, 0, 0x108]);
+    }
+
+    #[test]
+    fn reports_errors_with_their_line() {
+        let err = |source| assemble(source, 0).unwrap_err();
+
+        assert_eq!(err("nop\nfoo $t0"), "line 2: unknown instruction foo: foo $t0");
+        assert!(err("addiu $t0, $t0, 0x8000").starts_with("line 1: immediate"));
+        assert!(err("lw $t0, 4").contains("offset(base)"));
+        assert!(err("j nowhere").contains("unknown label nowhere"));
+        assert!(err("a: nop\na: nop").contains("defined twice"));
+        assert!(err("addu $t0, $t1").contains("expected 3 operands"));
+        assert!(err("sll $t0, $t0, 32").contains("out of range"));
+        assert!(err(".space 6").contains("positive multiple of 4"));
+        assert!(err(".space -4").contains("positive multiple of 4"));
+        assert!(err(".space 0").contains("positive multiple of 4"));
+    }
+}
@@ -0,0 +1,127 @@
+use super::instruction::{Instruction, RegisterIndex};
+
+/// Conventional names of the general purpose registers
+pub const REGISTER_NAMES: [&str; 32] = [
+    "zero", "at", "v0", "v1", "a0", "a1", "a2", "a3",
+    "t0", "t1", "t2", "t3", "t4", "t5", "t6", "t7",
+    "s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7",
+    "t8", "t9", "k0", "k1", "gp", "sp", "fp", "ra",
+];
+
+fn reg(r: RegisterIndex) -> String {
+    format!("${}", REGISTER_NAMES[r.0 as usize])
+}
+
+/// Signed hexadecimal immediate
+fn simm(i: u32) -> String {
+    let i = i as i32;
+
+    match i < 0 {
+        true => format!("-0x{:x}", -(i as i64)),
+        false => format!("0x{:x}", i),
+    }
+}
+
+/// Text of the instruction `op` located at `pc`, which is needed to resolve
+/// branch targets
+pub fn disassemble(op: u32, pc: u32) -> String {
+    let i = Instruction(op);
+
+    let (s, t, d) = (reg(i.s()), reg(i.t()), reg(i.d()));
+    let branch_target = pc.wrapping_add(4).wrapping_add(i.imm_se() << 2);
+    let mem = |name: &str| format!("{} {}, {}({})", name, t, simm(i.imm_se()), s);
+
+    match i.function() {
+        0x00 => {
+            let three = |name: &str| format!("{} {}, {}, {}", name, d, s, t);
+            let shift = |name: &str| format!("{} {}, {}, {}", name, d, t, i.shift_imm());
+            let shiftv = |name: &str| format!("{} {}, {}, {}", name, d, t, s);
+
+            match i.subfunction() {
+                0x00 if op == 0 => "nop".to_string(),
+                0x00 => shift("sll"),
+                0x02 => shift("srl"),
+                0x03 => shift("sra"),
+                0x04 => shiftv("sllv"),
+                0x06 => shiftv("srlv"),
+                0x07 => shiftv("srav"),
+                0x08 => format!("jr {}", s),
+                0x09 => format!("jalr {}, {}", d, s),
+                0x0c => "syscall".to_string(),
+                0x0d => format!("break 0x{:x}", (op >> 6) & 0xfffff),
+                0x10 => format!("mfhi {}", d),
+                0x11 => format!("mthi {}", s),
+                0x12 => format!("mflo {}", d),
+                0x13 => format!("mtlo {}", s),
+                0x18 => format!("mult {}, {}", s, t),
+                0x19 => format!("multu {}, {}", s, t),
+                0x1a => format!("div {}, {}", s, t),
+                0x1b => format!("divu {}, {}", s, t),
+                0x20 => three("add"),
+                0x21 => three("addu"),
+                0x22 => three("sub"),
+                0x23 => three("subu"),
+                0x24 => three("and"),
+                0x25 => three("or"),
+                0x26 => three("xor"),
+                0x27 => three("nor"),
+                0x2a => three("slt"),
+                0x2b => three("sltu"),
+                _ => format!(".word 0x{:08x}", op),
+            }
+        }
+        0x01 => {
+            let name = match (i.t().0 & 0x1e == 0x10, i.t().0 & 1 != 0) {
+                (false, false) => "bltz",
+                (false, true) => "bgez",
+                (true, false) => "bltzal",
+                (true, true) => "bgezal",
+            };
+
+            format!("{} {}, 0x{:08x}", name, s, branch_target)
+        }
+        0x02 => format!("j 0x{:08x}", (pc.wrapping_add(4) & 0xf000_0000) | (i.imm_jump() << 2)),
+        0x03 => format!("jal 0x{:08x}", (pc.wrapping_add(4) & 0xf000_0000) | (i.imm_jump() << 2)),
+        0x04 => format!("beq {}, {}, 0x{:08x}", s, t, branch_target),
+        0x05 => format!("bne {}, {}, 0x{:08x}", s, t, branch_target),
+        0x06 => format!("blez {}, 0x{:08x}", s, branch_target),
+        0x07 => format!("bgtz {}, 0x{:08x}", s, branch_target),
+        0x08 => format!("addi {}, {}, {}", t, s, simm(i.imm_se())),
+        0x09 => format!("addiu {}, {}, {}", t, s, simm(i.imm_se())),
+        0x0a => format!("slti {}, {}, {}", t, s, simm(i.imm_se())),
+        0x0b => format!("sltiu {}, {}, {}", t, s, simm(i.imm_se())),
+        0x0c => format!("andi {}, {}, 0x{:x}", t, s, i.imm()),
+        0x0d => format!("ori {}, {}, 0x{:x}", t, s, i.imm()),
+        0x0e => format!("xori {}, {}, 0x{:x}", t, s, i.imm()),
+        0x0f => format!("lui {}, 0x{:x}", t, i.imm()),
+        0x10..=0x13 => {
+            let cop = i.function() & 3;
+            let rd = i.d().0;
+
+            match i.cop_opcode() {
+                0x00 => format!("mfc{} {}, ${}", cop, t, rd),
+                0x02 => format!("cfc{} {}, ${}", cop, t, rd),
+                0x04 => format!("mtc{} {}, ${}", cop, t, rd),
+                0x06 => format!("ctc{} {}, ${}", cop, t, rd),
+                0x10..=0x1f if cop == 0 && i.subfunction() == 0x10 => "rfe".to_string(),
+                0x10..=0x1f => format!("cop{} 0x{:07x}", cop, op & 0x1ff_ffff),
+                _ => format!(".word 0x{:08x}", op),
+            }
+        }
+        0x20 => mem("lb"),
+        0x21 => mem("lh"),
+        0x22 => mem("lwl"),
+        0x23 => mem("lw"),
+        0x24 => mem("lbu"),
+        0x25 => mem("lhu"),
+        0x26 => mem("lwr"),
+        0x28 => mem("sb"),
+        0x29 => mem("sh"),
+        0x2a => mem("swl"),
+        0x2b => mem("sw"),
+        0x2e => mem("swr"),
+        0x30..=0x33 => format!("lwc{} ${}, {}({})", i.function() & 3, i.t().0, simm(i.imm_se()), s),
+        0x38..=0x3b => format!("swc{} ${}, {}({})", i.function() & 3, i.t().0, simm(i.imm_se()), s),
+        _ => format!(".word 0x{:08x}", op),
+    }
+}
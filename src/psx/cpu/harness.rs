@@ -0,0 +1,195 @@
+use std::io;
+
+use crate::psx::dma::Dma;
+use crate::psx::gpu::Gpu;
+use crate::psx::interconnect::Interconnect;
+use crate::psx::mdec::Mdec;
+use crate::psx::ram::Ram;
+use crate::psx::sio0::Sio0;
+use crate::psx::sio1::Sio1;
+
+use super::asm::assemble;
+use super::{Cpu, Registers};
+
+/// Default load address of the test programs, past the kernel area
+pub const ENTRY: u32 = 0x80010000;
+
+/// CPU with 2MiB of RAM and the I/O devices but no BIOS, to run tiny
+/// programs on the interpreter. Exceptions go to the RAM vector at
+/// 0x80000080 since SR.BEV is clear
+pub struct Machine {
+    cpu: Cpu,
+}
+
+impl Machine {
+    /// Load `program` at `entry` and start there
+    pub fn new(program: &[u32], entry: u32) -> Machine {
+        let inter = Interconnect::new(
+            None,
+            Ram::new(),
+            Dma::new(),
+            Mdec::new(),
+            Gpu::new(),
+            Sio0::new(),
+            Sio1::new(None),
+        );
+
+        let mut machine = Machine { cpu: Cpu::new(inter) };
+
+        machine.write(entry, program);
+        machine.cpu.set_pc(entry);
+
+        machine
+    }
+
+    /// Assemble `source` at `ENTRY` and start there, panics on syntax errors
+    pub fn from_asm(source: &str) -> Machine {
+        let program = assemble(source, ENTRY).unwrap_or_else(|e| panic!("{}", e));
+
+        Machine::new(&program, ENTRY)
+    }
+
+    /// Copy words to memory, bypassing the CPU
+    pub fn write(&mut self, addr: u32, words: &[u32]) {
+        for (i, &w) in words.iter().enumerate() {
+            self.store32(addr + i as u32 * 4, w);
+        }
+
+        self.cpu.flush_cache();
+    }
+
+    /// Run `count` instructions
+    pub fn run(&mut self, count: usize) {
+        for _ in 0..count {
+            self.cpu.run_next_instruction().unwrap();
+        }
+    }
+
+    /// Run until the CPU is about to execute `pc`, panics if it takes more
+    /// than `limit` instructions
+    pub fn run_until(&mut self, pc: u32, limit: usize) {
+        for _ in 0..limit {
+            if self.cpu.pc() == pc {
+                return;
+            }
+
+            self.cpu.run_next_instruction().unwrap();
+        }
+
+        panic!("PC {:08x} not reached after {} instructions, at {:08x}", pc, limit, self.cpu.pc());
+    }
+
+    /// Log the executed instructions to `trace`
+    pub fn set_trace(&mut self, trace: Option<Box<dyn io::Write>>) {
+        self.cpu.set_trace(trace);
+    }
+
+    /// CPU, to drive code that works on it directly
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    /// CPU cycles elapsed since the start
+    pub fn cycles(&self) -> u64 {
+        self.cpu.interconnect().cycles()
+    }
+
+    pub fn reg(&self, index: usize) -> u32 {
+        self.cpu.registers().regs[index]
+    }
+
+    pub fn set_reg(&mut self, index: u32, val: u32) {
+        self.cpu.set_register(index, val);
+    }
+
+    pub fn registers(&self) -> Registers {
+        self.cpu.registers()
+    }
+
+    pub fn load32(&mut self, addr: u32) -> u32 {
+        self.cpu.interconnect_mut().load32(addr).expect("load from an unmapped address")
+    }
+
+    pub fn store32(&mut self, addr: u32, val: u32) {
+        self.cpu.interconnect_mut().store32(addr, val).expect("store to an unmapped address");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_from_ram_without_bios() {
+        let mut m = Machine::new(
+            &[
+                0x3c081234, // lui   $t0, 0x1234
+                0x35085678, // ori   $t0, $t0, 0x5678
+                0xac080100, // sw    $t0, 0x100($zero)
+            ],
+            ENTRY,
+        );
+
+        m.run(3);
+
+        assert_eq!(m.reg(8), 0x12345678);
+        assert_eq!(m.load32(0x100), 0x12345678);
+        assert_eq!(m.registers().pc, ENTRY + 12);
+    }
+
+    #[test]
+    fn runs_until_the_end_of_a_loop() {
+        let mut m = Machine::new(
+            &[
+                0x2508ffff, // addiu $t0, $t0, -0x1
+                0x1500fffe, // bne   $t0, $zero, -2
+                0x00000000, // nop
+            ],
+            ENTRY,
+        );
+
+        m.set_reg(8, 3);
+        m.run_until(ENTRY + 12, 100);
+
+        assert_eq!(m.reg(8), 0);
+    }
+
+    #[test]
+    fn runs_assembled_programs() {
+        let mut m = Machine::from_asm(
+            "        la $t0, data
+                     li $t1, 0
+             sum:    lw $t2, 0($t0)
+                     addiu $t0, $t0, 4
+                     bnez $t2, sum
+                     addu $t1, $t1, $t2
+             end:    nop
+             data:   .word 1, 2, 3, 0",
+        );
+
+        // la is two words, li one
+        m.run_until(ENTRY + 0x1c, 100);
+
+        assert_eq!(m.reg(9), 6);
+        assert_eq!(m.reg(8), ENTRY + 0x30);
+    }
+
+    #[test]
+    fn exceptions_use_the_ram_vector() {
+        let mut m = Machine::new(&[0x0000000c], ENTRY); // syscall
+
+        m.run(1);
+
+        let r = m.registers();
+        assert_eq!(r.pc, 0x80000080);
+        assert_eq!(r.epc, ENTRY);
+        assert_eq!((r.cause >> 2) & 0x1f, 0x08);
+    }
+
+    #[test]
+    fn empty_bios_socket_reads_open_bus() {
+        let mut m = Machine::new(&[], ENTRY);
+
+        assert_eq!(m.load32(0xbfc00000), 0xffffffff);
+    }
+}
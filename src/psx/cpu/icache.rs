@@ -0,0 +1,94 @@
+/// Number of cache lines
+const LINE_COUNT: usize = 256;
+
+/// Instruction cache line, holds 4 consecutive words
+#[derive(Clone, Copy)]
+struct Line {
+    /// Address bits 12-30 of the cached words
+    tag: u32,
+    /// One valid flag per word
+    valid: u8,
+    words: [u32; 4],
+}
+
+/// 4KiB direct mapped instruction cache
+pub struct ICache {
+    lines: [Line; LINE_COUNT],
+}
+
+impl ICache {
+    pub fn new() -> ICache {
+        let line = Line {
+            tag: 0,
+            valid: 0,
+            words: [0; 4],
+        };
+
+        ICache {
+            lines: [line; LINE_COUNT],
+        }
+    }
+
+    /// Cached word at `addr`, None on a miss
+    pub fn lookup(&self, addr: u32) -> Option<u32> {
+        let line = &self.lines[((addr >> 4) as usize) % LINE_COUNT];
+        let index = word_index(addr);
+
+        match line.tag == tag(addr) && line.valid & (1 << index) != 0 {
+            true => Some(line.words[index]),
+            false => None,
+        }
+    }
+
+    /// Store a word fetched from memory, a line refilled for another tag
+    /// loses its other words
+    pub fn fill(&mut self, addr: u32, word: u32) {
+        let line = self.line(addr);
+        let index = word_index(addr);
+
+        if line.tag != tag(addr) {
+            line.tag = tag(addr);
+            line.valid = 0;
+        }
+
+        line.words[index] = word;
+        line.valid |= 1 << index;
+    }
+
+    fn line(&mut self, addr: u32) -> &mut Line {
+        &mut self.lines[((addr >> 4) as usize) % LINE_COUNT]
+    }
+
+    /// Load while the cache is isolated. In tag test mode the tag and valid
+    /// flags of the line are returned instead of the cached word
+    pub fn load_isolated(&mut self, addr: u32, tag_test: bool) -> u32 {
+        let line = self.line(addr);
+
+        match tag_test {
+            true => line.tag | line.valid as u32,
+            false => line.words[word_index(addr)],
+        }
+    }
+
+    /// Store while the cache is isolated. In tag test mode the line is
+    /// retagged and invalidated, that's how the BIOS flushes the cache
+    pub fn store_isolated(&mut self, addr: u32, val: u32, tag_test: bool) {
+        let line = self.line(addr);
+
+        match tag_test {
+            true => {
+                line.tag = tag(addr);
+                line.valid = 0;
+            }
+            false => line.words[word_index(addr)] = val,
+        }
+    }
+}
+
+fn word_index(addr: u32) -> usize {
+    ((addr >> 2) & 3) as usize
+}
+
+fn tag(addr: u32) -> u32 {
+    addr & 0x7ffff000
+}
@@ -1,7 +1,22 @@
-use crate::psx::Interconnect;
-
+use anyhow::{anyhow, Result};
+use std::fmt::Write;
+use std::io;
+
+use crate::psx::interconnect::{BusError, Interconnect};
+
+#[cfg(test)]
+pub mod asm;
+mod disasm;
+#[cfg(test)]
+pub mod harness;
+mod icache;
 mod instruction;
+#[cfg(test)]
+mod tests;
+
+pub use disasm::{disassemble, REGISTER_NAMES};
 
+use icache::ICache;
 use instruction::Instruction;
 
 use self::instruction::RegisterIndex;
@@ -18,6 +33,7 @@ pub struct Cpu {
     regs: [u32; 32],
     /// Interconnect of PSX BIOS and other peripherals
     inter: Interconnect,
+    icache: ICache,
     /// Option to handle with load delay instructions
     load: Option<(RegisterIndex, u32)>,
 
@@ -26,20 +42,54 @@ pub struct Cpu {
     lo: u32,
 
     // COP 0
+    /// Register 3 - BPC, execution breakpoint address
+    bpc: u32,
+    /// Register 5 - BDA, data breakpoint address
+    bda: u32,
+    /// Register 6 - JUMPDEST, target of the last taken jump
+    jumpdest: u32,
+    /// Register 7 - DCIC, breakpoint control
+    dcic: u32,
+    /// Register 9 - BDAM, data breakpoint address mask
+    bdam: u32,
+    /// Register 11 - BPCM, execution breakpoint address mask
+    bpcm: u32,
     /// Register 12 -Status Register
     sr: u32,
     /// Register 13 - Cause Register
     cause: u32,
     /// Register 14 - EPC
     epc: u32,
+    /// Register 8 - BadVaddr, address of the last address error
+    bad_vaddr: u32,
 
     branch: bool,
     delay_slot: bool,
+
+    /// Bus cycles spent by the data accesses of the current instruction
+    access_cycles: u32,
+
+    /// Halt with a report on bus errors instead of raising exceptions
+    strict: bool,
+    /// Report of the bus error that halted the CPU in strict mode
+    halt: Option<String>,
+
+    /// Log of the executed instructions
+    trace: Option<Box<dyn io::Write>>,
 }
 
 impl Cpu {
     const RESET_STATE_ADDR: u32 = 0xbfc00000;
 
+    /// COP0 revision, read from PRID (register 15)
+    const PRID: u32 = 0x00000002;
+
+    /// Average number of cycles taken by an instruction
+    const CYCLES_PER_INSTRUCTION: u32 = 2;
+
+    /// Cycles taken by an instruction found in the instruction cache
+    const CACHE_HIT_CYCLES: u32 = 1;
+
     pub fn new(inter: Interconnect) -> Cpu {
         let mut regs = [0xdeadbeef; 32];
         regs[0] = 0;
@@ -48,18 +98,110 @@ impl Cpu {
             curr_pc: Cpu::RESET_STATE_ADDR,
             regs,
             inter,
+            icache: ICache::new(),
             next_pc: Cpu::RESET_STATE_ADDR + 4,
             load: None,
             hi: 0,
             lo: 0,
+            bpc: 0,
+            bda: 0,
+            jumpdest: 0,
+            dcic: 0,
+            bdam: 0,
+            bpcm: 0,
             sr: 0,
             cause: 0,
             epc: 0,
+            bad_vaddr: 0,
             branch: false,
             delay_slot: false,
+            access_cycles: 0,
+            trace: None,
+            strict: false,
+            halt: None,
         }
     }
 
+    /// Development mode: any access to unmapped or unhandled addresses stops
+    /// the emulator
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+        self.inter.set_strict(strict);
+    }
+
+    /// Write every executed instruction to `trace`
+    pub fn set_trace(&mut self, trace: Option<Box<dyn io::Write>>) {
+        self.trace = trace;
+    }
+
+    /// Address of the next instruction to run
+    pub fn pc(&self) -> u32 {
+        self.pc
+    }
+
+    /// Jump to `pc`, dropping any pending branch
+    pub fn set_pc(&mut self, pc: u32) {
+        self.pc = pc;
+        self.next_pc = pc.wrapping_add(4);
+        self.branch = false;
+    }
+
+    /// Set general purpose register `index`, writes to $zero are ignored
+    pub fn set_register(&mut self, index: u32, val: u32) {
+        self.set_reg(RegisterIndex(index), val);
+    }
+
+    pub fn set_hi_lo(&mut self, hi: u32, lo: u32) {
+        self.hi = hi;
+        self.lo = lo;
+    }
+
+    pub fn set_sr(&mut self, sr: u32) {
+        self.sr = sr;
+    }
+
+    /// Write the value of a load still in its delay slot to its register,
+    /// before the registers are accessed from outside of the CPU
+    pub fn complete_load(&mut self) {
+        self.handle_load_delay();
+    }
+
+    /// Leave an exception handler like `rfe` followed by a jump to `pc`
+    pub fn return_from_exception(&mut self, pc: u32) {
+        let mode = self.sr & 0x3c;
+        self.sr &= !0xf;
+        self.sr |= mode >> 2;
+
+        self.set_pc(pc);
+    }
+
+    /// Invalidate the whole instruction cache, after code was written to
+    /// memory from outside of the CPU
+    pub fn flush_cache(&mut self) {
+        self.icache = ICache::new();
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            pc: self.pc,
+            regs: self.regs,
+            hi: self.hi,
+            lo: self.lo,
+            sr: self.sr,
+            cause: self.cause(),
+            epc: self.epc,
+            bad_vaddr: self.bad_vaddr,
+        }
+    }
+
+    pub fn interconnect(&self) -> &Interconnect {
+        &self.inter
+    }
+
+    pub fn interconnect_mut(&mut self) -> &mut Interconnect {
+        &mut self.inter
+    }
+
     fn reg(&self, index: RegisterIndex) -> u32 {
         self.regs[index.0 as usize]
     }
@@ -70,48 +212,326 @@ impl Cpu {
         self.regs[0] = 0;
     }
 
-    pub fn run_next_instruction(&mut self) {
+    /// Run the next instruction. In strict mode, bus errors stop the
+    /// emulation with a report of the CPU state
+    pub fn run_next_instruction(&mut self) -> Result<()> {
+        self.step();
+
+        match self.halt.take() {
+            Some(report) => Err(anyhow!(report)),
+            None => Ok(()),
+        }
+    }
+
+    fn step(&mut self) {
         self.curr_pc = self.pc;
 
-        if self.curr_pc % 4 != 0{
-            self.exception(Exception::LoadAddressError);
-            return ;
+        self.delay_slot = self.branch;
+        self.branch     = false;
+
+        // Misaligned jump targets fault when they are fetched, once the
+        // delay slot has run
+        if !self.curr_pc.is_multiple_of(4) {
+            self.address_error(Exception::LoadAddressError, self.curr_pc);
+            self.inter.tick(Cpu::CYCLES_PER_INSTRUCTION);
+            return;
         }
 
         self.pc = self.next_pc;
         self.next_pc = self.pc.wrapping_add(4);
 
-        let instruction = Instruction(self.load32(self.curr_pc));
+        // DCIC bit 24: break on execution
+        if self.breakpoint_enabled(1 << 24) && (self.curr_pc ^ self.bpc) & self.bpcm == 0 {
+            self.debug_break(DCIC_CODE_BREAK);
+            self.inter.tick(Cpu::CYCLES_PER_INSTRUCTION);
+            return;
+        }
 
-        self.delay_slot = self.branch;
-        self.branch     = false;
+        let (instruction, cycles) = match self.fetch(self.curr_pc) {
+            Ok((i, cycles)) => (Instruction(i), cycles),
+            Err(e) => {
+                self.bus_error(e, Exception::InstructionBusError);
+                self.inter.tick(Cpu::CYCLES_PER_INSTRUCTION);
+                return;
+            }
+        };
 
-        self.decode_and_execute(instruction);
+        if self.irq_pending() {
+            self.exception(Exception::Interrupt);
+        } else {
+            // Instructions preempted by an interrupt never ran, only log
+            // the ones that do
+            if let Some(trace) = &mut self.trace {
+                let Instruction(op) = instruction;
+                let _ = writeln!(trace, "{:08x}: {:08x}  {}", self.curr_pc, op, disassemble(op, self.curr_pc));
+            }
+
+            self.decode_and_execute(instruction);
+        }
+
+        let access_cycles = std::mem::take(&mut self.access_cycles);
+        self.inter.tick(cycles + access_cycles);
     }
 
-    fn load8(&self, addr: u32) -> u8 {
-        self.inter.load8(addr)
+    /// Fetch the instruction at `pc` through the instruction cache, returns
+    /// it with the cycles spent
+    fn fetch(&mut self, pc: u32) -> Result<(u32, u32), BusError> {
+        // Only KUSEG and KSEG0 are cached, CACHE_CONTROL bit 11 enables the
+        // cache
+        let cached = pc >> 29 <= 4 && self.inter.cache_control() & (1 << 11) != 0;
+
+        if !cached {
+            let instruction = self.inter.load32(pc)?;
+            return Ok((instruction, self.inter.access_cycles(pc, 32, false)));
+        }
+
+        if let Some(instruction) = self.icache.lookup(pc) {
+            return Ok((instruction, Cpu::CACHE_HIT_CYCLES));
+        }
+
+        let instruction = self.inter.load32(pc)?;
+        let mut cycles = self.inter.access_cycles(pc, 32, false);
+
+        self.icache.fill(pc, instruction);
+
+        // The refill continues up to the end of the line, a bus error there
+        // only leaves the remaining words invalid
+        let mut addr = pc.wrapping_add(4);
+
+        while addr & 0xf != 0 {
+            let Ok(word) = self.inter.load32(addr) else { break };
+
+            self.icache.fill(addr, word);
+            cycles += self.inter.access_cycles(addr, 32, false);
+            addr = addr.wrapping_add(4);
+        }
+
+        Ok((instruction, cycles))
+    }
+
+    /// CAUSE register with the hardware interrupt line in bit 10
+    fn cause(&self) -> u32 {
+        self.cause | ((self.inter.irq_active() as u32) << 10)
     }
 
-    fn load16(&self, addr: u32) -> u16{
-        self.inter.load16(addr)
+    /// An interrupt is taken when it's enabled in the SR mask and the
+    /// current interrupt enable bit (IEc) is set
+    fn irq_pending(&self) -> bool {
+        let pending = self.cause() & self.sr & 0xff00 != 0;
+
+        pending && self.sr & 1 != 0
+    }
+
+    /// Data accesses return None when they raised a bus error
+    fn data_access<T>(&mut self, result: Result<T, BusError>) -> Option<T> {
+        match result {
+            Ok(v) => Some(v),
+            Err(e) => {
+                self.bus_error(e, Exception::DataBusError);
+                None
+            }
+        }
+    }
+
+    fn load8(&mut self, addr: u32) -> Option<u8> {
+        if self.data_breakpoint(addr, false) {
+            return None;
+        }
+
+        if self.cache_isolated() {
+            let w = self.icache.load_isolated(addr, self.tag_test());
+            return Some((w >> ((addr & 3) * 8)) as u8);
+        }
+
+        self.access_cycles += self.inter.access_cycles(addr, 8, false);
+
+        let r = self.inter.load8(addr);
+        self.data_access(r)
+    }
+
+    fn load16(&mut self, addr: u32) -> Option<u16> {
+        if self.data_breakpoint(addr, false) {
+            return None;
+        }
+
+        if self.cache_isolated() {
+            let w = self.icache.load_isolated(addr, self.tag_test());
+            return Some((w >> ((addr & 3) * 8)) as u16);
+        }
+
+        self.access_cycles += self.inter.access_cycles(addr, 16, false);
+
+        let r = self.inter.load16(addr);
+        self.data_access(r)
     }
 
-    fn load32(&self, addr: u32) -> u32 {
-        self.inter.load32(addr)
+    fn load32(&mut self, addr: u32) -> Option<u32> {
+        if self.data_breakpoint(addr, false) {
+            return None;
+        }
+
+        if self.cache_isolated() {
+            let w = self.icache.load_isolated(addr, self.tag_test());
+            return Some(w);
+        }
+
+        self.access_cycles += self.inter.access_cycles(addr, 32, false);
+
+        let r = self.inter.load32(addr);
+        self.data_access(r)
     }
 
     fn store8(&mut self, addr: u32, val: u8) {
-        self.inter.store8(addr, val);
+        if self.data_breakpoint(addr, true) {
+            return;
+        }
+
+        if self.cache_isolated() {
+            // The whole word is written, with the byte in its lane
+            let val = (val as u32) << ((addr & 3) * 8);
+            self.icache.store_isolated(addr, val, self.tag_test());
+            return;
+        }
+
+        self.access_cycles += self.inter.access_cycles(addr, 8, true);
+
+        let r = self.inter.store8(addr, val);
+        self.data_access(r);
     }
 
     fn store16(&mut self, addr: u32, val: u16) {
-        self.inter.store16(addr, val);
+        if self.data_breakpoint(addr, true) {
+            return;
+        }
+
+        if self.cache_isolated() {
+            let val = (val as u32) << ((addr & 3) * 8);
+            self.icache.store_isolated(addr, val, self.tag_test());
+            return;
+        }
+
+        self.access_cycles += self.inter.access_cycles(addr, 16, true);
+
+        let r = self.inter.store16(addr, val);
+        self.data_access(r);
     }
 
     /// Store 32 bit value into memory
     fn store32(&mut self, addr: u32, val: u32) {
-        self.inter.store32(addr, val);
+        if self.data_breakpoint(addr, true) {
+            return;
+        }
+
+        if self.cache_isolated() {
+            self.icache.store_isolated(addr, val, self.tag_test());
+            return;
+        }
+
+        self.access_cycles += self.inter.access_cycles(addr, 32, true);
+
+        let r = self.inter.store32(addr, val);
+        self.data_access(r);
+    }
+
+    /// SR bit 16: data accesses go to the instruction cache, not the bus
+    fn cache_isolated(&self) -> bool {
+        self.sr & 0x10000 != 0
+    }
+
+    /// CACHE_CONTROL bit 2: isolated accesses target the cache tags
+    fn tag_test(&self) -> bool {
+        self.inter.cache_control() & 4 != 0
+    }
+
+    /// DCIC enable `bits` are set along with their master enables
+    fn breakpoint_enabled(&self, bits: u32) -> bool {
+        // Bits 23 and 31: super master enables, bit 30: master enable for
+        // the code and data breakpoints, bit 29: for the jump breakpoint
+        let master = match bits & (1 << 28) {
+            0 => 1 << 30,
+            _ => 1 << 29,
+        };
+
+        let mask = bits | master | (1 << 23) | (1 << 31);
+
+        self.dcic & mask == mask
+    }
+
+    /// Check the BDA breakpoint, returns true if it triggered
+    fn data_breakpoint(&mut self, addr: u32, write: bool) -> bool {
+        // DCIC bit 25: data access breakpoint, bits 26/27: on read/write
+        let (enable, status) = match write {
+            false => ((1 << 25) | (1 << 26), DCIC_READ_BREAK),
+            true => ((1 << 25) | (1 << 27), DCIC_WRITE_BREAK),
+        };
+
+        if !self.breakpoint_enabled(enable) || (addr ^ self.bda) & self.bdam != 0 {
+            return false;
+        }
+
+        self.debug_break(DCIC_DATA_BREAK | status);
+
+        true
+    }
+
+    /// Hardware breakpoint hit, flag it in DCIC and enter the debug handler
+    fn debug_break(&mut self, status: u32) {
+        self.dcic |= DCIC_ANY_BREAK | status;
+        self.enter_exception(Exception::Break, 0x80000040, 0xbfc00140);
+    }
+
+    /// Taken jump or branch to `target`. Returns false when a jump
+    /// breakpoint raised an exception instead, the instruction must then
+    /// leave the registers alone
+    fn jump(&mut self, target: u32) -> bool {
+        self.jumpdest = target;
+
+        // DCIC bit 28: break on any jump, before the jump is committed
+        if self.breakpoint_enabled(1 << 28) {
+            self.debug_break(DCIC_JUMP_BREAK);
+            return false;
+        }
+
+        self.next_pc = target;
+        self.branch  = true;
+
+        true
+    }
+
+    /// Misaligned access or fetch at `addr`
+    fn address_error(&mut self, exception: Exception, addr: u32) {
+        self.bad_vaddr = addr;
+        self.exception(exception);
+    }
+
+    fn bus_error(&mut self, err: BusError, exception: Exception) {
+        if self.strict {
+            self.halt = Some(self.report(&format!("Bus error: {}", err)));
+            return;
+        }
+
+        self.exception(exception);
+    }
+
+    /// Dump of the CPU state for fatal errors
+    fn report(&self, reason: &str) -> String {
+        let mut r = String::new();
+
+        let _ = writeln!(r, "{}", reason);
+        let _ = writeln!(
+            r,
+            "PC {:08x}  SR {:08x}  CAUSE {:08x}  EPC {:08x}  HI {:08x}  LO {:08x}",
+            self.curr_pc, self.sr, self.cause(), self.epc, self.hi, self.lo
+        );
+
+        for (i, regs) in self.regs.chunks(8).enumerate() {
+            for (j, v) in regs.iter().enumerate() {
+                let _ = write!(r, "r{:<2} {:08x}  ", i * 8 + j, v);
+            }
+            r.push('\n');
+        }
+
+        r
     }
 
     /// Decode instruction and execute them
@@ -224,16 +644,26 @@ impl Cpu {
 
     /// Triggers Exceptions
     fn exception(&mut self, cause:Exception){
+        self.enter_exception(cause, 0x80000080, 0xbfc00180);
+    }
+
+    /// Jump to the exception handler, in the BIOS if SR.BEV is set
+    fn enter_exception(&mut self, cause: Exception, ram_handler: u32, bios_handler: u32) {
+        // The load started by the previous instruction still lands
+        self.handle_load_delay();
+
         let handler: u32 = match self.sr & (1<<22) != 0{
-            true => 0xbfc00180,
-            false => 0x80000080,
+            true => bios_handler,
+            false => ram_handler,
         };
 
         let mode = self.sr & 0x3f;
         self.sr &= !0x3f;
         self.sr |= (mode << 2) & 0x3f;
 
-        self.cause = (cause as u32) << 2;
+        // Only the software interrupt bits survive
+        self.cause &= 0x300;
+        self.cause |= (cause as u32) << 2;
 
         self.epc = self.curr_pc;
 
@@ -268,12 +698,23 @@ impl Cpu {
         self.exception(Exception::CoprocessorError);
     }
 
+    /// Conditional branch, the next instruction is a delay slot whether
+    /// it's taken or not. Returns false like `jump` on a breakpoint
+    fn branch_if(&mut self, condition: bool, offset: u32) -> bool {
+        if !condition {
+            self.branch = true;
+            return true;
+        }
+
+        self.branch(offset)
+    }
+
     /// Branch with relative immediate offset
-    fn branch(&mut self, offset: u32) {
+    fn branch(&mut self, offset: u32) -> bool {
         let offset = offset << 2;
 
-        self.next_pc = self.pc.wrapping_add(offset);
-        self.branch  = true;
+        let target = self.pc.wrapping_add(offset);
+        self.jump(target)
     }
 
     /// Decider between bltz, bgez, bltzal, bgezal
@@ -294,13 +735,10 @@ impl Cpu {
 
         self.handle_load_delay();
 
-        if is_link{
-            let ra = self.next_pc;
-            self.set_reg(RegisterIndex(31),ra);
-        }
+        let ra = self.next_pc;
 
-        if test != 0{
-            self.branch(i);
+        if self.branch_if(test != 0, i) && is_link {
+            self.set_reg(RegisterIndex(31),ra);
         }
     }
 
@@ -400,11 +838,6 @@ impl Cpu {
     }
 
     fn op_sb(&mut self, instruction: Instruction) {
-        if self.sr & 0x10000 != 0 {
-            println!("ignoring store while cache is isolated");
-            return;
-        }
-
         let i = instruction.imm_se();
         let t = instruction.t();
         let s = instruction.s();
@@ -418,33 +851,23 @@ impl Cpu {
     }
 
     fn op_sh(&mut self, instruction: Instruction) {
-        if self.sr & 0x10000 != 0 {
-            println!("ignoring store while cache is isolated");
-            return;
-        }
-
         let i = instruction.imm_se();
         let t = instruction.t();
         let s = instruction.s();
 
         let addr = self.reg(s).wrapping_add(i);
         let v = self.reg(t);
-        if addr % 2 == 0 {
+        if addr.is_multiple_of(2) {
             self.handle_load_delay();
 
             self.store16(addr, v as u16);
         } else {
-            self.exception(Exception::StoreAddressError);
+            self.address_error(Exception::StoreAddressError, addr);
         }
 
     }
 
     fn op_swl(&mut self, instruction: Instruction) {
-        if self.sr & 0x10000 != 0 {
-            println!("ignoring load while cache is isolated");
-            return;
-        }
-
         let i = instruction.imm_se();
         let t = instruction.t();
         let s = instruction.s();
@@ -452,48 +875,40 @@ impl Cpu {
         let addr = self.reg(s).wrapping_add(i);
         let v = self.reg(t);
 
+        self.handle_load_delay();
+
         let aligned_addr = addr & !3;
 
-        let cur_mem = self.load32(aligned_addr);
+        let Some(cur_mem) = self.load32(aligned_addr) else { return };
 
         let mem = match addr & 3{
             0 => (cur_mem & 0xffffff00) | (v >> 24),
             1 => (cur_mem & 0xffff0000) | (v >> 16),
             2 => (cur_mem & 0xff000000) | (v >> 8),
-            3 => (cur_mem & 0x00000000) | (v >> 0),
+            3 => v,
             _ => unreachable!(),
         };
 
-        self.store32(addr, mem);
+        self.store32(aligned_addr, mem);
     }
 
     fn op_sw(&mut self, instruction: Instruction) {
-        if self.sr & 0x10000 != 0 {
-            println!("ignoring store while cache is isolated");
-            return;
-        }
-
         let i = instruction.imm_se();
         let t = instruction.t();
         let s = instruction.s();
 
         let addr = self.reg(s).wrapping_add(i);
         let v = self.reg(t);
-        if addr % 4 == 0{
+        if addr.is_multiple_of(4){
             self.handle_load_delay();
 
             self.store32(addr, v);
         } else {
-            self.exception(Exception::StoreAddressError);
+            self.address_error(Exception::StoreAddressError, addr);
         }
     }
 
     fn op_swr(&mut self, instruction: Instruction) {
-        if self.sr & 0x10000 != 0 {
-            println!("ignoring load while cache is isolated");
-            return;
-        }
-
         let i = instruction.imm_se();
         let t = instruction.t();
         let s = instruction.s();
@@ -501,19 +916,21 @@ impl Cpu {
         let addr = self.reg(s).wrapping_add(i);
         let v = self.reg(t);
 
+        self.handle_load_delay();
+
         let aligned_addr = addr & !3;
 
-        let cur_mem = self.load32(aligned_addr);
+        let Some(cur_mem) = self.load32(aligned_addr) else { return };
 
         let mem = match addr & 3{
-            0 => (cur_mem & 0x00000000) | (v << 0),
+            0 => v,
             1 => (cur_mem & 0x000000ff) | (v << 8),
             2 => (cur_mem & 0x0000ffff) | (v << 16),
             3 => (cur_mem & 0x00ffffff) | (v << 24),
             _ => unreachable!(),
         };
 
-        self.store32(addr, mem);
+        self.store32(aligned_addr, mem);
     }
 
     fn op_lb(&mut self, instruction: Instruction) {
@@ -523,7 +940,8 @@ impl Cpu {
 
         let addr = self.reg(s).wrapping_add(i);
 
-        let v = self.load8(addr) as i8;
+        let Some(v) = self.load8(addr) else { return };
+        let v = v as i8;
 
         self.handle_load_delay_chain(t, v as u32);
     }
@@ -535,7 +953,7 @@ impl Cpu {
 
         let addr = self.reg(s).wrapping_add(i);
 
-        let v = self.load8(addr);
+        let Some(v) = self.load8(addr) else { return };
 
         self.handle_load_delay_chain(t, v as u32);
     }
@@ -547,11 +965,11 @@ impl Cpu {
 
         let addr = self.reg(s).wrapping_add(i);
 
-        if addr % 2 == 0{
-            let v = self.load16(addr) as i16;
-            self.handle_load_delay_chain(t, v as u32);
+        if addr.is_multiple_of(2){
+            let Some(v) = self.load16(addr) else { return };
+            self.handle_load_delay_chain(t, v as i16 as u32);
         } else {
-            self.exception(Exception::LoadAddressError);
+            self.address_error(Exception::LoadAddressError, addr);
         }
     }
 
@@ -562,20 +980,15 @@ impl Cpu {
 
         let addr = self.reg(s).wrapping_add(i);
 
-        if addr % 2 == 0{
-            let v = self.load16(addr);
+        if addr.is_multiple_of(2){
+            let Some(v) = self.load16(addr) else { return };
             self.handle_load_delay_chain(t, v as u32);
         } else {
-            self.exception(Exception::LoadAddressError);
+            self.address_error(Exception::LoadAddressError, addr);
         }
     }
 
     fn op_lwl(&mut self, instruction: Instruction) {
-        if self.sr & 0x10000 != 0 {
-            println!("ignoring load while cache is isolated");
-            return;
-        }
-
         let i = instruction.imm_se();
         let t = instruction.t();
         let s = instruction.s();
@@ -591,13 +1004,13 @@ impl Cpu {
         }
 
         let aligned_addr = addr & !3;
-        let aligned_word = self.load32(aligned_addr);
+        let Some(aligned_word) = self.load32(aligned_addr) else { return };
 
         let v = match addr & 3{
             0 => (cur_v & 0x00ffffff) | (aligned_word << 24),
             1 => (cur_v & 0x0000ffff) | (aligned_word << 16),
             2 => (cur_v & 0x000000ff) | (aligned_word << 8),
-            3 => (cur_v & 0x00000000) | (aligned_word << 0),
+            3 => aligned_word,
             _ => unreachable!(),
         };
 
@@ -605,11 +1018,6 @@ impl Cpu {
     }
 
     fn op_lwr(&mut self, instruction: Instruction) {
-        if self.sr & 0x10000 != 0 {
-            println!("ignoring load while cache is isolated");
-            return;
-        }
-
         let i = instruction.imm_se();
         let t = instruction.t();
         let s = instruction.s();
@@ -625,13 +1033,13 @@ impl Cpu {
         }
 
         let aligned_addr = addr & !3;
-        let aligned_word = self.load32(aligned_addr);
+        let Some(aligned_word) = self.load32(aligned_addr) else { return };
 
         let v = match addr & 3{
-            0 => (cur_v & 0x00000000) | (aligned_word >> 0),
-            1 => (cur_v & 0xff000000) | (aligned_word << 8),
-            2 => (cur_v & 0xffff0000) | (aligned_word << 16),
-            3 => (cur_v & 0xffffff00) | (aligned_word << 24),
+            0 => aligned_word,
+            1 => (cur_v & 0xff000000) | (aligned_word >> 8),
+            2 => (cur_v & 0xffff0000) | (aligned_word >> 16),
+            3 => (cur_v & 0xffffff00) | (aligned_word >> 24),
             _ => unreachable!(),
         };
 
@@ -639,22 +1047,17 @@ impl Cpu {
     }
 
     fn op_lw(&mut self, instruction: Instruction) {
-        if self.sr & 0x10000 != 0 {
-            println!("ignoring load while cache is isolated");
-            return;
-        }
-
         let i = instruction.imm_se();
         let t = instruction.t();
         let s = instruction.s();
 
         let addr = self.reg(s).wrapping_add(i);
-        if addr % 4 == 0{
-            let v = self.load32(addr);
+        if addr.is_multiple_of(4){
+            let Some(v) = self.load32(addr) else { return };
 
             self.handle_load_delay_chain(t, v);
         } else {
-            self.exception(Exception::LoadAddressError);
+            self.address_error(Exception::LoadAddressError, addr);
         }
     }
 
@@ -976,16 +1379,23 @@ impl Cpu {
     fn op_j(&mut self, instruction: Instruction) {
         let i = instruction.imm_jump();
 
-        self.next_pc = (self.pc & 0xf000_0000) | (i << 2);
-        self.branch  = true;
+        let target = (self.pc & 0xf000_0000) | (i << 2);
+        self.jump(target);
 
         self.handle_load_delay();
     }
 
     fn op_jal(&mut self, instruction: Instruction) {
-        let ra = self.next_pc;
+        let i = instruction.imm_jump();
 
-        self.op_j(instruction);
+        let ra     = self.next_pc;
+        let target = (self.pc & 0xf000_0000) | (i << 2);
+
+        if !self.jump(target) {
+            return;
+        }
+
+        self.handle_load_delay();
 
         self.set_reg(RegisterIndex(31), ra);
     }
@@ -994,8 +1404,8 @@ impl Cpu {
     fn op_jr(&mut self, instruction: Instruction) {
         let s = instruction.s();
 
-        self.next_pc = self.reg(s);
-        self.branch  = true;
+        let target = self.reg(s);
+        self.jump(target);
 
         self.handle_load_delay();
     }
@@ -1004,9 +1414,14 @@ impl Cpu {
         let d = instruction.d();
         let s = instruction.s();
 
-        let ra       = self.next_pc;
-        self.next_pc = self.reg(s);
-        self.branch  = true;
+        let ra     = self.next_pc;
+        let target = self.reg(s);
+
+        if !self.jump(target) {
+            return;
+        }
+
+        self.handle_load_delay();
 
         self.set_reg(d, ra);
     }
@@ -1016,9 +1431,7 @@ impl Cpu {
         let t = instruction.t();
         let s = instruction.s();
 
-        if self.reg(s) == self.reg(t) {
-            self.branch(i);
-        }
+        self.branch_if(self.reg(s) == self.reg(t), i);
 
         self.handle_load_delay();
     }
@@ -1028,9 +1441,7 @@ impl Cpu {
         let t = instruction.t();
         let s = instruction.s();
 
-        if self.reg(s) != self.reg(t) {
-            self.branch(i);
-        }
+        self.branch_if(self.reg(s) != self.reg(t), i);
 
         self.handle_load_delay();
     }
@@ -1041,9 +1452,7 @@ impl Cpu {
 
         let v = self.reg(s) as i32;
 
-        if v <= 0{
-            self.branch(i);
-        }
+        self.branch_if(v <= 0, i);
 
         self.handle_load_delay();
     }
@@ -1054,9 +1463,7 @@ impl Cpu {
 
         let v = self.reg(s) as i32;
 
-        if v > 0{
-            self.branch(i);
-        }
+        self.branch_if(v > 0, i);
 
         self.handle_load_delay();
     }
@@ -1078,18 +1485,17 @@ impl Cpu {
         self.handle_load_delay();
 
         match cop_r.0 {
-            3 | 5 | 6 | 7 | 9 | 11 => {
-                if v != 0 {
-                    panic!("Unhandled write to cop0r{}", cop_r.0)
-                }
-            }
+            3  => self.bpc = v,
+            5  => self.bda = v,
+            7  => self.dcic = v & DCIC_WRITE_MASK,
+            9  => self.bdam = v,
+            11 => self.bpcm = v,
             12 => self.sr = v,
-            13 => {
-                if v != 0 {
-                    panic!("Unhandled CAUSE register")
-                }
-            }
-            _ => panic!("Unhandled cop0 register {:08x}", cop_r.0),
+            // Only the software interrupt bits are writable
+            13 => self.cause = (self.cause & !0x300) | (v & 0x300),
+            // JUMPDEST, BadVaddr, EPC and PRID are read only
+            6 | 8 | 14 | 15 => (),
+            _ => println!("Unhandled write to cop0r{} <- {:08x}", cop_r.0, v),
         }
     }
 
@@ -1098,10 +1504,23 @@ impl Cpu {
         let cop_r = instruction.d().0;
 
         let v = match cop_r {
+            3  => self.bpc,
+            5  => self.bda,
+            6  => self.jumpdest,
+            7  => self.dcic,
+            8  => self.bad_vaddr,
+            9  => self.bdam,
+            11 => self.bpcm,
             12 => self.sr,
-            13 => self.cause,
+            13 => self.cause(),
             14 => self.epc,
-            _ => panic!("Unhandled cop0 register {:08x}", cop_r),
+            15 => Cpu::PRID,
+            // These registers don't exist
+            0..=2 | 4 | 10 => return self.exception(Exception::IllegalInstruction),
+            _ => {
+                println!("Unhandled read from cop0r{}", cop_r);
+                0
+            }
         };
 
         self.handle_load_delay_chain(cpu_r, v);
@@ -1139,8 +1558,11 @@ impl Cpu {
             panic!("Invalid cop0 instruction: {}",instruction.0);
         }
 
-        let mode = self.sr & 0x3f;
-        self.sr &= !0x3f;
+        self.handle_load_delay();
+
+        // The old mode bits 4-5 are left alone
+        let mode = self.sr & 0x3c;
+        self.sr &= !0xf;
         self.sr |= mode >> 2;
     }
 
@@ -1150,12 +1572,49 @@ impl Cpu {
     }
 }
 
+/// CPU registers visible to the host
+#[derive(Clone, Copy, Debug)]
+pub struct Registers {
+    /// Address of the next instruction
+    pub pc: u32,
+    /// General purpose registers
+    pub regs: [u32; 32],
+    pub hi: u32,
+    pub lo: u32,
+    /// COP0 status register
+    pub sr: u32,
+    /// COP0 cause register
+    pub cause: u32,
+    /// COP0 exception return address
+    pub epc: u32,
+    /// COP0 address of the last address error
+    pub bad_vaddr: u32,
+}
+
+/// DCIC status bits, set when a breakpoint triggers
+const DCIC_ANY_BREAK: u32 = 1 << 0;
+const DCIC_CODE_BREAK: u32 = 1 << 1;
+const DCIC_DATA_BREAK: u32 = 1 << 2;
+const DCIC_READ_BREAK: u32 = 1 << 3;
+const DCIC_WRITE_BREAK: u32 = 1 << 4;
+const DCIC_JUMP_BREAK: u32 = 1 << 5;
+
+/// DCIC bits that exist: status bits 0-5, jump redirection 12-15 and the
+/// enables 23-31
+const DCIC_WRITE_MASK: u32 = 0xff80f03f;
+
 /// Exception types stored in CAUSE register (cop0 - $13)
 enum Exception {
+    /// Interrupt request
+    Interrupt = 0x0,
     /// Address error on load
     LoadAddressError = 0x4,
     /// Address error on store
     StoreAddressError = 0x5,
+    /// Bus error on instruction fetch
+    InstructionBusError = 0x6,
+    /// Bus error on data load or store
+    DataBusError = 0x7,
     /// System Call Exception
     SysCall = 0x8,
     /// Break Exception
@@ -1184,8 +1643,8 @@ pub mod map {
     /// Range contains starting value and length
     pub struct Range(u32, u32);
 
-    /// RAM range
-    pub const RAM: Range = Range(0x00000000, 2 * 1024 * 1024);
+    /// RAM area, the installed RAM is mirrored in it depending on RAM_SIZE
+    pub const RAM: Range = Range(0x00000000, 8 * 1024 * 1024);
 
     /// BIOS range
     pub const BIOS: Range = Range(0x1fc00000, 512 * 1024);
@@ -1196,6 +1655,12 @@ pub mod map {
     pub const RAM_SIZE: Range = Range(0x1f801060, 4);
     pub const CACHE_CONTROL: Range = Range(0xfffe0130, 4);
 
+    /// Data cache used as fast RAM
+    pub const SCRATCHPAD: Range = Range(0x1f800000, 1024);
+
+    /// I/O ports area, devices are mapped inside
+    pub const IO: Range = Range(0x1f801000, 4 * 1024);
+
     // I/O
     /// Expansion 1
     pub const EXPANSION_1: Range = Range(0x1f000000, 8 * 1024);
@@ -1212,12 +1677,27 @@ pub mod map {
     /// GPU registers
     pub const GPU: Range = Range(0x1f801810, 8);
 
+    /// Macroblock decoder registers
+    pub const MDEC: Range = Range(0x1f801820, 8);
+
+    /// Controller and memory card serial port registers
+    pub const PAD: Range = Range(0x1f801040, 16);
+
+    /// Link cable serial port registers
+    pub const SIO1: Range = Range(0x1f801050, 16);
+
+    /// CD-ROM controller registers
+    pub const CDROM: Range = Range(0x1f801800, 4);
+
     /// Sound registers
     pub const SPU: Range = Range(0x1f801c00, 640);
 
     /// Expansion 2
     pub const EXPANSION_2: Range = Range(0x1f802000, 8 * 1024);
 
+    /// Expansion 3
+    pub const EXPANSION_3: Range = Range(0x1fa00000, 2 * 1024 * 1024);
+
     pub fn mask_region(addr: u32) -> u32 {
         let index = (addr >> 29) as usize;
 
@@ -1228,7 +1708,7 @@ pub mod map {
         pub fn contains(self, addr: u32) -> Option<u32> {
             let Range(start, length) = self;
 
-            if addr >= start && addr <= start + length {
+            if addr >= start && addr < start + length {
                 Some(addr - start)
             } else {
                 None
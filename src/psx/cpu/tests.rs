@@ -0,0 +1,1048 @@
+//! Instruction conformance tests, one small program per behaviour. Inputs
+//! are set with `set_reg` so that the number of instructions to run is
+//! the number of lines in the program
+
+use super::harness::{Machine, ENTRY};
+
+/// Scratch data area, far from the programs
+const DATA: u32 = 0x80001000;
+
+/// Exception vector used while SR.BEV is clear
+const VECTOR: u32 = 0x80000080;
+
+/// Run the `count` first instructions of `source` with `regs` preset
+fn run(source: &str, regs: &[(u32, u32)], count: usize) -> Machine {
+    let mut m = Machine::from_asm(source);
+
+    for &(r, v) in regs {
+        m.set_reg(r, v);
+    }
+
+    m.run(count);
+    m
+}
+
+/// Exception code field of CAUSE
+fn exception_code(m: &Machine) -> u32 {
+    (m.registers().cause >> 2) & 0x1f
+}
+
+/// Check that an exception with `code` was raised by the instruction at `epc`
+fn assert_exception(m: &Machine, code: u32, epc: u32) {
+    let r = m.registers();
+
+    assert_eq!(r.pc, VECTOR, "not in the exception handler");
+    assert_eq!(exception_code(m), code, "exception code");
+    assert_eq!(r.epc, epc, "EPC");
+}
+
+// Register names used by the programs
+const V0: u32 = 2;
+const A0: u32 = 4;
+const A1: u32 = 5;
+const T0: u32 = 8;
+const T1: u32 = 9;
+const T2: u32 = 10;
+const T3: u32 = 11;
+const RA: u32 = 31;
+
+#[test]
+fn lui_and_immediate_logic() {
+    let m = run(
+        "lui $t0, 0x1234
+         ori $t1, $t0, 0xabcd
+         andi $t2, $a0, 0xf0f0
+         xori $t3, $a0, 0xffff",
+        &[(A0, 0xffff_00ff)],
+        4,
+    );
+
+    assert_eq!(m.reg(T0 as usize), 0x1234_0000);
+    // The logical immediates are zero extended
+    assert_eq!(m.reg(T1 as usize), 0x1234_abcd);
+    assert_eq!(m.reg(T2 as usize), 0x0000_00f0);
+    assert_eq!(m.reg(T3 as usize), 0xffff_ff00);
+}
+
+#[test]
+fn register_logic() {
+    let m = run(
+        "and $t0, $a0, $a1
+         or $t1, $a0, $a1
+         xor $t2, $a0, $a1
+         nor $t3, $a0, $a1",
+        &[(A0, 0xff00_ff00), (A1, 0x0ff0_0ff0)],
+        4,
+    );
+
+    assert_eq!(m.reg(T0 as usize), 0x0f00_0f00);
+    assert_eq!(m.reg(T1 as usize), 0xfff0_fff0);
+    assert_eq!(m.reg(T2 as usize), 0xf0f0_f0f0);
+    assert_eq!(m.reg(T3 as usize), 0x000f_000f);
+}
+
+#[test]
+fn unsigned_arithmetic_wraps() {
+    let m = run(
+        "addiu $t0, $a0, 1
+         addiu $t1, $zero, -1
+         addu $t2, $a0, $a0
+         subu $t3, $zero, $a1",
+        &[(A0, 0xffff_ffff), (A1, 1)],
+        4,
+    );
+
+    assert_eq!(m.reg(T0 as usize), 0);
+    assert_eq!(m.reg(T1 as usize), 0xffff_ffff);
+    assert_eq!(m.reg(T2 as usize), 0xffff_fffe);
+    assert_eq!(m.reg(T3 as usize), 0xffff_ffff);
+}
+
+#[test]
+fn signed_arithmetic_without_overflow() {
+    let m = run(
+        "add $t0, $a0, $a0
+         addi $t1, $a0, -0x8000
+         sub $t2, $a1, $a0",
+        &[(A0, (-1i32) as u32), (A1, 0x7fff_fffe)],
+        3,
+    );
+
+    assert_eq!(m.reg(T0 as usize), (-2i32) as u32);
+    assert_eq!(m.reg(T1 as usize), (-0x8001i32) as u32);
+    assert_eq!(m.reg(T2 as usize), 0x7fff_ffff);
+    assert_eq!(m.registers().pc, ENTRY + 12);
+}
+
+#[test]
+fn add_overflow_raises_an_exception() {
+    let m = run("add $t0, $a0, $a1", &[(A0, 0x7fff_ffff), (A1, 1), (T0, 5)], 1);
+
+    assert_exception(&m, 0xc, ENTRY);
+    // The destination isn't written
+    assert_eq!(m.reg(T0 as usize), 5);
+}
+
+#[test]
+fn addi_overflow_raises_an_exception() {
+    let m = run("addi $t0, $a0, -1", &[(A0, 0x8000_0000), (T0, 5)], 1);
+
+    assert_exception(&m, 0xc, ENTRY);
+    assert_eq!(m.reg(T0 as usize), 5);
+}
+
+#[test]
+fn sub_overflow_raises_an_exception() {
+    let m = run(
+        "nop
+         sub $t0, $a0, $a1",
+        &[(A0, 0x8000_0000), (A1, 1), (T0, 5)],
+        2,
+    );
+
+    assert_exception(&m, 0xc, ENTRY + 4);
+    assert_eq!(m.reg(T0 as usize), 5);
+}
+
+#[test]
+fn set_on_less_than() {
+    let m = run(
+        "slt $t0, $a0, $a1
+         sltu $t1, $a0, $a1
+         slti $t2, $a0, 0
+         sltiu $t3, $a1, -1",
+        &[(A0, (-1i32) as u32), (A1, 1)],
+        4,
+    );
+
+    assert_eq!(m.reg(T0 as usize), 1);
+    assert_eq!(m.reg(T1 as usize), 0);
+    assert_eq!(m.reg(T2 as usize), 1);
+    // The immediate is sign extended then compared unsigned
+    assert_eq!(m.reg(T3 as usize), 1);
+}
+
+#[test]
+fn shifts() {
+    let m = run(
+        "sll $t0, $a0, 4
+         srl $t1, $a0, 4
+         sra $t2, $a0, 4
+         sllv $t3, $a0, $a1
+         srlv $v0, $a0, $a1
+         srav $v1, $a0, $a1",
+        &[(A0, 0x8000_00f0), (A1, 33)],
+        6,
+    );
+
+    assert_eq!(m.reg(T0 as usize), 0x0000_0f00);
+    assert_eq!(m.reg(T1 as usize), 0x0800_000f);
+    assert_eq!(m.reg(T2 as usize), 0xf800_000f);
+    // Only the low 5 bits of the shift amount are used
+    assert_eq!(m.reg(T3 as usize), 0x0000_01e0);
+    assert_eq!(m.reg(V0 as usize), 0x4000_0078);
+    assert_eq!(m.reg(3), 0xc000_0078);
+}
+
+#[test]
+fn zero_register_is_read_only() {
+    let mut m = run(
+        "addiu $zero, $zero, 1
+         lw $zero, 0($a0)
+         nop
+         or $t0, $zero, $zero",
+        &[(A0, DATA)],
+        0,
+    );
+
+    m.write(DATA, &[0x1234]);
+    m.run(4);
+
+    assert_eq!(m.reg(0), 0);
+    assert_eq!(m.reg(T0 as usize), 0);
+}
+
+#[test]
+fn multiplication() {
+    let m = run(
+        "mult $a0, $a1
+         mfhi $t0
+         mflo $t1
+         multu $a0, $a1
+         mfhi $t2
+         mflo $t3",
+        &[(A0, (-2i32) as u32), (A1, 3)],
+        6,
+    );
+
+    assert_eq!(m.reg(T0 as usize), 0xffff_ffff);
+    assert_eq!(m.reg(T1 as usize), 0xffff_fffa);
+    // 0xfffffffe * 3
+    assert_eq!(m.reg(T2 as usize), 0x0000_0002);
+    assert_eq!(m.reg(T3 as usize), 0xffff_fffa);
+}
+
+#[test]
+fn hi_lo_moves() {
+    let m = run(
+        "mthi $a0
+         mtlo $a1
+         mfhi $t0
+         mflo $t1",
+        &[(A0, 0x1111_1111), (A1, 0x2222_2222)],
+        4,
+    );
+
+    let r = m.registers();
+    assert_eq!((r.hi, r.lo), (0x1111_1111, 0x2222_2222));
+    assert_eq!(m.reg(T0 as usize), 0x1111_1111);
+    assert_eq!(m.reg(T1 as usize), 0x2222_2222);
+}
+
+/// HI and LO after `div`/`divu $a0, $a1`
+fn divide(op: &str, n: u32, d: u32) -> (u32, u32) {
+    let m = run(&format!("{} $a0, $a1", op), &[(A0, n), (A1, d)], 1);
+    let r = m.registers();
+
+    (r.hi, r.lo)
+}
+
+#[test]
+fn signed_division() {
+    let neg = |v: i32| v as u32;
+
+    assert_eq!(divide("div", 7, 2), (1, 3));
+    // Rounds towards zero, the remainder has the sign of the dividend
+    assert_eq!(divide("div", neg(-7), 2), (neg(-1), neg(-3)));
+    assert_eq!(divide("div", 7, neg(-2)), (1, neg(-3)));
+}
+
+#[test]
+fn signed_division_edge_cases() {
+    let neg = |v: i32| v as u32;
+
+    // Division by zero: the quotient is -1 or 1 depending on the sign
+    assert_eq!(divide("div", 5, 0), (5, 0xffff_ffff));
+    assert_eq!(divide("div", 0, 0), (0, 0xffff_ffff));
+    assert_eq!(divide("div", neg(-5), 0), (neg(-5), 1));
+    // INT_MIN / -1 doesn't trap
+    assert_eq!(divide("div", 0x8000_0000, neg(-1)), (0, 0x8000_0000));
+}
+
+#[test]
+fn unsigned_division() {
+    assert_eq!(divide("divu", 0xffff_ffff, 0x10), (0xf, 0x0fff_ffff));
+    assert_eq!(divide("divu", 0x8000_0000, 0xffff_ffff), (0x8000_0000, 0));
+    assert_eq!(divide("divu", 0x1234, 0), (0x1234, 0xffff_ffff));
+}
+
+/// Run a conditional branch on `$a0`/`$a1` over two increments of `$v0`,
+/// returns true if it was taken. The delay slot always runs
+fn branch_taken(branch: &str, a0: u32, a1: u32) -> bool {
+    let mut m = Machine::from_asm(&format!(
+        "        li $v0, 0
+                 {} target
+                 addiu $v0, $v0, 1
+                 addiu $v0, $v0, 2
+         target: nop",
+        branch
+    ));
+
+    m.set_reg(A0, a0);
+    m.set_reg(A1, a1);
+    m.run_until(ENTRY + 16, 10);
+
+    match m.reg(V0 as usize) {
+        1 => true,
+        3 => false,
+        v => panic!("{}: unexpected $v0 {}", branch, v),
+    }
+}
+
+#[test]
+fn conditional_branches() {
+    let neg = (-1i32) as u32;
+
+    let cases = [
+        ("beq $a0, $a1,", 1, 1, true),
+        ("beq $a0, $a1,", 1, 2, false),
+        ("bne $a0, $a1,", 1, 2, true),
+        ("bne $a0, $a1,", 2, 2, false),
+        ("blez $a0,", 0, 0, true),
+        ("blez $a0,", neg, 0, true),
+        ("blez $a0,", 1, 0, false),
+        ("bgtz $a0,", 1, 0, true),
+        ("bgtz $a0,", 0, 0, false),
+        ("bgtz $a0,", 0x8000_0000, 0, false),
+        ("bltz $a0,", neg, 0, true),
+        ("bltz $a0,", 0, 0, false),
+        ("bgez $a0,", 0, 0, true),
+        ("bgez $a0,", neg, 0, false),
+        ("bltzal $a0,", neg, 0, true),
+        ("bltzal $a0,", 1, 0, false),
+        ("bgezal $a0,", 1, 0, true),
+        ("bgezal $a0,", neg, 0, false),
+    ];
+
+    for (branch, a0, a1, taken) in cases {
+        assert_eq!(branch_taken(branch, a0, a1), taken, "{} {:x} {:x}", branch, a0, a1);
+    }
+}
+
+#[test]
+fn branch_and_link_always_links() {
+    for branch in ["bltzal", "bgezal"] {
+        let m = run(&format!("{} $a0, 0x80020000\nnop", branch), &[(A0, 1)], 1);
+
+        // Return address past the delay slot, taken or not
+        assert_eq!(m.reg(RA as usize), ENTRY + 8, "{}", branch);
+    }
+}
+
+#[test]
+fn branches_are_relative_to_the_delay_slot() {
+    let mut m = Machine::from_asm(
+        "back: nop
+               b back
+               nop",
+    );
+
+    m.run(3);
+
+    assert_eq!(m.registers().pc, ENTRY);
+}
+
+#[test]
+fn jumps_run_their_delay_slot() {
+    let m = run(
+        "        j target
+                 li $t0, 1
+                 li $t1, 1
+         target: li $t2, 1",
+        &[(T0, 0), (T1, 0), (T2, 0)],
+        3,
+    );
+
+    assert_eq!(m.reg(T0 as usize), 1);
+    assert_eq!(m.reg(T1 as usize), 0);
+    assert_eq!(m.reg(T2 as usize), 1);
+}
+
+#[test]
+fn jump_and_link() {
+    let m = run(
+        "        jal target
+                 nop
+                 nop
+         target: nop",
+        &[],
+        2,
+    );
+
+    assert_eq!(m.registers().pc, ENTRY + 12);
+    assert_eq!(m.reg(RA as usize), ENTRY + 8);
+}
+
+#[test]
+fn jump_register_and_link() {
+    let m = run(
+        "jalr $t1, $a0
+         nop",
+        &[(A0, ENTRY + 0x100)],
+        2,
+    );
+
+    assert_eq!(m.registers().pc, ENTRY + 0x100);
+    assert_eq!(m.reg(T1 as usize), ENTRY + 8);
+
+    let m = run("jr $ra\nnop", &[(RA, ENTRY + 0x40)], 2);
+
+    assert_eq!(m.registers().pc, ENTRY + 0x40);
+}
+
+#[test]
+fn misaligned_jump_target_faults_on_fetch() {
+    let m = run(
+        "jr $a0
+         li $t0, 1",
+        &[(A0, ENTRY + 0x42), (T0, 0)],
+        3,
+    );
+
+    // The delay slot ran, the fault is on the target
+    assert_eq!(m.reg(T0 as usize), 1);
+    assert_exception(&m, 0x4, ENTRY + 0x42);
+    assert_eq!(m.registers().bad_vaddr, ENTRY + 0x42);
+}
+
+#[test]
+fn byte_and_halfword_loads() {
+    let mut m = run(
+        "lb $t0, 1($a0)
+         lbu $t1, 1($a0)
+         lh $t2, 2($a0)
+         lhu $t3, 2($a0)
+         nop",
+        &[(A0, DATA)],
+        0,
+    );
+
+    m.write(DATA, &[0x8281_8000]);
+    m.run(5);
+
+    assert_eq!(m.reg(T0 as usize), 0xffff_ff80);
+    assert_eq!(m.reg(T1 as usize), 0x0000_0080);
+    assert_eq!(m.reg(T2 as usize), 0xffff_8281);
+    assert_eq!(m.reg(T3 as usize), 0x0000_8281);
+}
+
+#[test]
+fn byte_and_halfword_stores() {
+    let mut m = run(
+        "sb $a1, 1($a0)
+         sh $a1, 6($a0)",
+        &[(A0, DATA), (A1, 0xaabb_ccdd)],
+        0,
+    );
+
+    m.write(DATA, &[0x3322_1100, 0x7766_5544]);
+    m.run(2);
+
+    assert_eq!(m.load32(DATA), 0x3322_dd00);
+    assert_eq!(m.load32(DATA + 4), 0xccdd_5544);
+}
+
+#[test]
+fn word_load_and_store() {
+    let mut m = run(
+        "lw $t0, 4($a0)
+         nop
+         sw $t0, -4($a0)",
+        &[(A0, DATA + 4)],
+        0,
+    );
+
+    m.write(DATA + 8, &[0xcafe_f00d]);
+    m.run(3);
+
+    assert_eq!(m.reg(T0 as usize), 0xcafe_f00d);
+    assert_eq!(m.load32(DATA), 0xcafe_f00d);
+}
+
+#[test]
+fn misaligned_accesses_raise_address_errors() {
+    let cases = [
+        ("lw $t0, 2($a0)", 0x4, DATA + 2),
+        ("lh $t0, 1($a0)", 0x4, DATA + 1),
+        ("lhu $t0, 3($a0)", 0x4, DATA + 3),
+        ("sw $t0, 1($a0)", 0x5, DATA + 1),
+        ("sh $t0, 1($a0)", 0x5, DATA + 1),
+    ];
+
+    for (source, code, addr) in cases {
+        let mut m = run(source, &[(A0, DATA), (T0, 5)], 0);
+
+        m.write(DATA, &[0x1234_5678, 0x1234_5678]);
+        m.run(1);
+
+        assert_eq!(exception_code(&m), code, "{}", source);
+        assert_eq!(m.registers().bad_vaddr, addr, "{}", source);
+        assert_eq!(m.registers().epc, ENTRY, "{}", source);
+        assert_eq!(m.reg(T0 as usize), 5, "{}", source);
+        assert_eq!(m.load32(DATA), 0x1234_5678, "{}", source);
+    }
+}
+
+#[test]
+fn loaded_value_isnt_visible_in_the_load_delay_slot() {
+    let mut m = run(
+        "lw $t0, 0($a0)
+         move $t1, $t0
+         move $t2, $t0",
+        &[(A0, DATA), (T0, 1)],
+        0,
+    );
+
+    m.write(DATA, &[0x1234]);
+
+    m.run(1);
+    assert_eq!(m.reg(T0 as usize), 1, "written before the delay slot");
+
+    m.run(2);
+    assert_eq!(m.reg(T1 as usize), 1);
+    assert_eq!(m.reg(T2 as usize), 0x1234);
+}
+
+#[test]
+fn consecutive_loads_to_the_same_register() {
+    let mut m = run(
+        "lw $t0, 0($a0)
+         lw $t0, 4($a0)
+         move $t1, $t0
+         move $t2, $t0",
+        &[(A0, DATA), (T0, 1)],
+        0,
+    );
+
+    m.write(DATA, &[0x1111, 0x2222]);
+    m.run(4);
+
+    // The first load is dropped
+    assert_eq!(m.reg(T1 as usize), 1);
+    assert_eq!(m.reg(T2 as usize), 0x2222);
+}
+
+#[test]
+fn consecutive_loads_to_different_registers() {
+    let mut m = run(
+        "lw $t0, 0($a0)
+         lw $t1, 4($a0)
+         move $t2, $t0
+         move $t3, $t1",
+        &[(A0, DATA)],
+        0,
+    );
+
+    m.write(DATA, &[0x1111, 0x2222]);
+    m.run(4);
+
+    assert_eq!(m.reg(T2 as usize), 0x1111);
+    assert_eq!(m.reg(T3 as usize), 0x2222);
+}
+
+#[test]
+fn write_in_the_load_delay_slot_wins() {
+    let mut m = run(
+        "lw $t0, 0($a0)
+         li $t0, 5
+         nop",
+        &[(A0, DATA)],
+        0,
+    );
+
+    m.write(DATA, &[0x1234]);
+    m.run(3);
+
+    assert_eq!(m.reg(T0 as usize), 5);
+}
+
+#[test]
+fn load_delay_completes_across_jumps_and_stores() {
+    for (source, count) in [
+        ("lw $t0, 0($a0)\njalr $t3, $a1\nmove $t1, $t0", 3),
+        ("lw $t0, 0($a0)\njr $a1\nmove $t1, $t0", 3),
+        ("lw $t0, 0($a0)\nswl $t2, 7($a0)\nmove $t1, $t0", 3),
+        ("lw $t0, 0($a0)\nswr $t2, 4($a0)\nmove $t1, $t0", 3),
+        ("lw $t0, 0($a0)\nrfe\nmove $t1, $t0", 3),
+    ] {
+        let mut m = run(source, &[(A0, DATA), (A1, ENTRY + 8), (T0, 1)], 0);
+
+        m.write(DATA, &[0x1234]);
+        m.run(count);
+
+        assert_eq!(m.reg(T1 as usize), 0x1234, "{}", source);
+    }
+}
+
+#[test]
+fn load_delay_completes_before_an_exception() {
+    let mut m = run(
+        "lw $t0, 0($a0)
+         syscall",
+        &[(A0, DATA), (T0, 1)],
+        0,
+    );
+
+    m.write(DATA, &[0x1234]);
+    m.run(2);
+
+    assert_exception(&m, 0x8, ENTRY + 4);
+    assert_eq!(m.reg(T0 as usize), 0x1234);
+}
+
+/// Value of `$t0` after an unaligned load at each offset of the word
+/// 0x33221100, starting from 0xaabbccdd
+fn unaligned_loads(op: &str) -> Vec<u32> {
+    (0..4)
+        .map(|offset| {
+            let mut m = run(&format!("{} $t0, {}($a0)\nnop", op, offset), &[(A0, DATA), (T0, 0xaabb_ccdd)], 0);
+
+            m.write(DATA, &[0x3322_1100]);
+            m.run(2);
+
+            m.reg(T0 as usize)
+        })
+        .collect()
+}
+
+#[test]
+fn load_word_left_merges() {
+    assert_eq!(unaligned_loads("lwl"), [0x00bb_ccdd, 0x1100_ccdd, 0x2211_00dd, 0x3322_1100]);
+}
+
+#[test]
+fn load_word_right_merges() {
+    assert_eq!(unaligned_loads("lwr"), [0x3322_1100, 0xaa33_2211, 0xaabb_3322, 0xaabb_cc33]);
+}
+
+#[test]
+fn unaligned_load_pair_uses_the_pending_load() {
+    let mut m = run(
+        "lwr $t0, 1($a0)
+         lwl $t0, 4($a0)
+         nop",
+        &[(A0, DATA)],
+        0,
+    );
+
+    m.write(DATA, &[0x3322_1100, 0x7766_5544]);
+    m.run(3);
+
+    assert_eq!(m.reg(T0 as usize), 0x4433_2211);
+}
+
+/// Memory at DATA after an unaligned store of 0xaabbccdd at each offset of
+/// the word 0x33221100
+fn unaligned_stores(op: &str) -> Vec<u32> {
+    (0..4)
+        .map(|offset| {
+            let mut m = run(&format!("{} $t0, {}($a0)", op, offset), &[(A0, DATA), (T0, 0xaabb_ccdd)], 0);
+
+            m.write(DATA, &[0x3322_1100, 0x7766_5544]);
+            m.run(1);
+
+            // The next word must be left alone
+            assert_eq!(m.load32(DATA + 4), 0x7766_5544, "{} offset {}", op, offset);
+
+            m.load32(DATA)
+        })
+        .collect()
+}
+
+#[test]
+fn store_word_left_merges() {
+    assert_eq!(unaligned_stores("swl"), [0x3322_11aa, 0x3322_aabb, 0x33aa_bbcc, 0xaabb_ccdd]);
+}
+
+#[test]
+fn store_word_right_merges() {
+    assert_eq!(unaligned_stores("swr"), [0xaabb_ccdd, 0xbbcc_dd00, 0xccdd_1100, 0xdd22_1100]);
+}
+
+#[test]
+fn syscall_and_break() {
+    let m = run("syscall", &[], 1);
+    assert_exception(&m, 0x8, ENTRY);
+
+    let m = run("nop\nbreak 0x10", &[], 2);
+    assert_exception(&m, 0x9, ENTRY + 4);
+}
+
+#[test]
+fn exception_pushes_the_mode_stack_and_rfe_pops_it() {
+    let mut m = run(
+        "mtc0 $a0, $12
+         syscall",
+        &[(A0, 0x0000_0003)],
+        2,
+    );
+
+    assert_eq!(m.registers().sr & 0x3f, 0x0c);
+
+    // Handler at the vector
+    m.write(VECTOR, &[0x42000010]); // rfe
+    m.run(1);
+
+    assert_eq!(m.registers().sr & 0x3f, 0x03);
+}
+
+#[test]
+fn rfe_keeps_the_old_mode_bits() {
+    let m = run(
+        "mtc0 $a0, $12
+         rfe",
+        &[(A0, 0x0000_003c)],
+        2,
+    );
+
+    assert_eq!(m.registers().sr & 0x3f, 0x3f);
+}
+
+#[test]
+fn exception_in_a_branch_delay_slot() {
+    let m = run(
+        "        nop
+                 beq $zero, $zero, target
+                 syscall
+         target: nop",
+        &[],
+        3,
+    );
+
+    let r = m.registers();
+
+    assert_exception(&m, 0x8, ENTRY + 4);
+    assert_ne!(r.cause & (1 << 31), 0, "BD not set");
+}
+
+#[test]
+fn exception_in_a_jump_delay_slot() {
+    let m = run(
+        "jr $a0
+         add $t0, $a1, $a1",
+        &[(A0, ENTRY + 0x100), (A1, 0x4000_0000)],
+        2,
+    );
+
+    assert_exception(&m, 0xc, ENTRY);
+    assert_ne!(m.registers().cause & (1 << 31), 0, "BD not set");
+}
+
+#[test]
+fn exception_in_a_not_taken_branch_delay_slot() {
+    let m = run(
+        "bne $zero, $zero, 0x80020000
+         syscall",
+        &[],
+        2,
+    );
+
+    // The delay slot of a branch is one, taken or not
+    assert_exception(&m, 0x8, ENTRY);
+    assert_ne!(m.registers().cause & (1 << 31), 0, "BD not set");
+}
+
+#[test]
+fn exception_outside_of_a_delay_slot_clears_bd() {
+    let mut m = run(
+        "j 0x80020000
+         syscall",
+        &[],
+        2,
+    );
+
+    assert_ne!(m.registers().cause & (1 << 31), 0, "BD not set");
+
+    // Second exception, from the handler
+    m.write(VECTOR, &[0x0000000c]); // syscall
+    m.run(1);
+
+    assert_exception(&m, 0x8, VECTOR);
+    assert_eq!(m.registers().cause & (1 << 31), 0);
+}
+
+#[test]
+fn software_interrupt() {
+    let m = run(
+        "mtc0 $a0, $12
+         mtc0 $a1, $13
+         nop",
+        &[(A0, 0x0000_0101), (A1, 0x0000_0100)],
+        3,
+    );
+
+    // Taken before the instruction runs, which gets restarted
+    assert_exception(&m, 0x0, ENTRY + 8);
+}
+
+#[test]
+fn illegal_instructions() {
+    for word in [0x0000_003fu32, 0xfc00_0000] {
+        let m = run(&format!(".word {:#x}", word), &[], 1);
+
+        assert_exception(&m, 0xa, ENTRY);
+    }
+}
+
+#[test]
+fn missing_coprocessors() {
+    for source in [
+        "cop1 0x0",
+        "cop3 0x0",
+        "lwc0 $0, 0x0($a0)",
+        "lwc1 $0, 0x0($a0)",
+        "lwc3 $0, 0x0($a0)",
+        "swc0 $0, 0x0($a0)",
+        "swc1 $0, 0x0($a0)",
+        "swc3 $0, 0x0($a0)",
+    ] {
+        let m = run(source, &[(A0, DATA)], 1);
+
+        assert_eq!(exception_code(&m), 0xb, "{}", source);
+    }
+}
+
+#[test]
+#[should_panic(expected = "GTE")]
+fn gte_is_not_emulated() {
+    run("cop2 0x0180001", &[], 1);
+}
+
+#[test]
+fn cop0_reads_have_a_load_delay() {
+    let m = run(
+        "mfc0 $t0, $15
+         move $t1, $t0
+         move $t2, $t0",
+        &[(T0, 1)],
+        3,
+    );
+
+    assert_eq!(m.reg(T1 as usize), 1);
+    // PRID
+    assert_eq!(m.reg(T2 as usize), 2);
+}
+
+#[test]
+fn cop0_writes() {
+    let m = run(
+        "mtc0 $a0, $12
+         mtc0 $a0, $13
+         mtc0 $a0, $14
+         mfc0 $t0, $12
+         mfc0 $t1, $13
+         mfc0 $t2, $14
+         nop",
+        &[(A0, 0x1234_0300)],
+        7,
+    );
+
+    assert_eq!(m.reg(T0 as usize), 0x1234_0300);
+    // Only the software interrupt bits of CAUSE are writable, EPC is read
+    // only. Interrupts are disabled in SR so nothing is taken
+    assert_eq!(m.reg(T1 as usize), 0x0000_0300);
+    assert_eq!(m.reg(T2 as usize), 0);
+}
+
+#[test]
+fn missing_cop0_registers_are_illegal() {
+    let m = run("mfc0 $t0, $4", &[], 1);
+
+    assert_exception(&m, 0xa, ENTRY);
+}
+
+#[test]
+fn jump_breakpoints_stop_before_the_jump() {
+    // DCIC: super master enables, jump master enable and break on jumps
+    let dcic = (1 << 31) | (1 << 29) | (1 << 28) | (1 << 23);
+
+    for jump in ["jal target", "jalr $ra, $a1", "bgezal $zero, target"] {
+        let source = format!(
+            "        mtc0 $a0, $7
+                     {}
+                     nop
+             target: nop",
+            jump
+        );
+
+        let mut m = run(&source, &[(A0, dcic), (A1, ENTRY + 12), (RA, 0x1234)], 2);
+
+        // Debug exception vector, reading back DCIC and CAUSE
+        let handler = super::asm::assemble("mfc0 $t0, $7\nmfc0 $t1, $13\nnop", 0x80000040).unwrap();
+        m.write(0x80000040, &handler);
+        m.run(3);
+
+        let r = m.registers();
+        assert_eq!(r.pc, 0x8000004c, "{}: the handler runs normally", jump);
+        assert_eq!(r.epc, ENTRY + 4, "{}", jump);
+        assert_eq!(m.reg(RA as usize), 0x1234, "{}: no link", jump);
+        assert_eq!(m.reg(T0 as usize) & 0x3f, 0x21, "{}: DCIC status", jump);
+        assert_eq!(m.reg(T1 as usize), 0x9 << 2, "{}: break, not in a delay slot", jump);
+    }
+}
+
+#[test]
+fn isolated_byte_and_halfword_stores_use_their_lane() {
+    let m = run(
+        "mtc0 $a1, $12
+         sb $a0, 1($t0)
+         sh $a0, 6($t0)
+         lw $t1, 0($t0)
+         lw $t2, 4($t0)
+         nop",
+        &[(A0, 0x1234_5678), (A1, 0x0001_0000), (T0, DATA)],
+        6,
+    );
+
+    // The cache line word is replaced as a whole
+    assert_eq!(m.reg(T1 as usize), 0x0000_7800);
+    assert_eq!(m.reg(T2 as usize), 0x5678_0000);
+}
+
+const CACHE_CONTROL: u32 = 0xfffe0130;
+
+/// Cycles taken by each of the next `count` instructions
+fn step_cycles(m: &mut Machine, count: usize) -> Vec<u64> {
+    (0..count)
+        .map(|_| {
+            let start = m.cycles();
+            m.run(1);
+            m.cycles() - start
+        })
+        .collect()
+}
+
+#[test]
+fn cache_hits_are_faster_than_refills() {
+    let mut m = Machine::from_asm(
+        "loop: addiu $t0, $t0, 1
+               bne $t0, $a0, loop
+               nop",
+    );
+    m.store32(CACHE_CONTROL, 1 << 11);
+    m.set_reg(T0, 0);
+    m.set_reg(A0, 2);
+
+    // The miss refills the whole line from RAM, the second pass only hits
+    assert_eq!(step_cycles(&mut m, 6), [20, 1, 1, 1, 1, 1]);
+    assert_eq!(m.reg(T0 as usize), 2);
+}
+
+#[test]
+fn refills_start_at_the_missed_word() {
+    let mut m = Machine::from_asm(
+        "       j mid
+                nop
+                nop
+                nop
+         start: nop
+                nop
+         mid:   j start
+                nop",
+    );
+    m.store32(CACHE_CONTROL, 1 << 11);
+
+    // Entering the second line at word 2 only fetches words 2 and 3, word 0
+    // misses later and refills up to the end of the line again
+    assert_eq!(step_cycles(&mut m, 6), [20, 1, 10, 1, 20, 1]);
+    assert_eq!(m.registers().pc, ENTRY + 0x18);
+}
+
+#[test]
+fn uncached_fetches_go_to_the_bus() {
+    let program = super::asm::assemble("loop: b loop\nnop", 0xa0010000).unwrap();
+
+    // KSEG1 is never cached
+    let mut m = Machine::new(&program, 0xa0010000);
+    m.store32(CACHE_CONTROL, 1 << 11);
+    assert_eq!(step_cycles(&mut m, 4), [5, 5, 5, 5]);
+
+    // Neither is KSEG0 with the cache disabled
+    let mut m = Machine::from_asm("loop: b loop\nnop");
+    assert_eq!(step_cycles(&mut m, 4), [5, 5, 5, 5]);
+}
+
+#[test]
+fn tag_test_stores_invalidate_cached_code() {
+    let mut m = Machine::from_asm("addiu $t0, $t0, 1");
+    let flush = super::asm::assemble(
+        "mtc0 $a1, $12
+         sw $zero, 0($a0)
+         mtc0 $zero, $12",
+        0xa0010100,
+    )
+    .unwrap();
+    m.write(0xa0010100, &flush);
+
+    // Cache enabled, isolated accesses hit the tags
+    m.store32(CACHE_CONTROL, 1 << 11 | 1 << 2);
+    m.set_reg(T0, 0);
+    m.run(1);
+
+    // Patch the code behind the cache's back, the stale copy still runs
+    let patched = super::asm::assemble("addiu $t0, $t0, 2", ENTRY).unwrap();
+    m.cpu_mut().interconnect_mut().store32(ENTRY, patched[0]).unwrap();
+    m.cpu_mut().set_pc(ENTRY);
+    m.run(1);
+    assert_eq!(m.reg(T0 as usize), 2);
+
+    // Isolated store to the line in tag test mode, the way the BIOS flushes
+    m.set_reg(A0, ENTRY);
+    m.set_reg(A1, 0x0001_0000);
+    m.cpu_mut().set_pc(0xa0010100);
+    m.run(3);
+
+    m.cpu_mut().set_pc(ENTRY);
+    m.run(1);
+    assert_eq!(m.reg(T0 as usize), 4);
+    assert_eq!(m.load32(ENTRY), patched[0], "the store didn't reach RAM");
+}
+
+/// Trace output shared with the test
+#[derive(Clone, Default)]
+struct TraceBuffer(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+impl std::io::Write for TraceBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn instructions_preempted_by_an_interrupt_are_not_traced() {
+    let mut m = Machine::from_asm(
+        "mtc0 $a1, $12
+         mtc0 $a0, $13
+         addiu $t0, $t0, 1",
+    );
+
+    let trace = TraceBuffer::default();
+    m.set_trace(Some(Box::new(trace.clone())));
+
+    // IEc and IM0 in SR, then software interrupt 0 in CAUSE
+    m.set_reg(A1, 0x0000_0101);
+    m.set_reg(A0, 0x0000_0100);
+    m.set_reg(T0, 0);
+    m.run(3);
+
+    assert_exception(&m, 0x0, ENTRY + 8);
+    assert_eq!(m.reg(T0 as usize), 0);
+
+    let trace = String::from_utf8(trace.0.borrow().clone()).unwrap();
+    assert_eq!(trace.lines().count(), 2, "{}", trace);
+}
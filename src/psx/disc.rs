@@ -0,0 +1,340 @@
+use anyhow::{anyhow, Result};
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use super::Region;
+
+/// Size of a raw CD sector, including sync, header and error correction
+pub const SECTOR_SIZE: usize = 2352;
+
+/// Offset of the Mode 2 subheader in a raw sector
+const SUBHEADER_OFFSET: usize = 16;
+/// Offset of the user data in a raw Mode 2 sector
+const DATA_OFFSET: usize = 24;
+
+/// Sync pattern found at the start of every raw data sector
+const SYNC: [u8; 12] = [
+    0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00,
+];
+
+/// Image made of consecutive CD sectors. Works for full disc images as well
+/// as single files extracted with their raw sectors (like `.STR` videos)
+pub struct Disc {
+    file: File,
+    format: SectorFormat,
+    /// Number of sectors in the image
+    sectors: u32,
+}
+
+impl Disc {
+    pub fn open(path: &Path) -> Result<Disc> {
+        let mut file = File::open(path)
+            .map_err(|e| anyhow!("Can't open disc image {}: {}", path.display(), e))?;
+
+        let len = file.metadata()?.len();
+
+        let mut sync = [0u8; 12];
+        let has_sync = file.read_exact(&mut sync).is_ok() && sync == SYNC;
+
+        let format = if len % SECTOR_SIZE as u64 == 0 && has_sync {
+            SectorFormat::Raw
+        } else if len % 2336 == 0 {
+            SectorFormat::Mode2
+        } else if len % 2048 == 0 {
+            SectorFormat::Cooked
+        } else {
+            return Err(anyhow!(
+                "{} is not a sector image ({} bytes)",
+                path.display(),
+                len
+            ));
+        };
+
+        let sectors = (len / format.size() as u64) as u32;
+
+        Ok(Disc {
+            file,
+            format,
+            sectors,
+        })
+    }
+
+    pub fn sectors(&self) -> u32 {
+        self.sectors
+    }
+
+    pub fn format(&self) -> SectorFormat {
+        self.format
+    }
+
+    /// Read sector `lba` (relative to the start of the image) and return
+    /// it as a raw 2352 bytes sector
+    pub fn read_sector(&mut self, lba: u32) -> Result<Sector> {
+        if lba >= self.sectors {
+            return Err(anyhow!("Sector {} is past the end of the image", lba));
+        }
+
+        let size = self.format.size();
+
+        self.file.seek(SeekFrom::Start(lba as u64 * size as u64))?;
+
+        let mut data = vec![0; SECTOR_SIZE];
+
+        match self.format {
+            SectorFormat::Raw => self.file.read_exact(&mut data)?,
+            SectorFormat::Mode2 => {
+                data[..12].copy_from_slice(&SYNC);
+                data[15] = 2;
+                self.file.read_exact(&mut data[SUBHEADER_OFFSET..])?
+            }
+            SectorFormat::Cooked => {
+                data[..12].copy_from_slice(&SYNC);
+                data[15] = 2;
+                self.file.read_exact(&mut data[DATA_OFFSET..DATA_OFFSET + 2048])?
+            }
+        }
+
+        Ok(Sector { data })
+    }
+
+    /// Region named in the license text of sector 4, as checked by the
+    /// console before booting
+    pub fn license_region(&mut self) -> Option<Region> {
+        let sector = self.read_sector(4).ok()?;
+        let text = String::from_utf8_lossy(sector.data_form1());
+
+        // "Sony Computer Entertainment Amer  ica", "Euro pe" or "Inc."
+        if text.contains("Amer") {
+            Some(Region::NtscU)
+        } else if text.contains("Euro") {
+            Some(Region::Pal)
+        } else if text.contains("Inc.") {
+            Some(Region::NtscJ)
+        } else {
+            None
+        }
+    }
+
+    /// Look up `path` in the ISO9660 filesystem of the image
+    pub fn find_file(&mut self, path: &str) -> Result<DirEntry> {
+        let pvd = self.read_sector(16)?;
+        let pvd = pvd.data_form1();
+
+        if &pvd[1..6] != b"CD001" {
+            return Err(anyhow!("Disc image has no ISO9660 filesystem"));
+        }
+
+        let mut dir = DirEntry::parse(&pvd[156..])
+            .ok_or_else(|| anyhow!("Invalid ISO9660 root directory"))?;
+
+        for component in path.split(['/', '\\']).filter(|c| !c.is_empty()) {
+            dir = self
+                .read_dir(&dir)?
+                .into_iter()
+                .find(|e| e.name.eq_ignore_ascii_case(component))
+                .ok_or_else(|| anyhow!("File {} not found on disc", path))?;
+        }
+
+        Ok(dir)
+    }
+
+    /// Contents of an ISO9660 file stored in Form 1 sectors
+    pub fn read_file(&mut self, file: &DirEntry) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(file.size as usize);
+
+        for i in 0..file.size.div_ceil(2048) {
+            let sector = self.read_sector(file.lba + i)?;
+            data.extend_from_slice(sector.data_form1());
+        }
+
+        data.truncate(file.size as usize);
+
+        Ok(data)
+    }
+
+    /// List the entries of an ISO9660 directory
+    pub fn read_dir(&mut self, dir: &DirEntry) -> Result<Vec<DirEntry>> {
+        if !dir.is_dir {
+            return Err(anyhow!("{} is not a directory", dir.name));
+        }
+
+        let mut entries = Vec::new();
+
+        for i in 0..dir.size.div_ceil(2048) {
+            let sector = self.read_sector(dir.lba + i)?;
+            let data = sector.data_form1();
+
+            let mut pos = 0;
+            while pos < data.len() && data[pos] != 0 {
+                let len = data[pos] as usize;
+
+                if let Some(entry) = DirEntry::parse(&data[pos..]) {
+                    // Skip the "." and ".." entries
+                    if !entry.name.is_empty() {
+                        entries.push(entry);
+                    }
+                }
+
+                pos += len;
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SectorFormat {
+    /// 2352 bytes, full raw sectors
+    Raw,
+    /// 2336 bytes, Mode 2 sectors without sync and header
+    Mode2,
+    /// 2048 bytes, user data only
+    Cooked,
+}
+
+impl SectorFormat {
+    fn size(self) -> usize {
+        match self {
+            SectorFormat::Raw => SECTOR_SIZE,
+            SectorFormat::Mode2 => 2336,
+            SectorFormat::Cooked => 2048,
+        }
+    }
+}
+
+/// Raw 2352 bytes CD sector
+pub struct Sector {
+    data: Vec<u8>,
+}
+
+impl Sector {
+    /// Mode 2 subheader
+    pub fn subheader(&self) -> SubHeader {
+        let s = &self.data[SUBHEADER_OFFSET..];
+
+        SubHeader {
+            file: s[0],
+            channel: s[1],
+            submode: s[2],
+            coding: s[3],
+        }
+    }
+
+    /// 2048 bytes of user data of a Form 1 sector
+    pub fn data_form1(&self) -> &[u8] {
+        &self.data[DATA_OFFSET..DATA_OFFSET + 2048]
+    }
+
+    /// 2324 bytes of user data of a Form 2 sector
+    pub fn data_form2(&self) -> &[u8] {
+        &self.data[DATA_OFFSET..DATA_OFFSET + 2324]
+    }
+}
+
+/// Mode 2 subheader, describes the content of XA sectors
+#[derive(Clone, Copy, Debug)]
+pub struct SubHeader {
+    pub file: u8,
+    pub channel: u8,
+    pub submode: u8,
+    pub coding: u8,
+}
+
+impl SubHeader {
+    /// Submode bit 1
+    pub fn is_video(&self) -> bool {
+        self.submode & 0x02 != 0
+    }
+
+    /// Submode bit 2
+    pub fn is_audio(&self) -> bool {
+        self.submode & 0x04 != 0
+    }
+
+    /// Submode bit 3
+    pub fn is_data(&self) -> bool {
+        self.submode & 0x08 != 0
+    }
+}
+
+/// ISO9660 directory record
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    /// File name without the ";1" version suffix
+    pub name: String,
+    pub lba: u32,
+    pub size: u32,
+    pub is_dir: bool,
+}
+
+impl DirEntry {
+    fn parse(record: &[u8]) -> Option<DirEntry> {
+        let len = *record.first()? as usize;
+        if len < 34 || record.len() < len {
+            return None;
+        }
+
+        let lba = u32::from_le_bytes(record[2..6].try_into().ok()?);
+        let size = u32::from_le_bytes(record[10..14].try_into().ok()?);
+        let is_dir = record[25] & 2 != 0;
+
+        let name_len = record[32] as usize;
+        let name = record[..len].get(33..33 + name_len)?;
+
+        // "." and ".." are encoded as 0x00 and 0x01
+        let name = match name {
+            [0] | [1] => String::new(),
+            _ => {
+                let name = String::from_utf8_lossy(name);
+                name.split(';').next().unwrap_or("").to_string()
+            }
+        };
+
+        Some(DirEntry {
+            name,
+            lba,
+            size,
+            is_dir,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(name: &[u8]) -> Vec<u8> {
+        let mut r = vec![0; 33];
+
+        r[0] = (33 + name.len()) as u8;
+        r[2..6].copy_from_slice(&24u32.to_le_bytes());
+        r[10..14].copy_from_slice(&2048u32.to_le_bytes());
+        r[32] = name.len() as u8;
+        r.extend_from_slice(name);
+        r
+    }
+
+    #[test]
+    fn parses_directory_records() {
+        let e = DirEntry::parse(&record(b"MOVIE.STR;1")).unwrap();
+
+        assert_eq!((e.name.as_str(), e.lba, e.size, e.is_dir), ("MOVIE.STR", 24, 2048, false));
+        assert_eq!(DirEntry::parse(&record(&[1])).unwrap().name, "");
+    }
+
+    #[test]
+    fn rejects_names_past_the_record() {
+        let mut r = record(b"A;1");
+        r[32] = 200;
+        r.resize(255, 0);
+
+        assert!(DirEntry::parse(&r).is_none());
+
+        r[0] = 20;
+        assert!(DirEntry::parse(&r).is_none());
+    }
+}
@@ -13,6 +13,8 @@ pub struct Dma{
     /// no apparent use - [0:5] bits
     irq_dummy: u8,
 
+    /// The 7 channel instances
+    channels: [Channel; 7],
 }
 
 impl Dma{
@@ -22,7 +24,8 @@ impl Dma{
               channel_irq_en: 0,
               channel_irq_flags: 0,
               force_irq: false,
-              irq_dummy: 0
+              irq_dummy: 0,
+              channels: [Channel::new(); 7],
         }
     }
 
@@ -58,7 +61,257 @@ impl Dma{
         self.channel_irq_en = ((val >> 16) & 0x7f) as u8;
         self.irq_en = (val >> 23) & 1 != 0;
 
-        let ack = ((val >> 24) & 0x3f) as u8;
+        let ack = ((val >> 24) & 0x7f) as u8;
         self.channel_irq_flags &= !ack;
     }
+
+    pub fn channel(&self, port: Port) -> &Channel{
+        &self.channels[port as usize]
+    }
+
+    pub fn channel_mut(&mut self, port: Port) -> &mut Channel{
+        &mut self.channels[port as usize]
+    }
+
+    /// Mark the transfer on `port` as finished and raise its IRQ flag if enabled
+    pub fn done(&mut self, port: Port){
+        self.channels[port as usize].done();
+
+        let bit = 1 << (port as usize);
+
+        if self.channel_irq_en & bit != 0 {
+            self.channel_irq_flags |= bit;
+        }
+    }
+}
+
+/// DMA channel state - offsets 0x00, 0x04 and 0x08 of each channel
+#[derive(Clone, Copy)]
+pub struct Channel{
+    /// Channel Control - Bit 24
+    enable: bool,
+    /// Bit 0
+    direction: Direction,
+    /// Bit 1
+    step: Step,
+    /// Bits [9:10]
+    sync: Sync,
+    /// Manual trigger - Bit 28
+    trigger: bool,
+    /// Chopping enable - Bit 8
+    chop: bool,
+    /// Chopping DMA window size - Bits [16:18]
+    chop_dma_sz: u8,
+    /// Chopping CPU window size - Bits [20:22]
+    chop_cpu_sz: u8,
+    /// Unknown R/W bits - Bits [29:30]
+    dummy: u8,
+    /// Start address - MADR register
+    base: u32,
+    /// Block size in words (or word count in Manual mode) - Bits [0:15] of BCR
+    block_size: u16,
+    /// Number of blocks in Request mode - Bits [16:31] of BCR
+    block_count: u16,
+}
+
+impl Channel{
+    fn new() -> Self{
+        Channel {
+            enable: false,
+            direction: Direction::ToRam,
+            step: Step::Increment,
+            sync: Sync::Manual,
+            trigger: false,
+            chop: false,
+            chop_dma_sz: 0,
+            chop_cpu_sz: 0,
+            dummy: 0,
+            base: 0,
+            block_size: 0,
+            block_count: 0,
+        }
+    }
+
+    pub fn control(&self) -> u32{
+        let mut r: u32 = 0;
+
+        r |= self.direction as u32;
+        r |= (self.step as u32) << 1;
+        r |= (self.chop as u32) << 8;
+        r |= (self.sync as u32) << 9;
+        r |= (self.chop_dma_sz as u32) << 16;
+        r |= (self.chop_cpu_sz as u32) << 20;
+        r |= (self.enable as u32) << 24;
+        r |= (self.trigger as u32) << 28;
+        r |= (self.dummy as u32) << 29;
+
+        r
+    }
+
+    pub fn set_control(&mut self, val: u32){
+        self.direction = match val & 1 != 0 {
+            true  => Direction::FromRam,
+            false => Direction::ToRam,
+        };
+
+        self.step = match (val >> 1) & 1 != 0 {
+            true  => Step::Decrement,
+            false => Step::Increment,
+        };
+
+        self.chop = (val >> 8) & 1 != 0;
+
+        self.sync = match (val >> 9) & 3 {
+            0 => Sync::Manual,
+            1 => Sync::Request,
+            2 => Sync::LinkedList,
+            // Mode 3 isn't documented, the hardware runs it like mode 0
+            _ => Sync::Manual,
+        };
+
+        self.chop_dma_sz = ((val >> 16) & 7) as u8;
+        self.chop_cpu_sz = ((val >> 20) & 7) as u8;
+
+        self.enable  = (val >> 24) & 1 != 0;
+        self.trigger = (val >> 28) & 1 != 0;
+
+        self.dummy = ((val >> 29) & 3) as u8;
+    }
+
+    pub fn base(&self) -> u32{
+        self.base
+    }
+
+    /// Only bits [0:23] of the address are significant
+    pub fn set_base(&mut self, val: u32){
+        self.base = val & 0xffffff;
+    }
+
+    pub fn block_control(&self) -> u32{
+        let bs = self.block_size as u32;
+        let bc = self.block_count as u32;
+
+        (bc << 16) | bs
+    }
+
+    pub fn set_block_control(&mut self, val: u32){
+        self.block_size  = val as u16;
+        self.block_count = (val >> 16) as u16;
+    }
+
+    /// A channel is active when enabled and, in Manual mode, triggered
+    pub fn active(&self) -> bool{
+        let trigger = match self.sync {
+            Sync::Manual => self.trigger,
+            _            => true,
+        };
+
+        self.enable && trigger
+    }
+
+    pub fn direction(&self) -> Direction{
+        self.direction
+    }
+
+    pub fn step(&self) -> Step{
+        self.step
+    }
+
+    pub fn sync(&self) -> Sync{
+        self.sync
+    }
+
+    /// Transfer size in words, None for linked list mode
+    pub fn transfer_size(&self) -> Option<u32>{
+        let bs = self.block_size as u32;
+        let bc = self.block_count as u32;
+
+        match self.sync {
+            // A zero word count means 0x10000 words
+            Sync::Manual     => Some(if bs == 0 { 0x10000 } else { bs }),
+            Sync::Request    => Some(bc * bs),
+            Sync::LinkedList => None,
+        }
+    }
+
+    /// Words in each block of a Request mode transfer
+    pub fn block_size(&self) -> u32{
+        self.block_size as u32
+    }
+
+    /// Blocks left to transfer in Request mode
+    pub fn remaining_blocks(&self) -> u16{
+        self.block_count
+    }
+
+    /// A Request mode block went through: MADR points past it and the block
+    /// count goes down, like on the hardware
+    pub fn block_done(&mut self, next_base: u32){
+        self.set_base(next_base);
+        self.block_count -= 1;
+    }
+
+    fn done(&mut self){
+        self.enable  = false;
+        self.trigger = false;
+    }
+}
+
+/// Transfer direction
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Direction{
+    ToRam   = 0,
+    FromRam = 1,
+}
+
+/// Address step after each word
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Step{
+    Increment = 0,
+    Decrement = 1,
+}
+
+/// Synchronization mode
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Sync{
+    /// Transfer everything at once after trigger
+    Manual     = 0,
+    /// Sync blocks to DMA requests
+    Request    = 1,
+    /// Used to transfer GPU command lists
+    LinkedList = 2,
+}
+
+/// The 7 DMA ports
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Port{
+    /// Macroblock decoder input
+    MdecIn  = 0,
+    /// Macroblock decoder output
+    MdecOut = 1,
+    /// Graphics Processing Unit
+    Gpu     = 2,
+    /// CD-ROM drive
+    CdRom   = 3,
+    /// Sound Processing Unit
+    Spu     = 4,
+    /// Extension port
+    Pio     = 5,
+    /// Used to clear the ordering table
+    Otc     = 6,
+}
+
+impl Port{
+    pub fn from_index(index: u32) -> Port{
+        match index {
+            0 => Port::MdecIn,
+            1 => Port::MdecOut,
+            2 => Port::Gpu,
+            3 => Port::CdRom,
+            4 => Port::Spu,
+            5 => Port::Pio,
+            6 => Port::Otc,
+            n => panic!("Invalid DMA port {}", n),
+        }
+    }
 }
@@ -0,0 +1,131 @@
+use anyhow::{anyhow, Result};
+
+use super::cpu::Cpu;
+
+/// Size of the PS-X EXE header, the code follows it
+const HEADER_SIZE: usize = 0x800;
+
+/// Part of the header the BIOS copies to the buffer given to `Load`, from
+/// the entry point to the end of the stack settings
+const EXEC_INFO: std::ops::Range<usize> = 0x10..0x4c;
+
+/// PS-X EXE executable
+pub struct Exe {
+    /// Entry point
+    pub pc: u32,
+    /// Initial $gp
+    pub gp: u32,
+    /// Load address of the code
+    pub dest: u32,
+    pub text: Vec<u8>,
+    /// Zero filled area, address and size
+    pub bss: u32,
+    pub bss_size: u32,
+    /// Initial $sp and $fp, 0 to keep the BIOS stack
+    pub sp: u32,
+    /// Header fields handed to `Exec`, see `EXEC_INFO`
+    pub exec_info: Vec<u8>,
+}
+
+impl Exe {
+    pub fn parse(data: &[u8]) -> Result<Exe> {
+        if data.len() < HEADER_SIZE || &data[0..8] != b"PS-X EXE" {
+            return Err(anyhow!("Not a PS-X EXE"));
+        }
+
+        let word = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+
+        let size = word(0x1c) as usize;
+        let text = data[HEADER_SIZE..]
+            .get(..size)
+            .ok_or_else(|| anyhow!("Truncated PS-X EXE"))?;
+
+        let sp = match word(0x30) {
+            0 => 0,
+            base => base.wrapping_add(word(0x34)),
+        };
+
+        Ok(Exe {
+            pc: word(0x10),
+            gp: word(0x14),
+            dest: word(0x18),
+            text: text.to_vec(),
+            bss: word(0x28),
+            bss_size: word(0x2c),
+            sp,
+            exec_info: data[EXEC_INFO].to_vec(),
+        })
+    }
+
+    /// Copy the code to RAM
+    pub fn load(&self, cpu: &mut Cpu) {
+        let ram = cpu.interconnect_mut().ram_mut();
+        let mask = ram.size() - 1;
+
+        for (i, &b) in self.text.iter().enumerate() {
+            ram.store8(self.dest.wrapping_add(i as u32) & mask, b);
+        }
+
+        cpu.flush_cache();
+    }
+
+    /// Load the code, clear the BSS and jump to the code. `sp` replaces
+    /// the stack of the header when not 0
+    pub fn start(&self, cpu: &mut Cpu, sp: u32) {
+        self.load(cpu);
+
+        let ram = cpu.interconnect_mut().ram_mut();
+        let mask = ram.size() - 1;
+
+        // Bigger than RAM means a bogus header, don't spin on it
+        for i in 0..self.bss_size.min(mask + 1) {
+            ram.store8(self.bss.wrapping_add(i) & mask, 0);
+        }
+
+        cpu.set_register(28, self.gp);
+
+        let sp = match sp {
+            0 => self.sp,
+            sp => sp,
+        };
+
+        if sp != 0 {
+            cpu.set_register(29, sp);
+            cpu.set_register(30, sp);
+        }
+
+        cpu.set_pc(self.pc);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psx::cpu::harness::{Machine, ENTRY};
+
+    #[test]
+    fn start_clears_the_bss() {
+        let mut m = Machine::new(&[], ENTRY);
+
+        m.write(0x80020000, &[0xffffffff; 4]);
+
+        let exe = Exe {
+            pc: ENTRY,
+            gp: 0,
+            dest: ENTRY,
+            text: vec![0; 4],
+            bss: 0x80020004,
+            bss_size: 8,
+            sp: 0,
+            exec_info: Vec::new(),
+        };
+
+        exe.start(m.cpu_mut(), 0);
+
+        assert_eq!(m.load32(0x80020000), 0xffffffff);
+        assert_eq!(m.load32(0x80020004), 0);
+        assert_eq!(m.load32(0x80020008), 0);
+        assert_eq!(m.load32(0x8002000c), 0xffffffff);
+        assert_eq!(m.registers().pc, ENTRY);
+    }
+}
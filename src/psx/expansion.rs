@@ -0,0 +1,248 @@
+use anyhow::{anyhow, Result};
+use std::{fs, path::Path};
+
+use super::sio1::Link;
+
+/// License string the BIOS looks for before calling a cartridge entry point
+const LICENSE: &[u8] = b"Licensed by Sony Computer Entertainment Inc.";
+
+/// Cartridge entry points, each followed by the license string: the BIOS
+/// calls the pre-boot one before initializing the kernel and the post-boot
+/// one before starting the shell
+const ENTRY_POINTS: [(usize, &str); 2] = [(0x80, "pre-boot"), (0x00, "post-boot")];
+
+/// Largest cartridge ROM, the default size of Expansion 1. In images
+/// bigger than 128KiB the registers below hide the ROM at their addresses
+const ROM_MAX_SIZE: usize = 512 * 1024;
+
+/// Status register reads between two polls of the host link
+const POLL_PERIOD: u32 = 64;
+
+/// Parallel port status register: bit 0 is set when the host sent a byte
+const PARALLEL_STATUS: u32 = 0x20010;
+/// Cartridge switch register: bit 0 is the switch position
+const SWITCH: u32 = 0x20018;
+/// Parallel port data register
+const PARALLEL_DATA: u32 = 0x60000;
+
+/// Cheat or development cartridge plugged in the parallel port
+/// (Expansion 1)
+pub struct Cartridge {
+    rom: Vec<u8>,
+    /// Front switch, enables the cheats on Action Replay style cartridges
+    switch: bool,
+    /// Host computer on the other end of the parallel port
+    link: Option<Box<dyn Link>>,
+    /// Byte sent by the host, waiting to be read from the data register
+    received: Option<u8>,
+    /// Status reads left before the next poll of the link
+    poll_countdown: u32,
+}
+
+impl Cartridge {
+    pub fn new(path: &Path, switch: bool) -> Result<Cartridge> {
+        let rom = fs::read(path)?;
+
+        if rom.is_empty() || rom.len() > ROM_MAX_SIZE {
+            return Err(anyhow!("Invalid expansion ROM size: {} bytes", rom.len()));
+        }
+
+        let cartridge = Cartridge {
+            rom,
+            switch,
+            link: None,
+            received: None,
+            poll_countdown: 0,
+        };
+
+        let entry_points: Vec<&str> = ENTRY_POINTS
+            .iter()
+            .filter(|(entry, _)| cartridge.rom.get(entry + 4..entry + 4 + LICENSE.len()) == Some(LICENSE))
+            .map(|(_, name)| *name)
+            .collect();
+
+        match entry_points.is_empty() {
+            true => println!("Expansion ROM has no licensed entry point, the BIOS won't run it"),
+            false => println!("Expansion ROM entry points: {}", entry_points.join(", ")),
+        }
+
+        Ok(cartridge)
+    }
+
+    /// Connect the parallel port to a host side tool (Caetla or Unirom
+    /// transfer program), through the same TCP messages as the link cable
+    pub fn set_link(&mut self, link: Option<Box<dyn Link>>) {
+        self.link = link;
+    }
+
+    pub fn load8(&mut self, offset: u32) -> u8 {
+        match offset {
+            PARALLEL_STATUS => {
+                self.receive();
+                self.received.is_some() as u8
+            }
+            SWITCH => self.switch as u8,
+            PARALLEL_DATA => {
+                self.receive();
+                // Nothing is driven when the host didn't send anything
+                self.received.take().unwrap_or(0)
+            }
+            _ => self.rom.get(offset as usize).copied().unwrap_or(0xff),
+        }
+    }
+
+    pub fn store8(&mut self, offset: u32, val: u8) {
+        match offset {
+            PARALLEL_DATA => {
+                // Without a host the byte is lost
+                if let Some(link) = &mut self.link {
+                    link.send(val);
+                }
+            }
+            _ => println!("Unhandled write to expansion cartridge {:05x} <- {:02x}", offset, val),
+        }
+    }
+
+    /// Fetch the next byte from the host if the previous one was read.
+    /// Software busy waits on the status register, the link is only polled
+    /// once in a while
+    fn receive(&mut self) {
+        let Some(link) = &mut self.link else {
+            return;
+        };
+
+        if self.received.is_some() {
+            return;
+        }
+
+        if self.poll_countdown == 0 {
+            link.poll();
+            self.poll_countdown = POLL_PERIOD;
+        }
+        self.poll_countdown -= 1;
+
+        self.received = link.receive();
+    }
+}
+
+/// Expansion 1 region, reads 0xff without a cartridge
+pub struct Expansion1 {
+    cartridge: Option<Cartridge>,
+}
+
+impl Expansion1 {
+    pub fn new(cartridge: Option<Cartridge>) -> Expansion1 {
+        Expansion1 { cartridge }
+    }
+
+    pub fn load8(&mut self, offset: u32) -> u8 {
+        match &mut self.cartridge {
+            Some(cartridge) => cartridge.load8(offset),
+            None => 0xff,
+        }
+    }
+
+    /// Little endian load of `bytes` bytes, the port is 8bit wide
+    pub fn load(&mut self, offset: u32, bytes: u32) -> u32 {
+        (0..bytes).fold(0, |v, i| v | (self.load8(offset + i) as u32) << (i * 8))
+    }
+
+    pub fn store8(&mut self, offset: u32, val: u8) {
+        if let Some(cartridge) = &mut self.cartridge {
+            cartridge.store8(offset, val);
+        }
+    }
+
+    /// Little endian store of `bytes` bytes, split in byte writes like
+    /// loads are
+    pub fn store(&mut self, offset: u32, val: u32, bytes: u32) {
+        for i in 0..bytes {
+            self.store8(offset + i, (val >> (i * 8)) as u8);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+
+    /// Host side of the parallel port
+    #[derive(Default)]
+    struct Host {
+        to_console: VecDeque<u8>,
+        from_console: Vec<u8>,
+    }
+
+    struct FakeLink(Rc<RefCell<Host>>);
+
+    impl Link for FakeLink {
+        fn poll(&mut self) {}
+
+        fn send(&mut self, byte: u8) {
+            self.0.borrow_mut().from_console.push(byte);
+        }
+
+        fn receive(&mut self) -> Option<u8> {
+            self.0.borrow_mut().to_console.pop_front()
+        }
+
+        fn set_outputs(&mut self, _dtr: bool, _rts: bool) {}
+
+        fn inputs(&self) -> (bool, bool) {
+            (false, false)
+        }
+    }
+
+    /// Expansion 1 with a `size` bytes ROM counting up and a fake host
+    fn expansion(size: usize) -> (Expansion1, Rc<RefCell<Host>>) {
+        let path = std::env::temp_dir().join(format!("psx-rust-exp-{}-{}.rom", std::process::id(), size));
+        let rom: Vec<u8> = (0..size).map(|i| i as u8).collect();
+        fs::write(&path, rom).unwrap();
+
+        let mut cartridge = Cartridge::new(&path, false).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let host = Rc::new(RefCell::new(Host::default()));
+        cartridge.set_link(Some(Box::new(FakeLink(host.clone()))));
+
+        (Expansion1::new(Some(cartridge)), host)
+    }
+
+    #[test]
+    fn parallel_port_exchanges_bytes_with_the_host() {
+        let (mut exp1, host) = expansion(1024);
+
+        assert_eq!(exp1.load8(PARALLEL_STATUS), 0);
+
+        host.borrow_mut().to_console.extend([0x12, 0x34]);
+
+        assert_eq!(exp1.load8(PARALLEL_STATUS), 1);
+        assert_eq!(exp1.load8(PARALLEL_DATA), 0x12);
+        assert_eq!(exp1.load8(PARALLEL_STATUS), 1);
+        assert_eq!(exp1.load8(PARALLEL_DATA), 0x34);
+        assert_eq!(exp1.load8(PARALLEL_STATUS), 0);
+
+        exp1.store8(PARALLEL_DATA, 0x56);
+        assert_eq!(host.borrow().from_console, [0x56]);
+    }
+
+    #[test]
+    fn wide_stores_are_split_in_bytes() {
+        let (mut exp1, host) = expansion(1024);
+
+        // Only the byte at the data register reaches the host
+        exp1.store(PARALLEL_DATA, 0xaabbccdd, 4);
+        exp1.store(PARALLEL_DATA - 1, 0x1122, 2);
+
+        assert_eq!(host.borrow().from_console, [0xdd, 0x11]);
+    }
+
+    #[test]
+    fn large_roms_are_mapped() {
+        let (mut exp1, _) = expansion(256 * 1024);
+
+        assert_eq!(exp1.load(0x3fffc, 4), 0xfffefdfc);
+        assert_eq!(exp1.load8(0x40000), 0xff);
+    }
+}
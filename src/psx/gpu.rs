@@ -0,0 +1,501 @@
+use super::system::CPU_CLOCK_HZ;
+
+/// VRAM dimensions in 16bit pixels
+const VRAM_WIDTH: usize = 1024;
+const VRAM_HEIGHT: usize = 512;
+
+/// GPU display configuration and VRAM. Only the video output side, VRAM
+/// fills and CPU to VRAM transfers are emulated so far, the other drawing
+/// commands are ignored
+pub struct Gpu {
+    display: Display,
+    vram: Vec<u16>,
+    /// Words of the GP0 command being received
+    command: Vec<u32>,
+    /// Number of words of the command, 0 for a polyline which runs until
+    /// its terminator
+    command_len: usize,
+    /// CPU to VRAM transfer in progress, takes the GP0 words in place of
+    /// commands
+    load: Option<VramLoad>,
+    /// Display disabled - GP1(0x03)
+    display_disabled: bool,
+    /// CPU cycles since the last vertical blanking
+    frame_cycles: u32,
+}
+
+/// Video output settings set through GP1, in video clock units
+#[derive(Clone, Copy, Debug)]
+pub struct Display {
+    /// Top left corner of the displayed area in VRAM - GP1(0x05)
+    pub start_x: u16,
+    pub start_y: u16,
+    /// Horizontal resolution, from GP1(0x08) bits 0-1 and 6
+    pub hres: HorizontalRes,
+    /// 480 lines mode, only effective when interlaced
+    pub vres_480: bool,
+    /// PAL video mode, NTSC otherwise
+    pub pal: bool,
+    /// 24 bits per pixel output
+    pub depth_24: bool,
+    pub interlaced: bool,
+    /// Horizontal display range in video clocks since HSYNC - GP1(0x06)
+    pub x1: u16,
+    pub x2: u16,
+    /// Vertical display range in scanlines since VSYNC - GP1(0x07)
+    pub y1: u16,
+    pub y2: u16,
+}
+
+/// Rectangle of VRAM written by a GP0(0xa0) transfer
+struct VramLoad {
+    x: u16,
+    y: u16,
+    width: u16,
+    height: u16,
+    /// Pixels written so far
+    pixel: u32,
+}
+
+/// Picture sent to the TV
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    /// 0x00RRGGBB pixels, line by line
+    pub pixels: Vec<u32>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HorizontalRes {
+    H256,
+    H320,
+    H368,
+    H512,
+    H640,
+}
+
+impl Display {
+    fn new() -> Display {
+        Display {
+            start_x: 0,
+            start_y: 0,
+            hres: HorizontalRes::H256,
+            vres_480: false,
+            pal: false,
+            depth_24: false,
+            interlaced: false,
+            x1: 0x200,
+            x2: 0x200 + 256 * 10,
+            y1: 0x010,
+            y2: 0x010 + 240,
+        }
+    }
+
+    /// Displayed pixels per line
+    pub fn width(&self) -> u32 {
+        match self.hres {
+            HorizontalRes::H256 => 256,
+            HorizontalRes::H320 => 320,
+            HorizontalRes::H368 => 368,
+            HorizontalRes::H512 => 512,
+            HorizontalRes::H640 => 640,
+        }
+    }
+
+    /// Displayed lines, both fields in 480 lines mode
+    pub fn height(&self) -> u32 {
+        let lines = self.y2.saturating_sub(self.y1) as u32;
+
+        match self.vres_480 && self.interlaced {
+            true => lines * 2,
+            false => lines,
+        }
+    }
+
+    /// Video clocks per displayed pixel
+    pub fn dot_clock_divider(&self) -> u32 {
+        match self.hres {
+            HorizontalRes::H256 => 10,
+            HorizontalRes::H320 => 8,
+            HorizontalRes::H368 => 7,
+            HorizontalRes::H512 => 5,
+            HorizontalRes::H640 => 4,
+        }
+    }
+
+    /// Frequency of the GPU video clock
+    pub fn video_clock_hz(&self) -> u32 {
+        match self.pal {
+            true => 53_203_425,
+            false => 53_693_175,
+        }
+    }
+
+    /// GP1(0x08) bits, as found in GPUSTAT bits 16-22
+    fn mode_bits(&self) -> u32 {
+        let hres = match self.hres {
+            HorizontalRes::H256 => 0,
+            HorizontalRes::H320 => 1,
+            HorizontalRes::H512 => 2,
+            HorizontalRes::H640 => 3,
+            HorizontalRes::H368 => 1 << 6,
+        };
+
+        hres | (self.vres_480 as u32) << 2
+            | (self.pal as u32) << 3
+            | (self.depth_24 as u32) << 4
+            | (self.interlaced as u32) << 5
+    }
+}
+
+impl Gpu {
+    pub fn new() -> Gpu {
+        Gpu {
+            display: Display::new(),
+            vram: vec![0; VRAM_WIDTH * VRAM_HEIGHT],
+            command: Vec::new(),
+            command_len: 0,
+            load: None,
+            display_disabled: true,
+            frame_cycles: 0,
+        }
+    }
+
+    /// Advance the video timing by `cycles` CPU cycles. Returns true when
+    /// the vertical blanking starts, once per field
+    pub fn tick(&mut self, cycles: u32) -> bool {
+        let refresh = match self.display.pal {
+            true => 50,
+            false => 60,
+        };
+
+        self.frame_cycles += cycles;
+
+        if self.frame_cycles >= CPU_CLOCK_HZ / refresh {
+            self.frame_cycles -= CPU_CLOCK_HZ / refresh;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn display(&self) -> Display {
+        self.display
+    }
+
+    /// Displayed picture as 0x00RRGGBB pixels, black when the display is
+    /// disabled
+    pub fn framebuffer(&self) -> Frame {
+        let d = &self.display;
+        let width = d.width();
+        let height = d.height().min(VRAM_HEIGHT as u32);
+
+        let mut pixels = Vec::with_capacity((width * height) as usize);
+
+        for y in 0..height {
+            let row = (d.start_y as usize + y as usize) % VRAM_HEIGHT * VRAM_WIDTH;
+
+            for x in 0..width {
+                let pixel = match (self.display_disabled, d.depth_24) {
+                    (true, _) => 0,
+                    (false, false) => {
+                        let v = self.vram[row + (d.start_x as usize + x as usize) % VRAM_WIDTH] as u32;
+
+                        let c = |shift: u32| {
+                            let c = (v >> shift) & 0x1f;
+                            (c << 3) | (c >> 2)
+                        };
+
+                        (c(0) << 16) | (c(5) << 8) | c(10)
+                    }
+                    (false, true) => {
+                        // 3 bytes per pixel packed in the 16bit VRAM words
+                        let byte = |i: u32| {
+                            let b = d.start_x as usize * 2 + (x * 3 + i) as usize;
+                            let v = self.vram[row + (b / 2) % VRAM_WIDTH];
+
+                            (v >> ((b & 1) * 8)) as u8 as u32
+                        };
+
+                        (byte(0) << 16) | (byte(1) << 8) | byte(2)
+                    }
+                };
+
+                pixels.push(pixel);
+            }
+        }
+
+        Frame { width, height, pixels }
+    }
+
+    /// GPUSTAT register
+    pub fn status(&self) -> u32 {
+        let mode = self.display.mode_bits();
+
+        // Bit 16 is the 368 pixels mode, bits 17-18 the other resolutions
+        let mut r = ((mode & 3) << 17) | ((mode >> 6) & 1) << 16;
+        r |= ((mode >> 2) & 0xf) << 19;
+        r |= (self.display_disabled as u32) << 23;
+
+        // Drawing is instantaneous: always ready for commands, VRAM reads
+        // and DMA blocks
+        r |= 1 << 26;
+        r |= 1 << 27;
+        r |= 1 << 28;
+
+        r
+    }
+
+    /// GPUREAD register
+    pub fn read(&self) -> u32 {
+        0
+    }
+
+    /// GP0 register: drawing commands and VRAM transfers
+    pub fn gp0(&mut self, val: u32) {
+        if let Some(load) = &mut self.load {
+            for pixel in [val as u16, (val >> 16) as u16] {
+                let x = (load.x as u32 + load.pixel % load.width as u32) as usize % VRAM_WIDTH;
+                let y = (load.y as u32 + load.pixel / load.width as u32) as usize % VRAM_HEIGHT;
+
+                self.vram[y * VRAM_WIDTH + x] = pixel;
+                load.pixel += 1;
+
+                // An odd size leaves the top half of the last word unused
+                if load.pixel == load.width as u32 * load.height as u32 {
+                    self.load = None;
+                    break;
+                }
+            }
+
+            return;
+        }
+
+        if self.command.is_empty() {
+            self.command_len = command_len(val);
+        }
+
+        self.command.push(val);
+
+        let done = match self.command_len {
+            // Polylines end on a 0x5xxx5xxx word, after two vertices
+            0 => self.command.len() > 3 && val & 0xf000_f000 == 0x5000_5000,
+            len => self.command.len() == len,
+        };
+
+        if done {
+            let command = std::mem::take(&mut self.command);
+            self.execute(&command);
+        }
+    }
+
+    fn execute(&mut self, command: &[u32]) {
+        match command[0] >> 24 {
+            0x02 => self.fill(command),
+            0xa0..=0xbf => {
+                let (x, y) = (command[1] & 0x3ff, (command[1] >> 16) & 0x1ff);
+                // Sizes wrap to 0x400 and 0x200 when 0
+                let width = (command[2].wrapping_sub(1) & 0x3ff) + 1;
+                let height = ((command[2] >> 16).wrapping_sub(1) & 0x1ff) + 1;
+
+                self.load = Some(VramLoad {
+                    x: x as u16,
+                    y: y as u16,
+                    width: width as u16,
+                    height: height as u16,
+                    pixel: 0,
+                });
+            }
+            _ => (),
+        }
+    }
+
+    /// GP0(0x02): fill a rectangle with a color. The position and width are
+    /// rounded to 16 pixels
+    fn fill(&mut self, command: &[u32]) {
+        let color = command[0];
+        let pixel = ((color >> 3) & 0x1f) | ((color >> 11) & 0x1f) << 5 | ((color >> 19) & 0x1f) << 10;
+
+        let x = (command[1] & 0x3f0) as usize;
+        let y = ((command[1] >> 16) & 0x1ff) as usize;
+        let width = (((command[2] & 0x3ff) + 0xf) & !0xf) as usize;
+        let height = ((command[2] >> 16) & 0x1ff) as usize;
+
+        for row in y..y + height {
+            for col in x..x + width {
+                self.vram[(row % VRAM_HEIGHT) * VRAM_WIDTH + col % VRAM_WIDTH] = pixel as u16;
+            }
+        }
+    }
+
+    /// GP1 register: display control. Returns true when the display
+    /// configuration changed
+    pub fn gp1(&mut self, val: u32) -> bool {
+        let display = &mut self.display;
+
+        match val >> 24 {
+            0x00 => {
+                *display = Display::new();
+                self.display_disabled = true;
+                self.command.clear();
+                self.load = None;
+            }
+            0x01 => {
+                self.command.clear();
+                self.load = None;
+                return false;
+            }
+            0x03 => self.display_disabled = val & 1 != 0,
+            0x05 => {
+                display.start_x = (val & 0x3fe) as u16;
+                display.start_y = ((val >> 10) & 0x1ff) as u16;
+            }
+            0x06 => {
+                display.x1 = (val & 0xfff) as u16;
+                display.x2 = ((val >> 12) & 0xfff) as u16;
+            }
+            0x07 => {
+                display.y1 = (val & 0x3ff) as u16;
+                display.y2 = ((val >> 10) & 0x3ff) as u16;
+            }
+            0x08 => {
+                display.hres = match (val & (1 << 6) != 0, val & 3) {
+                    (true, _) => HorizontalRes::H368,
+                    (false, 0) => HorizontalRes::H256,
+                    (false, 1) => HorizontalRes::H320,
+                    (false, 2) => HorizontalRes::H512,
+                    (false, _) => HorizontalRes::H640,
+                };
+                display.vres_480 = val & (1 << 2) != 0;
+                display.pal = val & (1 << 3) != 0;
+                display.depth_24 = val & (1 << 4) != 0;
+                display.interlaced = val & (1 << 5) != 0;
+            }
+            _ => {
+                println!("unhandled GP1 command {:08x}", val);
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// Number of words of the GP0 command starting with `val`, 0 for polylines
+fn command_len(val: u32) -> usize {
+    let op = val >> 24;
+    let gouraud = (op >> 4) & 1;
+    let textured = (op >> 2) & 1;
+
+    let len = match op {
+        0x02 => 3,
+        0x20..=0x3f => {
+            let vertices = match op & 8 {
+                0 => 3,
+                _ => 4,
+            };
+
+            1 + vertices * (1 + textured) + gouraud * (vertices - 1)
+        }
+        0x40..=0x5f if op & 8 != 0 => 0,
+        0x40..=0x5f => 3 + gouraud,
+        0x60..=0x7f => {
+            // Bits 3-4: 1x1, 8x8 or 16x16 size, variable otherwise
+            let size = match op & 0x18 {
+                0 => 1,
+                _ => 0,
+            };
+
+            2 + textured + size
+        }
+        0x80..=0x9f => 4,
+        0xa0..=0xdf => 3,
+        _ => 1,
+    };
+
+    len as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixel(gpu: &Gpu, x: usize, y: usize) -> u16 {
+        gpu.vram[y * VRAM_WIDTH + x]
+    }
+
+    #[test]
+    fn fill_rounds_to_16_pixels() {
+        let mut gpu = Gpu::new();
+
+        // Pure red, from (20, 8) with a width of 17
+        for w in [0x0200_00ff, 0x0008_0014, 0x0002_0011] {
+            gpu.gp0(w);
+        }
+
+        assert_eq!(pixel(&gpu, 16, 8), 0x001f);
+        assert_eq!(pixel(&gpu, 47, 9), 0x001f);
+        assert_eq!(pixel(&gpu, 48, 8), 0);
+        assert_eq!(pixel(&gpu, 15, 8), 0);
+        assert_eq!(pixel(&gpu, 16, 10), 0);
+    }
+
+    #[test]
+    fn cpu_to_vram_transfer() {
+        let mut gpu = Gpu::new();
+
+        // 3x2 pixels at (1022, 511), wrapping around VRAM
+        for w in [0xa000_0000, 0x01ff_03fe, 0x0002_0003, 0x0002_0001, 0x0004_0003, 0x0006_0005] {
+            gpu.gp0(w);
+        }
+
+        assert_eq!(pixel(&gpu, 1022, 511), 1);
+        assert_eq!(pixel(&gpu, 1023, 511), 2);
+        assert_eq!(pixel(&gpu, 0, 511), 3);
+        assert_eq!(pixel(&gpu, 1022, 0), 4);
+        assert_eq!(pixel(&gpu, 0, 0), 6);
+
+        // The transfer is over, the next word is a command again
+        for w in [0x0200_0000, 0, 0x0001_0010] {
+            gpu.gp0(w);
+        }
+        assert_eq!(pixel(&gpu, 0, 0), 0);
+    }
+
+    #[test]
+    fn ignored_commands_consume_their_parameters() {
+        let mut gpu = Gpu::new();
+
+        // Textured gouraud quad, then a polyline, their parameters look
+        // like fills
+        gpu.gp0(0x3c00_0000);
+        for _ in 0..11 {
+            gpu.gp0(0x0200_00ff);
+        }
+        gpu.gp0(0x4800_0000);
+        for _ in 0..3 {
+            gpu.gp0(0x0200_00ff);
+        }
+        gpu.gp0(0x5555_5555);
+
+        for w in [0x02ff_0000, 0, 0x0001_0010] {
+            gpu.gp0(w);
+        }
+
+        // The fill ran from its own words
+        assert_eq!(pixel(&gpu, 0, 0), 0x7c00);
+    }
+
+    #[test]
+    fn framebuffer_shows_vram() {
+        let mut gpu = Gpu::new();
+
+        for w in [0xa000_0000, 0, 0x0001_0002, 0x7fff_001f] {
+            gpu.gp0(w);
+        }
+        gpu.gp1(0x0300_0000);
+
+        let frame = gpu.framebuffer();
+        assert_eq!(frame.pixels[..3], [0xff0000, 0xffffff, 0]);
+    }
+}
@@ -0,0 +1,1572 @@
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, VecDeque};
+
+use super::cpu::{Cpu, Registers};
+use super::disc::{DirEntry, Disc};
+use super::exe::Exe;
+use super::system::SHELL_ENTRY;
+
+/// Marker put at the kernel entry points: opcode 0x3f is reserved so it
+/// can't be mistaken for code, the low bits name the entry point
+const TRAP: u32 = 0xfc000000;
+
+const TRAP_RESET: u32 = 0;
+const TRAP_EXCEPTION: u32 = 1;
+const TRAP_A: u32 = 2;
+const TRAP_B: u32 = 3;
+const TRAP_C: u32 = 4;
+/// A function called by the kernel returned
+const TRAP_CALLBACK_RETURN: u32 = 5;
+/// `WaitEvent` polls its event again
+const TRAP_WAIT: u32 = 6;
+/// The kernel is initialized and nothing replaced the shell
+const TRAP_SHELL: u32 = 7;
+
+/// Endless loop the CPU is parked in when there's nothing left to run
+const IDLE: u32 = 0xbfc00400;
+const CALLBACK_RETURN: u32 = 0xbfc00410;
+/// A few instructions for interrupts to happen while `WaitEvent` blocks
+const WAIT_LOOP: u32 = 0xbfc00420;
+const WAIT_TRAP: u32 = WAIT_LOOP + 12;
+
+/// Stack of the functions called from the interrupt handler
+const KERNEL_STACK: u32 = 0x8000f000;
+/// Memory of `alloc_kernel_memory`, below the kernel stack
+const KERNEL_HEAP: (u32, u32) = (0x8000a000, 0x4000);
+/// Addresses of the function tables returned by `GetB0Table` and co
+const B0_TABLE: u32 = 0x80000874;
+const C0_TABLE: u32 = 0x80000674;
+
+/// Longest guest string or buffer handled at once, the size of the RAM
+/// area. Anything longer only wraps over the RAM mirrors
+const MAX_GUEST_LEN: u32 = 8 * 1024 * 1024;
+/// Largest printf field width, guest format strings can ask for anything
+const MAX_WIDTH: usize = 0x1000;
+
+const I_STAT: u32 = 0x1f801070;
+const I_MASK: u32 = 0x1f801074;
+const GP0: u32 = 0x1f801810;
+const GP1: u32 = 0x1f801814;
+
+/// Event class of the root counters, the VBlank being counter 3
+const RCNT_CLASS: u32 = 0xf2000000;
+/// Event spec of a counter reaching its target or the VBlank
+const RCNT_SPEC: u32 = 0x0002;
+
+const EVENT_FREE: u32 = 0x0000;
+const EVENT_DISABLED: u32 = 0x1000;
+const EVENT_ENABLED: u32 = 0x2000;
+const EVENT_READY: u32 = 0x4000;
+
+/// Event mode: call the event function on delivery
+const EVENT_MODE_CALLBACK: u32 = 0x1000;
+
+const MAX_EVENTS: usize = 32;
+
+/// File descriptors 0 and 1 are the TTY
+const FIRST_FD: u32 = 2;
+
+/// Image of the ROM standing in for a Sony BIOS. The kernel runs natively,
+/// the ROM only holds its traps and a few loops
+pub fn rom() -> Vec<u8> {
+    let mut rom = vec![0; 512 * 1024];
+
+    let mut put = |addr: u32, word: u32| {
+        let offset = (addr & 0x7ffff) as usize;
+        rom[offset..offset + 4].copy_from_slice(&word.to_le_bytes());
+    };
+
+    put(0xbfc00000, TRAP | TRAP_RESET);
+    put(0xbfc00180, TRAP | TRAP_EXCEPTION);
+    // j IDLE
+    put(IDLE, 0x08000000 | ((IDLE >> 2) & 0x3ffffff));
+    put(CALLBACK_RETURN, TRAP | TRAP_CALLBACK_RETURN);
+    put(WAIT_TRAP, TRAP | TRAP_WAIT);
+
+    rom
+}
+
+/// High level emulation of the BIOS kernel: the A, B and C function
+/// tables, the syscalls and the interrupt handler are implemented in Rust
+/// and run when the CPU reaches their trap
+pub struct Kernel {
+    disc: Option<Disc>,
+    heap: Heap,
+    kernel_heap: Heap,
+    files: Vec<Option<File>>,
+    events: Vec<Event>,
+    /// Handlers queued with `SysEnqIntRP`, by priority, newest first
+    int_handlers: [Vec<u32>; 4],
+    /// `SetCustomExitFromException` buffer, 0 when not set
+    custom_exit: u32,
+    /// Registers of the code interrupted by the exception being handled
+    exception: Option<Registers>,
+    /// Software functions called by the kernel, innermost last
+    callbacks: Vec<Callbacks>,
+    /// `InitPad` buffers and their size
+    pads: [(u32, u32); 2],
+    pads_started: bool,
+    /// `ChangeClearRCnt` flags: acknowledge the counter interrupts
+    rcnt_clear: [bool; 4],
+    rand_seed: u32,
+    /// Entries left to return from `nextfile`
+    dir_search: VecDeque<DirEntry>,
+}
+
+/// Software functions to call before going back to what the kernel was
+/// doing
+struct Callbacks {
+    queue: VecDeque<Callback>,
+    running: Option<Callback>,
+    then: Resume,
+}
+
+#[derive(Clone, Copy)]
+enum Callback {
+    /// `SysEnqIntRP` structure: the second function is called with the
+    /// result of the first if it isn't 0
+    IntHandler(u32),
+    /// Function and its argument
+    Func(u32, u32),
+}
+
+enum Resume {
+    /// Return from a kernel function
+    Call { ra: u32, sp: u32, v0: u32 },
+    /// End of the interrupt handler
+    Interrupt,
+}
+
+#[derive(Clone, Copy)]
+struct Event {
+    class: u32,
+    spec: u32,
+    mode: u32,
+    func: u32,
+    status: u32,
+}
+
+struct File {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+/// First-fit allocator for `malloc` and co
+struct Heap {
+    start: u32,
+    end: u32,
+    /// Allocated blocks, by address
+    blocks: BTreeMap<u32, u32>,
+}
+
+impl Heap {
+    fn new(start: u32, size: u32) -> Heap {
+        Heap {
+            start,
+            end: start.wrapping_add(size),
+            blocks: BTreeMap::new(),
+        }
+    }
+
+    fn alloc(&mut self, size: u32) -> u32 {
+        let Some(size) = size.max(1).checked_add(3) else {
+            return 0;
+        };
+        let size = size & !3;
+        let mut addr = self.start;
+
+        for (&block, &len) in &self.blocks {
+            if block - addr >= size {
+                break;
+            }
+
+            addr = block + len;
+        }
+
+        if self.end.saturating_sub(addr) < size {
+            return 0;
+        }
+
+        self.blocks.insert(addr, size);
+
+        addr
+    }
+
+    /// Free the block at `addr`, returns its size
+    fn free(&mut self, addr: u32) -> u32 {
+        self.blocks.remove(&addr).unwrap_or(0)
+    }
+}
+
+impl Kernel {
+    pub fn new(disc: Option<Disc>) -> Kernel {
+        Kernel {
+            disc,
+            heap: Heap::new(0, 0),
+            kernel_heap: Heap::new(KERNEL_HEAP.0, KERNEL_HEAP.1),
+            files: Vec::new(),
+            events: Vec::new(),
+            int_handlers: [Vec::new(), Vec::new(), Vec::new(), Vec::new()],
+            custom_exit: 0,
+            exception: None,
+            callbacks: Vec::new(),
+            pads: [(0, 0); 2],
+            pads_started: false,
+            rcnt_clear: [true; 4],
+            rand_seed: 0,
+            dir_search: VecDeque::new(),
+        }
+    }
+
+    /// Run the kernel code the CPU is about to enter, if any. Returns false
+    /// when the CPU should run the instruction at PC itself. Text printed
+    /// by the software is appended to `tty`
+    pub fn intercept(&mut self, cpu: &mut Cpu, tty: &mut String) -> bool {
+        let Some(trap) = trap(cpu) else {
+            return false;
+        };
+
+        cpu.complete_load();
+
+        match trap {
+            TRAP_RESET => self.reset(cpu),
+            TRAP_EXCEPTION => self.exception(cpu),
+            TRAP_A | TRAP_B | TRAP_C => {
+                let r = cpu.registers();
+                let function = r.regs[9];
+
+                let v0 = match trap {
+                    TRAP_A => self.a_function(cpu, function, tty),
+                    TRAP_B => self.b_function(cpu, function, tty),
+                    _ => self.c_function(cpu, function),
+                };
+
+                if let Some(v0) = v0 {
+                    cpu.set_register(2, v0);
+                    cpu.set_pc(r.regs[31]);
+                }
+            }
+            TRAP_CALLBACK_RETURN => self.next_callback(cpu),
+            TRAP_WAIT => {
+                let r = cpu.registers();
+
+                if let Some(v0) = self.wait_event(cpu, r.regs[4]) {
+                    cpu.set_register(2, v0);
+                    cpu.set_pc(r.regs[31]);
+                }
+            }
+            TRAP_SHELL => {
+                println!("HLE BIOS: no executable to run");
+                cpu.set_pc(IDLE);
+            }
+            _ => unreachable!(),
+        }
+
+        // Kernel calls aren't free on the real console
+        cpu.interconnect_mut().tick(20);
+
+        true
+    }
+
+    fn reset(&mut self, cpu: &mut Cpu) {
+        for (addr, trap) in [
+            (0x80000080, TRAP_EXCEPTION),
+            (0x800000a0, TRAP_A),
+            (0x800000b0, TRAP_B),
+            (0x800000c0, TRAP_C),
+            (SHELL_ENTRY, TRAP_SHELL),
+        ] {
+            store32(cpu, addr, TRAP | trap);
+        }
+
+        // Interrupts disabled, handlers in RAM
+        cpu.set_sr(0);
+
+        cpu.set_register(29, 0x801ffff0);
+        cpu.set_register(30, 0x801ffff0);
+        cpu.set_pc(SHELL_ENTRY);
+    }
+
+    fn exception(&mut self, cpu: &mut Cpu) {
+        let r = cpu.registers();
+
+        match (r.cause >> 2) & 0x1f {
+            0x00 => self.interrupt(cpu, r),
+            0x08 => {
+                let sr = r.sr;
+
+                // The interrupt enable bits are the ones saved by the
+                // exception, restored by the return
+                let v0 = match r.regs[4] {
+                    0 => 0,
+                    // EnterCriticalSection
+                    1 => {
+                        cpu.set_sr(sr & !0x404);
+                        (sr & 0x404 == 0x404) as u32
+                    }
+                    // ExitCriticalSection
+                    2 => {
+                        cpu.set_sr(sr | 0x404);
+                        0
+                    }
+                    n => {
+                        println!("Unhandled HLE BIOS syscall {:x}", n);
+                        0
+                    }
+                };
+
+                cpu.set_register(2, v0);
+                cpu.return_from_exception(r.epc.wrapping_add(4));
+            }
+            code => {
+                println!("HLE BIOS: unhandled exception {:x} at {:08x}", code, r.epc);
+                cpu.return_from_exception(r.epc.wrapping_add(4));
+            }
+        }
+    }
+
+    fn interrupt(&mut self, cpu: &mut Cpu, r: Registers) {
+        self.exception = Some(r);
+
+        let pending = load32(cpu, I_STAT) & load32(cpu, I_MASK);
+
+        let mut queue: VecDeque<Callback> = self
+            .int_handlers
+            .iter()
+            .flatten()
+            .map(|&s| Callback::IntHandler(s))
+            .collect();
+
+        // Root counters 0 to 2 are IRQ 4 to 6, the VBlank counter 3 is IRQ 0
+        for (counter, irq) in [(0, 4), (1, 5), (2, 6), (3, 0)] {
+            if pending & (1 << irq) == 0 {
+                continue;
+            }
+
+            if irq == 0 {
+                self.read_pads(cpu);
+            }
+
+            queue.extend(self.deliver_event(RCNT_CLASS | counter, RCNT_SPEC));
+
+            if self.rcnt_clear[counter as usize] {
+                store32(cpu, I_STAT, !(1 << irq));
+            }
+        }
+
+        self.callbacks.push(Callbacks {
+            queue,
+            running: None,
+            then: Resume::Interrupt,
+        });
+
+        self.next_callback(cpu);
+    }
+
+    /// Call the next queued software function, or go back to what the
+    /// kernel was doing once they all ran
+    fn next_callback(&mut self, cpu: &mut Cpu) {
+        let interrupt = matches!(self.callbacks.last().map(|c| &c.then), Some(Resume::Interrupt));
+
+        let Some(callbacks) = self.callbacks.last_mut() else {
+            println!("HLE BIOS: return from an unknown callback");
+            cpu.set_pc(IDLE);
+            return;
+        };
+
+        if let Some(Callback::IntHandler(s)) = callbacks.running.take() {
+            let v0 = cpu.registers().regs[2];
+            let func2 = load32(cpu, s.wrapping_add(4));
+
+            if v0 != 0 && func2 != 0 {
+                callbacks.queue.push_front(Callback::Func(func2, v0));
+            }
+        }
+
+        while let Some(callback) = callbacks.queue.pop_front() {
+            let (func, arg) = match callback {
+                Callback::IntHandler(s) => (load32(cpu, s.wrapping_add(8)), 0),
+                Callback::Func(func, arg) => (func, arg),
+            };
+
+            if func == 0 {
+                continue;
+            }
+
+            callbacks.running = Some(callback);
+
+            if interrupt {
+                cpu.set_register(29, KERNEL_STACK);
+            }
+
+            cpu.set_register(4, arg);
+            cpu.set_register(31, CALLBACK_RETURN);
+            cpu.set_pc(func);
+            return;
+        }
+
+        match self.callbacks.pop().map(|c| c.then) {
+            Some(Resume::Call { ra, sp, v0 }) => {
+                cpu.set_register(2, v0);
+                cpu.set_register(29, sp);
+                cpu.set_pc(ra);
+            }
+            Some(Resume::Interrupt) => match self.custom_exit {
+                0 => self.return_from_exception(cpu),
+                buf => restore_state(cpu, buf, 1),
+            },
+            None => unreachable!(),
+        }
+    }
+
+    fn return_from_exception(&mut self, cpu: &mut Cpu) {
+        let Some(r) = self.exception.take() else {
+            println!("HLE BIOS: ReturnFromException outside of an exception");
+            return;
+        };
+
+        for (i, &val) in r.regs.iter().enumerate().skip(1) {
+            cpu.set_register(i as u32, val);
+        }
+
+        cpu.set_hi_lo(r.hi, r.lo);
+        cpu.return_from_exception(r.epc);
+    }
+
+    /// Copy the controller state to the `InitPad` buffers
+    fn read_pads(&mut self, cpu: &mut Cpu) {
+        if !self.pads_started {
+            return;
+        }
+
+        for (port, &(buf, size)) in self.pads.iter().enumerate() {
+            if buf == 0 || size < 2 {
+                continue;
+            }
+
+            match cpu.interconnect_mut().pad_port(port).poll_controller() {
+                Some(rx) => {
+                    store8(cpu, buf, 0x00);
+                    store8(cpu, buf.wrapping_add(1), rx[0]);
+
+                    for (i, &b) in rx.iter().skip(2).take(size as usize - 2).enumerate() {
+                        store8(cpu, buf.wrapping_add(2 + i as u32), b);
+                    }
+                }
+                None => store8(cpu, buf, 0xff),
+            }
+        }
+    }
+
+    /// Mark the matching events ready, returns the functions of those in
+    /// callback mode
+    fn deliver_event(&mut self, class: u32, spec: u32) -> Vec<Callback> {
+        let mut callbacks = Vec::new();
+
+        for e in &mut self.events {
+            if e.status != EVENT_ENABLED || e.class != class || e.spec != spec {
+                continue;
+            }
+
+            match e.mode {
+                EVENT_MODE_CALLBACK => callbacks.push(Callback::Func(e.func, 0)),
+                _ => e.status = EVENT_READY,
+            }
+        }
+
+        callbacks
+    }
+
+    fn event(&mut self, handle: u32) -> Option<&mut Event> {
+        if handle & 0xffff0000 != 0xf1000000 {
+            return None;
+        }
+
+        self.events
+            .get_mut((handle & 0xffff) as usize)
+            .filter(|e| e.status != EVENT_FREE)
+    }
+
+    /// `WaitEvent`, None while it blocks
+    fn wait_event(&mut self, cpu: &mut Cpu, handle: u32) -> Option<u32> {
+        let Some(e) = self.event(handle) else {
+            return Some(0);
+        };
+
+        match e.status {
+            EVENT_READY => {
+                e.status = EVENT_ENABLED;
+                Some(1)
+            }
+            EVENT_ENABLED => {
+                cpu.set_pc(WAIT_LOOP);
+                None
+            }
+            _ => Some(0),
+        }
+    }
+
+    /// Contents of the disc file named `cdrom:\DIR\FILE;1`
+    fn read_disc_file(&mut self, name: &str) -> Result<Vec<u8>> {
+        let disc = self.disc.as_mut().ok_or_else(|| anyhow!("No disc"))?;
+
+        let path = name.split_once(':').map_or(name, |(_, path)| path);
+        let path = path.split(';').next().unwrap_or("");
+
+        let file = disc.find_file(path)?;
+
+        disc.read_file(&file)
+    }
+
+    /// Devices other than the CD-ROM aren't supported, files are read
+    /// whole when opened
+    fn file_open(&mut self, cpu: &mut Cpu, name: u32) -> u32 {
+        let name = read_string(cpu, name);
+
+        if !name.starts_with("cdrom") {
+            println!("HLE BIOS: unsupported device for {}", name);
+            return !0;
+        }
+
+        let data = match self.read_disc_file(&name) {
+            Ok(data) => data,
+            Err(e) => {
+                println!("HLE BIOS: can't open {}: {}", name, e);
+                return !0;
+            }
+        };
+
+        let file = Some(File { data, pos: 0 });
+
+        let index = match self.files.iter().position(|f| f.is_none()) {
+            Some(i) => {
+                self.files[i] = file;
+                i
+            }
+            None => {
+                self.files.push(file);
+                self.files.len() - 1
+            }
+        };
+
+        index as u32 + FIRST_FD
+    }
+
+    fn file(&mut self, fd: u32) -> Option<&mut File> {
+        let index = fd.checked_sub(FIRST_FD)? as usize;
+
+        self.files.get_mut(index)?.as_mut()
+    }
+
+    fn file_read(&mut self, cpu: &mut Cpu, fd: u32, dst: u32, len: u32) -> u32 {
+        let Some(file) = self.file(fd) else {
+            return !0;
+        };
+
+        let end = (file.pos + len as usize).min(file.data.len());
+        let data = &file.data[file.pos..end];
+
+        write_bytes(cpu, dst, data);
+        file.pos = end;
+
+        data.len() as u32
+    }
+
+    fn file_write(&mut self, cpu: &mut Cpu, fd: u32, src: u32, len: u32, tty: &mut String) -> u32 {
+        if fd >= FIRST_FD {
+            println!("HLE BIOS: writing to files isn't supported");
+            return !0;
+        }
+
+        tty.extend(read_bytes(cpu, src, len).into_iter().map(|b| b as char));
+
+        len
+    }
+
+    fn file_seek(&mut self, fd: u32, offset: u32, whence: u32) -> u32 {
+        let Some(file) = self.file(fd) else {
+            return !0;
+        };
+
+        file.pos = match whence {
+            0 => offset as usize,
+            _ => file.pos.wrapping_add(offset as i32 as usize),
+        }
+        .min(file.data.len());
+
+        file.pos as u32
+    }
+
+    /// `firstfile`: list the directory of the pattern, `?` and `*`
+    /// wildcards are supported in the file name
+    fn first_file(&mut self, cpu: &mut Cpu, pattern: u32, entry: u32) -> u32 {
+        let pattern = read_string(cpu, pattern);
+        let path = pattern.split_once(':').map_or(pattern.as_str(), |(_, path)| path);
+        let path = path.split(';').next().unwrap_or("");
+
+        let (dir, name) = path.rsplit_once('\\').unwrap_or(("", path));
+
+        let Some(disc) = self.disc.as_mut() else {
+            return 0;
+        };
+
+        let entries = disc.find_file(dir).and_then(|dir| disc.read_dir(&dir));
+
+        self.dir_search = entries
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|e| wildcard_match(name, &e.name))
+            .collect();
+
+        self.next_file(cpu, entry)
+    }
+
+    fn next_file(&mut self, cpu: &mut Cpu, entry: u32) -> u32 {
+        let Some(file) = self.dir_search.pop_front() else {
+            return 0;
+        };
+
+        // Name on 20 bytes, attributes, size, next entry and first sector
+        let mut name = file.name.into_bytes();
+        name.resize(20, 0);
+        name[19] = 0;
+
+        write_bytes(cpu, entry, &name);
+        store32(cpu, entry.wrapping_add(0x14), 0);
+        store32(cpu, entry.wrapping_add(0x18), file.size);
+        store32(cpu, entry.wrapping_add(0x1c), 0);
+        store32(cpu, entry.wrapping_add(0x20), file.lba);
+
+        entry
+    }
+
+    /// `Load`: copy an executable to RAM and its header to `header`
+    fn load(&mut self, cpu: &mut Cpu, name: u32, header: u32) -> Option<Exe> {
+        let name = read_string(cpu, name);
+
+        let exe = self
+            .read_disc_file(&name)
+            .and_then(|data| Exe::parse(&data))
+            .map_err(|e| println!("HLE BIOS: can't load {}: {}", name, e))
+            .ok()?;
+
+        if header != 0 {
+            write_bytes(cpu, header, &exe.exec_info);
+        }
+
+        Some(exe)
+    }
+
+    fn a_function(&mut self, cpu: &mut Cpu, function: u32, tty: &mut String) -> Option<u32> {
+        let r = cpu.registers();
+        let [a0, a1, a2, a3] = [r.regs[4], r.regs[5], r.regs[6], r.regs[7]];
+
+        let v0 = match function {
+            0x00 => self.file_open(cpu, a0),
+            0x01 => self.file_seek(a0, a1, a2),
+            0x02 => self.file_read(cpu, a0, a1, a2),
+            0x03 => self.file_write(cpu, a0, a1, a2, tty),
+            0x04 => {
+                if let Some(file) = self.files.get_mut(a0.wrapping_sub(FIRST_FD) as usize) {
+                    *file = None;
+                }
+                a0
+            }
+            0x0e | 0x0f => (a0 as i32).unsigned_abs(),
+            0x10 | 0x11 => atoi(&read_string(cpu, a0)) as u32,
+            // SaveState (setjmp)
+            0x13 => {
+                let saved = [31, 29, 30, 16, 17, 18, 19, 20, 21, 22, 23, 28];
+
+                for (i, &reg) in saved.iter().enumerate() {
+                    store32(cpu, a0.wrapping_add(i as u32 * 4), r.regs[reg]);
+                }
+                0
+            }
+            // RestoreState (longjmp)
+            0x14 => {
+                restore_state(cpu, a0, a1);
+                return None;
+            }
+            0x15 => {
+                let end = a0.wrapping_add(strlen(cpu, a0));
+                copy_string(cpu, end, a1, u32::MAX);
+                a0
+            }
+            0x16 => {
+                let end = a0.wrapping_add(strlen(cpu, a0));
+                let len = strlen(cpu, a1).min(a2);
+                copy_bytes(cpu, end, a1, len);
+                store8(cpu, end.wrapping_add(len), 0);
+                a0
+            }
+            0x17 => compare(&read_string(cpu, a0), &read_string(cpu, a1), usize::MAX),
+            0x18 => compare(&read_string(cpu, a0), &read_string(cpu, a1), a2 as usize),
+            0x19 => {
+                copy_string(cpu, a0, a1, u32::MAX);
+                a0
+            }
+            0x1a => {
+                copy_string(cpu, a0, a1, a2);
+                a0
+            }
+            0x1b => strlen(cpu, a0),
+            0x1c | 0x1e => find_char(cpu, a0, a1 as u8, false),
+            0x1d | 0x1f => find_char(cpu, a0, a1 as u8, true),
+            0x24 => {
+                let haystack = read_string(cpu, a0);
+                let needle = read_string(cpu, a1);
+
+                haystack.find(&needle).map_or(0, |i| a0.wrapping_add(i as u32))
+            }
+            0x25 => (a0 as u8).to_ascii_uppercase() as u32,
+            0x26 => (a0 as u8).to_ascii_lowercase() as u32,
+            // bcopy(src, dst, len)
+            0x27 => {
+                copy_bytes(cpu, a1, a0, a2);
+                a1
+            }
+            0x28 => {
+                fill_bytes(cpu, a0, 0, a1);
+                a0
+            }
+            0x29 | 0x2d => compare_bytes(cpu, a0, a1, a2),
+            0x2a | 0x2c => {
+                copy_bytes(cpu, a0, a1, a2);
+                a0
+            }
+            0x2b => {
+                fill_bytes(cpu, a0, a1 as u8, a2);
+                a0
+            }
+            0x2e => (0..a2)
+                .map(|i| a0.wrapping_add(i))
+                .find(|&addr| load8(cpu, addr) == a1 as u8)
+                .unwrap_or(0),
+            0x2f => {
+                self.rand_seed = self.rand_seed.wrapping_mul(0x41c64e6d).wrapping_add(0x3039);
+                (self.rand_seed >> 16) & 0x7fff
+            }
+            0x30 => {
+                self.rand_seed = a0;
+                0
+            }
+            0x33 => self.heap.alloc(a0),
+            0x34 => {
+                self.heap.free(a0);
+                0
+            }
+            0x37 => {
+                let size = a0.wrapping_mul(a1);
+                let addr = self.heap.alloc(size);
+
+                if addr != 0 {
+                    fill_bytes(cpu, addr, 0, size);
+                }
+                addr
+            }
+            0x38 => {
+                let old = self.heap.free(a0);
+                let addr = self.heap.alloc(a1);
+
+                // The new block can start where the old one did, copying
+                // forward is fine
+                if addr != 0 && a0 != 0 {
+                    copy_bytes(cpu, addr, a0, old.min(a1));
+                }
+                addr
+            }
+            0x39 => {
+                self.heap = Heap::new(a0, a1);
+                0
+            }
+            0x3a => {
+                println!("HLE BIOS: exit({})", a0);
+                cpu.set_pc(IDLE);
+                return None;
+            }
+            // putchar, already sent to the TTY by the system
+            0x3c => a0,
+            0x3e => {
+                tty.push_str(&read_string(cpu, a0));
+                1
+            }
+            0x3f => {
+                // Arguments after the third one are on the stack, after the
+                // space reserved for the first four
+                let stack: Vec<u32> = (0..16).map(|i| load32(cpu, r.regs[29].wrapping_add(0x10 + i * 4))).collect();
+                let mut args = [a1, a2, a3].into_iter().chain(stack);
+
+                let format = read_string(cpu, a0);
+                let text = printf(cpu, &format, &mut args);
+                let len = text.len();
+
+                tty.push_str(&text);
+                len as u32
+            }
+            // Load
+            0x42 => match self.load(cpu, a0, a1) {
+                Some(exe) => {
+                    exe.load(cpu);
+                    1
+                }
+                None => 0,
+            },
+            // Exec
+            0x43 => {
+                exec(cpu, a0, a1, a2);
+                return None;
+            }
+            0x44 => {
+                cpu.flush_cache();
+                0
+            }
+            0x48 => {
+                store32(cpu, GP1, a0);
+                0
+            }
+            0x49 => {
+                store32(cpu, GP0, a0);
+                0
+            }
+            0x4d => load32(cpu, GP1),
+            // LoadExec
+            0x51 => match self.load(cpu, a0, 0) {
+                Some(exe) => {
+                    let sp = match a1 {
+                        0 => 0,
+                        base => base.wrapping_add(a2),
+                    };
+
+                    exe.start(cpu, sp);
+                    return None;
+                }
+                None => 0,
+            },
+            // Device registration, CD-ROM interrupt queueing and memory
+            // size, nothing to set up
+            0x96 | 0x97 | 0x99 | 0x9f | 0xa2 | 0xa3 => 0,
+            0xa1 => {
+                println!("HLE BIOS: boot or disc failure");
+                cpu.set_pc(IDLE);
+                return None;
+            }
+            _ => {
+                println!("Unhandled HLE BIOS function A({:02x})", function);
+                0
+            }
+        };
+
+        Some(v0)
+    }
+
+    fn b_function(&mut self, cpu: &mut Cpu, function: u32, tty: &mut String) -> Option<u32> {
+        let r = cpu.registers();
+        let [a0, a1, a2, a3] = [r.regs[4], r.regs[5], r.regs[6], r.regs[7]];
+
+        let v0 = match function {
+            0x00 => self.kernel_heap.alloc(a0),
+            0x01 => {
+                self.kernel_heap.free(a0);
+                0
+            }
+            // StartRCnt and StopRCnt unmask the counter interrupt
+            0x04 | 0x05 => {
+                let irq = match a0 & 3 {
+                    3 => 1,
+                    n => 0x10 << n,
+                };
+                let mask = load32(cpu, I_MASK);
+
+                match function {
+                    0x04 => store32(cpu, I_MASK, mask | irq),
+                    _ => store32(cpu, I_MASK, mask & !irq),
+                }
+                1
+            }
+            // DeliverEvent
+            0x07 => {
+                let queue: VecDeque<_> = self.deliver_event(a0, a1).into();
+
+                if queue.is_empty() {
+                    0
+                } else {
+                    self.callbacks.push(Callbacks {
+                        queue,
+                        running: None,
+                        then: Resume::Call { ra: r.regs[31], sp: r.regs[29], v0: 0 },
+                    });
+                    self.next_callback(cpu);
+                    return None;
+                }
+            }
+            // OpenEvent
+            0x08 => {
+                let event = Event {
+                    class: a0,
+                    spec: a1,
+                    mode: a2,
+                    func: a3,
+                    status: EVENT_DISABLED,
+                };
+
+                match self.events.iter().position(|e| e.status == EVENT_FREE) {
+                    Some(i) => {
+                        self.events[i] = event;
+                        0xf1000000 | i as u32
+                    }
+                    None if self.events.len() < MAX_EVENTS => {
+                        self.events.push(event);
+                        0xf1000000 | (self.events.len() - 1) as u32
+                    }
+                    None => !0,
+                }
+            }
+            0x09 | 0x0c | 0x0d => {
+                let status = match function {
+                    0x09 => EVENT_FREE,
+                    0x0c => EVENT_ENABLED,
+                    _ => EVENT_DISABLED,
+                };
+
+                match self.event(a0) {
+                    Some(e) => {
+                        e.status = status;
+                        1
+                    }
+                    None => 0,
+                }
+            }
+            0x0a => return self.wait_event(cpu, a0),
+            // TestEvent
+            0x0b => match self.event(a0) {
+                Some(e) if e.status == EVENT_READY => {
+                    e.status = EVENT_ENABLED;
+                    1
+                }
+                _ => 0,
+            },
+            // Threads are accepted but never switched to
+            0x0e => 0xff000001,
+            0x0f => 1,
+            0x12 => {
+                self.pads = [(a0, a1), (a2, a3)];
+                1
+            }
+            0x13 => {
+                self.pads_started = true;
+                let mask = load32(cpu, I_MASK);
+                store32(cpu, I_MASK, mask | 1);
+                1
+            }
+            0x14 => {
+                self.pads_started = false;
+                1
+            }
+            0x17 => {
+                self.return_from_exception(cpu);
+                return None;
+            }
+            0x18 => {
+                self.custom_exit = 0;
+                0
+            }
+            0x19 => {
+                self.custom_exit = a0;
+                0
+            }
+            // UnDeliverEvent
+            0x20 => {
+                for e in &mut self.events {
+                    if e.status == EVENT_READY && e.class == a0 && e.spec == a1 {
+                        e.status = EVENT_ENABLED;
+                    }
+                }
+                0
+            }
+            0x32..=0x36 => return self.a_function(cpu, function - 0x32, tty),
+            0x3d => a0,
+            0x3f => return self.a_function(cpu, 0x3e, tty),
+            0x42 => self.first_file(cpu, a0, a1),
+            0x43 => self.next_file(cpu, a0),
+            // Devices and memory cards, nothing to set up
+            0x47 | 0x4a | 0x4b | 0x4c | 0x5b => 0,
+            // Errors are only reported through the return values
+            0x54 | 0x55 => 0,
+            0x56 => C0_TABLE,
+            0x57 => B0_TABLE,
+            _ => {
+                println!("Unhandled HLE BIOS function B({:02x})", function);
+                0
+            }
+        };
+
+        Some(v0)
+    }
+
+    fn c_function(&mut self, cpu: &mut Cpu, function: u32) -> Option<u32> {
+        let r = cpu.registers();
+        let [a0, a1] = [r.regs[4], r.regs[5]];
+
+        let v0 = match function {
+            // SysEnqIntRP
+            0x02 => {
+                self.int_handlers[(a0 & 3) as usize].insert(0, a1);
+                0
+            }
+            // SysDeqIntRP
+            0x03 => {
+                self.int_handlers[(a0 & 3) as usize].retain(|&s| s != a1);
+                0
+            }
+            // ChangeClearRCnt
+            0x0a => {
+                let flag = &mut self.rcnt_clear[(a0 & 3) as usize];
+                let old = *flag;
+                *flag = a1 != 0;
+                old as u32
+            }
+            // Kernel initialization, done at reset
+            0x00 | 0x01 | 0x07 | 0x08 | 0x09 | 0x0c | 0x12 | 0x1c => 0,
+            _ => {
+                println!("Unhandled HLE BIOS function C({:02x})", function);
+                0
+            }
+        };
+
+        Some(v0)
+    }
+}
+
+/// Kernel entry point the CPU is about to run
+fn trap(cpu: &mut Cpu) -> Option<u32> {
+    let pc = cpu.pc();
+
+    match pc & 0x1fffffff {
+        0x80 | 0xa0 | 0xb0 | 0xc0 => (),
+        0x1fc00000 | 0x1fc00180 => (),
+        a if a == CALLBACK_RETURN & 0x1fffffff || a == WAIT_TRAP & 0x1fffffff => (),
+        a if a == SHELL_ENTRY & 0x1fffffff => (),
+        _ => return None,
+    }
+
+    // The software may have replaced the kernel vectors with its own code
+    let word = cpu.interconnect_mut().load32(pc).ok()?;
+
+    match word & !0x3ffffff == TRAP {
+        true => Some(word & 0x3ffffff),
+        false => None,
+    }
+}
+
+/// Return from a `SaveState` with `v0`
+fn restore_state(cpu: &mut Cpu, buf: u32, v0: u32) {
+    let saved = [31, 29, 30, 16, 17, 18, 19, 20, 21, 22, 23, 28];
+
+    for (i, &reg) in saved.iter().enumerate() {
+        let val = load32(cpu, buf.wrapping_add(i as u32 * 4));
+        cpu.set_register(reg, val);
+    }
+
+    cpu.set_register(2, v0);
+
+    let ra = cpu.registers().regs[31];
+    cpu.set_pc(ra);
+}
+
+/// `Exec`: start the executable described by `header`, as filled by `Load`
+fn exec(cpu: &mut Cpu, header: u32, arg0: u32, arg1: u32) {
+    let pc = load32(cpu, header);
+    let gp = load32(cpu, header.wrapping_add(0x04));
+    let bss = load32(cpu, header.wrapping_add(0x18));
+    let bss_size = load32(cpu, header.wrapping_add(0x1c));
+    let stack = load32(cpu, header.wrapping_add(0x20));
+    let stack_size = load32(cpu, header.wrapping_add(0x24));
+
+    fill_bytes(cpu, bss, 0, bss_size);
+
+    if stack != 0 {
+        cpu.set_register(29, stack.wrapping_add(stack_size));
+        cpu.set_register(30, stack.wrapping_add(stack_size));
+    }
+
+    cpu.set_register(28, gp);
+    cpu.set_register(4, arg0);
+    cpu.set_register(5, arg1);
+    cpu.set_register(31, IDLE);
+    cpu.flush_cache();
+    cpu.set_pc(pc);
+}
+
+fn load32(cpu: &mut Cpu, addr: u32) -> u32 {
+    cpu.interconnect_mut().load32(addr).unwrap_or(0)
+}
+
+fn store32(cpu: &mut Cpu, addr: u32, val: u32) {
+    let _ = cpu.interconnect_mut().store32(addr, val);
+}
+
+fn load8(cpu: &mut Cpu, addr: u32) -> u8 {
+    cpu.interconnect_mut().load8(addr).unwrap_or(0)
+}
+
+fn store8(cpu: &mut Cpu, addr: u32, val: u8) {
+    let _ = cpu.interconnect_mut().store8(addr, val);
+}
+
+/// Buffer `len` bytes of guest memory, capped to the RAM area so that a
+/// bogus guest length can't exhaust the host memory
+fn read_bytes(cpu: &mut Cpu, addr: u32, len: u32) -> Vec<u8> {
+    (0..len.min(MAX_GUEST_LEN)).map(|i| load8(cpu, addr.wrapping_add(i))).collect()
+}
+
+fn write_bytes(cpu: &mut Cpu, addr: u32, data: &[u8]) {
+    for (i, &b) in data.iter().enumerate() {
+        store8(cpu, addr.wrapping_add(i as u32), b);
+    }
+}
+
+/// memset, byte by byte as `len` comes from the guest
+fn fill_bytes(cpu: &mut Cpu, addr: u32, val: u8, len: u32) {
+    for i in 0..len {
+        store8(cpu, addr.wrapping_add(i), val);
+    }
+}
+
+/// memmove, byte by byte backwards when the destination overlaps the end
+/// of the source
+fn copy_bytes(cpu: &mut Cpu, dst: u32, src: u32, len: u32) {
+    let overlaps = dst.wrapping_sub(src) < len;
+
+    for i in 0..len {
+        let i = match overlaps {
+            true => len - 1 - i,
+            false => i,
+        };
+
+        let b = load8(cpu, src.wrapping_add(i));
+        store8(cpu, dst.wrapping_add(i), b);
+    }
+}
+
+/// memcmp
+fn compare_bytes(cpu: &mut Cpu, a: u32, b: u32, len: u32) -> u32 {
+    for i in 0..len {
+        let (x, y) = (load8(cpu, a.wrapping_add(i)), load8(cpu, b.wrapping_add(i)));
+
+        match x.cmp(&y) {
+            std::cmp::Ordering::Less => return !0,
+            std::cmp::Ordering::Equal => (),
+            std::cmp::Ordering::Greater => return 1,
+        }
+    }
+
+    0
+}
+
+fn strlen(cpu: &mut Cpu, addr: u32) -> u32 {
+    if addr == 0 {
+        return 0;
+    }
+
+    let mut len = 0;
+
+    // A string running over the whole RAM area is garbage
+    while len < MAX_GUEST_LEN && load8(cpu, addr.wrapping_add(len)) != 0 {
+        len += 1;
+    }
+
+    len
+}
+
+/// NUL terminated string, as Latin-1
+fn read_string(cpu: &mut Cpu, addr: u32) -> String {
+    let len = strlen(cpu, addr);
+
+    read_bytes(cpu, addr, len).into_iter().map(|b| b as char).collect()
+}
+
+/// strncpy: the destination is padded with NULs up to `max`
+fn copy_string(cpu: &mut Cpu, dst: u32, src: u32, max: u32) {
+    let len = strlen(cpu, src);
+
+    copy_bytes(cpu, dst, src, len.min(max));
+
+    match max {
+        u32::MAX => store8(cpu, dst.wrapping_add(len), 0),
+        _ => fill_bytes(cpu, dst.wrapping_add(len.min(max)), 0, max - len.min(max)),
+    }
+}
+
+/// strchr and strrchr
+fn find_char(cpu: &mut Cpu, addr: u32, c: u8, last: bool) -> u32 {
+    let len = strlen(cpu, addr);
+    let s = read_bytes(cpu, addr, len);
+
+    let found = match last {
+        true => s.iter().rposition(|&b| b == c),
+        false => s.iter().position(|&b| b == c),
+    };
+
+    found.map_or(0, |i| addr.wrapping_add(i as u32))
+}
+
+/// strcmp limited to `max` characters
+fn compare(a: &str, b: &str, max: usize) -> u32 {
+    let a = a.bytes().take(max);
+    let b = b.bytes().take(max);
+
+    match a.cmp(b) {
+        std::cmp::Ordering::Less => !0,
+        std::cmp::Ordering::Equal => 0,
+        std::cmp::Ordering::Greater => 1,
+    }
+}
+
+fn atoi(s: &str) -> i32 {
+    let s = s.trim_start();
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+
+    let value = digits
+        .bytes()
+        .take_while(|b| b.is_ascii_digit())
+        .fold(0i32, |v, b| v.wrapping_mul(10).wrapping_add((b - b'0') as i32));
+
+    match negative {
+        true => value.wrapping_neg(),
+        false => value,
+    }
+}
+
+/// File name matching with the `?` and `*` wildcards of `firstfile`
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let mut name = name.chars();
+
+    for p in pattern.chars() {
+        match p {
+            '*' => return true,
+            '?' => {
+                if name.next().is_none() {
+                    return false;
+                }
+            }
+            p => {
+                if !name.next().is_some_and(|c| c.eq_ignore_ascii_case(&p)) {
+                    return false;
+                }
+            }
+        }
+    }
+
+    name.next().is_none()
+}
+
+/// Format `format` like the BIOS printf: flags `-` and `0`, width,
+/// precision and the `d i u x X o c s p %` conversions
+fn printf(cpu: &mut Cpu, format: &str, args: &mut impl Iterator<Item = u32>) -> String {
+    let mut out = String::new();
+    let mut chars = format.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+
+        let mut left = false;
+        let mut zero = false;
+
+        while let Some(&f) = chars.peek() {
+            match f {
+                '-' => left = true,
+                '0' => zero = true,
+                '+' | ' ' | '#' => (),
+                _ => break,
+            }
+            chars.next();
+        }
+
+        let number = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+            let mut n = None;
+
+            while let Some(d) = chars.peek().and_then(|c| c.to_digit(10)) {
+                n = Some((n.unwrap_or(0) * 10 + d as usize).min(MAX_WIDTH));
+                chars.next();
+            }
+
+            n
+        };
+
+        let width = number(&mut chars).unwrap_or(0);
+        let precision = match chars.peek() {
+            Some('.') => {
+                chars.next();
+                number(&mut chars)
+            }
+            _ => None,
+        };
+
+        while matches!(chars.peek(), Some('l' | 'h')) {
+            chars.next();
+        }
+
+        let Some(conversion) = chars.next() else {
+            break;
+        };
+
+        let text = match conversion {
+            '%' => "%".to_string(),
+            'd' | 'i' => (args.next().unwrap_or(0) as i32).to_string(),
+            'u' => args.next().unwrap_or(0).to_string(),
+            'x' => format!("{:x}", args.next().unwrap_or(0)),
+            'X' => format!("{:X}", args.next().unwrap_or(0)),
+            'o' => format!("{:o}", args.next().unwrap_or(0)),
+            'p' => format!("{:08x}", args.next().unwrap_or(0)),
+            'c' => (args.next().unwrap_or(0) as u8 as char).to_string(),
+            's' => {
+                let addr = args.next().unwrap_or(0);
+                let s = read_string(cpu, addr);
+
+                match precision {
+                    Some(p) => s.chars().take(p).collect(),
+                    None => s,
+                }
+            }
+            c => {
+                out.push('%');
+                out.push(c);
+                continue;
+            }
+        };
+
+        let pad = width.saturating_sub(text.chars().count());
+
+        match (left, zero && conversion != 's' && conversion != 'c') {
+            (true, _) => {
+                out.push_str(&text);
+                out.extend(std::iter::repeat_n(' ', pad));
+            }
+            (false, true) => {
+                // The sign goes before the zeros
+                let (sign, digits) = match text.strip_prefix('-') {
+                    Some(digits) => ("-", digits),
+                    None => ("", text.as_str()),
+                };
+
+                out.push_str(sign);
+                out.extend(std::iter::repeat_n('0', pad));
+                out.push_str(digits);
+            }
+            (false, false) => {
+                out.extend(std::iter::repeat_n(' ', pad));
+                out.push_str(&text);
+            }
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psx::cpu::harness::{Machine, ENTRY};
+
+    /// Call B function `function` with `args` in $a0-$a3
+    fn b_call(kernel: &mut Kernel, m: &mut Machine, function: u32, args: &[u32]) -> Option<u32> {
+        for (i, &a) in args.iter().enumerate() {
+            m.set_reg(4 + i as u32, a);
+        }
+
+        kernel.b_function(m.cpu_mut(), function, &mut String::new())
+    }
+
+    fn format(m: &mut Machine, format: &str, args: &[u32]) -> String {
+        printf(m.cpu_mut(), format, &mut args.iter().copied())
+    }
+
+    #[test]
+    fn printf_formats_like_the_bios() {
+        let mut m = Machine::new(&[], ENTRY);
+
+        write_bytes(m.cpu_mut(), 0x1000, b"hello\0");
+
+        assert_eq!(format(&mut m, "%d %i %u", &[-5i32 as u32, 7, -1i32 as u32]), "-5 7 4294967295");
+        assert_eq!(format(&mut m, "%x %X %o %p", &[0xab, 0xab, 8, 0x1f]), "ab AB 10 0000001f");
+        assert_eq!(format(&mut m, "[%5d|%-5d|%05d]", &[42, 42, -42i32 as u32]), "[   42|42   |-0042]");
+        assert_eq!(format(&mut m, "%s, %.3s%c", &[0x1000, 0x1000, b'!' as u32]), "hello, hel!");
+        assert_eq!(format(&mut m, "%05s|%ld%%", &[0x1000, 3]), "hello|3%");
+        // Unknown conversions are printed as is, missing arguments read 0
+        assert_eq!(format(&mut m, "%q %d", &[]), "%q 0");
+    }
+
+    #[test]
+    fn atoi_parses_a_leading_number() {
+        assert_eq!(atoi("123"), 123);
+        assert_eq!(atoi("  -42abc"), -42);
+        assert_eq!(atoi("+7"), 7);
+        assert_eq!(atoi("x1"), 0);
+        assert_eq!(atoi(""), 0);
+        // Overflow wraps like the BIOS
+        assert_eq!(atoi("4294967297"), 1);
+    }
+
+    #[test]
+    fn wildcards_match_file_names() {
+        assert!(wildcard_match("SLUS_123.45;1", "slus_123.45;1"));
+        assert!(wildcard_match("SLUS_???.45;1", "SLUS_123.45;1"));
+        assert!(wildcard_match("SL*", "SLUS_123.45;1"));
+        assert!(wildcard_match("*", ""));
+        assert!(!wildcard_match("SLUS", "SLUS_123"));
+        assert!(!wildcard_match("SLUS_123?", "SLUS_123"));
+        assert!(!wildcard_match("SCES*", "SLUS_123"));
+    }
+
+    #[test]
+    fn heap_allocates_first_fit() {
+        let mut heap = Heap::new(0x1000, 0x100);
+
+        let a = heap.alloc(0x10);
+        let b = heap.alloc(1);
+        let c = heap.alloc(0x20);
+
+        assert_eq!((a, b, c), (0x1000, 0x1010, 0x1014));
+
+        // The freed block is reused by an allocation that fits in it
+        assert_eq!(heap.free(a), 0x10);
+        assert_eq!(heap.alloc(0x0c), 0x1000);
+        assert_eq!(heap.alloc(0x08), 0x1034);
+
+        assert_eq!(heap.free(0x1234), 0);
+    }
+
+    #[test]
+    fn heap_refuses_what_doesnt_fit() {
+        let mut heap = Heap::new(0x1000, 0x100);
+
+        assert_eq!(heap.alloc(0x101), 0);
+        assert_eq!(heap.alloc(u32::MAX), 0);
+        assert_eq!(heap.alloc(0x100), 0x1000);
+        assert_eq!(heap.alloc(1), 0);
+    }
+
+    #[test]
+    fn events_become_ready_when_delivered() {
+        let mut m = Machine::new(&[], ENTRY);
+        let mut kernel = Kernel::new(None);
+        let (class, spec) = (RCNT_CLASS | 3, RCNT_SPEC);
+
+        let handle = b_call(&mut kernel, &mut m, 0x08, &[class, spec, 0x2000, 0]).unwrap();
+        assert_eq!(handle, 0xf1000000);
+
+        // Disabled events ignore deliveries
+        assert_eq!(b_call(&mut kernel, &mut m, 0x07, &[class, spec]), Some(0));
+        assert_eq!(b_call(&mut kernel, &mut m, 0x0b, &[handle]), Some(0));
+
+        assert_eq!(b_call(&mut kernel, &mut m, 0x0c, &[handle]), Some(1));
+        assert_eq!(b_call(&mut kernel, &mut m, 0x07, &[class, spec + 1]), Some(0));
+        assert_eq!(b_call(&mut kernel, &mut m, 0x0b, &[handle]), Some(0));
+
+        assert_eq!(b_call(&mut kernel, &mut m, 0x07, &[class, spec]), Some(0));
+        assert_eq!(b_call(&mut kernel, &mut m, 0x0b, &[handle]), Some(1));
+        // Testing acknowledges the event
+        assert_eq!(b_call(&mut kernel, &mut m, 0x0b, &[handle]), Some(0));
+
+        // Closed handles are refused
+        assert_eq!(b_call(&mut kernel, &mut m, 0x09, &[handle]), Some(1));
+        assert_eq!(b_call(&mut kernel, &mut m, 0x0c, &[handle]), Some(0));
+    }
+
+    #[test]
+    fn delivered_events_call_their_callback() {
+        let mut m = Machine::new(&[], ENTRY);
+        let mut kernel = Kernel::new(None);
+        let (class, spec, func) = (0xf0000003, 0x20, 0x80020000);
+        let (ra, sp) = (ENTRY + 0x40, 0x801fff00);
+
+        let handle = b_call(&mut kernel, &mut m, 0x08, &[class, spec, EVENT_MODE_CALLBACK, func]).unwrap();
+        b_call(&mut kernel, &mut m, 0x0c, &[handle]);
+
+        m.set_reg(31, ra);
+        m.set_reg(29, sp);
+
+        // DeliverEvent jumps to the callback instead of returning
+        assert_eq!(b_call(&mut kernel, &mut m, 0x07, &[class, spec]), None);
+
+        let r = m.registers();
+        assert_eq!(r.pc, func);
+        assert_eq!(r.regs[4], 0);
+        assert_eq!(r.regs[31], CALLBACK_RETURN);
+
+        // Which returns to the caller of DeliverEvent
+        m.set_reg(29, 0x801ffe00);
+        kernel.next_callback(m.cpu_mut());
+
+        let r = m.registers();
+        assert_eq!(r.pc, ra);
+        assert_eq!(r.regs[2], 0);
+        assert_eq!(r.regs[29], sp);
+        // Callback events never become ready
+        assert_eq!(b_call(&mut kernel, &mut m, 0x0b, &[handle]), Some(0));
+    }
+
+    #[test]
+    fn memset_writes_byte_by_byte() {
+        let mut m = Machine::new(&[], ENTRY);
+
+        m.store32(0x1000, 0x11111111);
+        m.store32(0x1004, 0x11111111);
+
+        fill_bytes(m.cpu_mut(), 0x1001, 0xab, 6);
+
+        assert_eq!(m.load32(0x1000), 0xababab11);
+        assert_eq!(m.load32(0x1004), 0x11ababab);
+    }
+
+    /// Call A function `function` with `args` in $a0-$a3
+    fn a_call(kernel: &mut Kernel, m: &mut Machine, function: u32, args: &[u32]) -> Option<u32> {
+        for (i, &a) in args.iter().enumerate() {
+            m.set_reg(4 + i as u32, a);
+        }
+
+        kernel.a_function(m.cpu_mut(), function, &mut String::new())
+    }
+
+    #[test]
+    fn memmove_handles_overlaps() {
+        let mut m = Machine::new(&[], ENTRY);
+
+        write_bytes(m.cpu_mut(), 0x1000, b"abcdef");
+        copy_bytes(m.cpu_mut(), 0x1002, 0x1000, 4);
+        assert_eq!(read_bytes(m.cpu_mut(), 0x1000, 6), b"ababcd");
+
+        write_bytes(m.cpu_mut(), 0x1000, b"abcdef");
+        copy_bytes(m.cpu_mut(), 0x1000, 0x1002, 4);
+        assert_eq!(read_bytes(m.cpu_mut(), 0x1000, 6), b"cdefef");
+    }
+
+    #[test]
+    fn guest_lengths_dont_buffer_on_the_host() {
+        let mut m = Machine::new(&[], ENTRY);
+        let mut kernel = Kernel::new(None);
+
+        write_bytes(m.cpu_mut(), 0x1000, b"abcx");
+        write_bytes(m.cpu_mut(), 0x2000, b"abdx");
+
+        // memcmp and memchr stop at the first difference or match
+        assert_eq!(a_call(&mut kernel, &mut m, 0x2d, &[0x1000, 0x2000, u32::MAX]), Some(!0));
+        assert_eq!(a_call(&mut kernel, &mut m, 0x2d, &[0x1000, 0x2000, 2]), Some(0));
+        assert_eq!(a_call(&mut kernel, &mut m, 0x2e, &[0x1000, b'x' as u32, u32::MAX]), Some(0x1003));
+        assert_eq!(a_call(&mut kernel, &mut m, 0x2e, &[0x1000, b'x' as u32, 3]), Some(0));
+
+        // strcat at the end of the address space wraps around to RAM
+        write_bytes(m.cpu_mut(), 0x1004, b"\0");
+        assert_eq!(a_call(&mut kernel, &mut m, 0x15, &[0xffff_fffe, 0x1000]), Some(0xffff_fffe));
+        assert_eq!(read_bytes(m.cpu_mut(), 0, 3), b"cx\0");
+
+        assert_eq!(format(&mut m, "%99999999999999999999999d", &[1]).len(), MAX_WIDTH);
+    }
+}
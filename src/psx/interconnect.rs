@@ -1,220 +1,982 @@
 use super::bios::Bios;
 use super::cpu::map;
-use super::dma::Dma;
+use super::expansion::{Cartridge, Expansion1};
+use super::dma::{Direction, Dma, Port, Step, Sync};
+use super::gpu::Gpu;
+use super::irq::{Interrupt, InterruptState};
+use super::mdec::Mdec;
+use super::mem_control::MemControl;
 use super::ram::Ram;
+use super::sio0::{self, Sio0};
+use super::sio1::Sio1;
+
+use std::fmt;
+
+/// Value read when nothing drives the data bus
+const OPEN_BUS: u32 = 0xffffffff;
+
+/// Cycles taken by a 32bit RAM access
+const RAM_ACCESS_CYCLES: u32 = 5;
+/// Cycles taken by a scratchpad access
+const SCRATCHPAD_ACCESS_CYCLES: u32 = 1;
+/// Cycles taken by an access to the devices without MEMLCONTROL timings
+const DEVICE_ACCESS_CYCLES: u32 = 2;
+
+/// RAM_SIZE value set by the BIOS: 8MiB window with 2MiB chips
+const RAM_SIZE_DEFAULT: u32 = 0x00000b88;
+
+/// What answers an access in the 8MiB RAM area
+enum RamWindow {
+    /// RAM at this offset
+    Mapped(u32),
+    /// Nothing drives the bus, reads return open bus
+    HighZ,
+    /// Bus error
+    Locked,
+}
+
+/// Access to an address no device answers to, the CPU turns it into a bus
+/// error exception
+#[derive(Clone, Copy, Debug)]
+pub struct BusError {
+    pub addr: u32,
+    /// Access width in bits
+    pub size: u32,
+    pub store: bool,
+}
+
+impl BusError {
+    fn load(addr: u32, size: u32) -> BusError {
+        BusError { addr, size, store: false }
+    }
+
+    fn store(addr: u32, size: u32) -> BusError {
+        BusError { addr, size, store: true }
+    }
+}
+
+impl fmt::Display for BusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let access = match self.store {
+            true => "store",
+            false => "load",
+        };
+
+        write!(f, "{}{} at address {:08x}", access, self.size, self.addr)
+    }
+}
 
 /// Responsible for connecting the bios to other peripherals
 pub struct Interconnect {
-    bios: Bios,
+    /// None for CPU tests running without a BIOS, the socket then reads
+    /// as open bus
+    bios: Option<Bios>,
+    exp1: Expansion1,
     ram: Ram,
     dma: Dma,
+    mdec: Mdec,
+    gpu: Gpu,
+    sio0: Sio0,
+    sio1: Sio1,
+    irq: InterruptState,
+    mem_control: MemControl,
+    /// Data cache used as scratchpad RAM
+    scratchpad: Ram,
+    /// CACHE_CONTROL register
+    cache_control: u32,
+    /// RAM_SIZE register
+    ram_size: u32,
+    /// Report unhandled registers as bus errors instead of open bus
+    strict: bool,
+    /// CPU cycles elapsed since reset
+    cycles: u64,
 }
 
 impl Interconnect {
-    pub fn new(bios: Bios, ram: Ram, dma: Dma) -> Interconnect {
-        Interconnect { bios, ram, dma, }
+    pub fn new(bios: Option<Bios>, ram: Ram, dma: Dma, mdec: Mdec, gpu: Gpu, mut sio0: Sio0, sio1: Sio1) -> Interconnect {
+        sio0.set_display(gpu.display());
+
+        Interconnect {
+            bios,
+            exp1: Expansion1::new(None),
+            ram,
+            dma,
+            mdec,
+            gpu,
+            sio0,
+            sio1,
+            irq: InterruptState::new(),
+            mem_control: MemControl::new(),
+            scratchpad: Ram::with_size(1024),
+            cache_control: 0,
+            ram_size: RAM_SIZE_DEFAULT,
+            strict: false,
+            cycles: 0,
+        }
     }
 
-    pub fn load8(&self, addr: u32) -> u8 {
+    /// Advance the peripherals by `cycles` CPU cycles
+    pub fn tick(&mut self, cycles: u32) {
+        self.cycles += cycles as u64;
+
+        if self.gpu.tick(cycles) {
+            self.irq.assert(Interrupt::VBlank);
+        }
+
+        // Request mode transfers waiting for the MDEC
+        for port in [Port::MdecIn, Port::MdecOut] {
+            if self.dma.channel(port).active() {
+                self.do_dma(port);
+            }
+        }
+
+        if self.sio0.tick(cycles) {
+            self.irq.assert(Interrupt::PadMemCard);
+        }
+
+        if self.sio1.tick(cycles) {
+            self.irq.assert(Interrupt::Sio);
+        }
+    }
+
+    /// CPU cycles elapsed since reset
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn ram(&self) -> &Ram {
+        &self.ram
+    }
+
+    pub fn ram_mut(&mut self) -> &mut Ram {
+        &mut self.ram
+    }
+
+    pub fn gpu(&self) -> &Gpu {
+        &self.gpu
+    }
+
+    /// Controller port `port` (0 or 1)
+    pub fn pad_port(&mut self, port: usize) -> &mut sio0::Port {
+        self.sio0.port_mut(port)
+    }
+
+    /// Plug a cartridge in the parallel port
+    pub fn plug_cartridge(&mut self, cartridge: Cartridge) {
+        self.exp1 = Expansion1::new(Some(cartridge));
+    }
+
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Access that no device handled. In the I/O and expansion areas the
+    /// address is decoded but nothing drives the bus, reads return open bus.
+    /// Anywhere else the access times out with a bus error
+    fn unhandled(&self, err: BusError) -> Result<(), BusError> {
+        let decoded = map::IO.contains(err.addr).is_some()
+            || map::EXPANSION_1.contains(err.addr).is_some()
+            || map::EXPANSION_2.contains(err.addr).is_some()
+            || map::EXPANSION_3.contains(err.addr).is_some()
+            || map::SCRATCHPAD.contains(err.addr).is_some()
+            || self.mem_control.region(err.addr).is_some();
+
+        if !decoded || self.strict {
+            return Err(err);
+        }
+
+        println!("Unhandled {}", err);
+
+        Ok(())
+    }
+
+    /// Cycles taken by a `width` bits access to `addr`
+    pub fn access_cycles(&self, addr: u32, width: u32, store: bool) -> u32 {
+        if self.scratchpad_offset(addr).is_some() {
+            return SCRATCHPAD_ACCESS_CYCLES;
+        }
+
+        let addr = map::mask_region(addr);
+
+        if map::RAM.contains(addr).is_some() {
+            return RAM_ACCESS_CYCLES;
+        }
+
+        match self.mem_control.region(addr) {
+            Some(region) => self.mem_control.access_cycles(region, width, store),
+            None => DEVICE_ACCESS_CYCLES,
+        }
+    }
+
+    /// Decode an offset in the RAM area. RAM_SIZE bits 9-11 split the 8MiB
+    /// in a RAM window, possibly followed by a high-Z window, with the rest
+    /// locked. The installed RAM is mirrored over its window
+    fn ram_window(&self, offset: u32) -> RamWindow {
+        const MB: u32 = 1024 * 1024;
+
+        let (memory, high_z) = match (self.ram_size >> 9) & 7 {
+            0 => (MB, 0),
+            1 => (4 * MB, 0),
+            2 => (MB, MB),
+            3 => (4 * MB, 4 * MB),
+            4 => (2 * MB, 0),
+            5 => (8 * MB, 0),
+            6 => (2 * MB, 2 * MB),
+            7 => (8 * MB, 0),
+            _ => unreachable!(),
+        };
+
+        if offset < memory {
+            RamWindow::Mapped(offset & (self.ram.size() - 1))
+        } else if offset < memory + high_z {
+            RamWindow::HighZ
+        } else {
+            RamWindow::Locked
+        }
+    }
+
+    pub fn cache_control(&self) -> u32 {
+        self.cache_control
+    }
+
+    /// Offset in the scratchpad if `addr` maps to it. The scratchpad needs
+    /// CACHE_CONTROL bits 3 and 7 and can't be reached through KSEG1
+    fn scratchpad_offset(&self, addr: u32) -> Option<u32> {
+        if self.cache_control & 0x88 != 0x88 || addr >> 29 == 5 {
+            return None;
+        }
+
+        map::SCRATCHPAD.contains(map::mask_region(addr))
+    }
+
+    /// True when an unmasked interrupt is pending
+    pub fn irq_active(&self) -> bool {
+        self.irq.active()
+    }
+
+    pub fn load8(&mut self, addr: u32) -> Result<u8, BusError> {
+        if let Some(offset) = self.scratchpad_offset(addr) {
+            return Ok(self.scratchpad.load8(offset));
+        }
+
         let addr = map::mask_region(addr);
 
+        if let Some(offset) = map::PAD.contains(addr) {
+            if let Some(v) = self.pad_reg(offset) {
+                return Ok(v as u8);
+            }
+        }
+
+        if let Some(offset) = map::SIO1.contains(addr) {
+            if let Some(v) = self.sio1_reg(offset) {
+                return Ok(v as u8);
+            }
+        }
+
         if let Some(offset) = map::RAM.contains(addr) {
-            return self.ram.load8(offset);
+            return match self.ram_window(offset) {
+                RamWindow::Mapped(offset) => Ok(self.ram.load8(offset)),
+                RamWindow::HighZ => Ok(OPEN_BUS as u8),
+                RamWindow::Locked => Err(BusError::load(addr, 8)),
+            };
         }
 
-        if let Some(offset) = map::BIOS.contains(addr) {
-            return self.bios.load8(offset);
+        if let (Some(offset), Some(bios)) = (map::BIOS.contains(addr), &self.bios) {
+            return Ok(bios.load8(offset));
         }
 
-        if let Some(_) = map::EXPANSION_1.contains(addr) {
-            println!("Unhandled load8 at Expansion1 register {:08x}", addr);
-            return 0xff;
+        if let Some(offset) = self.mem_control.expansion_1(addr) {
+            return Ok(self.exp1.load8(offset));
         }
 
-        panic!("unhandled load8 at address {:08x}", addr);
+        self.unhandled(BusError::load(addr, 8)).map(|_| OPEN_BUS as u8)
     }
 
-    pub fn load16(&self, addr: u32) -> u16 {
+    pub fn load16(&mut self, addr: u32) -> Result<u16, BusError> {
+        if let Some(offset) = self.scratchpad_offset(addr) {
+            return Ok(self.scratchpad.load16(offset));
+        }
+
         let addr = map::mask_region(addr);
 
-        if let Some(_) = map::SPU.contains(addr){
+        if let Some(offset) = self.mem_control.expansion_1(addr) {
+            return Ok(self.exp1.load(offset, 2) as u16);
+        }
+
+        if let (Some(offset), Some(bios)) = (map::BIOS.contains(addr), &self.bios) {
+            return Ok(bios.load16(offset));
+        }
+
+        if let Some(offset) = map::PAD.contains(addr) {
+            if let Some(v) = self.pad_reg(offset) {
+                return Ok(v as u16);
+            }
+        }
+
+        if let Some(offset) = map::SIO1.contains(addr) {
+            if let Some(v) = self.sio1_reg(offset) {
+                return Ok(v as u16);
+            }
+        }
+
+        if map::SPU.contains(addr).is_some(){
             //println!("Unhandled read from SPU register {:08x}",addr);
-            return 0;
+            return Ok(0);
         }
 
         if let Some(offset) = map::IRQ_CONTROL.contains(addr) {
-            println!("unhandled load IRQ control: {}",offset);
-            return 0;
+            if let Some(v) = self.irq_reg(offset) {
+                return Ok(v as u16);
+            }
         }
 
         if let Some(offset) = map::RAM.contains(addr) {
-            return self.ram.load16(offset);
+            return match self.ram_window(offset) {
+                RamWindow::Mapped(offset) => Ok(self.ram.load16(offset)),
+                RamWindow::HighZ => Ok(OPEN_BUS as u16),
+                RamWindow::Locked => Err(BusError::load(addr, 16)),
+            };
         }
 
-        panic!("Unhandled load16 at address {:08x}",addr);
+        self.unhandled(BusError::load(addr, 16)).map(|_| OPEN_BUS as u16)
     }
 
-    pub fn load32(&self, addr: u32) -> u32 {
-        if addr % 4 != 0 {
-            panic!("Unaligned load32 address {:08x}", addr);
+    pub fn load32(&mut self, addr: u32) -> Result<u32, BusError> {
+        if let Some(offset) = self.scratchpad_offset(addr) {
+            return Ok(self.scratchpad.load32(offset));
         }
 
         let addr = map::mask_region(addr);
 
-        if let Some(offset) = map::BIOS.contains(addr) {
-            return self.bios.load32(offset);
+        if let Some(offset) = self.mem_control.expansion_1(addr) {
+            return Ok(self.exp1.load(offset, 4));
+        }
+
+        if let (Some(offset), Some(bios)) = (map::BIOS.contains(addr), &self.bios) {
+            return Ok(bios.load32(offset));
         }
 
         if let Some(offset) = map::RAM.contains(addr) {
-            return self.ram.load32(offset);
+            return match self.ram_window(offset) {
+                RamWindow::Mapped(offset) => Ok(self.ram.load32(offset)),
+                RamWindow::HighZ => Ok(OPEN_BUS),
+                RamWindow::Locked => Err(BusError::load(addr, 32)),
+            };
         }
 
         if let Some(offset) = map::IRQ_CONTROL.contains(addr) {
-            println!("unhandled load IRQ control: {}",offset);
-            return 0;
+            if let Some(v) = self.irq_reg(offset) {
+                return Ok(v);
+            }
+        }
+
+        if let Some(offset) = map::PAD.contains(addr) {
+            if let Some(v) = self.pad_reg(offset) {
+                return Ok(v);
+            }
+        }
+
+        if let Some(offset) = map::SIO1.contains(addr) {
+            if let Some(v) = self.sio1_reg(offset) {
+                return Ok(v);
+            }
         }
 
         if let Some(offset) = map::TIMERS.contains(addr){
             println!("Unhandled load 32 to time register: {:08x}",offset);
-            return 0;
+            return Ok(0);
         }
 
         if let Some(offset) = map::GPU.contains(addr) {
-            //println!("unhandled GPU read: {}",offset);
-            return match  offset {
-               4 => 0x10000000,
-               _ => 0,
-            };
+            return Ok(match offset {
+                0 => self.gpu.read(),
+                4 => self.gpu.status(),
+                _ => unreachable!(),
+            });
         }
 
         if let Some(offset) = map::DMA.contains(addr) {
-            println!("DMA Read: {:08x}",addr);
-            return self.dma_reg(offset);
+            if let Some(v) = self.dma_reg(offset) {
+                return Ok(v);
+            }
+        }
+
+        if map::CACHE_CONTROL.contains(addr).is_some() {
+            return Ok(self.cache_control);
+        }
+
+        if map::RAM_SIZE.contains(addr).is_some() {
+            return Ok(self.ram_size);
+        }
+
+        if let Some(offset) = map::MEMLCONTROL.contains(addr) {
+            return Ok(self.mem_control.load(offset));
         }
 
-        panic!("unhandled fetch32 at address {:08x}", addr);
+        if let Some(offset) = map::MDEC.contains(addr) {
+            return match offset {
+                0 => Ok(self.mdec.read_data()),
+                4 => Ok(self.mdec.status()),
+                _ => self.unhandled(BusError::load(addr, 32)).map(|_| OPEN_BUS),
+            };
+        }
+
+        self.unhandled(BusError::load(addr, 32)).map(|_| OPEN_BUS)
     }
 
-    pub fn store8(&mut self, addr: u32, val: u8) {
+    pub fn store8(&mut self, addr: u32, val: u8) -> Result<(), BusError> {
+        if let Some(offset) = self.scratchpad_offset(addr) {
+            self.scratchpad.store8(offset, val);
+            return Ok(());
+        }
+
         let addr = map::mask_region(addr);
 
+        if let Some(offset) = self.mem_control.expansion_1(addr) {
+            self.exp1.store8(offset, val);
+            return Ok(());
+        }
+
         if let Some(offset) = map::RAM.contains(addr) {
-            return self.ram.store8(offset, val);
+            return match self.ram_window(offset) {
+                RamWindow::Mapped(offset) => {
+                    self.ram.store8(offset, val);
+                    Ok(())
+                }
+                RamWindow::HighZ => Ok(()),
+                RamWindow::Locked => Err(BusError::store(addr, 8)),
+            };
         }
-        if let Some(offset) = map::EXPANSION_2.contains(addr) {
+        if let Some(offset) = self.mem_control.expansion_2(addr) {
             println!("Unhandled write byte to Expansion2 register {:x}", offset);
-            return;
+            return Ok(());
         }
 
-        panic!("unhandled store16 into address {:08x}", addr)
-    }
+        if let Some(offset) = map::PAD.contains(addr) {
+            if self.set_pad_reg(offset, val as u16) {
+                return Ok(());
+            }
+        }
 
-    pub fn store16(&mut self, addr: u32, val: u16) {
+        if let Some(offset) = map::SIO1.contains(addr) {
+            if self.set_sio1_reg(offset, val as u16) {
+                return Ok(());
+            }
+        }
+
+        self.unhandled(BusError::store(addr, 8))
+    }
 
-        if addr % 2 != 0 {
-            panic!("Unaligned store16 address {:08x}", addr)
+    pub fn store16(&mut self, addr: u32, val: u16) -> Result<(), BusError> {
+        if let Some(offset) = self.scratchpad_offset(addr) {
+            self.scratchpad.store16(offset, val);
+            return Ok(());
         }
 
         let addr = map::mask_region(addr);
 
+        if let Some(offset) = self.mem_control.expansion_1(addr) {
+            self.exp1.store(offset, val as u32, 2);
+            return Ok(());
+        }
+
         if let Some(offset) = map::RAM.contains(addr) {
-            return self.ram.store16(offset,val);
+            return match self.ram_window(offset) {
+                RamWindow::Mapped(offset) => {
+                    self.ram.store16(offset, val);
+                    Ok(())
+                }
+                RamWindow::HighZ => Ok(()),
+                RamWindow::Locked => Err(BusError::store(addr, 16)),
+            };
         }
 
         if let Some(_offset) = map::SPU.contains(addr) {
             // println!("Unhandled write half to SPU register {:x}", offset);
-            return;
+            return Ok(());
         }
 
         if let Some(offset) = map::IRQ_CONTROL.contains(addr) {
-            println!("unhandled write IRQ control: {} <- {:08x}",offset,val);
-            return;
+            if self.set_irq_reg(offset, val) {
+                return Ok(());
+            }
         }
-        
+
+        if let Some(offset) = map::PAD.contains(addr) {
+            if self.set_pad_reg(offset, val) {
+                return Ok(());
+            }
+        }
+
+        if let Some(offset) = map::SIO1.contains(addr) {
+            if self.set_sio1_reg(offset, val) {
+                return Ok(());
+            }
+        }
+
         if let Some(offset) = map::TIMERS.contains(addr){
             println!("Unhandled write to time register: {:08x}",offset);
-            return ;
+            return Ok(());
         }
 
-        panic!("unhandled store16 into address {:08x}", addr)
+        self.unhandled(BusError::store(addr, 16))
     }
 
-    pub fn store32(&mut self, addr: u32, val: u32) {
-        if addr % 4 != 0 {
-            panic!("Unaligned store32 address {:08x}", addr);
+    pub fn store32(&mut self, addr: u32, val: u32) -> Result<(), BusError> {
+        if let Some(offset) = self.scratchpad_offset(addr) {
+            self.scratchpad.store32(offset, val);
+            return Ok(());
         }
 
         let addr = map::mask_region(addr);
 
+        if let Some(offset) = self.mem_control.expansion_1(addr) {
+            self.exp1.store(offset, val, 4);
+            return Ok(());
+        }
+
         if let Some(offset) = map::MEMLCONTROL.contains(addr) {
-            match offset {
-                0 => {
-                    if val != 0x1f000000 {
-                        panic!("Bad expansion 1 base address: 0x{:08x}", val);
-                    }
-                }
-                4 => {
-                    if val != 0x1f802000 {
-                        panic!("Bad expansion 2 base address: 0x{:08x}", val);
-                    }
-                }
-                _ => println!("unhandled write MEMLCONTROL register {:08x}", addr),
-            }
-            return;
+            self.mem_control.store(offset, val);
+            return Ok(());
         }
 
-        if let Some(_) = map::RAM_SIZE.contains(addr) {
-            println!("unhandled write RAM_SIZE register");
-            return;
+        if map::RAM_SIZE.contains(addr).is_some() {
+            self.ram_size = val;
+            return Ok(());
         }
         
         if let Some(offset) = map::IRQ_CONTROL.contains(addr) {
-            println!("unhandled write IRQ control: {} <- {:08x}",offset,val);
-            return;
+            if self.set_irq_reg(offset, val as u16) {
+                return Ok(());
+            }
+        }
+
+        if let Some(offset) = map::PAD.contains(addr) {
+            if self.set_pad_reg(offset, val as u16) {
+                return Ok(());
+            }
+        }
+
+        if let Some(offset) = map::SIO1.contains(addr) {
+            if self.set_sio1_reg(offset, val as u16) {
+                return Ok(());
+            }
         }
 
         if let Some(offset) = map::TIMERS.contains(addr){
             println!("Unhandled write32 to time register: {} <- {:08x}",offset,val);
-            return ;
+            return Ok(());
         }
 
         if let Some(offset) = map::GPU.contains(addr) {
-            println!("unhandled GPU store: {} <- {:08x}",offset,val);
-            return ;
+            match offset {
+                0 => self.gpu.gp0(val),
+                4 => {
+                    // Light guns need the display timings to locate the beam
+                    if self.gpu.gp1(val) {
+                        self.sio0.set_display(self.gpu.display());
+                    }
+                }
+                _ => unreachable!(),
+            }
+            return Ok(());
         }
 
         if let Some(offset) = map::RAM.contains(addr) {
-            self.ram.store32(offset, val);
-            return;
+            return match self.ram_window(offset) {
+                RamWindow::Mapped(offset) => {
+                    self.ram.store32(offset, val);
+                    Ok(())
+                }
+                RamWindow::HighZ => Ok(()),
+                RamWindow::Locked => Err(BusError::store(addr, 32)),
+            };
         }
 
-        if let Some(_) = map::CACHE_CONTROL.contains(addr) {
-            println!("unhandled write CACHE_CONTROL register");
-            return;
+        if map::CACHE_CONTROL.contains(addr).is_some() {
+            self.cache_control = val;
+            return Ok(());
         }
 
         if let Some(offset) = map::DMA.contains(addr) {
-            println!("DMA Store: {:08x} <- {:08x}",addr,val);
-            return self.set_dma_reg(offset,val);
+            if self.set_dma_reg(offset,val) {
+                return Ok(());
+            }
+        }
+
+        if let Some(offset) = map::MDEC.contains(addr) {
+            match offset {
+                0 => self.mdec.command(val),
+                4 => self.mdec.set_control(val),
+                _ => return self.unhandled(BusError::store(addr, 32)),
+            }
+            return Ok(());
+        }
+
+        self.unhandled(BusError::store(addr, 32))
+    }
+
+    /// Register readers return None and writers false for offsets without
+    /// a register, the access is then handled like an unmapped one
+    fn irq_reg(&self, offset: u32) -> Option<u32> {
+        match offset {
+            0 => Some(self.irq.status() as u32),
+            4 => Some(self.irq.mask() as u32),
+            _ => None,
+        }
+    }
+
+    fn set_irq_reg(&mut self, offset: u32, val: u16) -> bool {
+        match offset {
+            0 => self.irq.ack(val),
+            4 => self.irq.set_mask(val),
+            _ => return false,
+        }
+
+        true
+    }
+
+    fn pad_reg(&mut self, offset: u32) -> Option<u32> {
+        match offset {
+            0   => Some(self.sio0.read_data() as u32),
+            4   => Some(self.sio0.status()),
+            8   => Some(self.sio0.mode() as u32),
+            0xa => Some(self.sio0.control() as u32),
+            0xe => Some(self.sio0.baud() as u32),
+            _   => None,
+        }
+    }
+
+    fn set_pad_reg(&mut self, offset: u32, val: u16) -> bool {
+        match offset {
+            0   => self.sio0.write_data(val as u8),
+            8   => self.sio0.set_mode(val),
+            0xa => self.sio0.set_control(val),
+            0xe => self.sio0.set_baud(val),
+            _   => return false,
         }
 
-        panic!("unhandled store32 at address {:08x}", addr);
+        true
+    }
+
+    fn sio1_reg(&mut self, offset: u32) -> Option<u32> {
+        match offset {
+            0   => Some(self.sio1.read_data() as u32),
+            4   => Some(self.sio1.status()),
+            8   => Some(self.sio1.mode() as u32),
+            0xa => Some(self.sio1.control() as u32),
+            0xc => Some(self.sio1.misc() as u32),
+            0xe => Some(self.sio1.baud() as u32),
+            _   => None,
+        }
     }
 
-    fn dma_reg(&self, offset: u32) -> u32{
+    fn set_sio1_reg(&mut self, offset: u32, val: u16) -> bool {
         match offset {
-            0x70 => self.dma.control(),
-            0x74 => self.dma.interrupt(),
-            _    => panic!("unhandled DMA access"),
+            0   => self.sio1.write_data(val as u8),
+            8   => self.sio1.set_mode(val),
+            0xa => self.sio1.set_control(val),
+            0xc => self.sio1.set_misc(val),
+            0xe => self.sio1.set_baud(val),
+            _   => return false,
+        }
+
+        true
+    }
+
+    fn dma_reg(&self, offset: u32) -> Option<u32> {
+        let major = (offset & 0x70) >> 4;
+        let minor = offset & 0xf;
+
+        match major {
+            0..=6 => {
+                let channel = self.dma.channel(Port::from_index(major));
+
+                match minor {
+                    0 => Some(channel.base()),
+                    4 => Some(channel.block_control()),
+                    8 => Some(channel.control()),
+                    _ => None,
+                }
+            }
+            7 => match minor {
+                0 => Some(self.dma.control()),
+                4 => Some(self.dma.interrupt()),
+                _ => None,
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    fn set_dma_reg(&mut self, offset: u32, val: u32) -> bool {
+        let major = (offset & 0x70) >> 4;
+        let minor = offset & 0xf;
+
+        match major {
+            0..=6 => {
+                let port = Port::from_index(major);
+                let channel = self.dma.channel_mut(port);
+
+                match minor {
+                    0 => channel.set_base(val),
+                    4 => channel.set_block_control(val),
+                    8 => channel.set_control(val),
+                    _ => return false,
+                }
+
+                if self.dma.channel(port).active() {
+                    self.do_dma(port);
+                }
+            }
+            7 => match minor {
+                0 => self.dma.set_control(val),
+                4 => self.dma.set_interrupt(val),
+                _ => return false,
+            },
+            _ => unreachable!(),
+        }
+
+        true
+    }
+
+    /// Mark the transfer as finished, the DMA interrupt fires on the rising
+    /// edge of the master IRQ flag
+    fn dma_done(&mut self, port: Port){
+        let before = self.dma.irq_status();
+
+        self.dma.done(port);
+
+        if !before && self.dma.irq_status() {
+            self.irq.assert(Interrupt::Dma);
+        }
+    }
+
+    /// True when the device on `port` is ready for a Request mode block
+    fn dma_request(&self, port: Port) -> bool {
+        match port {
+            Port::MdecIn  => self.mdec.dma_in_request(),
+            Port::MdecOut => self.mdec.dma_out_request(),
+            _             => true,
+        }
+    }
+
+    /// Run the DMA transfer on `port`. Manual mode runs at once, Request
+    /// mode moves one block per device request and leaves the channel
+    /// active while the device isn't ready, `tick` resumes it
+    fn do_dma(&mut self, port: Port){
+        let channel = *self.dma.channel(port);
+
+        match channel.sync() {
+            Sync::Manual => {
+                let size = channel.transfer_size().unwrap_or(0);
+
+                self.dma_words(port, channel.base(), size, channel.step());
+            }
+            Sync::Request => {
+                while self.dma.channel(port).remaining_blocks() > 0 {
+                    if !self.dma_request(port) {
+                        return;
+                    }
+
+                    let channel = *self.dma.channel(port);
+                    let next = self.dma_words(port, channel.base(), channel.block_size(), channel.step());
+
+                    match next {
+                        Some(next) => self.dma.channel_mut(port).block_done(next),
+                        None => break,
+                    }
+                }
+            }
+            Sync::LinkedList => println!("Unhandled linked list DMA on port {:?}", port),
+        }
+
+        self.dma_done(port);
+    }
+
+    /// Move `size` words between RAM at `base` and the device on `port`.
+    /// Returns the address following the last word, None when the port
+    /// isn't supported
+    fn dma_words(&mut self, port: Port, base: u32, size: u32, step: Step) -> Option<u32> {
+        let direction = self.dma.channel(port).direction();
+
+        let step: u32 = match step {
+            Step::Increment => 4,
+            Step::Decrement => (-4i32) as u32,
+        };
+
+        let mut addr = base;
+        let mask = (self.ram.size() - 1) & !3;
+
+        for remaining in (0..size).rev() {
+            // Addresses wrap around the RAM
+            let cur_addr = addr & mask;
+
+            match direction {
+                Direction::FromRam => {
+                    let v = self.ram.load32(cur_addr);
+
+                    match port {
+                        Port::MdecIn => self.mdec.command(v),
+                        _ => {
+                            println!("Unhandled DMA from RAM to port {:?}", port);
+                            return None;
+                        }
+                    }
+                }
+                Direction::ToRam => {
+                    let v = match port {
+                        Port::MdecOut => self.mdec.read_data(),
+                        // Ordering table clear: each entry points to the previous one
+                        Port::Otc => match remaining {
+                            0 => 0xffffff,
+                            _ => addr.wrapping_sub(4) & 0xffffff,
+                        },
+                        _ => {
+                            println!("Unhandled DMA from port {:?} to RAM", port);
+                            return None;
+                        }
+                    };
+
+                    self.ram.store32(cur_addr, v);
+                }
+            }
+
+            addr = addr.wrapping_add(step);
         }
+
+        Some(addr)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psx::sio1::Sio1;
+
+    const DMA0: u32 = 0x1f801080;
+    const MDEC0: u32 = 0x1f801820;
+    const MDEC1: u32 = 0x1f801824;
+
+    fn interconnect() -> Interconnect {
+        Interconnect::new(None, Ram::new(), Dma::new(), Mdec::new(), Gpu::new(), Sio0::new(), Sio1::new(None))
+    }
+
+    #[test]
+    fn request_mode_dma_waits_for_the_mdec() {
+        let mut inter = interconnect();
+
+        // DMA0 request enable, then 2 blocks of 16 words from RAM
+        inter.store32(MDEC1, 1 << 30).unwrap();
+        inter.store32(DMA0, 0x1000).unwrap();
+        inter.store32(DMA0 + 4, (2 << 16) | 16).unwrap();
+        inter.store32(DMA0 + 8, 0x0100_0201).unwrap();
+        inter.tick(100);
+
+        // Idle decoder: nothing requested, nothing sent
+        assert_ne!(inter.load32(DMA0 + 8).unwrap() & (1 << 24), 0);
+        assert_eq!(inter.load32(DMA0).unwrap(), 0x1000);
+
+        // Set scale table, 32 parameter words
+        inter.store32(MDEC0, 0x6000_0000).unwrap();
+        inter.tick(100);
+
+        assert_eq!(inter.load32(DMA0 + 8).unwrap() & (1 << 24), 0);
+        assert_eq!(inter.load32(DMA0).unwrap(), 0x1080);
+        assert_eq!(inter.load32(DMA0 + 4).unwrap(), 16);
+        assert_eq!(inter.load32(MDEC1).unwrap() & (1 << 29), 0, "MDEC still receiving");
+    }
+
+    #[test]
+    fn dma_sync_mode_3_runs_like_manual() {
+        let mut inter = interconnect();
+
+        // Ordering table clear of 4 entries
+        inter.store32(DMA0 + 0x60, 0x2000).unwrap();
+        inter.store32(DMA0 + 0x64, 4).unwrap();
+        inter.store32(DMA0 + 0x68, 0x1100_0602).unwrap();
+
+        assert_eq!(inter.load32(0x2000).unwrap(), 0x001ffc);
+        assert_eq!(inter.load32(0x1ff4).unwrap(), 0xffffff);
+    }
+
+    #[test]
+    fn missing_registers_are_unhandled_accesses() {
+        let mut inter = interconnect();
+
+        // Reserved DMA and IRQ registers, writes to the read only SIO0 status
+        assert_eq!(inter.load32(DMA0 + 0xc).unwrap(), OPEN_BUS);
+        assert_eq!(inter.load16(0x1f801074 + 2).unwrap(), OPEN_BUS as u16);
+        inter.store16(0x1f801044, 0).unwrap();
+        inter.store32(DMA0 + 0x7c, 0).unwrap();
+
+        inter.set_strict(true);
+
+        assert!(inter.load32(DMA0 + 0xc).is_err());
+        assert!(inter.store16(0x1f801044, 0).is_err());
+        assert!(inter.load16(MDEC0 + 2).is_err());
     }
 
-    fn set_dma_reg(&mut self, offset: u32, val: u32){
-        match offset{
-            0x70 => self.dma.set_control(val),
-            0x74 => self.dma.set_interrupt(val),
-            _    => panic!("Unhandled DMA write access 0x{:x} <- {:08x}",offset,val)
+    #[test]
+    fn bios_is_readable_at_every_width() {
+        let mut data = vec![0; 512 * 1024];
+        data[0x100..0x104].copy_from_slice(&[0x11, 0x22, 0x33, 0x44]);
+
+        let mut inter = interconnect();
+        inter.bios = Some(Bios::new(data).unwrap());
+
+        for base in [0x1fc00100, 0x9fc00100, 0xbfc00100] {
+            assert_eq!(inter.load8(base + 1).unwrap(), 0x22);
+            assert_eq!(inter.load16(base + 2).unwrap(), 0x4433);
+            assert_eq!(inter.load32(base).unwrap(), 0x44332211);
         }
     }
 
+    #[test]
+    fn ram_size_splits_the_ram_area() {
+        const MB: u32 = 1024 * 1024;
+        const RAM_SIZE: u32 = 0x1f801060;
+
+        // RAM_SIZE bits 9-11: size of the RAM window and of the High-Z window
+        // following it, the rest of the 8MiB is locked
+        let layouts = [
+            (0, MB, 0),
+            (1, 4 * MB, 0),
+            (2, MB, MB),
+            (3, 4 * MB, 4 * MB),
+            (4, 2 * MB, 0),
+            (5, 8 * MB, 0),
+            (6, 2 * MB, 2 * MB),
+            (7, 8 * MB, 0),
+        ];
+
+        for ram in [Ram::new, Ram::dev_kit] {
+            for (bits, memory, high_z) in layouts {
+                let mut inter = Interconnect::new(None, ram(), Dma::new(), Mdec::new(), Gpu::new(), Sio0::new(), Sio1::new(None));
+                inter.store32(RAM_SIZE, RAM_SIZE_DEFAULT & !(7 << 9) | bits << 9).unwrap();
+
+                let size = inter.ram().size();
+                let layout = format!("RAM_SIZE {} with {}MiB", bits, size / MB);
+
+                // The installed RAM is mirrored up to the end of the window
+                let last = memory - 4;
+                inter.store32(last, 0x1234_5678).unwrap();
+                assert_eq!(inter.ram().load32(last & (size - 1)), 0x1234_5678, "{}", layout);
+                assert_eq!(inter.load32(0x8000_0000 | last).unwrap(), 0x1234_5678, "{}", layout);
+
+                if high_z > 0 {
+                    assert_eq!(inter.load32(memory).unwrap(), OPEN_BUS, "{}", layout);
+                    assert_eq!(inter.load16(memory + high_z - 2).unwrap(), OPEN_BUS as u16, "{}", layout);
+                    inter.store32(memory, 0).unwrap();
+                }
+
+                let locked = memory + high_z;
+                if locked < 8 * MB {
+                    assert!(inter.load32(locked).is_err(), "{}", layout);
+                    assert!(inter.load8(8 * MB - 1).is_err(), "{}", layout);
+                    assert!(inter.store16(locked, 0).is_err(), "{}", layout);
+                }
+            }
+        }
+    }
 }
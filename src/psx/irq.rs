@@ -0,0 +1,53 @@
+/// Interrupt controller state - I_STAT and I_MASK registers
+pub struct InterruptState {
+    /// Interrupt status - offset 0x0
+    status: u16,
+    /// Interrupt mask - offset 0x4
+    mask: u16,
+}
+
+impl InterruptState {
+    pub fn new() -> Self {
+        InterruptState { status: 0, mask: 0 }
+    }
+
+    /// True when an unmasked interrupt is pending, drives CAUSE bit 10
+    pub fn active(&self) -> bool {
+        self.status & self.mask != 0
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// Writing 0 to a status bit acknowledges the interrupt
+    pub fn ack(&mut self, ack: u16) {
+        self.status &= ack;
+    }
+
+    pub fn mask(&self) -> u16 {
+        self.mask
+    }
+
+    pub fn set_mask(&mut self, mask: u16) {
+        // Only the 11 first interrupts exist
+        self.mask = mask & 0x7ff;
+    }
+
+    pub fn assert(&mut self, which: Interrupt) {
+        self.status |= 1 << (which as usize);
+    }
+}
+
+/// Interrupt sources, in I_STAT bit order
+#[derive(Clone, Copy, Debug)]
+pub enum Interrupt {
+    /// Start of the vertical blanking
+    VBlank     = 0,
+    /// DMA transfer done
+    Dma        = 3,
+    /// Controller and memory card byte received
+    PadMemCard = 7,
+    /// Link cable serial port
+    Sio        = 8,
+}
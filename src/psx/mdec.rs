@@ -0,0 +1,577 @@
+use std::collections::VecDeque;
+
+/// Macroblock Decoder, decompresses the run-length encoded frames used by
+/// FMVs and sends them back as 4/8/15/24 bits pixels
+pub struct Mdec {
+    /// Command currently receiving parameters
+    command: Command,
+    /// Number of parameter words still expected by `command`
+    remaining: u32,
+    /// Parameter words received for the current command
+    params: Vec<u32>,
+    /// Decoded pixel words waiting to be read
+    output: VecDeque<u32>,
+
+    /// Output depth - Status bits [25:26]
+    depth: Depth,
+    /// Signed output - Status bit 24
+    signed: bool,
+    /// Bit 15 of 15bits pixels - Status bit 23
+    bit15: bool,
+    /// Current block - Status bits [16:18]
+    current_block: u8,
+    /// DMA0 data-in request enable - Control bit 30
+    dma_in_en: bool,
+    /// DMA1 data-out request enable - Control bit 29
+    dma_out_en: bool,
+
+    /// Luminance quantization table, in zigzag order
+    iq_y: [u8; 64],
+    /// Color quantization table, in zigzag order
+    iq_uv: [u8; 64],
+    /// IDCT scale table
+    scale: [i16; 64],
+}
+
+impl Mdec {
+    pub fn new() -> Self {
+        Mdec {
+            command: Command::None,
+            remaining: 0,
+            params: Vec::new(),
+            output: VecDeque::new(),
+            depth: Depth::Mono4,
+            signed: false,
+            bit15: false,
+            current_block: 4,
+            dma_in_en: false,
+            dma_out_en: false,
+            iq_y: [0; 64],
+            iq_uv: [0; 64],
+            scale: [0; 64],
+        }
+    }
+
+    /// Status register - MDEC1 read
+    pub fn status(&self) -> u32 {
+        let mut r: u32 = 0;
+
+        let receiving = self.remaining != 0;
+
+        r |= self.remaining.wrapping_sub(1) & 0xffff;
+        r |= (self.current_block as u32) << 16;
+        r |= (self.bit15 as u32) << 23;
+        r |= (self.signed as u32) << 24;
+        r |= (self.depth as u32) << 25;
+        r |= ((self.dma_out_en && !self.output.is_empty()) as u32) << 27;
+        r |= ((self.dma_in_en && receiving) as u32) << 28;
+        r |= (receiving as u32) << 29;
+        r |= (self.output.is_empty() as u32) << 31;
+
+        r
+    }
+
+    /// Control register - MDEC1 write
+    pub fn set_control(&mut self, val: u32) {
+        if val & (1 << 31) != 0 {
+            self.reset();
+        }
+
+        self.dma_in_en  = val & (1 << 30) != 0;
+        self.dma_out_en = val & (1 << 29) != 0;
+    }
+
+    /// Command and parameters - MDEC0 write
+    pub fn command(&mut self, val: u32) {
+        if self.remaining != 0 {
+            self.params.push(val);
+            self.remaining -= 1;
+
+            if self.remaining == 0 {
+                self.execute();
+            }
+            return;
+        }
+
+        let (command, remaining) = match val >> 29 {
+            1 => {
+                self.depth = Depth::from_field((val >> 27) & 3);
+                self.signed = val & (1 << 26) != 0;
+                self.bit15 = val & (1 << 25) != 0;
+
+                (Command::DecodeMacroblock, val & 0xffff)
+            }
+            2 => {
+                // Bit 0 selects luminance only (16 words) or luminance and color (32 words)
+                let color = val & 1 != 0;
+
+                (Command::SetQuantTable { color }, if color { 32 } else { 16 })
+            }
+            3 => (Command::SetScaleTable, 32),
+            // The other commands still take the parameter count, the
+            // words are dropped
+            _ => (Command::None, val & 0xffff),
+        };
+
+        self.command = command;
+        self.remaining = remaining;
+        self.params.clear();
+
+        if remaining == 0 {
+            self.execute();
+        }
+    }
+
+    /// Decoded data - MDEC0 read, 0 when the output FIFO is empty
+    pub fn read_data(&mut self) -> u32 {
+        self.output.pop_front().unwrap_or(0)
+    }
+
+    /// True while the decoder wants more data from DMA0
+    pub fn dma_in_request(&self) -> bool {
+        self.dma_in_en && self.remaining != 0
+    }
+
+    /// True while decoded data can be sent through DMA1
+    pub fn dma_out_request(&self) -> bool {
+        self.dma_out_en && !self.output.is_empty()
+    }
+
+    fn reset(&mut self) {
+        self.command = Command::None;
+        self.remaining = 0;
+        self.params.clear();
+        self.output.clear();
+        self.depth = Depth::Mono4;
+        self.signed = false;
+        self.bit15 = false;
+        self.current_block = 4;
+    }
+
+    fn execute(&mut self) {
+        let params = std::mem::take(&mut self.params);
+
+        match self.command {
+            Command::DecodeMacroblock => self.decode(&params),
+            Command::SetQuantTable { color } => {
+                let bytes = words_to_bytes(&params);
+
+                self.iq_y.copy_from_slice(&bytes[0..64]);
+                if color {
+                    self.iq_uv.copy_from_slice(&bytes[64..128]);
+                }
+            }
+            Command::SetScaleTable => {
+                let halfwords = words_to_halfwords(&params);
+
+                for (s, &h) in self.scale.iter_mut().zip(halfwords.iter()) {
+                    *s = h as i16;
+                }
+            }
+            Command::None => (),
+        }
+
+        self.command = Command::None;
+    }
+
+    /// Decode every macroblock contained in the parameter words
+    fn decode(&mut self, params: &[u32]) {
+        let halfwords = words_to_halfwords(params);
+        let mut src = RleReader::new(&halfwords);
+
+        loop {
+            let decoded = match self.depth {
+                Depth::Mono4 | Depth::Mono8 => self.decode_mono(&mut src),
+                Depth::Rgb15 | Depth::Rgb24 => self.decode_color(&mut src),
+            };
+
+            if !decoded {
+                break;
+            }
+        }
+    }
+
+    /// Decode a single 8x8 luminance block into 4 or 8 bits pixels
+    fn decode_mono(&mut self, src: &mut RleReader) -> bool {
+        let mut blk = [0i16; 64];
+
+        self.current_block = 4;
+
+        if !src.decode_block(&mut blk, &self.iq_y, &self.scale) {
+            return false;
+        }
+
+        let pixels: Vec<u8> = blk
+            .iter()
+            .map(|&y| self.convert(y.clamp(-128, 127)))
+            .collect();
+
+        let bytes: Vec<u8> = match self.depth {
+            Depth::Mono4 => pixels
+                .chunks(2)
+                .map(|p| (p[0] >> 4) | (p[1] & 0xf0))
+                .collect(),
+            _ => pixels,
+        };
+
+        self.push_bytes(&bytes);
+
+        true
+    }
+
+    /// Decode a 16x16 macroblock made of Cr, Cb and four Y blocks
+    fn decode_color(&mut self, src: &mut RleReader) -> bool {
+        let mut cr = [0i16; 64];
+        let mut cb = [0i16; 64];
+        let mut y = [0i16; 64];
+
+        let mut rgb = [[0u8; 3]; 16 * 16];
+
+        self.current_block = 4;
+        if !src.decode_block(&mut cr, &self.iq_uv, &self.scale) {
+            return false;
+        }
+
+        self.current_block = 5;
+        if !src.decode_block(&mut cb, &self.iq_uv, &self.scale) {
+            return false;
+        }
+
+        for (block, &(xx, yy)) in [(0, 0), (8, 0), (0, 8), (8, 8)].iter().enumerate() {
+            self.current_block = block as u8;
+            if !src.decode_block(&mut y, &self.iq_y, &self.scale) {
+                return false;
+            }
+
+            self.yuv_to_rgb(&mut rgb, &y, &cr, &cb, xx, yy);
+        }
+
+        self.current_block = 4;
+
+        let bytes: Vec<u8> = match self.depth {
+            Depth::Rgb24 => rgb.iter().flatten().copied().collect(),
+            _ => rgb
+                .iter()
+                .flat_map(|&[r, g, b]| {
+                    let r = (r >> 3) as u16;
+                    let g = (g >> 3) as u16;
+                    let b = (b >> 3) as u16;
+
+                    let p = r | (g << 5) | (b << 10) | ((self.bit15 as u16) << 15);
+
+                    p.to_le_bytes()
+                })
+                .collect(),
+        };
+
+        self.push_bytes(&bytes);
+
+        true
+    }
+
+    /// Combine one Y block with the subsampled Cr/Cb blocks into `rgb`
+    fn yuv_to_rgb(&self, rgb: &mut [[u8; 3]; 256], y: &[i16; 64], cr: &[i16; 64], cb: &[i16; 64], xx: usize, yy: usize) {
+        for py in 0..8 {
+            for px in 0..8 {
+                let c = ((px + xx) / 2) + ((py + yy) / 2) * 8;
+
+                let r = cr[c] as i32;
+                let b = cb[c] as i32;
+
+                // Fixed point 8.8 version of the conversion coefficients
+                let g = (-88 * b - 183 * r) >> 8;
+                let r = (359 * r) >> 8;
+                let b = (454 * b) >> 8;
+
+                let l = y[px + py * 8] as i32;
+
+                let r = (l + r).clamp(-128, 127) as i16;
+                let g = (l + g).clamp(-128, 127) as i16;
+                let b = (l + b).clamp(-128, 127) as i16;
+
+                rgb[(px + xx) + (py + yy) * 16] = [self.convert(r), self.convert(g), self.convert(b)];
+            }
+        }
+    }
+
+    /// Convert a clamped signed sample to the output signedness
+    fn convert(&self, v: i16) -> u8 {
+        match self.signed {
+            true => v as u8,
+            false => (v as u8) ^ 0x80,
+        }
+    }
+
+    /// Pack little endian bytes into the output FIFO
+    fn push_bytes(&mut self, bytes: &[u8]) {
+        for w in bytes.chunks(4) {
+            let mut word = [0u8; 4];
+            word[..w.len()].copy_from_slice(w);
+
+            self.output.push_back(u32::from_le_bytes(word));
+        }
+    }
+}
+
+/// Reads run-length encoded coefficients from the halfword stream
+struct RleReader<'a> {
+    data: &'a [u16],
+    pos: usize,
+}
+
+impl<'a> RleReader<'a> {
+    /// Padding halfword inserted between blocks
+    const PADDING: u16 = 0xfe00;
+
+    fn new(data: &'a [u16]) -> Self {
+        RleReader { data, pos: 0 }
+    }
+
+    fn next(&mut self) -> Option<u16> {
+        let v = self.data.get(self.pos).copied();
+        self.pos += 1;
+        v
+    }
+
+    /// Decode, dequantize and IDCT a single block. Returns false when the
+    /// stream runs out of data
+    fn decode_block(&mut self, blk: &mut [i16; 64], qt: &[u8; 64], scale: &[i16; 64]) -> bool {
+        let mut coeffs = [0i32; 64];
+
+        let mut n = loop {
+            match self.next() {
+                Some(Self::PADDING) => continue,
+                Some(n) => break n,
+                None => return false,
+            }
+        };
+
+        let q_scale = ((n >> 10) & 0x3f) as i32;
+        let mut k = 0;
+        let mut val = signed10bit(n) * qt[0] as i32;
+
+        loop {
+            if q_scale == 0 {
+                val = signed10bit(n) * 2;
+            }
+
+            let val_sat = val.clamp(-0x400, 0x3ff);
+
+            if q_scale > 0 {
+                coeffs[ZIGZAG[k]] = val_sat;
+            } else {
+                coeffs[k] = val_sat;
+            }
+
+            n = match self.next() {
+                Some(n) => n,
+                None => return false,
+            };
+
+            k += ((n >> 10) & 0x3f) as usize + 1;
+
+            if k > 63 {
+                break;
+            }
+
+            val = (signed10bit(n) * qt[k] as i32 * q_scale + 4) / 8;
+        }
+
+        idct(&coeffs, blk, scale);
+
+        true
+    }
+}
+
+/// Two pass inverse discrete cosine transform using the uploaded scale table
+fn idct(coeffs: &[i32; 64], blk: &mut [i16; 64], scale: &[i16; 64]) {
+    let mut tmp = [0i32; 64];
+
+    // Columns
+    for x in 0..8 {
+        for y in 0..8 {
+            let sum: i64 = (0..8)
+                .map(|z| coeffs[x + z * 8] as i64 * scale[y + z * 8] as i64)
+                .sum();
+
+            tmp[x + y * 8] = ((sum + (1 << 15)) >> 16) as i32;
+        }
+    }
+
+    // Rows
+    for y in 0..8 {
+        for x in 0..8 {
+            let sum: i64 = (0..8)
+                .map(|z| tmp[z + y * 8] as i64 * scale[x + z * 8] as i64)
+                .sum();
+
+            blk[x + y * 8] = ((sum + (1 << 15)) >> 16).clamp(-0x8000, 0x7fff) as i16;
+        }
+    }
+}
+
+/// Sign extend the 10 low bits of an RLE halfword
+fn signed10bit(n: u16) -> i32 {
+    (((n & 0x3ff) << 6) as i16 >> 6) as i32
+}
+
+fn words_to_halfwords(words: &[u32]) -> Vec<u16> {
+    words
+        .iter()
+        .flat_map(|&w| [w as u16, (w >> 16) as u16])
+        .collect()
+}
+
+fn words_to_bytes(words: &[u32]) -> Vec<u8> {
+    words.iter().flat_map(|w| w.to_le_bytes()).collect()
+}
+
+/// Position in the 8x8 block of each coefficient in stream order
+pub const ZIGZAG: [usize; 64] = [
+     0,  1,  8, 16,  9,  2,  3, 10,
+    17, 24, 32, 25, 18, 11,  4,  5,
+    12, 19, 26, 33, 40, 48, 41, 34,
+    27, 20, 13,  6,  7, 14, 21, 28,
+    35, 42, 49, 56, 57, 50, 43, 36,
+    29, 22, 15, 23, 30, 37, 44, 51,
+    58, 59, 52, 45, 38, 31, 39, 46,
+    53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+#[derive(Clone, Copy)]
+enum Command {
+    None,
+    DecodeMacroblock,
+    SetQuantTable { color: bool },
+    SetScaleTable,
+}
+
+/// Output pixel depth
+#[derive(Clone, Copy)]
+enum Depth {
+    Mono4  = 0,
+    Mono8  = 1,
+    Rgb24 = 2,
+    Rgb15 = 3,
+}
+
+impl Depth {
+    fn from_field(field: u32) -> Depth {
+        match field {
+            0 => Depth::Mono4,
+            1 => Depth::Mono8,
+            2 => Depth::Rgb24,
+            _ => Depth::Rgb15,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RLE block with only a DC coefficient, quantization scale 1
+    fn dc_block(dc: u16) -> u32 {
+        (RleReader::PADDING as u32) << 16 | 1 << 10 | (dc & 0x3ff) as u32
+    }
+
+    /// Decoder whose IDCT turns a DC coefficient of 128 into 8 everywhere
+    fn setup() -> Mdec {
+        let mut mdec = Mdec::new();
+
+        mdec.set_control(1 << 29);
+
+        // DC quantization factor 2
+        mdec.command(2 << 29);
+        for i in 0..16 {
+            mdec.command(if i == 0 { 2 } else { 0 });
+        }
+
+        // Only the first row of the scale table is used by a DC block
+        mdec.command(3 << 29);
+        for i in 0..32 {
+            mdec.command(if i < 4 { 0x4000_4000 } else { 0 });
+        }
+
+        mdec
+    }
+
+    fn decode(mdec: &mut Mdec, depth: u32, signed: bool, bit15: bool, blocks: &[u32]) -> Vec<u32> {
+        mdec.command(1 << 29 | depth << 27 | (signed as u32) << 26 | (bit15 as u32) << 25 | blocks.len() as u32);
+
+        for &b in blocks {
+            mdec.command(b);
+        }
+
+        std::iter::from_fn(|| mdec.dma_out_request().then(|| mdec.read_data())).collect()
+    }
+
+    #[test]
+    fn status_reports_parameters_and_fifos() {
+        let mut mdec = Mdec::new();
+
+        assert_eq!(mdec.status(), 0x8004_ffff);
+
+        mdec.set_control(3 << 29);
+        mdec.command(1 << 29 | 3 << 27 | 1 << 26 | 1 << 25 | 2);
+
+        // Two words expected, output depth, signedness and bit 15
+        assert_eq!(mdec.status(), 0x8004_0001 | 3 << 25 | 1 << 24 | 1 << 23 | 1 << 28 | 1 << 29);
+        assert!(mdec.dma_in_request());
+
+        mdec.command(0);
+        assert_eq!(mdec.status() & 0xffff, 0);
+
+        // Reset
+        mdec.set_control(1 << 31);
+        assert_eq!(mdec.status(), 0x8004_ffff);
+        assert!(!mdec.dma_in_request());
+    }
+
+    #[test]
+    fn decodes_monochrome_blocks() {
+        let mut mdec = setup();
+
+        let words = decode(&mut mdec, 1, false, false, &[dc_block(0x40)]);
+        assert_eq!(words, [0x8888_8888; 16]);
+
+        let words = decode(&mut mdec, 1, true, false, &[dc_block(0x40)]);
+        assert_eq!(words, [0x0808_0808; 16]);
+
+        // 4 bits, two pixels per byte
+        let words = decode(&mut mdec, 0, false, false, &[dc_block(0x40), dc_block(0x3c0)]);
+        assert_eq!(&words[..8], [0x8888_8888; 8]);
+        assert_eq!(&words[8..], [0x7777_7777; 8]);
+
+        assert_eq!(mdec.status() >> 31, 1);
+        assert_eq!(mdec.read_data(), 0);
+    }
+
+    #[test]
+    fn decodes_color_macroblocks() {
+        let mut mdec = setup();
+        // Cr, Cb and four Y blocks
+        let mut blocks = vec![dc_block(0), dc_block(0)];
+        blocks.extend([dc_block(0x40); 4]);
+
+        let words = decode(&mut mdec, 2, false, false, &blocks);
+        assert_eq!(words, [0x8888_8888; 16 * 16 * 3 / 4]);
+
+        let words = decode(&mut mdec, 3, false, true, &blocks);
+        assert_eq!(words, [0xc631_c631; 16 * 16 / 2]);
+    }
+
+    #[test]
+    fn skips_unknown_commands_with_their_parameters() {
+        let mut mdec = setup();
+
+        mdec.command(2);
+        assert_eq!(mdec.status() & 0xffff, 1);
+
+        mdec.command(0x1234);
+        mdec.command(0x5678);
+
+        let words = decode(&mut mdec, 1, true, false, &[dc_block(0x40)]);
+        assert_eq!(words, [0x0808_0808; 16]);
+    }
+}
@@ -0,0 +1,272 @@
+use super::cpu::map;
+
+/// Devices with a delay/size register in MEMLCONTROL
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Region {
+    Expansion1,
+    Expansion3,
+    Bios,
+    Spu,
+    CdRom,
+    Expansion2,
+}
+
+/// Memory control registers (MEMLCONTROL): base addresses of the expansion
+/// regions and bus timings of the slow devices
+pub struct MemControl {
+    /// Expansion 1 base address - offset 0x00
+    expansion_1_base: u32,
+    /// Expansion 2 base address - offset 0x04
+    expansion_2_base: u32,
+    /// Delay/size registers - offsets 0x08 to 0x1c, in `Region` order
+    delay_size: [u32; 6],
+    /// COM_DELAY register - offset 0x20
+    common_delay: u32,
+}
+
+impl MemControl {
+    pub fn new() -> MemControl {
+        // Values set by the BIOS at boot
+        MemControl {
+            expansion_1_base: 0x1f000000,
+            expansion_2_base: 0x1f802000,
+            delay_size: [
+                0x0013243f, 0x00003022, 0x0013243f, 0x200931e1, 0x00020843, 0x00070777,
+            ],
+            common_delay: 0x00031125,
+        }
+    }
+
+    pub fn load(&self, offset: u32) -> u32 {
+        match offset {
+            0x00 => self.expansion_1_base,
+            0x04 => self.expansion_2_base,
+            0x08..=0x1c => self.delay_size[(offset as usize - 8) / 4],
+            0x20 => self.common_delay,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn store(&mut self, offset: u32, val: u32) {
+        // The base addresses can only move inside 0x1f000000-0x1fffffff
+        let base = 0x1f000000 | (val & 0xffffff);
+
+        match offset {
+            0x00 => self.expansion_1_base = base,
+            0x04 => self.expansion_2_base = base,
+            0x08..=0x1c => self.delay_size[(offset as usize - 8) / 4] = val,
+            0x20 => self.common_delay = val & 0xffff,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Offset in Expansion 1 if `addr` (masked) falls in its window
+    pub fn expansion_1(&self, addr: u32) -> Option<u32> {
+        self.window(Region::Expansion1, self.expansion_1_base, addr)
+    }
+
+    /// Offset in Expansion 2 if `addr` (masked) falls in its window
+    pub fn expansion_2(&self, addr: u32) -> Option<u32> {
+        self.window(Region::Expansion2, self.expansion_2_base, addr)
+    }
+
+    /// Delay/size bits 16-20: the region spans 2^n bytes from its base
+    fn window(&self, region: Region, base: u32, addr: u32) -> Option<u32> {
+        let size = 1u64 << ((self.delay_size(region) >> 16) & 0x1f);
+        let offset = addr.wrapping_sub(base);
+
+        match (offset as u64) < size {
+            true => Some(offset),
+            false => None,
+        }
+    }
+
+    /// Region with its own bus timings containing `addr` (masked)
+    pub fn region(&self, addr: u32) -> Option<Region> {
+        if self.expansion_1(addr).is_some() {
+            return Some(Region::Expansion1);
+        }
+
+        if self.expansion_2(addr).is_some() {
+            return Some(Region::Expansion2);
+        }
+
+        if map::EXPANSION_3.contains(addr).is_some() {
+            return Some(Region::Expansion3);
+        }
+
+        if map::BIOS.contains(addr).is_some() {
+            return Some(Region::Bios);
+        }
+
+        if map::SPU.contains(addr).is_some() {
+            return Some(Region::Spu);
+        }
+
+        if map::CDROM.contains(addr).is_some() {
+            return Some(Region::CdRom);
+        }
+
+        None
+    }
+
+    fn delay_size(&self, region: Region) -> u32 {
+        self.delay_size[region as usize]
+    }
+
+    /// Cycles taken by a `width` bits access to `region`. Accesses wider
+    /// than the device bus are split in several transfers, the first one
+    /// taking longer than the following ones
+    pub fn access_cycles(&self, region: Region, width: u32, store: bool) -> u32 {
+        let config = self.delay_size(region);
+
+        // Bits 0-3: write delay, bits 4-7: read delay
+        let delay = match store {
+            true => config & 0xf,
+            false => (config >> 4) & 0xf,
+        };
+
+        // Bit 12: 16bit bus instead of 8bit
+        let bus_width = match config & (1 << 12) != 0 {
+            true => 16,
+            false => 8,
+        };
+
+        let com = |n: u32| (self.common_delay >> (n * 4)) & 0xf;
+
+        let mut first = 0;
+        let mut seq = 0;
+        let mut min = 0;
+
+        // Bits 8-11 select which of the COM_DELAY periods apply
+        if config & (1 << 8) != 0 {
+            first += com(0).saturating_sub(1);
+            seq += com(0).saturating_sub(1);
+        }
+
+        if config & (1 << 10) != 0 {
+            first += com(2);
+            seq += com(2);
+        }
+
+        if config & (1 << 11) != 0 {
+            min = com(3);
+        }
+
+        if first < 6 {
+            first += 1;
+        }
+
+        first = (first + delay + 2).max(min + 6);
+        seq = (seq + delay + 2).max(min + 2);
+
+        let transfers = (width / bus_width).max(1);
+
+        first + (transfers - 1) * seq
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boot_timings() {
+        let mc = MemControl::new();
+
+        // 8 bit BIOS ROM, read delay 3 and COM_DELAY period 2: 7 cycles for
+        // the first byte then 6 for each of the following ones
+        assert_eq!(mc.access_cycles(Region::Bios, 8, false), 7);
+        assert_eq!(mc.access_cycles(Region::Bios, 16, false), 13);
+        assert_eq!(mc.access_cycles(Region::Bios, 32, false), 25);
+
+        // 16 bit SPU bus, read delay 14 and period 0
+        assert_eq!(mc.access_cycles(Region::Spu, 16, false), 21);
+        assert_eq!(mc.access_cycles(Region::Spu, 32, false), 41);
+        assert_eq!(mc.access_cycles(Region::Spu, 16, true), 8);
+
+        // 8 bit CD-ROM, the period 3 minimum doesn't kick in
+        assert_eq!(mc.access_cycles(Region::CdRom, 8, false), 7);
+    }
+
+    #[test]
+    fn com_delay_periods() {
+        let mut mc = MemControl::new();
+
+        // Periods 0 to 3: 3, 0, 2 and 9
+        mc.store(0x20, 0x9203);
+
+        // Read delay 1, no period: at least 6 cycles then 3 per transfer
+        mc.store(0x10, 0x0000_0010);
+        assert_eq!(mc.access_cycles(Region::Bios, 8, false), 6);
+        assert_eq!(mc.access_cycles(Region::Bios, 16, false), 6 + 3);
+
+        // Period 0 minus one and period 2 on each transfer
+        mc.store(0x10, 0x0000_0510);
+        assert_eq!(mc.access_cycles(Region::Bios, 8, false), 8);
+        assert_eq!(mc.access_cycles(Region::Bios, 16, false), 8 + 7);
+
+        // Period 3 raises the minimums
+        mc.store(0x10, 0x0000_0d10);
+        assert_eq!(mc.access_cycles(Region::Bios, 16, false), 15 + 11);
+        mc.store(0x10, 0x0000_0810);
+        assert_eq!(mc.access_cycles(Region::Bios, 16, false), 15 + 11);
+
+        // Separate write delay
+        mc.store(0x10, 0x0000_0015);
+        assert_eq!(mc.access_cycles(Region::Bios, 8, true), 8);
+        assert_eq!(mc.access_cycles(Region::Bios, 8, false), 6);
+    }
+
+    #[test]
+    fn wide_accesses_are_split_on_the_bus_width() {
+        let mut mc = MemControl::new();
+        mc.store(0x20, 0);
+
+        // Read delay 4: 7 cycles for the first transfer, 6 for the others
+        for (config, width, cycles) in [
+            // 8 bit bus
+            (0x0000_0040, 8, 7),
+            (0x0000_0040, 16, 13),
+            (0x0000_0040, 32, 25),
+            // 16 bit bus
+            (0x0000_1040, 8, 7),
+            (0x0000_1040, 16, 7),
+            (0x0000_1040, 32, 13),
+        ] {
+            mc.store(0x08, config);
+            assert_eq!(mc.access_cycles(Region::Expansion1, width, false), cycles, "{:08x} {}", config, width);
+        }
+    }
+
+    #[test]
+    fn expansion_windows_follow_their_base_and_size() {
+        let mut mc = MemControl::new();
+
+        // 512KiB at the default base
+        assert_eq!(mc.expansion_1(0x1f07_ffff), Some(0x7ffff));
+        assert_eq!(mc.expansion_1(0x1f08_0000), None);
+
+        // Only the low 24 bits of the base can change
+        mc.store(0x00, 0xff40_0000);
+        assert_eq!(mc.load(0x00), 0x1f40_0000);
+        assert_eq!(mc.expansion_1(0x1f00_0000), None);
+        assert_eq!(mc.expansion_1(0x1f40_0010), Some(0x10));
+        assert_eq!(mc.region(0x1f40_0010), Some(Region::Expansion1));
+
+        // Delay/size bits 16-20: 2^4 bytes
+        mc.store(0x08, 0x0004_0000);
+        assert_eq!(mc.expansion_1(0x1f40_000f), Some(0xf));
+        assert_eq!(mc.expansion_1(0x1f40_0010), None);
+
+        // 128 bytes at the default Expansion 2 base
+        assert_eq!(mc.expansion_2(0x1f80_207f), Some(0x7f));
+        assert_eq!(mc.expansion_2(0x1f80_2080), None);
+
+        mc.store(0x04, 0x1f90_0000);
+        mc.store(0x1c, 0x0008_0000);
+        assert_eq!(mc.expansion_2(0x1f90_00ff), Some(0xff));
+        assert_eq!(mc.region(0x1f90_00ff), Some(Region::Expansion2));
+        assert_eq!(mc.expansion_2(0x1f80_2000), None);
+    }
+}
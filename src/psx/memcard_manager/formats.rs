@@ -0,0 +1,255 @@
+use anyhow::{anyhow, Result};
+use std::path::Path;
+
+use super::{Card, SaveFile, BLOCK_COUNT, BLOCK_FIRST, FILENAME_LEN, NO_NEXT_BLOCK};
+use crate::psx::sio0::memory_card::{sector_checksum, CARD_SIZE, SECTOR_SIZE};
+
+/// Action Replay `.psx` header: file name then ASCII title
+const PSX_HEADER_SIZE: usize = 54;
+const PSX_TITLE_OFFSET: usize = 0x15;
+
+/// DexDrive `.gme` header, followed by the raw card
+const GME_HEADER_SIZE: usize = 0xf40;
+const GME_SIGNATURE: &[u8] = b"123-456-STD";
+
+/// Save file formats, picked from the file extension
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    /// PSXGameEdit / MemcardRex single save: directory frame + data
+    Mcs,
+    /// Action Replay / Xplorer single save
+    Psx,
+    /// InterAct DexDrive card image
+    Gme,
+    /// Raw card image (ePSXe `.mcd`, `.mcr`)
+    Mcd,
+}
+
+impl Format {
+    pub fn from_path(path: &Path) -> Result<Format> {
+        let ext = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+
+        match ext.as_deref() {
+            Some("mcs") => Ok(Format::Mcs),
+            Some("psx") => Ok(Format::Psx),
+            Some("gme") => Ok(Format::Gme),
+            Some("mcd") | Some("mcr") => Ok(Format::Mcd),
+            _ => Err(anyhow!("{}: unknown save format", path.display())),
+        }
+    }
+
+    /// Saves contained in a file of this format
+    pub fn import(self, data: &[u8]) -> Result<Vec<SaveFile>> {
+        match self {
+            Format::Mcs => {
+                if data.len() < SECTOR_SIZE {
+                    return Err(anyhow!("truncated .mcs file"));
+                }
+
+                let (frame, data) = data.split_at(SECTOR_SIZE);
+
+                if frame[SECTOR_SIZE - 1] != sector_checksum(frame) {
+                    return Err(anyhow!("bad directory frame checksum in .mcs file"));
+                }
+
+                if frame[0] != BLOCK_FIRST {
+                    return Err(anyhow!("invalid .mcs file, block state {:02x}", frame[0]));
+                }
+
+                Ok(vec![SaveFile {
+                    filename: c_string(&frame[0xa..0xa + FILENAME_LEN]),
+                    data: data.to_vec(),
+                }])
+            }
+            Format::Psx => {
+                if data.len() < PSX_HEADER_SIZE {
+                    return Err(anyhow!("truncated .psx file"));
+                }
+
+                Ok(vec![SaveFile {
+                    filename: c_string(&data[..PSX_TITLE_OFFSET]),
+                    data: data[PSX_HEADER_SIZE..].to_vec(),
+                }])
+            }
+            Format::Gme | Format::Mcd => {
+                let card = Card::from_image(data)?;
+
+                if let Some(block) = card.bad_checksums().first() {
+                    return Err(anyhow!("bad checksum in directory frame {}", block + 1));
+                }
+
+                card.saves().iter().map(|s| card.save_file(s.slot)).collect()
+            }
+        }
+    }
+
+    /// Single save file holding `save`
+    pub fn export(self, save: &SaveFile) -> Result<Vec<u8>> {
+        let data = match self {
+            Format::Mcs => {
+                let mut frame = [0u8; SECTOR_SIZE];
+
+                frame[0] = BLOCK_FIRST;
+                frame[4..8].copy_from_slice(&(save.data.len() as u32).to_le_bytes());
+                frame[8..10].copy_from_slice(&NO_NEXT_BLOCK.to_le_bytes());
+                frame[0xa..0xa + save.filename.len()].copy_from_slice(save.filename.as_bytes());
+                frame[SECTOR_SIZE - 1] = sector_checksum(&frame);
+
+                [&frame[..], &save.data].concat()
+            }
+            Format::Psx => {
+                let mut header = [0u8; PSX_HEADER_SIZE];
+
+                header[..save.filename.len()].copy_from_slice(save.filename.as_bytes());
+
+                let title = ascii_title(&save.title());
+                let len = title.len().min(PSX_HEADER_SIZE - PSX_TITLE_OFFSET - 1);
+                header[PSX_TITLE_OFFSET..PSX_TITLE_OFFSET + len]
+                    .copy_from_slice(&title.as_bytes()[..len]);
+
+                [&header[..], &save.data].concat()
+            }
+            Format::Gme | Format::Mcd => {
+                let mut card = Card::formatted();
+                card.write_save(save)?;
+
+                match self {
+                    Format::Gme => gme_image(&card),
+                    _ => card.image().to_vec(),
+                }
+            }
+        };
+
+        Ok(data)
+    }
+}
+
+/// Raw card inside a DexDrive image, if `data` is one
+pub fn gme_card(data: &[u8]) -> Option<&[u8]> {
+    match data.starts_with(GME_SIGNATURE) && data.len() == GME_HEADER_SIZE + CARD_SIZE {
+        true => Some(&data[GME_HEADER_SIZE..]),
+        false => None,
+    }
+}
+
+/// DexDrive image of `card`, with empty save comments
+pub fn gme_image(card: &Card) -> Vec<u8> {
+    let mut header = vec![0u8; GME_HEADER_SIZE];
+
+    header[..GME_SIGNATURE.len()].copy_from_slice(GME_SIGNATURE);
+    header[0x12] = 0x01;
+    header[0x14] = 0x01;
+    header[0x15] = b'M';
+
+    // Copies of the state byte and the next block pointer of each directory
+    // frame
+    for block in 0..BLOCK_COUNT {
+        let frame = card.frame(block);
+
+        header[0x16 + block] = frame[0];
+        header[0x26 + block] = frame[8];
+    }
+
+    [&header[..], card.image()].concat()
+}
+
+fn c_string(bytes: &[u8]) -> String {
+    let len = bytes.iter().position(|&c| c == 0).unwrap_or(bytes.len());
+
+    String::from_utf8_lossy(&bytes[..len]).into_owned()
+}
+
+/// Titles are usually full width Shift-JIS, the Action Replay header wants
+/// plain ASCII
+fn ascii_title(title: &str) -> String {
+    title
+        .chars()
+        .map(|c| match c {
+            '\u{3000}' => ' ',
+            '\u{ff01}'..='\u{ff5e}' => char::from_u32(c as u32 - 0xff01 + 0x21).unwrap(),
+            c if c.is_ascii() => c,
+            _ => '?',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psx::memcard_manager::BLOCK_SIZE;
+
+    /// Two blocks save titled "ＡＢＣ" in full width Shift-JIS
+    fn save() -> SaveFile {
+        let mut data: Vec<u8> = (0..2 * BLOCK_SIZE).map(|i| (i * 7) as u8).collect();
+
+        data[..4].copy_from_slice(b"SC\x11\x02");
+        data[4..0x44].fill(0);
+        data[4..14].copy_from_slice(&[0x82, 0x60, 0x82, 0x61, 0x82, 0x62, 0x81, 0x40, 0x81, 0x40]);
+
+        SaveFile {
+            filename: "BASLUS-00001GAME0".to_string(),
+            data,
+        }
+    }
+
+    fn round_trip(format: Format) {
+        let save = save();
+
+        let saves = format.import(&format.export(&save).unwrap()).unwrap();
+
+        assert_eq!(saves.len(), 1, "{:?}", format);
+        assert_eq!(saves[0].filename, save.filename, "{:?}", format);
+        assert!(saves[0].data == save.data, "{:?}", format);
+    }
+
+    #[test]
+    fn exported_saves_import_unchanged() {
+        for format in [Format::Mcs, Format::Psx, Format::Gme, Format::Mcd] {
+            round_trip(format);
+        }
+    }
+
+    #[test]
+    fn decodes_shift_jis_titles() {
+        let save = save();
+
+        assert_eq!(save.title(), "ＡＢＣ");
+
+        // The Action Replay header gets an ASCII version
+        let psx = Format::Psx.export(&save).unwrap();
+        assert_eq!(&psx[PSX_TITLE_OFFSET..PSX_TITLE_OFFSET + 4], b"ABC\0");
+    }
+
+    #[test]
+    fn rejects_bad_checksums() {
+        let mut mcs = Format::Mcs.export(&save()).unwrap();
+        mcs[0x20] ^= 1;
+        assert!(Format::Mcs.import(&mcs).is_err());
+
+        let mut card = Format::Mcd.export(&save()).unwrap();
+        card[2 * SECTOR_SIZE + 0x20] ^= 1;
+        assert!(Format::Mcd.import(&card).is_err());
+
+        let mut gme = Format::Gme.export(&save()).unwrap();
+        gme[GME_HEADER_SIZE + SECTOR_SIZE + 0x20] ^= 1;
+        assert!(Format::Gme.import(&gme).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        for format in [Format::Mcs, Format::Psx, Format::Gme, Format::Mcd] {
+            let data = format.export(&save()).unwrap();
+
+            let short = match format {
+                Format::Mcs => SECTOR_SIZE - 1,
+                Format::Psx => PSX_HEADER_SIZE - 1,
+                _ => data.len() - 1,
+            };
+
+            assert!(format.import(&data[..short]).is_err(), "{:?}", format);
+        }
+    }
+}
@@ -0,0 +1,391 @@
+use anyhow::{anyhow, Result};
+use encoding_rs::SHIFT_JIS;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use super::sio0::memory_card::{
+    formatted_image, sector_checksum, write_atomic, CARD_SIZE, SECTOR_SIZE,
+};
+
+mod formats;
+
+use formats::Format;
+
+/// Number of save blocks on a card, block 0 holds the directory
+pub const BLOCK_COUNT: usize = 15;
+
+/// Size of a save block
+pub const BLOCK_SIZE: usize = 64 * SECTOR_SIZE;
+
+/// Directory frame allocation states
+const BLOCK_FIRST: u8 = 0x51;
+const BLOCK_MIDDLE: u8 = 0x52;
+const BLOCK_LAST: u8 = 0x53;
+const BLOCK_FREE: u8 = 0xa0;
+const BLOCK_DELETED_FIRST: u8 = 0xa1;
+const BLOCK_DELETED_MIDDLE: u8 = 0xa2;
+const BLOCK_DELETED_LAST: u8 = 0xa3;
+
+/// End of the block chain in the "next block" directory field
+const NO_NEXT_BLOCK: u16 = 0xffff;
+
+/// Maximum length of a save file name, not counting the terminating zero
+const FILENAME_LEN: usize = 20;
+
+/// Memory card manager tool mode
+pub enum Command {
+    /// Show the saves on a card
+    List { card: PathBuf },
+    /// Copy the save starting at `slot` (1-15) from one card to another
+    Copy { src: PathBuf, slot: usize, dst: PathBuf },
+    /// Delete the save starting at `slot`
+    Delete { card: PathBuf, slot: usize },
+    /// Write the save starting at `slot` to a single save file
+    Export { card: PathBuf, slot: usize, file: PathBuf },
+    /// Add the saves found in a single save file or card image to a card
+    Import { card: PathBuf, file: PathBuf },
+}
+
+pub fn run(command: &Command) -> Result<()> {
+    match command {
+        Command::List { card } => list(&Card::load(card)?),
+        Command::Copy { src, slot, dst } => {
+            let save = Card::load(src)?.save_file(*slot)?;
+
+            let mut card = Card::load(dst)?;
+            let slot = card.write_save(&save)?;
+            card.store(dst)?;
+
+            println!("Copied {} to slot {}", save.filename, slot);
+        }
+        Command::Delete { card: path, slot } => {
+            let mut card = Card::load(path)?;
+            card.delete(*slot)?;
+            card.store(path)?;
+        }
+        Command::Export { card, slot, file } => {
+            let card = Card::load(card)?;
+            let save = card.save_file(*slot)?;
+
+            let data = Format::from_path(file)?.export(&save)?;
+            fs::write(file, data)?;
+        }
+        Command::Import { card: path, file } => {
+            let data = fs::read(file)?;
+            let saves = Format::from_path(file)?.import(&data)?;
+
+            let mut card = Card::load(path)?;
+            for save in &saves {
+                let slot = card.write_save(save)?;
+                println!("Imported {} to slot {}", save.filename, slot);
+            }
+            card.store(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn list(card: &Card) {
+    for block in card.bad_checksums() {
+        println!("Warning: bad checksum in directory frame {}", block + 1);
+    }
+
+    println!("Slot  Blocks  Product     Title");
+
+    for save in card.saves() {
+        println!(
+            "{:4}  {:6}  {:10}  {}",
+            save.slot,
+            save.blocks.len(),
+            save.product_code(),
+            card.title(&save)
+        );
+    }
+
+    println!("{} free blocks", card.free_blocks().len());
+}
+
+/// Save as stored in a memory card directory
+pub struct Save {
+    /// First block, numbered 1-15 like the BIOS does
+    pub slot: usize,
+    /// Name in the directory, region + product code + game defined part
+    pub filename: String,
+    /// Blocks holding the data, in order
+    pub blocks: Vec<usize>,
+}
+
+impl Save {
+    /// Product code embedded in the file name, after the 2 characters region
+    pub fn product_code(&self) -> &str {
+        self.filename.get(2..12).unwrap_or("")
+    }
+}
+
+/// Save contents independent from the card layout, used when moving saves
+/// between cards and files
+pub struct SaveFile {
+    pub filename: String,
+    /// Multiple of BLOCK_SIZE
+    pub data: Vec<u8>,
+}
+
+impl SaveFile {
+    pub fn title(&self) -> String {
+        decode_title(&self.data)
+    }
+}
+
+/// Title stored in the header of the first block, decoded from Shift-JIS
+fn decode_title(header: &[u8]) -> String {
+    if &header[..2] != b"SC" {
+        return String::new();
+    }
+
+    let title = &header[4..0x44];
+    let len = title.iter().position(|&c| c == 0).unwrap_or(title.len());
+
+    let (title, _) = SHIFT_JIS.decode_without_bom_handling(&title[..len]);
+
+    title.trim_end_matches([' ', '\u{3000}']).to_string()
+}
+
+/// Raw memory card image
+pub struct Card {
+    data: Vec<u8>,
+}
+
+impl Card {
+    /// Load a raw (`.mcr`, `.mcd`) or DexDrive (`.gme`) card image
+    pub fn load(path: &Path) -> Result<Card> {
+        let data = fs::read(path)
+            .map_err(|e| anyhow!("Can't read memory card {}: {}", path.display(), e))?;
+
+        Card::from_image(&data).map_err(|e| anyhow!("{}: {}", path.display(), e))
+    }
+
+    pub fn from_image(data: &[u8]) -> Result<Card> {
+        let data = match formats::gme_card(data) {
+            Some(card) => card,
+            None => data,
+        };
+
+        if data.len() != CARD_SIZE {
+            return Err(anyhow!("expected {} bytes card image, got {}", CARD_SIZE, data.len()));
+        }
+
+        if &data[..2] != b"MC" {
+            return Err(anyhow!("not a formatted memory card"));
+        }
+
+        Ok(Card { data: data.to_vec() })
+    }
+
+    pub fn formatted() -> Card {
+        Card {
+            data: formatted_image(),
+        }
+    }
+
+    /// Write the card back, in DexDrive format if the extension asks for it
+    pub fn store(&self, path: &Path) -> Result<()> {
+        let data = match Format::from_path(path) {
+            Ok(Format::Gme) => formats::gme_image(self),
+            _ => self.data.clone(),
+        };
+
+        write_atomic(path, &data)
+    }
+
+    pub fn image(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Directory frame describing block `block` (0-14)
+    fn frame(&self, block: usize) -> &[u8] {
+        let start = (block + 1) * SECTOR_SIZE;
+        &self.data[start..start + SECTOR_SIZE]
+    }
+
+    fn frame_mut(&mut self, block: usize) -> &mut [u8] {
+        let start = (block + 1) * SECTOR_SIZE;
+        &mut self.data[start..start + SECTOR_SIZE]
+    }
+
+    fn update_checksum(&mut self, block: usize) {
+        let frame = self.frame_mut(block);
+        frame[SECTOR_SIZE - 1] = sector_checksum(frame);
+    }
+
+    fn block_data(&self, block: usize) -> &[u8] {
+        let start = (block + 1) * BLOCK_SIZE;
+        &self.data[start..start + BLOCK_SIZE]
+    }
+
+    fn state(&self, block: usize) -> u8 {
+        self.frame(block)[0]
+    }
+
+    fn next_block(&self, block: usize) -> u16 {
+        let frame = self.frame(block);
+        u16::from_le_bytes([frame[8], frame[9]])
+    }
+
+    /// Directory frames whose checksum doesn't match their contents
+    pub fn bad_checksums(&self) -> Vec<usize> {
+        (0..BLOCK_COUNT)
+            .filter(|&b| {
+                let frame = self.frame(b);
+                frame[SECTOR_SIZE - 1] != sector_checksum(frame)
+            })
+            .collect()
+    }
+
+    pub fn free_blocks(&self) -> Vec<usize> {
+        (0..BLOCK_COUNT)
+            .filter(|&b| {
+                matches!(
+                    self.state(b),
+                    BLOCK_FREE | BLOCK_DELETED_FIRST | BLOCK_DELETED_MIDDLE | BLOCK_DELETED_LAST
+                )
+            })
+            .collect()
+    }
+
+    pub fn saves(&self) -> Vec<Save> {
+        (0..BLOCK_COUNT)
+            .filter(|&b| self.state(b) == BLOCK_FIRST)
+            .map(|first| {
+                let mut blocks = vec![first];
+                let mut block = first;
+
+                // Follow the chain, a corrupted directory could loop
+                while blocks.len() < BLOCK_COUNT && self.state(block) != BLOCK_LAST {
+                    let next = self.next_block(block) as usize;
+                    if next >= BLOCK_COUNT || blocks.contains(&next) {
+                        break;
+                    }
+
+                    blocks.push(next);
+                    block = next;
+                }
+
+                let name = &self.frame(first)[0xa..0xa + FILENAME_LEN];
+                let len = name.iter().position(|&c| c == 0).unwrap_or(FILENAME_LEN);
+
+                Save {
+                    slot: first + 1,
+                    filename: String::from_utf8_lossy(&name[..len]).into_owned(),
+                    blocks,
+                }
+            })
+            .collect()
+    }
+
+    fn save(&self, slot: usize) -> Result<Save> {
+        self.saves()
+            .into_iter()
+            .find(|s| s.slot == slot)
+            .ok_or_else(|| anyhow!("No save starting at slot {}", slot))
+    }
+
+    pub fn title(&self, save: &Save) -> String {
+        decode_title(self.block_data(save.blocks[0]))
+    }
+
+    pub fn save_file(&self, slot: usize) -> Result<SaveFile> {
+        let save = self.save(slot)?;
+
+        let data = save
+            .blocks
+            .iter()
+            .flat_map(|&b| self.block_data(b).iter().copied())
+            .collect();
+
+        Ok(SaveFile {
+            filename: save.filename,
+            data,
+        })
+    }
+
+    /// Store `save` in free blocks, returns the slot of its first block
+    pub fn write_save(&mut self, save: &SaveFile) -> Result<usize> {
+        if save.data.is_empty() || !save.data.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(anyhow!("{}: invalid save size {}", save.filename, save.data.len()));
+        }
+
+        if save.filename.is_empty() || save.filename.len() > FILENAME_LEN {
+            return Err(anyhow!("Invalid save name {:?}", save.filename));
+        }
+
+        if self.saves().iter().any(|s| s.filename == save.filename) {
+            return Err(anyhow!("{} already exists on the card", save.filename));
+        }
+
+        let count = save.data.len() / BLOCK_SIZE;
+        let free = self.free_blocks();
+
+        if free.len() < count {
+            return Err(anyhow!(
+                "{} needs {} blocks, only {} free",
+                save.filename,
+                count,
+                free.len()
+            ));
+        }
+
+        let blocks = &free[..count];
+
+        for (i, &block) in blocks.iter().enumerate() {
+            let state = match (i, i + 1 == count) {
+                (0, _) => BLOCK_FIRST,
+                (_, true) => BLOCK_LAST,
+                _ => BLOCK_MIDDLE,
+            };
+
+            let next = blocks.get(i + 1).map(|&b| b as u16).unwrap_or(NO_NEXT_BLOCK);
+
+            let frame = self.frame_mut(block);
+            frame.fill(0);
+            frame[0] = state;
+            frame[8..10].copy_from_slice(&next.to_le_bytes());
+
+            // Only the first frame has the file size and name
+            if i == 0 {
+                frame[4..8].copy_from_slice(&(save.data.len() as u32).to_le_bytes());
+                frame[0xa..0xa + save.filename.len()].copy_from_slice(save.filename.as_bytes());
+            }
+
+            self.update_checksum(block);
+
+            let start = (block + 1) * BLOCK_SIZE;
+            self.data[start..start + BLOCK_SIZE]
+                .copy_from_slice(&save.data[i * BLOCK_SIZE..(i + 1) * BLOCK_SIZE]);
+        }
+
+        Ok(blocks[0] + 1)
+    }
+
+    /// Mark the blocks as deleted the way the BIOS does, the data stays on
+    /// the card
+    pub fn delete(&mut self, slot: usize) -> Result<()> {
+        let save = self.save(slot)?;
+
+        for &block in &save.blocks {
+            let frame = self.frame_mut(block);
+
+            frame[0] = match frame[0] {
+                BLOCK_FIRST => BLOCK_DELETED_FIRST,
+                BLOCK_MIDDLE => BLOCK_DELETED_MIDDLE,
+                _ => BLOCK_DELETED_LAST,
+            };
+
+            self.update_checksum(block);
+        }
+
+        Ok(())
+    }
+}
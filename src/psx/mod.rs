@@ -1,27 +1,152 @@
-use std::path::Path;
-
-use anyhow::Result;
+use std::{fmt, path::PathBuf};
 
 mod bios;
 mod cpu;
+mod disc;
 mod interconnect;
+mod irq;
 mod ram;
 mod dma;
+mod exe;
+mod expansion;
+mod gpu;
+mod hle;
+mod mdec;
+mod mem_control;
+pub mod memcard_manager;
+mod sio0;
+mod sio1;
+pub mod str_player;
+mod system;
+
+pub use bios::{identify_bios, select_bios, BiosCheck, BiosInfo};
+pub use cpu::{disassemble, Registers, REGISTER_NAMES};
+pub use gpu::{Display, Frame, HorizontalRes};
+pub use sio0::{Axis, Button, GunButton, MouseButton, Peripheral, Port, Response, Rumble};
+pub use system::{disc_region, System, SystemBuilder, AUDIO_SAMPLE_RATE, CPU_CLOCK_HZ};
+
+/// Controller plugged in a port or in a multitap slot
+#[derive(Clone, Copy)]
+pub enum Controller {
+    None,
+    /// SCPH-1080 digital pad
+    Digital,
+    /// SCPH-1200 analog controller with rumble
+    DualShock,
+    /// SCPH-1030 mouse
+    Mouse,
+    /// Namco GunCon light gun
+    GunCon,
+}
 
-use bios::Bios;
-use cpu::Cpu;
-use interconnect::Interconnect;
+/// Devices plugged in a port, or in one of the slots of a multitap
+#[derive(Clone)]
+pub struct Slot {
+    pub controller: Controller,
+    /// Raw `.mcr` image, created if it doesn't exist
+    pub memory_card: Option<PathBuf>,
+}
 
-use self::{ram::Ram, dma::Dma};
+impl Slot {
+    pub fn new(controller: Controller) -> Slot {
+        Slot {
+            controller,
+            memory_card: None,
+        }
+    }
+}
+
+/// What is plugged in one of the two ports
+pub enum PortConfig {
+    Direct(Slot),
+    Multitap([Slot; 4]),
+}
+
+/// TCP connection on the same machine, to another instance for the link
+/// cable or to a host tool for the parallel port
+#[derive(Clone, Copy)]
+pub enum LinkConfig {
+    /// Wait for the other end on this TCP port
+    Listen(u16),
+    /// Connect to the other end on this TCP port
+    Connect(u16),
+}
 
-pub fn run() -> Result<()> {
-    let bios = Bios::new(&Path::new("./bios/scph1001.bin"))?;
-    let ram = Ram::new();
-    let dma  = Dma::new();
-    let inter = Interconnect::new(bios, ram, dma);
-    let mut cpu = Cpu::new(inter);
+/// Console region
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Region {
+    /// Japan
+    NtscJ,
+    /// North America
+    NtscU,
+    /// Europe
+    Pal,
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Region::NtscJ => "NTSC-J",
+            Region::NtscU => "NTSC-U",
+            Region::Pal => "PAL",
+        };
+
+        f.write_str(name)
+    }
+}
+
+/// Emulator settings picked on the command line
+pub struct Config {
+    pub ports: [PortConfig; 2],
+    pub link: Option<LinkConfig>,
+    /// Halt with a report on accesses to unmapped or unhandled addresses
+    /// instead of emulating bus errors and open bus
+    pub strict: bool,
+    /// 8MiB of RAM like the development consoles
+    pub dev_kit: bool,
+    /// ROM image of a cartridge plugged in the parallel port
+    pub expansion_rom: Option<PathBuf>,
+    /// Cartridge switch position
+    pub cartridge_switch: bool,
+    /// Host link on the parallel port of the cartridge
+    pub parallel_link: Option<LinkConfig>,
+    /// Region forced on the command line. It only sets the frame rate
+    /// instead of the video mode picked by the software, and the frontend
+    /// uses it to pick a BIOS. The console itself isn't changed: the GPU
+    /// mode, the BIOS and what the software sees stay as they are
+    pub region: Option<Region>,
+    /// Patch the BIOS to skip the intro. There's no patch for the region
+    /// check, discs of other regions boot with OpenBIOS or `hle_bios`
+    pub fast_boot: bool,
+    /// Patch the BIOS to enable its TTY output
+    pub bios_tty: bool,
+    /// Run the built-in high level emulated kernel instead of a BIOS image
+    pub hle_bios: bool,
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            ports: [
+                PortConfig::Direct(Slot::new(Controller::Digital)),
+                PortConfig::Direct(Slot::new(Controller::None)),
+            ],
+            link: None,
+            strict: false,
+            dev_kit: false,
+            expansion_rom: None,
+            cartridge_switch: false,
+            parallel_link: None,
+            region: None,
+            fast_boot: false,
+            bios_tty: false,
+            hle_bios: false,
+        }
+    }
+}
 
-    loop {
-        cpu.run_next_instruction();
+impl Default for Config {
+    fn default() -> Config {
+        Config::new()
     }
 }
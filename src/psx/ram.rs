@@ -6,10 +6,33 @@ pub struct Ram {
 
 impl Ram {
     pub fn new() -> Self {
-        let data = vec![0xca; 2 * 1024 * 1024];
+        Ram::with_size(2 * 1024 * 1024)
+    }
+
+    /// 8MiB RAM of the development consoles
+    pub fn dev_kit() -> Self {
+        Ram::with_size(8 * 1024 * 1024)
+    }
+
+    /// RAM of `size` bytes, also used for the scratchpad
+    pub fn with_size(size: usize) -> Self {
+        let data = vec![0xca; size];
         Ram { data }
     }
 
+    /// Size in bytes, a power of two
+    pub fn size(&self) -> u32 {
+        self.data.len() as u32
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
     pub fn load8(&self, offset: u32) -> u8 {
         self.data[offset as usize]
     }
@@ -0,0 +1,290 @@
+use super::{Axis, Button, Peripheral, Response, Rumble};
+
+/// SCPH-1200 DualShock analog controller
+pub struct DualShock {
+    /// Button state, active low
+    buttons: u16,
+    /// Stick positions in RX, RY, LX, LY order, 0x80 is the center
+    axes: [u8; 4],
+    /// Analog mode, red LED on
+    analog: bool,
+    /// Analog button disabled by the game
+    analog_locked: bool,
+    /// Config mode entered with command 0x43
+    config: bool,
+    /// Motor assigned to each of the 6 parameter bytes of command 0x42:
+    /// 0x00 small motor, 0x01 large motor, 0xff nothing
+    motor_map: [u8; 6],
+    /// Current motor output
+    rumble: Rumble,
+
+    /// Position in the current command, None when not addressed
+    seq: Option<u8>,
+    /// Command being executed
+    command: u8,
+    /// Reply bytes following the ID of the current command
+    reply: [u8; 6],
+    /// Number of valid bytes in `reply`
+    reply_len: u8,
+}
+
+impl DualShock {
+    pub fn new() -> Self {
+        DualShock {
+            buttons: 0xffff,
+            axes: [0x80; 4],
+            analog: false,
+            analog_locked: false,
+            config: false,
+            motor_map: [0xff; 6],
+            rumble: Rumble { small: 0, large: 0 },
+            seq: None,
+            command: 0,
+            reply: [0; 6],
+            reply_len: 0,
+        }
+    }
+
+    /// Low byte of the controller ID, the high nibble gives the type and
+    /// the low nibble the number of halfwords following the ID
+    fn id(&self) -> u8 {
+        match (self.config, self.analog) {
+            (true, _) => 0xf3,
+            (false, true) => 0x73,
+            (false, false) => 0x41,
+        }
+    }
+
+    /// Build the reply for `command`, using the mode in effect before it runs
+    fn start_command(&mut self, command: u8) -> bool {
+        let mut reply = [0u8; 6];
+
+        let poll = |s: &DualShock, reply: &mut [u8; 6]| {
+            reply[0] = s.buttons as u8;
+            reply[1] = (s.buttons >> 8) as u8;
+
+            if s.analog {
+                reply[2..6].copy_from_slice(&s.axes);
+            }
+        };
+
+        let valid = match (self.config, command) {
+            (_, 0x42) | (false, 0x43) => {
+                poll(self, &mut reply);
+                true
+            }
+            // Query model: DualShock, LED state
+            (true, 0x45) => {
+                reply = [0x01, 0x02, self.analog as u8, 0x02, 0x01, 0x00];
+                true
+            }
+            (true, 0x47) => {
+                reply = [0x00, 0x00, 0x02, 0x00, 0x01, 0x00];
+                true
+            }
+            // Motor mapping, the previous mapping is returned
+            (true, 0x4d) => {
+                reply = self.motor_map;
+                true
+            }
+            // Other config commands reply with zeros, or with values
+            // selected by their first parameter
+            (true, _) => true,
+            (false, _) => false,
+        };
+
+        self.command = command;
+        self.reply = reply;
+        self.reply_len = match self.config || self.analog {
+            true => 6,
+            false => 2,
+        };
+
+        valid
+    }
+
+    /// Handle the parameter byte sent at position `index` of the reply
+    fn parameter(&mut self, index: usize, tx: u8) {
+        match (self.config, self.command) {
+            (_, 0x42) => {
+                match self.motor_map[index] {
+                    0x00 => self.rumble.small = if tx & 1 != 0 { 0xff } else { 0 },
+                    0x01 => self.rumble.large = tx,
+                    _ => (),
+                }
+            }
+            (_, 0x43) if index == 0 => self.config = tx == 0x01,
+            (true, 0x44) => match index {
+                0 => self.analog = tx == 0x01,
+                1 => self.analog_locked = tx == 0x03,
+                _ => (),
+            },
+            (true, 0x46) if index == 0 => {
+                self.reply = match tx {
+                    0x01 => [0x00, 0x00, 0x01, 0x01, 0x01, 0x14],
+                    _ => [0x00, 0x00, 0x01, 0x02, 0x00, 0x0a],
+                };
+            }
+            (true, 0x4c) if index == 0 => {
+                self.reply[3] = match tx {
+                    0x01 => 0x07,
+                    _ => 0x04,
+                };
+            }
+            (true, 0x4d) => {
+                self.motor_map[index] = tx;
+
+                // Motors are stopped until the game sends new values
+                self.rumble = Rumble { small: 0, large: 0 };
+            }
+            _ => (),
+        }
+    }
+}
+
+impl Peripheral for DualShock {
+    fn select(&mut self) {
+        self.seq = Some(0);
+    }
+
+    fn exchange(&mut self, tx: u8) -> Response {
+        let Some(seq) = self.seq else {
+            return Response::HIGH_Z;
+        };
+
+        self.seq = Some(seq + 1);
+
+        match seq {
+            // Address byte
+            0 if tx == 0x01 => Response::new(0xff, true),
+            0 => {
+                self.seq = None;
+                Response::HIGH_Z
+            }
+            1 => {
+                let id = self.id();
+
+                match self.start_command(tx) {
+                    true => Response::new(id, true),
+                    false => {
+                        self.seq = None;
+                        Response::HIGH_Z
+                    }
+                }
+            }
+            2 => Response::new(0x5a, true),
+            _ => {
+                let index = (seq - 3) as usize;
+
+                let data = self.reply[index];
+                self.parameter(index, tx);
+
+                let last = index + 1 == self.reply_len as usize;
+                if last {
+                    self.seq = None;
+                }
+
+                Response::new(data, !last)
+            }
+        }
+    }
+
+    fn set_button_state(&mut self, button: Button, pressed: bool) {
+        let mask = 1 << (button as u16);
+
+        match pressed {
+            true => self.buttons &= !mask,
+            false => self.buttons |= mask,
+        }
+    }
+
+    fn set_axis_state(&mut self, axis: Axis, value: u8) {
+        self.axes[axis as usize] = value;
+    }
+
+    fn press_analog_button(&mut self) {
+        if !self.analog_locked {
+            self.analog = !self.analog;
+        }
+    }
+
+    fn rumble(&self) -> Option<Rumble> {
+        Some(self.rumble)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Send a whole command, returns the received bytes and whether the
+    /// last one was acknowledged
+    fn command(pad: &mut DualShock, tx: &[u8]) -> (Vec<u8>, bool) {
+        pad.select();
+
+        let replies: Vec<Response> = tx.iter().map(|&b| pad.exchange(b)).collect();
+
+        (replies.iter().map(|r| r.data).collect(), replies.last().unwrap().ack)
+    }
+
+    #[test]
+    fn starts_as_a_digital_pad() {
+        let mut pad = DualShock::new();
+
+        pad.set_button_state(Button::L3, true);
+
+        assert_eq!(command(&mut pad, &[0x01, 0x42, 0, 0, 0]), (vec![0xff, 0x41, 0x5a, 0xfd, 0xff], false));
+        // Config commands are refused outside of config mode
+        assert_eq!(command(&mut pad, &[0x01, 0x45, 0]).0, [0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn config_mode_switches_to_analog_and_maps_rumble() {
+        let mut pad = DualShock::new();
+        let zeros = [0; 6];
+
+        // Enter config mode, the reply is still a digital poll
+        let (rx, _) = command(&mut pad, &[0x01, 0x43, 0x00, 0x01, 0x00]);
+        assert_eq!(rx, [0xff, 0x41, 0x5a, 0xff, 0xff]);
+
+        // Analog mode with the button locked
+        let (rx, ack) = command(&mut pad, &[0x01, 0x44, 0x00, 0x01, 0x03, 0, 0, 0, 0]);
+        assert_eq!(rx, [0xff, 0xf3, 0x5a, 0, 0, 0, 0, 0, 0]);
+        assert!(!ack);
+
+        let (rx, _) = command(&mut pad, &[[0x01, 0x45, 0x00].as_slice(), &zeros].concat());
+        assert_eq!(rx[3..], [0x01, 0x02, 0x01, 0x02, 0x01, 0x00]);
+
+        let (rx, _) = command(&mut pad, &[[0x01, 0x46, 0x00, 0x01].as_slice(), &zeros[1..]].concat());
+        assert_eq!(rx[3..], [0x00, 0x00, 0x01, 0x01, 0x01, 0x14]);
+
+        let (rx, _) = command(&mut pad, &[[0x01, 0x47, 0x00].as_slice(), &zeros].concat());
+        assert_eq!(rx[3..], [0x00, 0x00, 0x02, 0x00, 0x01, 0x00]);
+
+        let (rx, _) = command(&mut pad, &[[0x01, 0x4c, 0x00, 0x01].as_slice(), &zeros[1..]].concat());
+        assert_eq!(rx[3..], [0x00, 0x00, 0x00, 0x07, 0x00, 0x00]);
+
+        // Small motor on the first byte, large one on the second
+        let (rx, _) = command(&mut pad, &[0x01, 0x4d, 0x00, 0x00, 0x01, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(rx[3..], [0xff; 6]);
+
+        // Leave config mode
+        let (rx, _) = command(&mut pad, &[[0x01, 0x43, 0x00, 0x00].as_slice(), &zeros[1..]].concat());
+        assert_eq!(rx[1], 0xf3);
+
+        // Locked, the controller stays in analog mode
+        pad.press_analog_button();
+        pad.set_axis_state(Axis::LeftX, 0x12);
+
+        let (rx, ack) = command(&mut pad, &[0x01, 0x42, 0x00, 0x01, 0x80, 0, 0, 0, 0]);
+        assert_eq!(rx, [0xff, 0x73, 0x5a, 0xff, 0xff, 0x80, 0x80, 0x12, 0x80]);
+        assert!(!ack);
+        assert_eq!(pad.rumble(), Some(Rumble { small: 0xff, large: 0x80 }));
+
+        // Remapping stops the motors
+        command(&mut pad, &[0x01, 0x43, 0x00, 0x01, 0, 0, 0, 0, 0]);
+        let (rx, _) = command(&mut pad, &[0x01, 0x4d, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(rx[3..], [0x00, 0x01, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(pad.rumble(), Some(Rumble { small: 0, large: 0 }));
+    }
+}
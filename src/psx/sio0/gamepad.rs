@@ -0,0 +1,133 @@
+use super::{Peripheral, Response};
+
+/// Controller buttons, the value is the bit position in the button state
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Button {
+    Select   = 0,
+    L3       = 1,
+    R3       = 2,
+    Start    = 3,
+    Up       = 4,
+    Right    = 5,
+    Down     = 6,
+    Left     = 7,
+    L2       = 8,
+    R2       = 9,
+    L1       = 10,
+    R1       = 11,
+    Triangle = 12,
+    Circle   = 13,
+    Cross    = 14,
+    Square   = 15,
+}
+
+/// SCPH-1080 digital controller
+pub struct DigitalPad {
+    /// Button state, active low
+    buttons: u16,
+    /// Position in the current command, None when not addressed
+    seq: Option<u8>,
+}
+
+impl DigitalPad {
+    /// Controller ID, sent low byte first
+    const ID: u16 = 0x5a41;
+
+    pub fn new() -> Self {
+        DigitalPad {
+            buttons: 0xffff,
+            seq: None,
+        }
+    }
+}
+
+impl Peripheral for DigitalPad {
+    fn select(&mut self) {
+        self.seq = Some(0);
+    }
+
+    fn exchange(&mut self, tx: u8) -> Response {
+        let Some(seq) = self.seq else {
+            return Response::HIGH_Z;
+        };
+
+        self.seq = Some(seq + 1);
+
+        match seq {
+            // Address byte
+            0 if tx == 0x01 => Response::new(0xff, true),
+            // Only the read command is supported
+            1 if tx == 0x42 => Response::new(Self::ID as u8, true),
+            2 => Response::new((Self::ID >> 8) as u8, true),
+            3 => Response::new(self.buttons as u8, true),
+            // Last byte, no ACK
+            4 => {
+                self.seq = None;
+                Response::new((self.buttons >> 8) as u8, false)
+            }
+            _ => {
+                self.seq = None;
+                Response::HIGH_Z
+            }
+        }
+    }
+
+    fn set_button_state(&mut self, button: Button, pressed: bool) {
+        // L3 and R3 don't exist on the digital pad
+        if button == Button::L3 || button == Button::R3 {
+            return;
+        }
+
+        let mask = 1 << (button as u16);
+
+        match pressed {
+            true => self.buttons &= !mask,
+            false => self.buttons |= mask,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(pad: &mut DigitalPad, tx: &[u8]) -> Vec<(u8, bool)> {
+        pad.select();
+
+        tx.iter()
+            .map(|&b| {
+                let r = pad.exchange(b);
+                (r.data, r.ack)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reports_the_buttons() {
+        let mut pad = DigitalPad::new();
+
+        pad.set_button_state(Button::Cross, true);
+        pad.set_button_state(Button::Start, true);
+        // Not on this controller
+        pad.set_button_state(Button::L3, true);
+
+        assert_eq!(
+            command(&mut pad, &[0x01, 0x42, 0x00, 0x00, 0x00]),
+            [(0xff, true), (0x41, true), (0x5a, true), (0xf7, true), (0xbf, false)]
+        );
+
+        pad.set_button_state(Button::Cross, false);
+        assert_eq!(command(&mut pad, &[0x01, 0x42, 0x00, 0x00, 0x00])[4], (0xff, false));
+    }
+
+    #[test]
+    fn ignores_other_addresses_and_commands() {
+        let mut pad = DigitalPad::new();
+
+        // Memory card address: the pad stays off the bus until selected again
+        assert_eq!(command(&mut pad, &[0x81, 0x52, 0x00]), [(0xff, false); 3]);
+        assert_eq!(command(&mut pad, &[0x01, 0x43, 0x00]), [(0xff, true), (0xff, false), (0xff, false)]);
+        // Without a select nothing answers
+        assert!(!pad.exchange(0x01).ack);
+    }
+}
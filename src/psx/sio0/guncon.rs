@@ -0,0 +1,192 @@
+use super::{Display, Peripheral, Response};
+
+/// Light gun buttons
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GunButton {
+    Trigger,
+    A,
+    B,
+}
+
+/// Frequency of the GunCon counter used for the X coordinate
+const GUNCON_CLOCK_HZ: u64 = 8_000_000;
+
+/// Coordinates reported when the gun doesn't see the beam. Games detect a
+/// reload by the trigger being pulled while aiming off-screen
+const OFF_SCREEN: (u16, u16) = (0x0001, 0x000a);
+
+/// NPC-103 Namco GunCon
+pub struct GunCon {
+    /// Button state, active low
+    buttons: u16,
+    /// Aim in the displayed picture, None when off-screen
+    aim: Option<(f32, f32)>,
+    /// Current video timings, used to convert the aim to beam coordinates
+    display: Option<Display>,
+    /// Coordinates latched at the start of the current poll
+    report: [u8; 4],
+    /// Position in the current command, None when not addressed
+    seq: Option<u8>,
+}
+
+impl GunCon {
+    /// Controller ID, sent low byte first
+    const ID: u16 = 0x5a63;
+
+    pub fn new() -> GunCon {
+        GunCon {
+            buttons: 0xffff,
+            aim: None,
+            display: None,
+            report: [0; 4],
+            seq: None,
+        }
+    }
+
+    /// Beam position when it crosses the aimed point: X in 8MHz clocks since
+    /// HSYNC and Y in scanlines since VSYNC
+    fn position(&self) -> (u16, u16) {
+        let (Some((ax, ay)), Some(display)) = (self.aim, self.display) else {
+            return OFF_SCREEN;
+        };
+
+        if !(0.0..=1.0).contains(&ax) || !(0.0..=1.0).contains(&ay) {
+            return OFF_SCREEN;
+        }
+
+        let width = display.x2.saturating_sub(display.x1) as f32;
+        let height = display.y2.saturating_sub(display.y1) as f32;
+
+        if width == 0.0 || height == 0.0 {
+            return OFF_SCREEN;
+        }
+
+        // Snap to the dot clock, the gun can't see between two pixels
+        let divider = display.dot_clock_divider();
+        let dot = (ax * width) as u32 / divider;
+        let video_clocks = display.x1 as u64 + (dot * divider) as u64;
+
+        let x = video_clocks * GUNCON_CLOCK_HZ / display.video_clock_hz() as u64;
+        let y = display.y1 as u32 + (ay * height) as u32;
+
+        (x as u16, y as u16)
+    }
+}
+
+impl Peripheral for GunCon {
+    fn select(&mut self) {
+        self.seq = Some(0);
+    }
+
+    fn exchange(&mut self, tx: u8) -> Response {
+        let Some(seq) = self.seq else {
+            return Response::HIGH_Z;
+        };
+
+        self.seq = Some(seq + 1);
+
+        match seq {
+            // Address byte
+            0 if tx == 0x01 => Response::new(0xff, true),
+            // Only the read command is supported
+            1 if tx == 0x42 => {
+                let (x, y) = self.position();
+
+                self.report = [x as u8, (x >> 8) as u8, y as u8, (y >> 8) as u8];
+
+                Response::new(Self::ID as u8, true)
+            }
+            2 => Response::new((Self::ID >> 8) as u8, true),
+            3 => Response::new(self.buttons as u8, true),
+            4 => Response::new((self.buttons >> 8) as u8, true),
+            5..=7 => Response::new(self.report[seq as usize - 5], true),
+            // Last byte, no ACK
+            8 => {
+                self.seq = None;
+                Response::new(self.report[3], false)
+            }
+            _ => {
+                self.seq = None;
+                Response::HIGH_Z
+            }
+        }
+    }
+
+    fn set_aim(&mut self, aim: Option<(f32, f32)>) {
+        self.aim = aim;
+    }
+
+    fn set_gun_button(&mut self, button: GunButton, pressed: bool) {
+        let mask = match button {
+            GunButton::A => 1 << 3,
+            GunButton::Trigger => 1 << 13,
+            GunButton::B => 1 << 14,
+        };
+
+        match pressed {
+            true => self.buttons &= !mask,
+            false => self.buttons |= mask,
+        }
+    }
+
+    fn set_display(&mut self, display: Display) {
+        self.display = Some(display);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psx::gpu::Gpu;
+
+    /// X and Y reported by a poll
+    fn poll(gun: &mut GunCon) -> (u16, u16) {
+        gun.select();
+
+        let rx: Vec<Response> = [0x01, 0x42, 0, 0, 0, 0, 0, 0, 0].iter().map(|&b| gun.exchange(b)).collect();
+
+        assert_eq!((rx[1].data, rx[2].data), (0x63, 0x5a));
+        assert!(!rx[8].ack);
+
+        (
+            u16::from_le_bytes([rx[5].data, rx[6].data]),
+            u16::from_le_bytes([rx[7].data, rx[8].data]),
+        )
+    }
+
+    #[test]
+    fn reports_the_beam_position() {
+        let mut gun = GunCon::new();
+        // NTSC, 256 pixels: display area from 0x200 to 0xc00 video clocks,
+        // lines 16 to 256
+        gun.set_display(Gpu::new().display());
+
+        gun.set_aim(Some((0.0, 0.0)));
+        // 0x200 video clocks at 53.69MHz, in 8MHz clocks
+        assert_eq!(poll(&mut gun), (76, 16));
+
+        gun.set_aim(Some((0.5, 0.5)));
+        // Pixel 128: 0x200 + 1280 video clocks
+        assert_eq!(poll(&mut gun), (266, 136));
+
+        // Snapped to the start of the pixel
+        gun.set_aim(Some((0.5 + 9.0 / 2560.0, 0.5)));
+        assert_eq!(poll(&mut gun).0, 266);
+    }
+
+    #[test]
+    fn reports_off_screen_aims() {
+        let mut gun = GunCon::new();
+
+        gun.set_aim(Some((0.5, 0.5)));
+        // No video timings yet
+        assert_eq!(poll(&mut gun), (1, 0xa));
+
+        gun.set_display(Gpu::new().display());
+        gun.set_aim(None);
+        assert_eq!(poll(&mut gun), (1, 0xa));
+
+        gun.set_aim(Some((1.5, 0.5)));
+        assert_eq!(poll(&mut gun), (1, 0xa));
+    }
+}
@@ -0,0 +1,429 @@
+use anyhow::{anyhow, Result};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use super::{Peripheral, Response};
+
+/// Size of a memory card sector (also called frame)
+pub const SECTOR_SIZE: usize = 128;
+
+/// Number of sectors on a card
+pub const SECTOR_COUNT: usize = 1024;
+
+/// Size of a raw memory card image
+pub const CARD_SIZE: usize = SECTOR_SIZE * SECTOR_COUNT;
+
+/// FLAG bit 2: the previous write failed
+const FLAG_ERROR: u8 = 0x04;
+/// FLAG bit 3: directory not read yet, set when a card is inserted
+const FLAG_NEW_CARD: u8 = 0x08;
+
+/// Memory card end byte for a good command
+const STATUS_GOOD: u8 = b'G';
+/// Memory card end byte for a write with a bad checksum
+const STATUS_BAD_CHECKSUM: u8 = b'N';
+/// Memory card end byte for an invalid sector number
+const STATUS_BAD_SECTOR: u8 = 0xff;
+
+/// Frames without a write before the image is saved, games write a save
+/// one sector at a time
+const FLUSH_DELAY_FRAMES: u32 = 60;
+
+/// SCPH-1020 memory card backed by a raw `.mcr` image
+pub struct MemoryCard {
+    data: Vec<u8>,
+    /// Image written back once the guest stops writing
+    path: Option<PathBuf>,
+    /// Sectors written since the image was last saved
+    dirty: bool,
+    /// Frames since the last write
+    idle_frames: u32,
+    flag: u8,
+
+    /// Position in the current command, None when not addressed
+    seq: Option<u16>,
+    command: Command,
+    /// Sector of the current read or write
+    sector: u16,
+    /// Sector data received by a write
+    buffer: [u8; SECTOR_SIZE],
+    /// Running checksum of the current read or write
+    checksum: u8,
+    /// Last byte received, echoed back during writes
+    previous: u8,
+    /// End byte of the current command
+    status: u8,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Command {
+    None,
+    Read,
+    Write,
+    GetId,
+}
+
+impl MemoryCard {
+    /// Load the card image at `path`, creating a freshly formatted one if it
+    /// doesn't exist yet
+    pub fn open(path: &Path) -> Result<MemoryCard> {
+        let data = match path.exists() {
+            true => {
+                let data = fs::read(path).map_err(|e| {
+                    anyhow!("Can't read memory card {}: {}", path.display(), e)
+                })?;
+
+                if data.len() != CARD_SIZE {
+                    return Err(anyhow!(
+                        "Invalid memory card {}: expected {} bytes, got {}",
+                        path.display(),
+                        CARD_SIZE,
+                        data.len()
+                    ));
+                }
+
+                data
+            }
+            false => {
+                let data = formatted_image();
+                write_atomic(path, &data)?;
+                data
+            }
+        };
+
+        Ok(MemoryCard::with_data(data, Some(path.to_path_buf())))
+    }
+
+    /// Card that isn't saved anywhere
+    pub fn with_data(data: Vec<u8>, path: Option<PathBuf>) -> MemoryCard {
+        MemoryCard {
+            data,
+            path,
+            dirty: false,
+            idle_frames: 0,
+            flag: FLAG_NEW_CARD,
+            seq: None,
+            command: Command::None,
+            sector: 0,
+            buffer: [0; SECTOR_SIZE],
+            checksum: 0,
+            previous: 0,
+            status: STATUS_GOOD,
+        }
+    }
+
+    fn sector_valid(&self) -> bool {
+        (self.sector as usize) < SECTOR_COUNT
+    }
+
+    fn read_byte(&mut self, offset: usize) -> u8 {
+        let b = self.data[self.sector as usize * SECTOR_SIZE + offset];
+        self.checksum ^= b;
+        b
+    }
+
+    /// Store the received sector, the image is saved by `flush`
+    fn commit_write(&mut self) {
+        let start = self.sector as usize * SECTOR_SIZE;
+        self.data[start..start + SECTOR_SIZE].copy_from_slice(&self.buffer);
+
+        // The directory has been accessed, the card isn't "new" anymore
+        self.flag &= !FLAG_NEW_CARD;
+
+        self.dirty = true;
+        self.idle_frames = 0;
+    }
+
+    fn save(&mut self) -> Result<()> {
+        if let Some(path) = &self.path {
+            write_atomic(path, &self.data)
+                .map_err(|e| anyhow!("Can't save memory card {}: {}", path.display(), e))?;
+        }
+
+        self.dirty = false;
+
+        Ok(())
+    }
+
+    fn read(&mut self, seq: u16, tx: u8) -> Response {
+        match seq {
+            2 => Response::new(0x5a, true),
+            3 => Response::new(0x5d, true),
+            4 => {
+                self.sector = (tx as u16) << 8;
+                Response::new(0x00, true)
+            }
+            5 => {
+                let msb = (self.sector >> 8) as u8;
+                self.sector |= tx as u16;
+                Response::new(msb, true)
+            }
+            6 => Response::new(0x5c, true),
+            7 => Response::new(0x5d, true),
+            8 | 9 if !self.sector_valid() => {
+                // Invalid sectors abort the command after a 0xff address
+                if seq == 9 {
+                    self.seq = None;
+                }
+                Response::new(0xff, seq == 8)
+            }
+            8 => {
+                let msb = (self.sector >> 8) as u8;
+                self.checksum = msb;
+                Response::new(msb, true)
+            }
+            9 => {
+                let lsb = self.sector as u8;
+                self.checksum ^= lsb;
+                Response::new(lsb, true)
+            }
+            10..=137 => {
+                let b = self.read_byte((seq - 10) as usize);
+                Response::new(b, true)
+            }
+            138 => Response::new(self.checksum, true),
+            _ => {
+                self.seq = None;
+                Response::new(STATUS_GOOD, false)
+            }
+        }
+    }
+
+    fn write(&mut self, seq: u16, tx: u8) -> Response {
+        let previous = self.previous;
+        self.previous = tx;
+
+        match seq {
+            2 => Response::new(0x5a, true),
+            3 => Response::new(0x5d, true),
+            4 => {
+                self.sector = (tx as u16) << 8;
+                self.checksum = tx;
+                Response::new(0x00, true)
+            }
+            5 => {
+                self.sector |= tx as u16;
+                self.checksum ^= tx;
+                Response::new(previous, true)
+            }
+            6..=133 => {
+                self.buffer[(seq - 6) as usize] = tx;
+                self.checksum ^= tx;
+                Response::new(previous, true)
+            }
+            134 => {
+                self.status = if !self.sector_valid() {
+                    STATUS_BAD_SECTOR
+                } else if tx != self.checksum {
+                    STATUS_BAD_CHECKSUM
+                } else {
+                    STATUS_GOOD
+                };
+                Response::new(previous, true)
+            }
+            135 => Response::new(0x5c, true),
+            136 => Response::new(0x5d, true),
+            _ => {
+                self.seq = None;
+
+                match self.status {
+                    STATUS_GOOD => {
+                        self.flag &= !FLAG_ERROR;
+                        self.commit_write();
+                    }
+                    _ => self.flag |= FLAG_ERROR,
+                }
+
+                Response::new(self.status, false)
+            }
+        }
+    }
+
+    fn get_id(&mut self, seq: u16) -> Response {
+        // Sectors of 128 bytes, 1024 of them
+        const ID: [u8; 8] = [0x5a, 0x5d, 0x5c, 0x5d, 0x04, 0x00, 0x00, 0x80];
+
+        let i = seq as usize - 2;
+
+        let last = i + 1 == ID.len();
+        if last {
+            self.seq = None;
+        }
+
+        Response::new(ID[i], !last)
+    }
+}
+
+impl Peripheral for MemoryCard {
+    fn select(&mut self) {
+        self.seq = Some(0);
+        self.command = Command::None;
+    }
+
+    fn exchange(&mut self, tx: u8) -> Response {
+        let Some(seq) = self.seq else {
+            return Response::HIGH_Z;
+        };
+
+        self.seq = Some(seq + 1);
+
+        match seq {
+            // Address byte
+            0 if tx == 0x81 => Response::new(0xff, true),
+            0 => {
+                self.seq = None;
+                Response::HIGH_Z
+            }
+            1 => {
+                self.command = match tx {
+                    b'R' => Command::Read,
+                    b'W' => Command::Write,
+                    b'S' => Command::GetId,
+                    _ => {
+                        self.seq = None;
+                        return Response::new(self.flag, false);
+                    }
+                };
+
+                Response::new(self.flag, true)
+            }
+            _ => match self.command {
+                Command::Read => self.read(seq, tx),
+                Command::Write => self.write(seq, tx),
+                Command::GetId => self.get_id(seq),
+                Command::None => unreachable!(),
+            },
+        }
+    }
+
+    fn flush(&mut self, force: bool) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        if !force {
+            self.idle_frames += 1;
+
+            if self.idle_frames < FLUSH_DELAY_FRAMES {
+                return Ok(());
+            }
+        }
+
+        self.save()
+    }
+}
+
+impl Drop for MemoryCard {
+    /// Last chance to save, for frontends that didn't force a flush
+    fn drop(&mut self) {
+        if self.dirty {
+            if let Err(e) = self.save() {
+                println!("{}", e);
+            }
+        }
+    }
+}
+
+/// Replace `path` with `data` without leaving a half written file behind
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, path)?;
+
+    Ok(())
+}
+
+/// Checksum stored in the last byte of the header and directory sectors
+pub fn sector_checksum(sector: &[u8]) -> u8 {
+    sector[..SECTOR_SIZE - 1].iter().fold(0, |c, &b| c ^ b)
+}
+
+/// Blank card as produced by the BIOS memory card manager
+pub fn formatted_image() -> Vec<u8> {
+    let mut data = vec![0; CARD_SIZE];
+
+    let mut set_sector = |index: usize, f: &dyn Fn(&mut [u8])| {
+        let sector = &mut data[index * SECTOR_SIZE..(index + 1) * SECTOR_SIZE];
+        f(sector);
+        sector[SECTOR_SIZE - 1] = sector_checksum(sector);
+    };
+
+    // Header
+    set_sector(0, &|s| s[..2].copy_from_slice(b"MC"));
+
+    // Free directory entries
+    for i in 1..16 {
+        set_sector(i, &|s| {
+            s[0] = 0xa0;
+            s[8..10].copy_from_slice(&[0xff, 0xff]);
+        });
+    }
+
+    // Empty broken sector list
+    for i in 16..36 {
+        set_sector(i, &|s| {
+            s[..4].copy_from_slice(&[0xff; 4]);
+            s[8..10].copy_from_slice(&[0xff, 0xff]);
+        });
+    }
+
+    // Write test sector, copy of the header
+    set_sector(63, &|s| s[..2].copy_from_slice(b"MC"));
+
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run a whole write command for `sector`
+    fn write_sector(card: &mut MemoryCard, sector: u16, data: &[u8; SECTOR_SIZE]) -> u8 {
+        let [msb, lsb] = sector.to_be_bytes();
+        let checksum = data.iter().fold(msb ^ lsb, |c, &b| c ^ b);
+
+        card.select();
+
+        let mut tx = vec![0x81, b'W', 0, 0, msb, lsb];
+        tx.extend_from_slice(data);
+        tx.extend_from_slice(&[checksum, 0, 0, 0]);
+
+        tx.iter().map(|&b| card.exchange(b).data).last().unwrap()
+    }
+
+    #[test]
+    fn saves_once_the_guest_stops_writing() {
+        let path = std::env::temp_dir().join(format!("psx-rust-card-{}.mcr", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut card = MemoryCard::open(&path).unwrap();
+
+        assert_eq!(write_sector(&mut card, 0x40, &[0x55; SECTOR_SIZE]), STATUS_GOOD);
+
+        let saved = |offset| fs::read(&path).unwrap()[offset];
+
+        for _ in 1..FLUSH_DELAY_FRAMES {
+            card.flush(false).unwrap();
+        }
+        assert_eq!(saved(0x40 * SECTOR_SIZE), 0);
+
+        card.flush(false).unwrap();
+        assert_eq!(saved(0x40 * SECTOR_SIZE), 0x55);
+
+        // Forced flushes don't wait, dropping the card saves it too
+        write_sector(&mut card, 0x41, &[0xaa; SECTOR_SIZE]);
+        card.flush(true).unwrap();
+        assert_eq!(saved(0x41 * SECTOR_SIZE), 0xaa);
+
+        write_sector(&mut card, 0x42, &[0x33; SECTOR_SIZE]);
+        drop(card);
+        assert_eq!(saved(0x42 * SECTOR_SIZE), 0x33);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,554 @@
+mod dualshock;
+mod gamepad;
+mod guncon;
+pub mod memory_card;
+mod mouse;
+mod multitap;
+
+pub use dualshock::DualShock;
+pub use gamepad::{Button, DigitalPad};
+pub use guncon::{GunButton, GunCon};
+pub use memory_card::MemoryCard;
+pub use mouse::{Mouse, MouseButton};
+pub use multitap::Multitap;
+
+use anyhow::Result;
+
+use super::gpu::Display;
+
+/// Cycles between the end of a byte transfer and the device pulling /ACK low
+const ACK_DELAY: u32 = 340;
+
+/// Cycles the /ACK line stays low
+const ACK_PULSE: u32 = 100;
+
+/// Device plugged into one of the SIO0 ports
+pub trait Peripheral {
+    /// The select line of the port went active, a new command begins
+    fn select(&mut self);
+
+    /// Exchange one byte. The first byte of each command is the address
+    /// (0x01 for controllers, 0x81 for memory cards); devices that aren't
+    /// addressed must leave the bus alone
+    fn exchange(&mut self, tx: u8) -> Response;
+
+    /// Host side button state, ignored by devices without buttons
+    fn set_button_state(&mut self, _button: Button, _pressed: bool) {}
+
+    /// Host side analog stick position, ignored by digital devices
+    fn set_axis_state(&mut self, _axis: Axis, _value: u8) {}
+
+    /// Host side press of the analog mode button
+    fn press_analog_button(&mut self) {}
+
+    /// Motor output for devices with rumble
+    fn rumble(&self) -> Option<Rumble> {
+        None
+    }
+
+    /// Host side mouse movement since the last call
+    fn move_mouse(&mut self, _dx: i32, _dy: i32) {}
+
+    fn set_mouse_button(&mut self, _button: MouseButton, _pressed: bool) {}
+
+    /// Host side light gun aim as a position in the displayed picture, 0.0
+    /// to 1.0 on both axes. None when aiming off-screen
+    fn set_aim(&mut self, _aim: Option<(f32, f32)>) {}
+
+    fn set_gun_button(&mut self, _button: GunButton, _pressed: bool) {}
+
+    /// The GPU display configuration changed
+    fn set_display(&mut self, _display: Display) {}
+
+    /// Save pending changes to the host, called once per frame. Devices may
+    /// wait for the guest to stop writing unless `force` is set
+    fn flush(&mut self, _force: bool) -> Result<()> {
+        Ok(())
+    }
+
+    /// Slot `slot` of a multitap, used to reach the devices plugged behind it
+    fn sub_port(&mut self, _slot: usize) -> Option<&mut Port> {
+        None
+    }
+}
+
+/// Analog stick axes, in the order they are sent by the controller
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Axis {
+    RightX = 0,
+    RightY = 1,
+    LeftX  = 2,
+    LeftY  = 3,
+}
+
+/// Vibration motors state
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Rumble {
+    /// Small motor, only on (0xff) or off (0x00)
+    pub small: u8,
+    /// Large motor speed
+    pub large: u8,
+}
+
+/// What a device drives on the bus for a single byte exchange
+#[derive(Clone, Copy)]
+pub struct Response {
+    /// Received byte, the data line is pulled up so 0xff means "nothing"
+    pub data: u8,
+    /// True if the device pulls /ACK to request the next byte
+    pub ack: bool,
+}
+
+impl Response {
+    /// Bus left floating by a device that isn't addressed
+    pub const HIGH_Z: Response = Response { data: 0xff, ack: false };
+
+    pub fn new(data: u8, ack: bool) -> Response {
+        Response { data, ack }
+    }
+}
+
+/// One of the two physical ports, with a controller and a memory card slot
+/// sharing the same lines
+pub struct Port {
+    controller: Option<Box<dyn Peripheral>>,
+    memory_card: Option<Box<dyn Peripheral>>,
+}
+
+impl Port {
+    fn new() -> Port {
+        Port {
+            controller: None,
+            memory_card: None,
+        }
+    }
+
+    pub fn set_controller(&mut self, device: Option<Box<dyn Peripheral>>) {
+        self.controller = device;
+    }
+
+    pub fn set_memory_card(&mut self, device: Option<Box<dyn Peripheral>>) {
+        self.memory_card = device;
+    }
+
+    fn devices(&mut self) -> impl Iterator<Item = &mut Box<dyn Peripheral>> {
+        self.controller.iter_mut().chain(self.memory_card.iter_mut())
+    }
+
+    fn select(&mut self) {
+        for d in self.devices() {
+            d.select();
+        }
+    }
+
+    /// The data lines are open drain: every device sees the byte and the
+    /// responses are ANDed together
+    fn exchange(&mut self, tx: u8) -> Response {
+        self.devices().fold(Response::HIGH_Z, |r, d| {
+            let dr = d.exchange(tx);
+
+            Response::new(r.data & dr.data, r.ack || dr.ack)
+        })
+    }
+
+    /// Forward host input to the devices plugged in this port
+    pub fn set_button_state(&mut self, button: Button, pressed: bool) {
+        for d in self.devices() {
+            d.set_button_state(button, pressed);
+        }
+    }
+
+    pub fn set_axis_state(&mut self, axis: Axis, value: u8) {
+        for d in self.devices() {
+            d.set_axis_state(axis, value);
+        }
+    }
+
+    pub fn press_analog_button(&mut self) {
+        for d in self.devices() {
+            d.press_analog_button();
+        }
+    }
+
+    pub fn move_mouse(&mut self, dx: i32, dy: i32) {
+        for d in self.devices() {
+            d.move_mouse(dx, dy);
+        }
+    }
+
+    pub fn set_mouse_button(&mut self, button: MouseButton, pressed: bool) {
+        for d in self.devices() {
+            d.set_mouse_button(button, pressed);
+        }
+    }
+
+    pub fn set_aim(&mut self, aim: Option<(f32, f32)>) {
+        for d in self.devices() {
+            d.set_aim(aim);
+        }
+    }
+
+    pub fn set_gun_button(&mut self, button: GunButton, pressed: bool) {
+        for d in self.devices() {
+            d.set_gun_button(button, pressed);
+        }
+    }
+
+    fn set_display(&mut self, display: Display) {
+        for d in self.devices() {
+            d.set_display(display);
+        }
+    }
+
+    /// Save the memory cards plugged in this port, or behind a multitap
+    pub fn flush(&mut self, force: bool) -> Result<()> {
+        for d in self.devices() {
+            d.flush(force)?;
+        }
+
+        Ok(())
+    }
+
+    /// Motor output of the controller, if it has any
+    pub fn rumble(&self) -> Option<Rumble> {
+        self.controller.as_ref().and_then(|d| d.rumble())
+    }
+
+    /// Run a whole controller read command (0x01, 0x42) without going
+    /// through the serial interface, as done by the HLE BIOS pad driver.
+    /// Returns the ID byte, 0x5a and the controller data, None when no
+    /// controller answers
+    pub fn poll_controller(&mut self) -> Option<Vec<u8>> {
+        self.select();
+
+        if !self.exchange(0x01).ack {
+            return None;
+        }
+
+        let mut rx = Vec::new();
+        let mut tx = 0x42;
+
+        loop {
+            let r = self.exchange(tx);
+            rx.push(r.data);
+
+            if !r.ack {
+                return Some(rx);
+            }
+
+            tx = 0x00;
+        }
+    }
+
+    /// Slot of the multitap plugged in this port
+    pub fn sub_port(&mut self, slot: usize) -> Option<&mut Port> {
+        self.controller.as_mut().and_then(|d| d.sub_port(slot))
+    }
+}
+
+/// Controller and memory card serial interface (JOY registers)
+pub struct Sio0 {
+    ports: [Port; 2],
+
+    /// Control register - offset 0xa
+    control: u16,
+    /// Mode register - offset 0x8
+    mode: u16,
+    /// Baudrate reload value - offset 0xe
+    baud: u16,
+    /// Received byte, the RX FIFO is only emulated one byte deep
+    rx: Option<u8>,
+    /// IRQ request - Status bit 9
+    irq: bool,
+    /// /ACK input level, true when pulled low - Status bit 7
+    ack: bool,
+    /// Transfer or acknowledge sequence in progress
+    state: State,
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Idle,
+    /// Byte being shifted out, the response is latched at the end
+    Transfer { cycles: u32, response: Response },
+    /// Waiting for the device to pull /ACK
+    AckDelay { cycles: u32 },
+    /// /ACK is held low
+    AckPulse { cycles: u32 },
+}
+
+impl Sio0 {
+    pub fn new() -> Self {
+        Sio0 {
+            ports: [Port::new(), Port::new()],
+            control: 0,
+            mode: 0,
+            baud: 0,
+            rx: None,
+            irq: false,
+            ack: false,
+            state: State::Idle,
+        }
+    }
+
+    pub fn port_mut(&mut self, port: usize) -> &mut Port {
+        &mut self.ports[port]
+    }
+
+    pub fn set_display(&mut self, display: Display) {
+        for port in &mut self.ports {
+            port.set_display(display);
+        }
+    }
+
+    /// Advance the serial state machine, returns true when IRQ7 must be raised
+    pub fn tick(&mut self, cycles: u32) -> bool {
+        let mut raise = false;
+        let mut cycles = cycles;
+
+        while cycles > 0 {
+            match self.state {
+                State::Idle => break,
+                State::Transfer { cycles: c, response } => {
+                    if c > cycles {
+                        self.state = State::Transfer { cycles: c - cycles, response };
+                        break;
+                    }
+                    cycles -= c;
+
+                    self.rx = Some(response.data);
+
+                    self.state = match response.ack {
+                        true => State::AckDelay { cycles: ACK_DELAY },
+                        false => State::Idle,
+                    };
+                }
+                State::AckDelay { cycles: c } => {
+                    if c > cycles {
+                        self.state = State::AckDelay { cycles: c - cycles };
+                        break;
+                    }
+                    cycles -= c;
+
+                    self.ack = true;
+
+                    // Control bit 12: ACK interrupt enable
+                    if self.control & (1 << 12) != 0 && !self.irq {
+                        self.irq = true;
+                        raise = true;
+                    }
+
+                    self.state = State::AckPulse { cycles: ACK_PULSE };
+                }
+                State::AckPulse { cycles: c } => {
+                    if c > cycles {
+                        self.state = State::AckPulse { cycles: c - cycles };
+                        break;
+                    }
+                    cycles -= c;
+
+                    self.ack = false;
+                    self.state = State::Idle;
+                }
+            }
+        }
+
+        raise
+    }
+
+    /// JOY_DATA write, starts a byte transfer
+    pub fn write_data(&mut self, val: u8) {
+        if let State::Transfer { .. } = self.state {
+            println!("SIO0 write while a transfer is in progress");
+        }
+
+        // Control bit 0: TX enable, bit 1: /JOYn select
+        let response = if self.control & 3 == 3 {
+            self.ports[self.selected_port()].exchange(val)
+        } else {
+            Response::HIGH_Z
+        };
+
+        self.state = State::Transfer {
+            cycles: self.transfer_cycles(),
+            response,
+        };
+    }
+
+    /// JOY_DATA read, pops the received byte
+    pub fn read_data(&mut self) -> u8 {
+        self.rx.take().unwrap_or(0xff)
+    }
+
+    /// JOY_STAT register
+    pub fn status(&self) -> u32 {
+        let mut r: u32 = 0;
+
+        let transferring = matches!(self.state, State::Transfer { .. });
+
+        // TX ready flags 1 and 2
+        r |= (!transferring as u32) | ((!transferring as u32) << 2);
+        r |= (self.rx.is_some() as u32) << 1;
+        r |= (self.ack as u32) << 7;
+        r |= (self.irq as u32) << 9;
+
+        r
+    }
+
+    pub fn mode(&self) -> u16 {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, val: u16) {
+        self.mode = val;
+    }
+
+    pub fn control(&self) -> u16 {
+        self.control
+    }
+
+    /// JOY_CTRL write
+    pub fn set_control(&mut self, val: u16) {
+        let was_selected = self.control & 2 != 0;
+
+        // Bit 6: reset, bit 4: acknowledge
+        if val & (1 << 6) != 0 {
+            self.reset();
+            return;
+        }
+
+        if val & (1 << 4) != 0 {
+            self.irq = false;
+        }
+
+        // The acknowledge and reset bits aren't stored
+        self.control = val & !((1 << 4) | (1 << 6));
+
+        let selected = self.control & 2 != 0;
+
+        if selected && !was_selected {
+            self.ports[self.selected_port()].select();
+        }
+    }
+
+    pub fn baud(&self) -> u16 {
+        self.baud
+    }
+
+    pub fn set_baud(&mut self, val: u16) {
+        self.baud = val;
+    }
+
+    /// Control bit 13: port selection
+    fn selected_port(&self) -> usize {
+        ((self.control >> 13) & 1) as usize
+    }
+
+    /// Time to shift 8 bits at the configured baudrate
+    fn transfer_cycles(&self) -> u32 {
+        let factor = match self.mode & 3 {
+            2 => 16,
+            3 => 64,
+            _ => 1,
+        };
+
+        (self.baud as u32 * factor * 8).max(1)
+    }
+
+    fn reset(&mut self) {
+        self.control = 0;
+        self.mode = 0;
+        self.baud = 0;
+        self.rx = None;
+        self.irq = false;
+        self.ack = false;
+        self.state = State::Idle;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Baudrate reload value of the BIOS pad driver, with a x1 factor
+    const BAUD: u16 = 0x88;
+    const TRANSFER: u32 = BAUD as u32 * 8;
+
+    /// TX enable, port 1 selected, ACK interrupt enabled
+    const CONTROL: u16 = 0x1003;
+
+    fn sio0_with_pad() -> Sio0 {
+        let mut sio0 = Sio0::new();
+
+        sio0.port_mut(0).set_controller(Some(Box::new(DigitalPad::new())));
+        sio0.set_mode(0x0d);
+        sio0.set_baud(BAUD);
+        sio0.set_control(CONTROL);
+        sio0
+    }
+
+    #[test]
+    fn acknowledged_bytes_raise_irq7() {
+        let mut sio0 = sio0_with_pad();
+
+        sio0.write_data(0x01);
+        assert_eq!(sio0.status() & 7, 0);
+
+        assert!(!sio0.tick(TRANSFER - 1));
+        assert_eq!(sio0.status() & 2, 0);
+        assert!(!sio0.tick(1));
+        // TX ready and RX not empty
+        assert_eq!(sio0.status() & 7, 7);
+
+        assert!(!sio0.tick(ACK_DELAY - 1));
+        assert!(sio0.tick(1));
+        assert_eq!(sio0.status() & (1 << 7 | 1 << 9), 1 << 7 | 1 << 9);
+
+        // /ACK goes back up, the IRQ stays until acknowledged
+        sio0.tick(ACK_PULSE);
+        assert_eq!(sio0.status() & (1 << 7 | 1 << 9), 1 << 9);
+        assert_eq!(sio0.read_data(), 0xff);
+        assert_eq!(sio0.status() & 2, 0);
+
+        sio0.set_control(CONTROL | 1 << 4);
+        assert_eq!(sio0.status() & (1 << 9), 0);
+    }
+
+    #[test]
+    fn reads_the_digital_pad() {
+        let mut sio0 = sio0_with_pad();
+
+        sio0.port_mut(0).set_button_state(Button::Circle, true);
+
+        let mut rx = Vec::new();
+        let mut irqs = 0;
+
+        for tx in [0x01, 0x42, 0x00, 0x00, 0x00] {
+            sio0.write_data(tx);
+            irqs += sio0.tick(TRANSFER + ACK_DELAY + ACK_PULSE) as u32;
+            rx.push(sio0.read_data());
+            sio0.set_control(CONTROL | 1 << 4);
+        }
+
+        assert_eq!(rx, [0xff, 0x41, 0x5a, 0xff, 0xdf]);
+        // No ACK after the last byte
+        assert_eq!(irqs, 4);
+    }
+
+    #[test]
+    fn unselected_ports_read_high_z() {
+        let mut sio0 = sio0_with_pad();
+
+        // Second port, nothing plugged
+        sio0.set_control(0);
+        sio0.set_control(CONTROL | 1 << 13);
+        sio0.write_data(0x01);
+
+        assert!(!sio0.tick(TRANSFER + ACK_DELAY));
+        assert_eq!(sio0.read_data(), 0xff);
+
+        // Reset
+        sio0.set_control(1 << 6);
+        assert_eq!((sio0.control(), sio0.mode(), sio0.baud()), (0, 0, 0));
+    }
+}
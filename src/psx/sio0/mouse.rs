@@ -0,0 +1,154 @@
+use super::{Peripheral, Response};
+
+/// Mouse buttons
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MouseButton {
+    Left,
+    Right,
+}
+
+/// SCPH-1030 mouse
+pub struct Mouse {
+    /// Button state, active low. Bits 8 and 9 always read 0
+    buttons: u16,
+    /// Movement accumulated since the last poll
+    dx: i32,
+    dy: i32,
+    /// Deltas latched at the start of the current poll
+    report: [u8; 2],
+    /// Position in the current command, None when not addressed
+    seq: Option<u8>,
+}
+
+impl Mouse {
+    /// Controller ID, sent low byte first
+    const ID: u16 = 0x5a12;
+
+    pub fn new() -> Mouse {
+        Mouse {
+            buttons: 0xfcff,
+            dx: 0,
+            dy: 0,
+            report: [0; 2],
+            seq: None,
+        }
+    }
+
+    /// Take up to one report worth of movement, the rest is sent with the
+    /// next polls
+    fn latch_deltas(&mut self) {
+        let dx = self.dx.clamp(-128, 127);
+        let dy = self.dy.clamp(-128, 127);
+
+        self.dx -= dx;
+        self.dy -= dy;
+
+        self.report = [dx as i8 as u8, dy as i8 as u8];
+    }
+}
+
+impl Peripheral for Mouse {
+    fn select(&mut self) {
+        self.seq = Some(0);
+    }
+
+    fn exchange(&mut self, tx: u8) -> Response {
+        let Some(seq) = self.seq else {
+            return Response::HIGH_Z;
+        };
+
+        self.seq = Some(seq + 1);
+
+        match seq {
+            // Address byte
+            0 if tx == 0x01 => Response::new(0xff, true),
+            // Only the read command is supported
+            1 if tx == 0x42 => {
+                self.latch_deltas();
+                Response::new(Self::ID as u8, true)
+            }
+            2 => Response::new((Self::ID >> 8) as u8, true),
+            3 => Response::new(self.buttons as u8, true),
+            4 => Response::new((self.buttons >> 8) as u8, true),
+            5 => Response::new(self.report[0], true),
+            // Last byte, no ACK
+            6 => {
+                self.seq = None;
+                Response::new(self.report[1], false)
+            }
+            _ => {
+                self.seq = None;
+                Response::HIGH_Z
+            }
+        }
+    }
+
+    fn move_mouse(&mut self, dx: i32, dy: i32) {
+        self.dx = self.dx.saturating_add(dx);
+        self.dy = self.dy.saturating_add(dy);
+    }
+
+    fn set_mouse_button(&mut self, button: MouseButton, pressed: bool) {
+        let mask = match button {
+            MouseButton::Left => 1 << 11,
+            MouseButton::Right => 1 << 10,
+        };
+
+        match pressed {
+            true => self.buttons &= !mask,
+            false => self.buttons |= mask,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn poll(mouse: &mut Mouse) -> Vec<(u8, bool)> {
+        mouse.select();
+
+        [0x01, 0x42, 0, 0, 0, 0, 0]
+            .iter()
+            .map(|&b| {
+                let r = mouse.exchange(b);
+                (r.data, r.ack)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reports_buttons_and_deltas() {
+        let mut mouse = Mouse::new();
+
+        mouse.set_mouse_button(MouseButton::Left, true);
+        mouse.move_mouse(10, -3);
+
+        assert_eq!(
+            poll(&mut mouse),
+            [(0xff, true), (0x12, true), (0x5a, true), (0xff, true), (0xf4, true), (0x0a, true), (0xfd, false)]
+        );
+
+        // Deltas are consumed by the poll
+        mouse.set_mouse_button(MouseButton::Left, false);
+        mouse.set_mouse_button(MouseButton::Right, true);
+        let rx = poll(&mut mouse);
+        assert_eq!((rx[4].0, rx[5].0, rx[6].0), (0xf8, 0, 0));
+    }
+
+    #[test]
+    fn splits_big_movements_across_polls() {
+        let mut mouse = Mouse::new();
+
+        mouse.move_mouse(300, -200);
+
+        let deltas: Vec<(u8, u8)> = (0..4)
+            .map(|_| {
+                let rx = poll(&mut mouse);
+                (rx[5].0, rx[6].0)
+            })
+            .collect();
+
+        assert_eq!(deltas, [(127, -128i8 as u8), (127, -72i8 as u8), (46, 0), (0, 0)]);
+    }
+}
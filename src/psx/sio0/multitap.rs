@@ -0,0 +1,283 @@
+use anyhow::Result;
+
+use super::{Display, Peripheral, Port, Response};
+
+/// Bytes returned for each sub-port in multitap mode: ID, 0x5a and up to
+/// 6 data bytes
+const SLOT_REPLY_LEN: u16 = 8;
+
+/// SCPH-1070 multitap, four controller and memory card slots behind a
+/// single port
+pub struct Multitap {
+    slots: [Port; 4],
+    /// Multitap mode for controller accesses, requested by the game with
+    /// the third byte of the previous poll
+    multi: bool,
+    /// Mode of the current command, latched on the address byte
+    mode: Mode,
+    /// Position in the current command, None when not addressed
+    seq: Option<u16>,
+    /// Address byte of the current command
+    address: u8,
+    /// Command byte of the current command
+    command: u8,
+}
+
+#[derive(Clone, Copy)]
+enum Mode {
+    /// Bytes are forwarded to a single slot
+    Direct(usize),
+    /// Poll of the four controllers at once
+    Multi,
+}
+
+impl Multitap {
+    pub fn new() -> Multitap {
+        Multitap {
+            slots: [Port::new(), Port::new(), Port::new(), Port::new()],
+            multi: false,
+            mode: Mode::Direct(0),
+            seq: None,
+            address: 0,
+            command: 0,
+        }
+    }
+
+    pub fn slot_mut(&mut self, slot: usize) -> &mut Port {
+        &mut self.slots[slot]
+    }
+
+    /// Controller access in multitap mode: tap ID, then 8 bytes per slot
+    fn exchange_multi(&mut self, seq: u16, tx: u8) -> Response {
+        match seq {
+            1 => {
+                self.command = tx;
+                Response::new(0x80, true)
+            }
+            2 => {
+                self.multi = self.command == 0x42 && tx == 0x01;
+                Response::new(0x5a, true)
+            }
+            _ => {
+                let index = seq - 3;
+                let slot = &mut self.slots[(index / SLOT_REPLY_LEN) as usize];
+
+                // Each controller sees a complete command of its own
+                if index.is_multiple_of(SLOT_REPLY_LEN) {
+                    slot.select();
+                    slot.exchange(0x01);
+                }
+
+                let data = slot.exchange(tx).data;
+
+                let last = index + 1 == 4 * SLOT_REPLY_LEN;
+                if last {
+                    self.seq = None;
+                }
+
+                Response::new(data, !last)
+            }
+        }
+    }
+
+    fn exchange_direct(&mut self, slot: usize, seq: u16, tx: u8) -> Response {
+        // Memory card accesses leave the mode alone
+        if self.address & 0x80 == 0 {
+            match seq {
+                1 => self.command = tx,
+                2 => self.multi = self.command == 0x42 && tx == 0x01,
+                _ => (),
+            }
+        }
+
+        let response = self.slots[slot].exchange(tx);
+
+        if !response.ack {
+            self.seq = None;
+        }
+
+        response
+    }
+}
+
+impl Peripheral for Multitap {
+    fn select(&mut self) {
+        self.seq = Some(0);
+    }
+
+    fn exchange(&mut self, tx: u8) -> Response {
+        let Some(seq) = self.seq else {
+            return Response::HIGH_Z;
+        };
+
+        self.seq = Some(seq + 1);
+
+        if seq == 0 {
+            // The low nibble of the address selects the slot, the memory
+            // cards are reached with 0x81-0x84
+            let slot = match tx {
+                0x01..=0x04 | 0x81..=0x84 => (tx & 0xf) as usize - 1,
+                _ => {
+                    self.seq = None;
+                    return Response::HIGH_Z;
+                }
+            };
+
+            self.address = tx;
+
+            self.mode = match (tx, self.multi) {
+                (0x01, true) => Mode::Multi,
+                _ => Mode::Direct(slot),
+            };
+
+            return match self.mode {
+                Mode::Multi => Response::new(0xff, true),
+                Mode::Direct(slot) => {
+                    let port = &mut self.slots[slot];
+
+                    port.select();
+                    let response = port.exchange(tx & 0xf0 | 1);
+
+                    if !response.ack {
+                        self.seq = None;
+                    }
+
+                    response
+                }
+            };
+        }
+
+        match self.mode {
+            Mode::Multi => self.exchange_multi(seq, tx),
+            Mode::Direct(slot) => self.exchange_direct(slot, seq, tx),
+        }
+    }
+
+    fn set_display(&mut self, display: Display) {
+        for slot in &mut self.slots {
+            slot.set_display(display);
+        }
+    }
+
+    fn flush(&mut self, force: bool) -> Result<()> {
+        for slot in &mut self.slots {
+            slot.flush(force)?;
+        }
+
+        Ok(())
+    }
+
+    fn sub_port(&mut self, slot: usize) -> Option<&mut Port> {
+        self.slots.get_mut(slot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psx::sio0::{Button, DigitalPad};
+    use std::{cell::RefCell, rc::Rc};
+
+    /// Device answering `reply` to every byte and recording what it got
+    struct Probe {
+        reply: u8,
+        received: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl Peripheral for Probe {
+        fn select(&mut self) {}
+
+        fn exchange(&mut self, tx: u8) -> Response {
+            self.received.borrow_mut().push(tx);
+            Response::new(self.reply, true)
+        }
+    }
+
+    fn command(tap: &mut Multitap, tx: &[u8]) -> Vec<(u8, bool)> {
+        tap.select();
+
+        tx.iter()
+            .map(|&b| {
+                let r = tap.exchange(b);
+                (r.data, r.ack)
+            })
+            .collect()
+    }
+
+    fn tap_with_pads() -> Multitap {
+        let mut tap = Multitap::new();
+
+        for slot in [0, 2] {
+            let mut pad = DigitalPad::new();
+            pad.set_button_state(if slot == 0 { Button::Cross } else { Button::Start }, true);
+            tap.slot_mut(slot).set_controller(Some(Box::new(pad)));
+        }
+
+        tap
+    }
+
+    #[test]
+    fn forwards_direct_accesses_to_a_slot() {
+        let mut tap = tap_with_pads();
+
+        assert_eq!(
+            command(&mut tap, &[0x01, 0x42, 0x00, 0x00, 0x00]),
+            [(0xff, true), (0x41, true), (0x5a, true), (0xff, true), (0xbf, false)]
+        );
+        assert_eq!(command(&mut tap, &[0x03, 0x42, 0x00, 0x00, 0x00])[3], (0xf7, true));
+        // Nothing in slot 2
+        assert_eq!(command(&mut tap, &[0x02, 0x42]), [(0xff, false), (0xff, false)]);
+        assert_eq!(command(&mut tap, &[0x05, 0x42]), [(0xff, false), (0xff, false)]);
+    }
+
+    #[test]
+    fn polls_the_four_slots_in_multitap_mode() {
+        let mut tap = tap_with_pads();
+
+        // Ask for multitap mode with the third byte
+        command(&mut tap, &[0x01, 0x42, 0x01, 0x00, 0x00]);
+
+        let mut tx = vec![0x01, 0x42, 0x00];
+        for _ in 0..4 {
+            tx.extend([0x42, 0, 0, 0, 0, 0, 0, 0]);
+        }
+
+        let rx = command(&mut tap, &tx);
+
+        assert_eq!(rx[..3], [(0xff, true), (0x80, true), (0x5a, true)]);
+
+        let data: Vec<u8> = rx[3..].iter().map(|r| r.0).collect();
+        let slot = |n: usize| &data[n * 8..n * 8 + 8];
+
+        assert_eq!(slot(0), [0x41, 0x5a, 0xff, 0xbf, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(slot(1), [0xff; 8]);
+        assert_eq!(slot(2), [0x41, 0x5a, 0xf7, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(slot(3), [0xff; 8]);
+
+        // Every byte is acknowledged but the last one
+        assert!(rx[..rx.len() - 1].iter().all(|r| r.1));
+        assert!(!rx[rx.len() - 1].1);
+
+        // The 0x00 sent in the third byte went back to single slot mode
+        assert_eq!(command(&mut tap, &[0x01, 0x42])[1], (0x41, true));
+    }
+
+    #[test]
+    fn addresses_memory_cards_by_slot() {
+        let mut tap = Multitap::new();
+        let received = Rc::new(RefCell::new(Vec::new()));
+
+        tap.slot_mut(1).set_memory_card(Some(Box::new(Probe {
+            reply: 0x5d,
+            received: received.clone(),
+        })));
+
+        // The card behind the tap sees the usual 0x81 address
+        assert_eq!(command(&mut tap, &[0x82, b'R', 0x00]), [(0x5d, true); 3]);
+        assert_eq!(*received.borrow(), [0x81, b'R', 0x00]);
+
+        // Other slots don't reach it
+        assert_eq!(command(&mut tap, &[0x81, b'R']), [(0xff, false), (0xff, false)]);
+        assert_eq!(received.borrow().len(), 3);
+    }
+}
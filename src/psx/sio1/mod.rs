@@ -0,0 +1,436 @@
+use std::collections::VecDeque;
+
+mod tcp;
+
+pub use tcp::TcpLink;
+
+/// Depth of the RX FIFO
+const RX_FIFO_DEPTH: usize = 8;
+
+/// Cycles between two polls of the link for incoming data and line changes
+const POLL_PERIOD: u32 = 2048;
+
+/// Other end of the serial cable
+pub trait Link {
+    /// Exchange pending data with the peer, called periodically
+    fn poll(&mut self);
+
+    /// Send a byte to the peer
+    fn send(&mut self, byte: u8);
+
+    /// Byte received from the peer, if any
+    fn receive(&mut self) -> Option<u8>;
+
+    /// Drive our DTR and RTS outputs, wired to the peer's DSR and CTS
+    fn set_outputs(&mut self, dtr: bool, rts: bool);
+
+    /// DSR and CTS inputs
+    fn inputs(&self) -> (bool, bool);
+}
+
+/// Serial port used by the link cable (SIO registers)
+pub struct Sio1 {
+    link: Option<Box<dyn Link>>,
+
+    /// Control register - offset 0xa
+    control: u16,
+    /// Mode register - offset 0x8
+    mode: u16,
+    /// Misc register - offset 0xc
+    misc: u16,
+    /// Baudrate reload value - offset 0xe
+    baud: u16,
+
+    rx: VecDeque<u8>,
+    /// Byte being shifted out and remaining cycles
+    tx: Option<(u8, u32)>,
+    /// Byte written while another one is being sent
+    tx_pending: Option<u8>,
+    /// RX FIFO overrun - Status bit 4
+    overrun: bool,
+    /// IRQ request - Status bit 9
+    irq: bool,
+    /// DSR and CTS input levels - Status bits 7 and 8
+    dsr: bool,
+    cts: bool,
+    /// Cycles until the next link poll
+    poll_cycles: u32,
+}
+
+impl Sio1 {
+    pub fn new(link: Option<Box<dyn Link>>) -> Sio1 {
+        Sio1 {
+            link,
+            control: 0,
+            mode: 0,
+            misc: 0,
+            baud: 0,
+            rx: VecDeque::new(),
+            tx: None,
+            tx_pending: None,
+            overrun: false,
+            irq: false,
+            dsr: false,
+            cts: false,
+            poll_cycles: 0,
+        }
+    }
+
+    /// Advance the transfers, returns true when IRQ8 must be raised
+    pub fn tick(&mut self, cycles: u32) -> bool {
+        let mut raise = false;
+
+        if let Some((byte, c)) = self.tx {
+            if c > cycles {
+                self.tx = Some((byte, c - cycles));
+            } else {
+                if let Some(link) = &mut self.link {
+                    link.send(byte);
+                }
+
+                self.tx = None;
+                self.start_pending();
+
+                // Control bit 10: TX interrupt enable
+                raise |= self.control & (1 << 10) != 0;
+            }
+        }
+
+        if self.poll_cycles > cycles {
+            self.poll_cycles -= cycles;
+        } else {
+            self.poll_cycles = POLL_PERIOD;
+            raise |= self.poll();
+        }
+
+        if raise && !self.irq {
+            self.irq = true;
+            return true;
+        }
+
+        false
+    }
+
+    /// Fetch incoming data and input line changes from the link
+    fn poll(&mut self) -> bool {
+        let Some(link) = &mut self.link else {
+            return false;
+        };
+
+        let mut raise = false;
+
+        link.poll();
+
+        let (dsr, cts) = link.inputs();
+
+        // Control bit 12: DSR interrupt enable
+        if dsr && !self.dsr && self.control & (1 << 12) != 0 {
+            raise = true;
+        }
+
+        self.dsr = dsr;
+        self.cts = cts;
+
+        // Control bit 2: RX enable
+        let rx_enabled = self.control & (1 << 2) != 0;
+
+        while let Some(byte) = link.receive() {
+            if !rx_enabled {
+                continue;
+            }
+
+            if self.rx.len() == RX_FIFO_DEPTH {
+                self.overrun = true;
+                continue;
+            }
+
+            self.rx.push_back(byte);
+
+            // Control bits 8-9: RX interrupt after 1, 2, 4 or 8 bytes.
+            // Control bit 11: RX interrupt enable
+            let threshold = 1 << ((self.control >> 8) & 3);
+            if self.control & (1 << 11) != 0 && self.rx.len() == threshold {
+                raise = true;
+            }
+        }
+
+        if self.tx.is_none() {
+            self.start_pending();
+        }
+
+        raise
+    }
+
+    /// Start sending the byte waiting in the TX buffer, once the peer is
+    /// ready to receive it
+    fn start_pending(&mut self) {
+        // Control bit 0: TX enable. The transmitter waits for CTS
+        if self.control & 1 == 0 || !self.cts {
+            return;
+        }
+
+        if let Some(byte) = self.tx_pending.take() {
+            self.tx = Some((byte, self.transfer_cycles()));
+        }
+    }
+
+    /// SIO_TX_DATA write
+    pub fn write_data(&mut self, val: u8) {
+        if self.tx_pending.is_some() {
+            println!("SIO1 write while the TX buffer is full");
+        }
+
+        self.tx_pending = Some(val);
+
+        if self.tx.is_none() {
+            self.start_pending();
+        }
+    }
+
+    /// SIO_RX_DATA read, pops the RX FIFO
+    pub fn read_data(&mut self) -> u8 {
+        self.rx.pop_front().unwrap_or(0)
+    }
+
+    /// SIO_STAT register
+    pub fn status(&self) -> u32 {
+        let mut r: u32 = 0;
+
+        r |= self.tx_pending.is_none() as u32;
+        r |= (!self.rx.is_empty() as u32) << 1;
+        r |= ((self.tx.is_none() && self.tx_pending.is_none()) as u32) << 2;
+        r |= (self.overrun as u32) << 4;
+        r |= (self.dsr as u32) << 7;
+        r |= (self.cts as u32) << 8;
+        r |= (self.irq as u32) << 9;
+
+        r
+    }
+
+    pub fn mode(&self) -> u16 {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, val: u16) {
+        self.mode = val;
+    }
+
+    pub fn control(&self) -> u16 {
+        self.control
+    }
+
+    /// SIO_CTRL write
+    pub fn set_control(&mut self, val: u16) {
+        // Bit 6: reset, bit 4: acknowledge
+        if val & (1 << 6) != 0 {
+            self.reset();
+            return;
+        }
+
+        if val & (1 << 4) != 0 {
+            self.irq = false;
+            self.overrun = false;
+        }
+
+        // The acknowledge and reset bits aren't stored
+        self.control = val & !((1 << 4) | (1 << 6));
+
+        // Disabling the receiver flushes the FIFO
+        if self.control & (1 << 2) == 0 {
+            self.rx.clear();
+        }
+
+        // Bit 1: DTR, bit 5: RTS
+        let dtr = self.control & (1 << 1) != 0;
+        let rts = self.control & (1 << 5) != 0;
+
+        if let Some(link) = &mut self.link {
+            link.set_outputs(dtr, rts);
+        }
+
+        if self.tx.is_none() {
+            self.start_pending();
+        }
+    }
+
+    pub fn misc(&self) -> u16 {
+        self.misc
+    }
+
+    pub fn set_misc(&mut self, val: u16) {
+        self.misc = val;
+    }
+
+    pub fn baud(&self) -> u16 {
+        self.baud
+    }
+
+    pub fn set_baud(&mut self, val: u16) {
+        self.baud = val;
+    }
+
+    /// Time to shift a character with its start, parity and stop bits
+    fn transfer_cycles(&self) -> u32 {
+        let factor = match self.mode & 3 {
+            2 => 16,
+            3 => 64,
+            _ => 1,
+        };
+
+        // Bits 2-3: character length (5-8 bits), bit 4: parity enable,
+        // bits 6-7: stop bits (reserved acting as 1, 1, 1.5, 2). Counted in
+        // half bits for the 1.5 setting
+        let data_bits = 5 + ((self.mode >> 2) & 3) as u32;
+        let parity = ((self.mode >> 4) & 1) as u32;
+        let stop_half_bits = match (self.mode >> 6) & 3 {
+            0 | 1 => 2,
+            2 => 3,
+            _ => 4,
+        };
+
+        let bit_cycles = ((self.baud as u32 * factor) & !1).max(factor);
+
+        bit_cycles * (2 * (1 + data_bits + parity) + stop_half_bits) / 2
+    }
+
+    fn reset(&mut self) {
+        self.control = 0;
+        self.mode = 0;
+        self.misc = 0;
+        self.baud = 0;
+        self.rx.clear();
+        self.tx = None;
+        self.tx_pending = None;
+        self.overrun = false;
+        self.irq = false;
+
+        if let Some(link) = &mut self.link {
+            link.set_outputs(false, false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    #[derive(Default)]
+    struct Peer {
+        to_console: VecDeque<u8>,
+        from_console: Vec<u8>,
+        /// Our DTR and RTS
+        outputs: (bool, bool),
+        /// Our DSR and CTS
+        inputs: (bool, bool),
+    }
+
+    struct FakeLink(Rc<RefCell<Peer>>);
+
+    impl Link for FakeLink {
+        fn poll(&mut self) {}
+
+        fn send(&mut self, byte: u8) {
+            self.0.borrow_mut().from_console.push(byte);
+        }
+
+        fn receive(&mut self) -> Option<u8> {
+            self.0.borrow_mut().to_console.pop_front()
+        }
+
+        fn set_outputs(&mut self, dtr: bool, rts: bool) {
+            self.0.borrow_mut().outputs = (dtr, rts);
+        }
+
+        fn inputs(&self) -> (bool, bool) {
+            self.0.borrow().inputs
+        }
+    }
+
+    fn sio1() -> (Sio1, Rc<RefCell<Peer>>) {
+        let peer = Rc::new(RefCell::new(Peer::default()));
+        let sio1 = Sio1::new(Some(Box::new(FakeLink(peer.clone()))));
+
+        (sio1, peer)
+    }
+
+    #[test]
+    fn character_time_counts_every_bit() {
+        let mut sio1 = Sio1::new(None);
+
+        // x16 factor, bit time of 512 cycles
+        sio1.set_baud(0x20);
+
+        // 8 data bits, no parity, start bit and stop bits
+        let time = |sio1: &mut Sio1, mode: u16| {
+            sio1.set_mode(mode);
+            sio1.transfer_cycles()
+        };
+
+        assert_eq!(time(&mut sio1, 0x0e), 512 * 10);
+        assert_eq!(time(&mut sio1, 0x4e), 512 * 10);
+        assert_eq!(time(&mut sio1, 0x8e), 512 * 21 / 2);
+        assert_eq!(time(&mut sio1, 0xce), 512 * 11);
+        // 5 data bits with parity
+        assert_eq!(time(&mut sio1, 0x52), 512 * 8);
+    }
+
+    #[test]
+    fn sends_once_the_peer_is_ready() {
+        let (mut sio1, peer) = sio1();
+
+        sio1.set_mode(0x4e);
+        sio1.set_baud(0x20);
+        // TX enable, DTR, RTS and TX interrupt
+        sio1.set_control(1 | 1 << 1 | 1 << 5 | 1 << 10);
+        assert_eq!(peer.borrow().outputs, (true, true));
+
+        sio1.write_data(0x42);
+        // The byte waits for CTS in the TX buffer
+        assert_eq!(sio1.status() & 5, 0);
+
+        peer.borrow_mut().inputs = (true, true);
+        sio1.tick(POLL_PERIOD);
+        assert_eq!(sio1.status() & (1 << 7 | 1 << 8), 1 << 7 | 1 << 8);
+        assert_eq!(sio1.status() & 5, 1);
+
+        assert!(!sio1.tick(5119));
+        assert!(sio1.tick(1));
+        assert_eq!(peer.borrow().from_console, [0x42]);
+        assert_eq!(sio1.status() & (1 | 1 << 2 | 1 << 9), 1 | 1 << 2 | 1 << 9);
+
+        sio1.set_control(1 | 1 << 4);
+        assert_eq!(sio1.status() & (1 << 9), 0);
+        assert_eq!(peer.borrow().outputs, (false, false));
+    }
+
+    #[test]
+    fn receives_into_the_fifo() {
+        let (mut sio1, peer) = sio1();
+
+        // RX enable, interrupt after 2 bytes
+        sio1.set_control(1 << 2 | 1 << 8 | 1 << 11);
+
+        peer.borrow_mut().to_console.extend([1]);
+        assert!(!sio1.tick(POLL_PERIOD));
+        peer.borrow_mut().to_console.extend([2]);
+        assert!(sio1.tick(POLL_PERIOD));
+
+        assert_eq!(sio1.status() & 2, 2);
+        assert_eq!((sio1.read_data(), sio1.read_data()), (1, 2));
+        assert_eq!(sio1.status() & 2, 0);
+
+        // 8 bytes deep
+        peer.borrow_mut().to_console.extend(0..10);
+        sio1.tick(POLL_PERIOD);
+        assert_eq!(sio1.status() & (1 << 4), 1 << 4);
+        assert_eq!((0..9).map(|_| sio1.read_data()).collect::<Vec<_>>(), [0, 1, 2, 3, 4, 5, 6, 7, 0]);
+
+        sio1.set_control(1 << 2 | 1 << 4);
+        assert_eq!(sio1.status() & (1 << 4 | 1 << 9), 0);
+
+        // Reset
+        sio1.set_control(1 << 6);
+        assert_eq!((sio1.control(), sio1.mode(), sio1.baud()), (0, 0, 0));
+    }
+}
@@ -0,0 +1,259 @@
+use anyhow::{anyhow, Result};
+use std::{
+    collections::VecDeque,
+    io::{ErrorKind, Read, Write},
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+};
+
+use super::Link;
+
+/// Message carrying a data byte
+const MSG_DATA: u8 = 0;
+/// Message carrying the sender's DTR (bit 0) and RTS (bit 1) outputs
+const MSG_LINES: u8 = 1;
+
+/// Polls between two connection attempts while the peer isn't there
+const CONNECT_RETRY_POLLS: u32 = 512;
+
+/// Link cable tunneled through a TCP connection to another emulator
+/// instance on the same machine. Each byte and each change of the control
+/// lines is sent as a two bytes message
+pub struct TcpLink {
+    /// Set for the instance waiting for its peer
+    listener: Option<TcpListener>,
+    /// Set for the instance connecting to its peer
+    peer: Option<SocketAddr>,
+    stream: Option<TcpStream>,
+    /// Polls left before the next connection attempt
+    retry: u32,
+
+    /// Bytes waiting to be written to the socket
+    out: Vec<u8>,
+    /// Incomplete message read from the socket
+    partial: Option<u8>,
+    received: VecDeque<u8>,
+
+    /// Our DTR and RTS outputs, sent again on each new connection
+    outputs: u8,
+    /// Peer outputs, seen as our DSR and CTS inputs
+    inputs: u8,
+}
+
+impl TcpLink {
+    fn new(listener: Option<TcpListener>, peer: Option<SocketAddr>) -> TcpLink {
+        TcpLink {
+            listener,
+            peer,
+            stream: None,
+            retry: 0,
+            out: Vec::new(),
+            partial: None,
+            received: VecDeque::new(),
+            outputs: 0,
+            inputs: 0,
+        }
+    }
+
+    /// Wait for the other instance on localhost `port`
+    pub fn listen(port: u16) -> Result<TcpLink> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+            .map_err(|e| anyhow!("Can't listen on port {}: {}", port, e))?;
+
+        listener.set_nonblocking(true)?;
+
+        Ok(TcpLink::new(Some(listener), None))
+    }
+
+    /// Connect to the other instance on localhost `port`, retrying until it
+    /// is started
+    pub fn connect(port: u16) -> TcpLink {
+        TcpLink::new(None, Some(SocketAddr::from((Ipv4Addr::LOCALHOST, port))))
+    }
+
+    fn try_connect(&mut self) {
+        let stream = if let Some(listener) = &self.listener {
+            match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(_) => return,
+            }
+        } else if let Some(peer) = self.peer {
+            if self.retry > 0 {
+                self.retry -= 1;
+                return;
+            }
+
+            match TcpStream::connect(peer) {
+                Ok(stream) => stream,
+                Err(_) => {
+                    self.retry = CONNECT_RETRY_POLLS;
+                    return;
+                }
+            }
+        } else {
+            return;
+        };
+
+        if stream.set_nonblocking(true).is_err() {
+            return;
+        }
+        let _ = stream.set_nodelay(true);
+
+        println!("Link cable connected");
+
+        self.stream = Some(stream);
+        self.partial = None;
+        self.out.clear();
+        self.out.extend_from_slice(&[MSG_LINES, self.outputs]);
+    }
+
+    fn disconnect(&mut self) {
+        println!("Link cable disconnected");
+
+        self.stream = None;
+        self.inputs = 0;
+    }
+
+    fn flush(&mut self) {
+        let Some(stream) = &mut self.stream else {
+            return;
+        };
+
+        while !self.out.is_empty() {
+            match stream.write(&self.out) {
+                Ok(0) => return self.disconnect(),
+                Ok(n) => {
+                    self.out.drain(..n);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(_) => return self.disconnect(),
+            }
+        }
+    }
+
+    fn read(&mut self) {
+        let Some(stream) = &mut self.stream else {
+            return;
+        };
+
+        let mut buf = [0u8; 256];
+
+        loop {
+            let n = match stream.read(&mut buf) {
+                Ok(0) => return self.disconnect(),
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(_) => return self.disconnect(),
+            };
+
+            for &b in &buf[..n] {
+                match self.partial.take() {
+                    None => self.partial = Some(b),
+                    Some(MSG_DATA) => self.received.push_back(b),
+                    Some(MSG_LINES) => self.inputs = b,
+                    Some(kind) => println!("Link cable: unknown message {:02x}", kind),
+                }
+            }
+        }
+    }
+
+    fn push(&mut self, kind: u8, value: u8) {
+        // Bytes sent while the cable is unplugged are lost
+        if self.stream.is_some() {
+            self.out.extend_from_slice(&[kind, value]);
+            self.flush();
+        }
+    }
+}
+
+impl Link for TcpLink {
+    fn poll(&mut self) {
+        if self.stream.is_none() {
+            self.try_connect();
+        }
+
+        self.flush();
+        self.read();
+    }
+
+    fn send(&mut self, byte: u8) {
+        self.push(MSG_DATA, byte);
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        self.received.pop_front()
+    }
+
+    fn set_outputs(&mut self, dtr: bool, rts: bool) {
+        let outputs = dtr as u8 | (rts as u8) << 1;
+
+        if outputs != self.outputs {
+            self.outputs = outputs;
+            self.push(MSG_LINES, outputs);
+        }
+    }
+
+    fn inputs(&self) -> (bool, bool) {
+        (self.inputs & 1 != 0, self.inputs & 2 != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{thread, time::Duration};
+
+    /// Poll `link` until `done`, panics after a second
+    fn poll_until(link: &mut TcpLink, mut done: impl FnMut(&mut TcpLink) -> bool) {
+        for _ in 0..100 {
+            link.poll();
+
+            if done(link) {
+                return;
+            }
+
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        panic!("link timed out");
+    }
+
+    fn read_exact(stream: &mut TcpStream, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        stream.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn frames_bytes_and_lines_in_two_byte_messages() {
+        let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap().port();
+
+        let mut link = TcpLink::listen(port).unwrap();
+        link.set_outputs(true, false);
+
+        let mut peer = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+        poll_until(&mut link, |l| l.stream.is_some());
+
+        // The lines are sent again on connection
+        assert_eq!(read_exact(&mut peer, 2), [MSG_LINES, 1]);
+
+        link.send(0x42);
+        link.set_outputs(true, true);
+        assert_eq!(read_exact(&mut peer, 4), [MSG_DATA, 0x42, MSG_LINES, 3]);
+
+        // Messages may be split across reads
+        peer.write_all(&[MSG_DATA, 0x99, MSG_LINES]).unwrap();
+        poll_until(&mut link, |l| l.partial.is_some());
+        peer.write_all(&[2]).unwrap();
+        poll_until(&mut link, |l| l.inputs() == (false, true));
+
+        assert_eq!(link.receive(), Some(0x99));
+        assert_eq!(link.receive(), None);
+
+        // The peer going away drops its lines
+        drop(peer);
+        poll_until(&mut link, |l| l.stream.is_none());
+        assert_eq!(link.inputs(), (false, false));
+    }
+}
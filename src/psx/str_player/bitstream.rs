@@ -0,0 +1,447 @@
+use anyhow::{anyhow, Result};
+
+/// Halfword emitted at the end of each block
+const END_OF_BLOCK: u16 = 0xfe00;
+
+/// Uncompress a "BS" video frame (versions 1, 2 and 3) into the run-length
+/// halfwords expected by the MDEC decode macroblock command
+pub fn uncompress_frame(frame: &[u8], width: u16, height: u16) -> Result<Vec<u16>> {
+    if frame.len() < 8 {
+        return Err(anyhow!("Truncated frame header"));
+    }
+
+    let halfword = |o: usize| u16::from_le_bytes([frame[o], frame[o + 1]]);
+
+    let mdec_size = halfword(0) as usize;
+    let magic = halfword(2);
+    let q_scale = halfword(4);
+    let version = halfword(6);
+
+    if magic != 0x3800 {
+        return Err(anyhow!("Invalid frame magic {:04x}", magic));
+    }
+
+    if !(1..=3).contains(&version) {
+        return Err(anyhow!("Unsupported bitstream version {}", version));
+    }
+
+    let macroblocks = (width as usize).div_ceil(16) * (height as usize).div_ceil(16);
+
+    let mut reader = BitReader::new(&frame[8..]);
+    let mut out = Vec::with_capacity(mdec_size * 2);
+
+    // Cr, Cb and Y DC predictors for version 3
+    let mut dc = [0i32; 3];
+
+    for _ in 0..macroblocks {
+        for block in 0..6 {
+            let dc_value = match version {
+                3 => {
+                    let predictor = match block {
+                        0 => 0,
+                        1 => 1,
+                        _ => 2,
+                    };
+
+                    let diff = reader.read_dc_diff(predictor == 2)?;
+
+                    dc[predictor] += diff * 4;
+                    dc[predictor]
+                }
+                _ => reader.read_signed(10)?,
+            };
+
+            out.push((q_scale << 10) | (dc_value as u16 & 0x3ff));
+
+            loop {
+                match reader.read_ac()? {
+                    Ac::EndOfBlock => break,
+                    Ac::Coefficient { run, level } => {
+                        out.push(((run as u16) << 10) | (level as u16 & 0x3ff))
+                    }
+                }
+            }
+
+            out.push(END_OF_BLOCK);
+        }
+    }
+
+    // The header gives the uncompressed size in words, rounded up to 32 bytes
+    let padded = mdec_size * 2;
+    while out.len() < padded {
+        out.push(END_OF_BLOCK);
+    }
+
+    if out.len() % 2 != 0 {
+        out.push(END_OF_BLOCK);
+    }
+
+    Ok(out)
+}
+
+enum Ac {
+    EndOfBlock,
+    Coefficient { run: u32, level: i32 },
+}
+
+/// Reads bits MSB first out of little endian halfwords
+struct BitReader<'a> {
+    data: &'a [u8],
+    /// Position in bits
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader { data, pos: 0 }
+    }
+
+    fn bit(&self, pos: usize) -> Option<u32> {
+        let halfword = pos / 16;
+        let lo = *self.data.get(halfword * 2)? as u16;
+        let hi = *self.data.get(halfword * 2 + 1)? as u16;
+
+        let v = lo | (hi << 8);
+
+        Some(((v >> (15 - pos % 16)) & 1) as u32)
+    }
+
+    fn peek(&self, count: usize) -> Result<u32> {
+        let mut v = 0;
+
+        for i in 0..count {
+            let bit = self
+                .bit(self.pos + i)
+                .ok_or_else(|| anyhow!("Unexpected end of bitstream"))?;
+
+            v = (v << 1) | bit;
+        }
+
+        Ok(v)
+    }
+
+    fn read(&mut self, count: usize) -> Result<u32> {
+        let v = self.peek(count)?;
+        self.pos += count;
+        Ok(v)
+    }
+
+    fn read_signed(&mut self, count: usize) -> Result<i32> {
+        let v = self.read(count)?;
+        let shift = 32 - count;
+
+        Ok(((v << shift) as i32) >> shift)
+    }
+
+    /// Bits left before the end of the last complete halfword
+    fn remaining(&self) -> usize {
+        (self.data.len() / 2 * 16).saturating_sub(self.pos)
+    }
+
+    /// Match the next bits against a variable length code table
+    fn read_vlc<T: Copy>(&mut self, table: &[Vlc<T>]) -> Option<T> {
+        // Near the end of the stream there might be fewer bits than the
+        // longest code, pad with zeros and skip the codes that don't fit
+        let available = self.remaining().min(VLC_MAX_LEN);
+        let bits = self.peek(available).ok()? << (VLC_MAX_LEN - available);
+
+        let &(len, _, v) = table
+            .iter()
+            .find(|&&(len, code, _)| len <= available && bits >> (VLC_MAX_LEN - len) == code)?;
+
+        self.pos += len;
+
+        Some(v)
+    }
+
+    fn read_ac(&mut self) -> Result<Ac> {
+        if self.peek(2)? == 0b10 {
+            self.pos += 2;
+            return Ok(Ac::EndOfBlock);
+        }
+
+        if self.peek(6)? == 0b000001 {
+            // Escape code: 6 bits run and 10 bits signed level
+            self.pos += 6;
+            let run = self.read(6)?;
+            let level = self.read_signed(10)?;
+
+            return Ok(Ac::Coefficient { run, level });
+        }
+
+        let (run, level) = self
+            .read_vlc(AC_TABLE)
+            .ok_or_else(|| anyhow!("Invalid AC code at bit {}", self.pos))?;
+
+        let level = match self.read(1)? {
+            0 => level as i32,
+            _ => -(level as i32),
+        };
+
+        Ok(Ac::Coefficient {
+            run: run as u32,
+            level,
+        })
+    }
+
+    /// Version 3 DC differential, using the MPEG-1 DC size codes
+    fn read_dc_diff(&mut self, luma: bool) -> Result<i32> {
+        let table = match luma {
+            true => DC_LUMA_SIZE,
+            false => DC_CHROMA_SIZE,
+        };
+
+        let size = self
+            .read_vlc(table)
+            .ok_or_else(|| anyhow!("Invalid DC size code at bit {}", self.pos))?;
+
+        if size == 0 {
+            return Ok(0);
+        }
+
+        let bits = self.read(size)? as i32;
+
+        // A leading zero bit means a negative value
+        match bits & (1 << (size - 1)) {
+            0 => Ok(bits - (1 << size) + 1),
+            _ => Ok(bits),
+        }
+    }
+}
+
+/// Variable length code as (length in bits, code, value)
+type Vlc<T> = (usize, u32, T);
+
+/// Longest code of the tables
+const VLC_MAX_LEN: usize = 16;
+
+const DC_LUMA_SIZE: &[Vlc<usize>] = &[
+    (3, 0b100, 0),
+    (2, 0b00, 1),
+    (2, 0b01, 2),
+    (3, 0b101, 3),
+    (3, 0b110, 4),
+    (4, 0b1110, 5),
+    (5, 0b11110, 6),
+    (6, 0b111110, 7),
+    (7, 0b1111110, 8),
+];
+
+const DC_CHROMA_SIZE: &[Vlc<usize>] = &[
+    (2, 0b00, 0),
+    (2, 0b01, 1),
+    (2, 0b10, 2),
+    (3, 0b110, 3),
+    (4, 0b1110, 4),
+    (5, 0b11110, 5),
+    (6, 0b111110, 6),
+    (7, 0b1111110, 7),
+    (8, 0b11111110, 8),
+];
+
+/// MPEG-1 DCT coefficient codes as (run, level), sign bit excluded
+const AC_TABLE: &[Vlc<(u8, u8)>] = &[
+    (2, 0b11, (0, 1)),
+    (3, 0b011, (1, 1)),
+    (4, 0b0100, (0, 2)),
+    (4, 0b0101, (2, 1)),
+    (5, 0b00101, (0, 3)),
+    (5, 0b00111, (3, 1)),
+    (5, 0b00110, (4, 1)),
+    (6, 0b000110, (1, 2)),
+    (6, 0b000111, (5, 1)),
+    (6, 0b000101, (6, 1)),
+    (6, 0b000100, (7, 1)),
+    (7, 0b0000110, (0, 4)),
+    (7, 0b0000100, (2, 2)),
+    (7, 0b0000111, (8, 1)),
+    (7, 0b0000101, (9, 1)),
+    (8, 0b00100110, (0, 5)),
+    (8, 0b00100001, (0, 6)),
+    (8, 0b00100101, (1, 3)),
+    (8, 0b00100100, (3, 2)),
+    (8, 0b00100111, (10, 1)),
+    (8, 0b00100011, (11, 1)),
+    (8, 0b00100010, (12, 1)),
+    (8, 0b00100000, (13, 1)),
+    (10, 0b0000001010, (0, 7)),
+    (10, 0b0000001100, (1, 4)),
+    (10, 0b0000001011, (2, 3)),
+    (10, 0b0000001111, (4, 2)),
+    (10, 0b0000001001, (5, 2)),
+    (10, 0b0000001110, (14, 1)),
+    (10, 0b0000001101, (15, 1)),
+    (10, 0b0000001000, (16, 1)),
+    (12, 0b000000011101, (0, 8)),
+    (12, 0b000000011000, (0, 9)),
+    (12, 0b000000010011, (0, 10)),
+    (12, 0b000000010000, (0, 11)),
+    (12, 0b000000011011, (1, 5)),
+    (12, 0b000000010100, (2, 4)),
+    (12, 0b000000011100, (3, 3)),
+    (12, 0b000000010010, (4, 3)),
+    (12, 0b000000011110, (6, 2)),
+    (12, 0b000000010101, (7, 2)),
+    (12, 0b000000010001, (8, 2)),
+    (12, 0b000000011111, (17, 1)),
+    (12, 0b000000011010, (18, 1)),
+    (12, 0b000000011001, (19, 1)),
+    (12, 0b000000010111, (20, 1)),
+    (12, 0b000000010110, (21, 1)),
+    (13, 0b0000000011010, (0, 12)),
+    (13, 0b0000000011001, (0, 13)),
+    (13, 0b0000000011000, (0, 14)),
+    (13, 0b0000000010111, (0, 15)),
+    (13, 0b0000000010110, (1, 6)),
+    (13, 0b0000000010101, (1, 7)),
+    (13, 0b0000000010100, (2, 5)),
+    (13, 0b0000000010011, (3, 4)),
+    (13, 0b0000000010010, (5, 3)),
+    (13, 0b0000000010001, (9, 2)),
+    (13, 0b0000000010000, (10, 2)),
+    (13, 0b0000000011111, (22, 1)),
+    (13, 0b0000000011110, (23, 1)),
+    (13, 0b0000000011101, (24, 1)),
+    (13, 0b0000000011100, (25, 1)),
+    (13, 0b0000000011011, (26, 1)),
+    (14, 0b00000000011111, (0, 16)),
+    (14, 0b00000000011110, (0, 17)),
+    (14, 0b00000000011101, (0, 18)),
+    (14, 0b00000000011100, (0, 19)),
+    (14, 0b00000000011011, (0, 20)),
+    (14, 0b00000000011010, (0, 21)),
+    (14, 0b00000000011001, (0, 22)),
+    (14, 0b00000000011000, (0, 23)),
+    (14, 0b00000000010111, (0, 24)),
+    (14, 0b00000000010110, (0, 25)),
+    (14, 0b00000000010101, (0, 26)),
+    (14, 0b00000000010100, (0, 27)),
+    (14, 0b00000000010011, (0, 28)),
+    (14, 0b00000000010010, (0, 29)),
+    (14, 0b00000000010001, (0, 30)),
+    (14, 0b00000000010000, (0, 31)),
+    (15, 0b000000000011000, (0, 32)),
+    (15, 0b000000000010111, (0, 33)),
+    (15, 0b000000000010110, (0, 34)),
+    (15, 0b000000000010101, (0, 35)),
+    (15, 0b000000000010100, (0, 36)),
+    (15, 0b000000000010011, (0, 37)),
+    (15, 0b000000000010010, (0, 38)),
+    (15, 0b000000000010001, (0, 39)),
+    (15, 0b000000000010000, (0, 40)),
+    (15, 0b000000000011111, (1, 8)),
+    (15, 0b000000000011110, (1, 9)),
+    (15, 0b000000000011101, (1, 10)),
+    (15, 0b000000000011100, (1, 11)),
+    (15, 0b000000000011011, (1, 12)),
+    (15, 0b000000000011010, (1, 13)),
+    (15, 0b000000000011001, (1, 14)),
+    (16, 0b0000000000010011, (1, 15)),
+    (16, 0b0000000000010010, (1, 16)),
+    (16, 0b0000000000010001, (1, 17)),
+    (16, 0b0000000000010000, (1, 18)),
+    (16, 0b0000000000010100, (6, 3)),
+    (16, 0b0000000000011010, (11, 2)),
+    (16, 0b0000000000011001, (12, 2)),
+    (16, 0b0000000000011000, (13, 2)),
+    (16, 0b0000000000010111, (14, 2)),
+    (16, 0b0000000000010110, (15, 2)),
+    (16, 0b0000000000010101, (16, 2)),
+    (16, 0b0000000000011111, (27, 1)),
+    (16, 0b0000000000011110, (28, 1)),
+    (16, 0b0000000000011101, (29, 1)),
+    (16, 0b0000000000011100, (30, 1)),
+    (16, 0b0000000000011011, (31, 1)),
+];
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Pack a string of bits MSB first into little endian halfwords, the
+    /// way the bitstream is stored. Spaces are ignored
+    pub fn pack(bits: &str) -> Vec<u8> {
+        let bits: Vec<u16> = bits
+            .chars()
+            .filter(|&c| c != ' ')
+            .map(|c| (c == '1') as u16)
+            .collect();
+
+        bits.chunks(16)
+            .flat_map(|chunk| {
+                let h = chunk
+                    .iter()
+                    .enumerate()
+                    .fold(0u16, |h, (i, &b)| h | (b << (15 - i)));
+
+                h.to_le_bytes()
+            })
+            .collect()
+    }
+
+    /// Frame with its 8 bytes header
+    pub fn frame(mdec_size: u16, q_scale: u16, version: u16, bits: &str) -> Vec<u8> {
+        let mut frame = Vec::new();
+
+        for h in [mdec_size, 0x3800, q_scale, version] {
+            frame.extend_from_slice(&h.to_le_bytes());
+        }
+
+        frame.extend(pack(bits));
+        frame
+    }
+
+    #[test]
+    fn version_2_dc_and_ac_codes() {
+        // First block: DC 5, then run 0 level 1, run 1 level -1, an
+        // escaped run 6 level -3 and the end of block. The other 5 blocks
+        // only have a DC of -1
+        let bits = "0000000101 11 0 011 1 000001 000110 1111111101 10".to_string()
+            + &" 1111111111 10".repeat(5);
+
+        let out = uncompress_frame(&frame(10, 3, 2, &bits), 16, 16).unwrap();
+
+        let dc = |v: i32| (3 << 10) | (v as u16 & 0x3ff);
+        let mut expected = vec![dc(5), 0x0001, 0x07ff, (6 << 10) | 0x3fd, END_OF_BLOCK];
+        for _ in 0..5 {
+            expected.extend([dc(-1), END_OF_BLOCK]);
+        }
+        // Padding to the size given by the header
+        expected.extend([END_OF_BLOCK; 5]);
+
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn version_3_dc_predictors() {
+        // Cr: size 2 "10" -> +2, Cb: size 1 "0" -> -1, Y0: size 0, Y1:
+        // size 1 "1" -> +1, Y2: size 2 "01" -> -2, Y3: size 0
+        let bits = "10 10 10 01 0 10 100 10 00 1 10 01 01 10 100 10";
+
+        let out = uncompress_frame(&frame(6, 1, 3, bits), 16, 16).unwrap();
+
+        // Differences are scaled by 4 and accumulated, the luminance
+        // blocks share a predictor
+        let dcs: Vec<u16> = out.iter().step_by(2).map(|h| h & 0x3ff).collect();
+        assert_eq!(dcs, [8, 0x3fc, 0, 4, 0x3fc, 0x3fc]);
+    }
+
+    #[test]
+    fn vlc_codes_at_the_end_of_the_stream() {
+        // "11" (run 0, level 1) in the last two bits of the data
+        let data = pack("0000000000000011");
+        let mut reader = BitReader::new(&data);
+        reader.pos = 14;
+
+        assert_eq!(reader.read_vlc(AC_TABLE), Some((0, 1)));
+        assert_eq!(reader.read_vlc(AC_TABLE), None);
+    }
+
+    #[test]
+    fn rejects_invalid_frames() {
+        assert!(uncompress_frame(&[0; 4], 16, 16).is_err());
+        assert!(uncompress_frame(&frame(0, 0, 4, ""), 16, 16).is_err());
+        // Truncated bitstream
+        assert!(uncompress_frame(&frame(0, 0, 2, "0000000101"), 16, 16).is_err());
+    }
+}
@@ -0,0 +1,408 @@
+use anyhow::{anyhow, Result};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use super::disc::{Disc, SectorFormat};
+use super::mdec::{Mdec, ZIGZAG};
+
+mod bitstream;
+mod writer;
+mod xa;
+
+use xa::{Coding, XaDecoder};
+
+/// Standalone `.STR` movie player, decodes the video with the emulated MDEC
+/// and dumps PNG frames plus a WAV file of the XA audio
+pub struct Options {
+    /// Extracted `.STR` file (raw 2352 or 2336 bytes sectors) or disc image
+    pub input: PathBuf,
+    /// Path of the movie in the ISO9660 filesystem when `input` is a disc
+    pub file: Option<String>,
+    /// Directory receiving the frames and the audio track
+    pub out_dir: PathBuf,
+}
+
+/// Size of the STR header at the start of each video sector
+const STR_HEADER_SIZE: usize = 32;
+
+/// MDEC decode macroblock command, 24 bits unsigned output
+const MDEC_DECODE_24BITS: u32 = 0x30000000;
+
+/// MDEC set quantization tables command (luminance and color)
+const MDEC_SET_QUANT: u32 = 0x40000001;
+
+/// MDEC set IDCT scale table command
+const MDEC_SET_SCALE: u32 = 0x60000000;
+
+/// MPEG-1 default intra quantization matrix, with the PSX DC value
+const QUANT_TABLE: [u8; 64] = [
+     2, 16, 19, 22, 26, 27, 29, 34,
+    16, 16, 22, 24, 27, 29, 34, 37,
+    19, 22, 26, 27, 29, 34, 34, 38,
+    22, 22, 26, 27, 29, 34, 37, 40,
+    22, 26, 27, 29, 32, 35, 40, 48,
+    26, 27, 29, 32, 35, 40, 48, 58,
+    26, 27, 29, 34, 38, 46, 56, 69,
+    27, 29, 35, 38, 46, 56, 69, 83,
+];
+
+/// IDCT scale table uploaded by the PsyQ libraries
+const SCALE_TABLE: [u16; 64] = [
+    0x5a82, 0x5a82, 0x5a82, 0x5a82, 0x5a82, 0x5a82, 0x5a82, 0x5a82,
+    0x7d8a, 0x6a6d, 0x471c, 0x18f8, 0xe707, 0xb8e3, 0x9592, 0x8275,
+    0x7641, 0x30fb, 0xcf04, 0x89be, 0x89be, 0xcf04, 0x30fb, 0x7641,
+    0x6a6d, 0xe707, 0x8275, 0xb8e3, 0x471c, 0x7d8a, 0x18f8, 0x9592,
+    0x5a82, 0xa57d, 0xa57d, 0x5a82, 0x5a82, 0xa57d, 0xa57d, 0x5a82,
+    0x471c, 0x8275, 0x18f8, 0x6a6d, 0x9592, 0xe707, 0x7d8a, 0xb8e3,
+    0x30fb, 0x89be, 0x7641, 0xcf04, 0xcf04, 0x7641, 0x89be, 0x30fb,
+    0x18f8, 0xb8e3, 0x6a6d, 0x8275, 0x7d8a, 0x9592, 0x471c, 0xe707,
+];
+
+pub fn play(options: &Options) -> Result<()> {
+    let mut disc = Disc::open(&options.input)?;
+
+    if disc.format() == SectorFormat::Cooked {
+        return Err(anyhow!(
+            "{} only contains 2048 bytes sectors, STR playback needs raw sectors",
+            options.input.display()
+        ));
+    }
+
+    let (start, count) = match &options.file {
+        Some(name) => {
+            let entry = disc.find_file(name)?;
+
+            // The directory size of Form 2 files is given in 2048 bytes sectors
+            (entry.lba, entry.size.div_ceil(2048))
+        }
+        None => (0, disc.sectors()),
+    };
+
+    fs::create_dir_all(&options.out_dir)?;
+
+    let mut player = Player::new();
+
+    for lba in start..start + count {
+        let sector = disc.read_sector(lba)?;
+        let subheader = sector.subheader();
+
+        if subheader.is_audio() {
+            player.audio_sector(
+                (subheader.file, subheader.channel),
+                Coding::from_subheader(subheader.coding),
+                sector.data_form2(),
+            );
+        } else if subheader.is_video() || subheader.is_data() {
+            if let Some(frame) = player.video_sector(sector.data_form1())? {
+                let path = options
+                    .out_dir
+                    .join(format!("frame_{:05}.png", player.frames_written));
+
+                writer::write_png(&path, frame.width, frame.height, &frame.rgb)?;
+                player.frames_written += 1;
+            }
+        }
+    }
+
+    println!("Wrote {} frames to {}", player.frames_written, options.out_dir.display());
+
+    if let Some(coding) = player.coding {
+        let path = options.out_dir.join("audio.wav");
+
+        writer::write_wav(&path, coding.sample_rate, coding.channels(), &player.samples)?;
+
+        println!(
+            "Wrote {} Hz {} audio to {}",
+            coding.sample_rate,
+            if coding.stereo { "stereo" } else { "mono" },
+            path.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Decoded video frame
+struct Frame {
+    width: u32,
+    height: u32,
+    rgb: Vec<u8>,
+}
+
+/// Frame being reassembled from its sectors
+struct PendingFrame {
+    number: u32,
+    size: usize,
+    width: u16,
+    height: u16,
+    chunks: Vec<Option<Vec<u8>>>,
+}
+
+struct Player {
+    mdec: Mdec,
+    xa: XaDecoder,
+    pending: Option<PendingFrame>,
+    frames_written: u32,
+    /// XA file and channel being played, the first one found in the stream
+    audio_channel: Option<(u8, u8)>,
+    coding: Option<Coding>,
+    samples: Vec<i16>,
+}
+
+impl Player {
+    fn new() -> Self {
+        let mut mdec = Mdec::new();
+
+        // Reset, then upload the tables like the PsyQ DecDCTReset does
+        mdec.set_control(1 << 31);
+
+        let mut quant = [0u8; 64];
+        for (k, &pos) in ZIGZAG.iter().enumerate() {
+            quant[k] = QUANT_TABLE[pos];
+        }
+
+        mdec.command(MDEC_SET_QUANT);
+        for _ in 0..2 {
+            for w in quant.chunks(4) {
+                mdec.command(u32::from_le_bytes([w[0], w[1], w[2], w[3]]));
+            }
+        }
+
+        mdec.command(MDEC_SET_SCALE);
+        for h in SCALE_TABLE.chunks(2) {
+            mdec.command(h[0] as u32 | ((h[1] as u32) << 16));
+        }
+
+        Player {
+            mdec,
+            xa: XaDecoder::new(),
+            pending: None,
+            frames_written: 0,
+            audio_channel: None,
+            coding: None,
+            samples: Vec::new(),
+        }
+    }
+
+    fn audio_sector(&mut self, channel: (u8, u8), coding: Coding, data: &[u8]) {
+        let selected = *self.audio_channel.get_or_insert(channel);
+        if selected != channel {
+            return;
+        }
+
+        match self.coding {
+            None => self.coding = Some(coding),
+            Some(c) if c != coding => {
+                println!("Ignoring XA sector with a different coding: {:?}", coding);
+                return;
+            }
+            _ => (),
+        }
+
+        let samples = self.xa.decode_sector(data, coding);
+        self.samples.extend_from_slice(&samples);
+    }
+
+    /// Collect a video sector, returns the frame once all of its chunks
+    /// have been received
+    fn video_sector(&mut self, data: &[u8]) -> Result<Option<Frame>> {
+        let halfword = |o: usize| u16::from_le_bytes([data[o], data[o + 1]]);
+        let word = |o: usize| u32::from_le_bytes([data[o], data[o + 1], data[o + 2], data[o + 3]]);
+
+        // STR sectors start with a 0x0160 status and a 0x8001 type
+        if halfword(0) != 0x0160 || halfword(2) != 0x8001 {
+            return Ok(None);
+        }
+
+        let chunk = halfword(4) as usize;
+        let chunks = halfword(6) as usize;
+        let number = word(8);
+        let size = word(12) as usize;
+        let width = halfword(16);
+        let height = halfword(18);
+
+        if chunk >= chunks {
+            return Err(anyhow!("Invalid STR chunk {}/{}", chunk, chunks));
+        }
+
+        let start_new = match &self.pending {
+            // A different chunk count means the frame can't be completed
+            Some(p) => p.number != number || p.chunks.len() != chunks,
+            None => true,
+        };
+
+        if start_new {
+            if let Some(p) = &self.pending {
+                println!("Dropping incomplete frame {}", p.number);
+            }
+
+            self.pending = Some(PendingFrame {
+                number,
+                size,
+                width,
+                height,
+                chunks: vec![None; chunks],
+            });
+        }
+
+        let pending = self.pending.as_mut().unwrap();
+        pending.chunks[chunk] = Some(data[STR_HEADER_SIZE..].to_vec());
+
+        if pending.chunks.iter().any(|c| c.is_none()) {
+            return Ok(None);
+        }
+
+        let pending = self.pending.take().unwrap();
+
+        let mut bitstream: Vec<u8> = pending.chunks.into_iter().flatten().flatten().collect();
+        bitstream.truncate(pending.size);
+
+        self.decode_frame(&bitstream, pending.width, pending.height)
+            .map(Some)
+            .map_err(|e| anyhow!("Frame {}: {}", pending.number, e))
+    }
+
+    /// Send the frame through the MDEC and reassemble the macroblocks
+    fn decode_frame(&mut self, bitstream: &[u8], width: u16, height: u16) -> Result<Frame> {
+        let halfwords = bitstream::uncompress_frame(bitstream, width, height)?;
+
+        let words: Vec<u32> = halfwords
+            .chunks(2)
+            .map(|h| h[0] as u32 | ((h[1] as u32) << 16))
+            .collect();
+
+        if words.len() > 0xffff {
+            return Err(anyhow!("Frame too big for a single MDEC command"));
+        }
+
+        self.mdec.command(MDEC_DECODE_24BITS | words.len() as u32);
+        for w in words {
+            self.mdec.command(w);
+        }
+
+        let mb_w = (width as usize).div_ceil(16);
+        let mb_h = (height as usize).div_ceil(16);
+
+        let (width, height) = (width as usize, height as usize);
+        let mut rgb = vec![0u8; width * height * 3];
+
+        // Macroblocks are sent column by column
+        for mb in 0..mb_w * mb_h {
+            let mut block = Vec::with_capacity(16 * 16 * 3);
+
+            for _ in 0..16 * 16 * 3 / 4 {
+                // Status bit 31: output FIFO empty
+                if self.mdec.status() & (1 << 31) != 0 {
+                    return Err(anyhow!("MDEC produced fewer macroblocks than expected"));
+                }
+
+                block.extend_from_slice(&self.mdec.read_data().to_le_bytes());
+            }
+
+            let bx = (mb / mb_h) * 16;
+            let by = (mb % mb_h) * 16;
+
+            for y in 0..16 {
+                for x in 0..16 {
+                    let (px, py) = (bx + x, by + y);
+
+                    if px < width && py < height {
+                        let src = (y * 16 + x) * 3;
+                        let dst = (py * width + px) * 3;
+
+                        rgb[dst..dst + 3].copy_from_slice(&block[src..src + 3]);
+                    }
+                }
+            }
+        }
+
+        // Drop anything left over by padding
+        while self.mdec.status() & (1 << 31) == 0 {
+            self.mdec.read_data();
+        }
+
+        Ok(Frame {
+            width: width as u32,
+            height: height as u32,
+            rgb,
+        })
+    }
+}
+
+impl Options {
+    pub fn new(input: &Path) -> Self {
+        Options {
+            input: input.to_path_buf(),
+            file: None,
+            out_dir: PathBuf::from("str_out"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::bitstream::tests::frame;
+    use super::*;
+
+    /// 16x16 version 2 frame with DC only blocks
+    fn flat_frame(cr: i16, cb: i16, y: i16) -> Vec<u8> {
+        let block = |dc: i16| format!("{:010b} 10 ", dc as u16 & 0x3ff);
+
+        let mut bits = block(cr) + &block(cb);
+        for _ in 0..4 {
+            bits += &block(y);
+        }
+
+        frame(6, 1, 2, &bits)
+    }
+
+    #[test]
+    fn decodes_a_gray_frame() {
+        let mut player = Player::new();
+
+        let f = player.decode_frame(&flat_frame(0, 0, 0), 16, 16).unwrap();
+
+        assert_eq!((f.width, f.height), (16, 16));
+        assert!(f.rgb.iter().all(|&c| c == 128));
+        // Nothing left over for the next frame
+        assert!(player.mdec.status() & (1 << 31) != 0);
+    }
+
+    #[test]
+    fn decodes_a_uniform_color() {
+        let mut player = Player::new();
+
+        let f = player.decode_frame(&flat_frame(0, 0, 64), 16, 16).unwrap();
+        let first = [f.rgb[0], f.rgb[1], f.rgb[2]];
+
+        assert!(first[0] > 128 && first[0] == first[1] && first[1] == first[2]);
+        assert!(f.rgb.chunks(3).all(|p| p == first));
+
+        // Positive Cr makes it redder
+        let f = player.decode_frame(&flat_frame(64, 0, 0), 16, 16).unwrap();
+        assert!(f.rgb[0] > 128 && f.rgb[1] < 128);
+    }
+
+    #[test]
+    fn restarts_frames_whose_chunk_count_changes() {
+        let mut player = Player::new();
+
+        let sector = |chunk: u16, chunks: u16| {
+            let mut data = vec![0; 2048];
+
+            data[0..2].copy_from_slice(&0x0160u16.to_le_bytes());
+            data[2..4].copy_from_slice(&0x8001u16.to_le_bytes());
+            data[4..6].copy_from_slice(&chunk.to_le_bytes());
+            data[6..8].copy_from_slice(&chunks.to_le_bytes());
+            data
+        };
+
+        assert!(player.video_sector(&sector(0, 2)).unwrap().is_none());
+        assert!(player.video_sector(&sector(3, 4)).unwrap().is_none());
+        assert_eq!(player.pending.as_ref().unwrap().chunks.len(), 4);
+
+        assert!(player.video_sector(&sector(2, 2)).is_err());
+    }
+}
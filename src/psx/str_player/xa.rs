@@ -0,0 +1,241 @@
+/// Number of 128 bytes sound groups in an XA audio sector
+const SOUND_GROUPS: usize = 18;
+
+/// ADPCM prediction filters (positive, negative)
+const FILTERS: [(i32, i32); 4] = [(0, 0), (60, 0), (115, -52), (98, -55)];
+
+/// XA-ADPCM audio format, from the coding info byte of the subheader
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Coding {
+    pub stereo: bool,
+    pub sample_rate: u32,
+    /// 4 or 8 bits per sample
+    pub bits: u8,
+}
+
+impl Coding {
+    pub fn from_subheader(coding: u8) -> Coding {
+        Coding {
+            stereo: coding & 1 != 0,
+            sample_rate: match coding & 4 != 0 {
+                true => 18900,
+                false => 37800,
+            },
+            bits: match coding & 0x10 != 0 {
+                true => 8,
+                false => 4,
+            },
+        }
+    }
+
+    pub fn channels(&self) -> u16 {
+        match self.stereo {
+            true => 2,
+            false => 1,
+        }
+    }
+}
+
+/// XA-ADPCM decoder, keeps the filter history of each channel between sectors
+pub struct XaDecoder {
+    /// Last two decoded samples for each channel
+    history: [(i32, i32); 2],
+}
+
+impl XaDecoder {
+    pub fn new() -> Self {
+        XaDecoder {
+            history: [(0, 0); 2],
+        }
+    }
+
+    /// Decode the 18 sound groups of a Form 2 sector. Stereo samples are
+    /// returned interleaved
+    pub fn decode_sector(&mut self, data: &[u8], coding: Coding) -> Vec<i16> {
+        let mut out = Vec::new();
+
+        for group in data.chunks_exact(128).take(SOUND_GROUPS) {
+            self.decode_group(group, coding, &mut out);
+        }
+
+        out
+    }
+
+    fn decode_group(&mut self, group: &[u8], coding: Coding, out: &mut Vec<i16>) {
+        let units = match coding.bits {
+            4 => 8,
+            _ => 4,
+        };
+
+        if coding.stereo {
+            // Even units are the left channel, odd units the right one
+            for pair in 0..units / 2 {
+                let left = self.decode_unit(group, pair * 2, coding.bits, 0);
+                let right = self.decode_unit(group, pair * 2 + 1, coding.bits, 1);
+
+                for (l, r) in left.iter().zip(right.iter()) {
+                    out.push(*l);
+                    out.push(*r);
+                }
+            }
+        } else {
+            for unit in 0..units {
+                let samples = self.decode_unit(group, unit, coding.bits, 0);
+                out.extend_from_slice(&samples);
+            }
+        }
+    }
+
+    /// Decode the 28 samples of a sound unit
+    fn decode_unit(&mut self, group: &[u8], unit: usize, bits: u8, channel: usize) -> [i16; 28] {
+        let param = group[4 + unit];
+
+        // Samples are stored in the top bits of a 16 bit value and shifted
+        // right, the reserved values 13 to 15 act as 9
+        let shift = match param & 0xf {
+            s @ 0..=12 => s,
+            _ => 9,
+        };
+        let (pos, neg) = FILTERS[((param >> 4) & 3) as usize];
+
+        let (mut old, mut older) = self.history[channel];
+
+        let mut samples = [0i16; 28];
+
+        for (i, sample) in samples.iter_mut().enumerate() {
+            let data = match bits {
+                4 => {
+                    let byte = group[16 + i * 4 + unit / 2];
+                    let nibble = (byte >> ((unit & 1) * 4)) & 0xf;
+
+                    (((nibble as u16) << 12) as i16) as i32
+                }
+                _ => {
+                    let byte = group[16 + i * 4 + unit];
+
+                    (((byte as u16) << 8) as i16) as i32
+                }
+            };
+
+            let v = (data >> shift) + ((old * pos + older * neg + 32) >> 6);
+            let v = v.clamp(-0x8000, 0x7fff);
+
+            older = old;
+            old = v;
+
+            *sample = v as i16;
+        }
+
+        self.history[channel] = (old, older);
+
+        samples
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MONO_4BITS: Coding = Coding {
+        stereo: false,
+        sample_rate: 37800,
+        bits: 4,
+    };
+
+    /// Sound group where every sample of `unit` is `nibble`
+    fn group(unit: usize, param: u8, nibble: u8) -> Vec<u8> {
+        let mut group = vec![0; 128];
+
+        group[4 + unit] = param;
+
+        for i in 0..28 {
+            group[16 + i * 4 + unit / 2] |= nibble << ((unit & 1) * 4);
+        }
+
+        group
+    }
+
+    #[test]
+    fn decodes_without_filter() {
+        let mut xa = XaDecoder::new();
+
+        // Filter 0, shift 8: 0x1000 >> 8
+        let samples = xa.decode_sector(&group(0, 0x08, 0x1), MONO_4BITS);
+        assert_eq!(samples.len(), 8 * 28);
+        assert!(samples[..28].iter().all(|&s| s == 16));
+        assert!(samples[28..].iter().all(|&s| s == 0));
+
+        // -0x1000 >> 8
+        let samples = xa.decode_sector(&group(1, 0x08, 0xf), MONO_4BITS);
+        assert!(samples[28..56].iter().all(|&s| s == -16));
+
+        // Shifts 13 to 15 act as 9: 0x7000 >> 9
+        let samples = xa.decode_sector(&group(2, 0x0d, 0x7), MONO_4BITS);
+        assert!(samples[56..84].iter().all(|&s| s == 56));
+    }
+
+    #[test]
+    fn decodes_8bit_samples() {
+        let mut xa = XaDecoder::new();
+        let coding = Coding {
+            bits: 8,
+            ..MONO_4BITS
+        };
+
+        let mut data = vec![0; 128];
+        // Unit 1, filter 0, shift 4
+        data[5] = 0x04;
+        for i in 0..28 {
+            data[16 + i * 4 + 1] = 0xc0;
+        }
+
+        // 4 units of 28 samples, 0xc000 >> 4
+        let samples = xa.decode_sector(&data, coding);
+        assert_eq!(samples.len(), 4 * 28);
+        assert!(samples[28..56].iter().all(|&s| s == -0x400));
+        assert!(samples[..28].iter().all(|&s| s == 0));
+    }
+
+    #[test]
+    fn filter_history_carries_over() {
+        let mut xa = XaDecoder::new();
+
+        // Filter 1: 60/64 of the previous sample, on all the units
+        let mut data = group(0, 0x18, 0x1);
+        data[4..12].fill(0x18);
+        data[16..].fill(0x11);
+
+        // 16 + (old * 60 + 32) >> 6
+        let samples = xa.decode_sector(&data, MONO_4BITS);
+        assert_eq!(samples[..3], [16, 31, 45]);
+
+        // The next unit starts from the last samples of the previous one,
+        // even across sectors
+        let mut data = group(0, 0x10, 0x0);
+        data[5] = 0x10;
+
+        let last = samples[samples.len() - 1] as i32;
+
+        let samples = xa.decode_sector(&data, MONO_4BITS);
+        let expected = (last * 60 + 32) >> 6;
+        assert_eq!(samples[0] as i32, expected);
+        assert_eq!(samples[28] as i32, (samples[27] as i32 * 60 + 32) >> 6);
+    }
+
+    #[test]
+    fn stereo_interleaves_even_and_odd_units() {
+        let mut xa = XaDecoder::new();
+        let coding = Coding {
+            stereo: true,
+            ..MONO_4BITS
+        };
+
+        let mut data = group(0, 0x08, 0x1);
+        for (d, s) in data.iter_mut().zip(group(1, 0x08, 0xf)) {
+            *d |= s;
+        }
+
+        let samples = xa.decode_sector(&data, coding);
+        assert_eq!(samples[..4], [16, -16, 16, -16]);
+    }
+}
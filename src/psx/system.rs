@@ -0,0 +1,390 @@
+use anyhow::{anyhow, Result};
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use super::bios::{self, Bios, BiosCheck};
+use super::cpu::{Cpu, Registers};
+use super::disc::Disc;
+use super::dma::Dma;
+use super::exe::Exe;
+use super::expansion::Cartridge;
+use super::gpu::{Frame, Gpu};
+use super::hle::Kernel;
+use super::interconnect::Interconnect;
+use super::mdec::Mdec;
+use super::ram::Ram;
+use super::sio0::{DigitalPad, DualShock, GunCon, MemoryCard, Mouse, Multitap, Peripheral, Port, Sio0};
+use super::sio1::{Link, Sio1, TcpLink};
+use super::{Config, Controller, LinkConfig, PortConfig, Region, Slot};
+
+/// CPU clock frequency
+pub const CPU_CLOCK_HZ: u32 = 33_868_800;
+
+/// Output rate of the sound processor, in stereo frames per second
+pub const AUDIO_SAMPLE_RATE: u32 = 44_100;
+
+/// Entry point of the BIOS shell. Side-loaded executables are started
+/// instead of it, once the kernel is initialized
+pub const SHELL_ENTRY: u32 = 0x80030000;
+
+/// Builds a `System` from a BIOS image and the software to run
+pub struct SystemBuilder {
+    bios: Option<Vec<u8>>,
+    disc: Option<PathBuf>,
+    exe: Option<Vec<u8>>,
+    config: Config,
+}
+
+impl SystemBuilder {
+    /// 512KiB BIOS image, required unless the HLE BIOS is used
+    pub fn bios(mut self, bios: Vec<u8>) -> SystemBuilder {
+        self.bios = Some(bios);
+        self
+    }
+
+    /// Disc image to boot. The CD-ROM drive isn't emulated yet, the
+    /// executable named in the SYSTEM.CNF of the disc is side-loaded instead
+    pub fn disc(mut self, path: &Path) -> SystemBuilder {
+        self.disc = Some(path.to_path_buf());
+        self
+    }
+
+    /// PS-X EXE to side-load, takes precedence over the disc executable
+    pub fn exe(mut self, exe: Vec<u8>) -> SystemBuilder {
+        self.exe = Some(exe);
+        self
+    }
+
+    pub fn config(mut self, config: Config) -> SystemBuilder {
+        self.config = config;
+        self
+    }
+
+    pub fn build(self) -> Result<System> {
+        let config = &self.config;
+
+        let mut bios = match (config.hle_bios, self.bios) {
+            (true, _) => Bios::hle(),
+            (false, Some(bios)) => Bios::new(bios)?,
+            (false, None) => return Err(anyhow!("No BIOS image")),
+        };
+
+        if config.fast_boot {
+            bios.patch(&bios::FAST_BOOT)?;
+        }
+
+        if config.bios_tty {
+            bios.patch(&bios::TTY)?;
+        }
+
+        let bios_check = bios.check();
+
+        let kernel = match (config.hle_bios, &self.disc) {
+            (true, Some(disc)) => Some(Kernel::new(Some(Disc::open(disc)?))),
+            (true, None) => Some(Kernel::new(None)),
+            (false, _) => None,
+        };
+
+        let exe = match (self.exe, &self.disc) {
+            (Some(exe), _) => Some(Exe::parse(&exe)?),
+            (None, Some(disc)) => Some(Exe::parse(&boot_exe(&mut Disc::open(disc)?)?)?),
+            (None, None) => None,
+        };
+
+        let ram = match config.dev_kit {
+            true => Ram::dev_kit(),
+            false => Ram::new(),
+        };
+        let dma  = Dma::new();
+        let mdec = Mdec::new();
+        let gpu  = Gpu::new();
+
+        let mut sio0 = Sio0::new();
+
+        for (i, port_config) in config.ports.iter().enumerate() {
+            let port = sio0.port_mut(i);
+
+            match port_config {
+                PortConfig::Direct(slot) => plug(port, slot)?,
+                PortConfig::Multitap(slots) => {
+                    let mut tap = Multitap::new();
+
+                    for (j, slot) in slots.iter().enumerate() {
+                        plug(tap.slot_mut(j), slot)?;
+                    }
+
+                    port.set_controller(Some(Box::new(tap)));
+                }
+            }
+        }
+
+        let sio1 = Sio1::new(tcp_link(config.link)?);
+
+        let mut inter = Interconnect::new(Some(bios), ram, dma, mdec, gpu, sio0, sio1);
+
+        match &config.expansion_rom {
+            Some(path) => {
+                let mut cartridge = Cartridge::new(path, config.cartridge_switch)?;
+                cartridge.set_link(tcp_link(config.parallel_link)?);
+
+                inter.plug_cartridge(cartridge);
+            }
+            None if config.parallel_link.is_some() => {
+                return Err(anyhow!("The parallel port link needs an expansion ROM"));
+            }
+            None => (),
+        }
+
+        let mut cpu = Cpu::new(inter);
+        cpu.set_strict(config.strict);
+
+        Ok(System {
+            cpu,
+            kernel,
+            bios_check,
+            exe,
+            region: config.region,
+            audio_cycles: 0,
+            tty: String::new(),
+        })
+    }
+}
+
+/// Emulated console
+pub struct System {
+    cpu: Cpu,
+    /// Kernel standing in for the BIOS in HLE mode
+    kernel: Option<Kernel>,
+    /// What the BIOS image was identified as, None with the HLE kernel
+    bios_check: Option<BiosCheck>,
+    /// Executable waiting for the BIOS to reach its shell
+    exe: Option<Exe>,
+    /// Forced frame rate, see `Config::region`
+    region: Option<Region>,
+    /// Cycles already turned into audio samples
+    audio_cycles: u64,
+    /// Characters printed through the BIOS since the last `take_tty`
+    tty: String,
+}
+
+impl System {
+    pub fn builder() -> SystemBuilder {
+        SystemBuilder {
+            bios: None,
+            disc: None,
+            exe: None,
+            config: Config::new(),
+        }
+    }
+
+    /// Run a single CPU instruction. Errors when strict mode stopped the
+    /// emulation
+    pub fn step(&mut self) -> Result<()> {
+        self.capture_tty();
+
+        if self.cpu.pc() == SHELL_ENTRY {
+            if let Some(exe) = self.exe.take() {
+                exe.start(&mut self.cpu, 0);
+            }
+        }
+
+        if let Some(kernel) = &mut self.kernel {
+            if kernel.intercept(&mut self.cpu, &mut self.tty) {
+                return Ok(());
+            }
+        }
+
+        self.cpu.run_next_instruction()
+    }
+
+    /// Run for the duration of one video frame
+    pub fn run_frame(&mut self) -> Result<()> {
+        let end = self.cycles() + self.frame_cycles();
+
+        while self.cycles() < end {
+            self.step()?;
+        }
+
+        Ok(())
+    }
+
+    /// CPU cycles in a video frame
+    pub fn frame_cycles(&self) -> u64 {
+        let pal = match self.region {
+            Some(region) => region == Region::Pal,
+            None => self.cpu.interconnect().gpu().display().pal,
+        };
+
+        let refresh = match pal {
+            true => 50,
+            false => 60,
+        };
+
+        (CPU_CLOCK_HZ / refresh) as u64
+    }
+
+    /// What the BIOS image was identified as, None with the HLE kernel
+    pub fn bios_check(&self) -> Option<BiosCheck> {
+        self.bios_check
+    }
+
+    /// CPU cycles elapsed since reset
+    pub fn cycles(&self) -> u64 {
+        self.cpu.interconnect().cycles()
+    }
+
+    /// Picture currently displayed
+    pub fn framebuffer(&self) -> Frame {
+        self.cpu.interconnect().gpu().framebuffer()
+    }
+
+    /// Interleaved stereo samples produced since the last call, at
+    /// `AUDIO_SAMPLE_RATE`. The SPU isn't emulated yet so they are silent
+    pub fn take_audio_samples(&mut self) -> Vec<i16> {
+        let cycles_per_sample = (CPU_CLOCK_HZ / AUDIO_SAMPLE_RATE) as u64;
+
+        let frames = (self.cycles() - self.audio_cycles) / cycles_per_sample;
+        self.audio_cycles += frames * cycles_per_sample;
+
+        vec![0; frames as usize * 2]
+    }
+
+    /// Main RAM contents
+    pub fn ram(&self) -> &[u8] {
+        self.cpu.interconnect().ram().data()
+    }
+
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        self.cpu.interconnect_mut().ram_mut().data_mut()
+    }
+
+    pub fn registers(&self) -> Registers {
+        self.cpu.registers()
+    }
+
+    /// Text printed by the software through the BIOS since the last call
+    pub fn take_tty(&mut self) -> String {
+        std::mem::take(&mut self.tty)
+    }
+
+    /// Write every executed instruction, disassembled, to `trace`
+    pub fn set_trace(&mut self, trace: Option<Box<dyn io::Write>>) {
+        self.cpu.set_trace(trace);
+    }
+
+    /// Controller port `port` (0 or 1), to feed it host input
+    pub fn port(&mut self, port: usize) -> &mut Port {
+        self.cpu.interconnect_mut().pad_port(port)
+    }
+
+    /// Save the memory cards, call it once per frame. Cards are written
+    /// once the guest stops writing to them for a while, or right away with
+    /// `force` (before exiting)
+    pub fn flush_memory_cards(&mut self, force: bool) -> Result<()> {
+        for port in 0..2 {
+            self.port(port).flush(force)?;
+        }
+
+        Ok(())
+    }
+
+    /// Catch the BIOS putchar calls: A(0x3c) and B(0x3d), character in $a0
+    fn capture_tty(&mut self) {
+        let pc = self.cpu.pc() & 0x1fffffff;
+
+        if pc != 0xa0 && pc != 0xb0 {
+            return;
+        }
+
+        let r = self.cpu.registers();
+
+        // $t1 holds the function number
+        if (pc, r.regs[9]) == (0xa0, 0x3c) || (pc, r.regs[9]) == (0xb0, 0x3d) {
+            self.tty.push(r.regs[4] as u8 as char);
+        }
+    }
+
+}
+
+fn tcp_link(config: Option<LinkConfig>) -> Result<Option<Box<dyn Link>>> {
+    Ok(match config {
+        None => None,
+        Some(LinkConfig::Listen(port)) => Some(Box::new(TcpLink::listen(port)?)),
+        Some(LinkConfig::Connect(port)) => Some(Box::new(TcpLink::connect(port))),
+    })
+}
+
+/// Executable named by the `BOOT` line of the disc SYSTEM.CNF, or
+/// PSX.EXE for discs without one
+fn boot_exe(disc: &mut Disc) -> Result<Vec<u8>> {
+    let path = boot_path(disc)?;
+    let file = disc.find_file(&path)?;
+
+    disc.read_file(&file)
+}
+
+/// Path of the executable the BIOS boots from the disc
+fn boot_path(disc: &mut Disc) -> Result<String> {
+    let cnf = match disc.find_file("SYSTEM.CNF") {
+        Ok(cnf) => disc.read_file(&cnf)?,
+        Err(_) => return Ok("PSX.EXE".to_string()),
+    };
+    let cnf = String::from_utf8_lossy(&cnf);
+
+    let boot = cnf
+        .lines()
+        .filter_map(|l| l.split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case("BOOT"))
+        .map(|(_, value)| value.trim().to_string())
+        .ok_or_else(|| anyhow!("No BOOT entry in SYSTEM.CNF"))?;
+
+    // cdrom:\SLUS_000.01;1
+    let boot = boot.trim_start_matches("cdrom:");
+
+    Ok(boot.split(';').next().unwrap_or("").to_string())
+}
+
+/// Region of the disc at `path`, from its license sector or failing that
+/// from the product code of its executable
+pub fn disc_region(path: &Path) -> Result<Option<Region>> {
+    let mut disc = Disc::open(path)?;
+
+    if let Some(region) = disc.license_region() {
+        return Ok(Some(region));
+    }
+
+    let Ok(boot) = boot_path(&mut disc) else {
+        return Ok(None);
+    };
+
+    let name = boot.rsplit(['/', '\\']).next().unwrap_or("").to_ascii_uppercase();
+
+    let region = match name.get(..4) {
+        Some("SLUS" | "SCUS") => Some(Region::NtscU),
+        Some("SLES" | "SCES" | "SCED" | "SLED") => Some(Region::Pal),
+        Some("SLPS" | "SCPS" | "SLPM" | "SCPM" | "SIPS" | "PAPX") => Some(Region::NtscJ),
+        _ => None,
+    };
+
+    Ok(region)
+}
+
+/// Connect the devices of `slot` to `port`
+fn plug(port: &mut Port, slot: &Slot) -> Result<()> {
+    let pad: Option<Box<dyn Peripheral>> = match slot.controller {
+        Controller::None      => None,
+        Controller::Digital   => Some(Box::new(DigitalPad::new())),
+        Controller::DualShock => Some(Box::new(DualShock::new())),
+        Controller::Mouse     => Some(Box::new(Mouse::new())),
+        Controller::GunCon    => Some(Box::new(GunCon::new())),
+    };
+    port.set_controller(pad);
+
+    if let Some(path) = &slot.memory_card {
+        port.set_memory_card(Some(Box::new(MemoryCard::open(path)?)));
+    }
+
+    Ok(())
+}